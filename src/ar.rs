@@ -0,0 +1,68 @@
+use std::{fs, path::Path};
+
+use clap::Args;
+
+use crate::common::{Archive, ArchiveMember, ObjectModule};
+
+#[derive(Args, Clone)]
+#[command(about = "Collect object modules into a library archive.
+When linking, rlink only uses the members which define symbols other modules need
+")]
+pub struct ArArgs {
+    #[arg(
+        short = 't',
+        help = "List the members of the archive and the symbols they define, instead of creating it"
+    )]
+    list: bool,
+    archive: String,
+    files: Vec<String>,
+}
+
+fn create(args: &ArArgs) -> Result<(), String> {
+    if args.files.is_empty() {
+        return Err(String::from("no object modules given"));
+    }
+    let members = args
+        .files
+        .iter()
+        .map(|f| {
+            let data = fs::read(f).map_err(|e| format!("cannot read `{}`: {}", f, e))?;
+            if data.len() < 52 {
+                return Err(format!("`{}` is not an object module", f));
+            }
+            let module =
+                ObjectModule::from_slice_u8(&data).map_err(|e| format!("`{}`: {}", f, e))?;
+            let name = Path::new(f)
+                .file_name()
+                .map_or(f.clone(), |n| n.to_string_lossy().into_owned());
+            Ok(ArchiveMember { name, module })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let archive = Archive::new(members);
+    fs::write(&args.archive, archive.to_bytes())
+        .map_err(|e| format!("cannot write `{}`: {}", args.archive, e))
+}
+
+fn list(args: &ArArgs) -> Result<(), String> {
+    let data =
+        fs::read(&args.archive).map_err(|e| format!("cannot read `{}`: {}", args.archive, e))?;
+    let archive = Archive::from_bytes(&data).map_err(|e| format!("`{}`: {}", args.archive, e))?;
+    for (i, member) in archive.members.iter().enumerate() {
+        println!("{}", member.name);
+        for sym in archive.index.iter().filter(|s| s.member as usize == i) {
+            println!("    {}", sym.name);
+        }
+    }
+    Ok(())
+}
+
+pub fn ar(args: &ArArgs) {
+    let res = match args.list {
+        true => list(args),
+        false => create(args),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
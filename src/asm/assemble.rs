@@ -0,0 +1,1251 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    ops::Range,
+};
+
+use super::{
+    encode::{encode, find_grammar, Emit, FixupKind, GrammarError},
+    expr::{AddrPart, BinOp, EvalError, Expr, Value},
+    lint::{lint, Lint, LintLine},
+    parse::{parse_line, recover_labels, Directive, Line, Operand, Stmt},
+    pseudo, AsmError, AsmOptions, Grammar, SourceLine,
+};
+use crate::{
+    common::{
+        instruction::opcodes::*, Instruction, LineEntry, LineInfo, Location, ObjectHeader,
+        ObjectModule, RefEntry, RefInfo, RefType, RefUnknown, RelEntry, RelType, StrTabBuilder,
+        SymEntry, SYM_DEF, SYM_EQ, SYM_FORW, SYM_GLB, SYM_LBL, SYM_LIT, SYM_WEAK,
+    },
+    sim::PAGE_SIZE,
+};
+
+#[derive(Clone, Debug)]
+enum SymValue {
+    /// Offset of a label within a section
+    Label(Location, u32),
+    /// Value assigned with `name = expr`
+    Equate(Value),
+}
+
+#[derive(Clone, Debug)]
+struct Symbol {
+    value: SymValue,
+    src: SourceLine,
+}
+
+/// A value which still needs to be patched into a section once all symbols
+/// are known.
+#[derive(Clone, Debug)]
+struct Fixup {
+    sect: Location,
+    offset: u32,
+    kind: FixupKind,
+    expr: Expr,
+    src: SourceLine,
+    /// Operands the value came from, for diagnostics
+    span: Option<Range<usize>>,
+    /// Source file the value should be evaluated in
+    unit: usize,
+    /// Index of the source line, and of the instruction within that line's
+    /// expansion, which produced this fixup
+    site: (usize, usize),
+}
+
+/// Span of section contents produced by one source line, used to build the
+/// listing file.
+#[derive(Clone, Debug)]
+struct ListEntry {
+    sect: Location,
+    offset: u32,
+    len: u32,
+    src: SourceLine,
+    /// Whether the line was a pseudo-instruction, in which case each word it
+    /// expanded to is listed individually
+    pseudo: bool,
+}
+
+/// `.comm` and `.lcomm` blocks of at most this many bytes go in sbss rather
+/// than bss.
+const SMALL_DATA_SIZE: i64 = 8;
+
+/// Single pass assembler. Code and data are emitted as lines are processed,
+/// with any operand that cannot be evaluated immediately recorded as a
+/// `Fixup` and patched once the whole program has been seen.
+pub struct Assembler {
+    sections: [Vec<u8>; 4],
+    /// Sizes of the sbss and bss sections, which have no contents
+    uninit: [u32; 2],
+    current: Location,
+    /// Symbols defined by each source file being assembled. Ordered, so
+    /// the symbol table is the same from one run to the next.
+    symbols: Vec<BTreeMap<String, Symbol>>,
+    /// Names declared `.globl` (or `.weak`) by each source file
+    globals: Vec<BTreeSet<String>>,
+    /// Names declared `.weak` by each source file
+    weak: Vec<BTreeSet<String>>,
+    /// Names each source file refers to before defining them
+    forward: Vec<HashSet<String>>,
+    /// Index of the source file currently being assembled
+    unit: usize,
+    fixups: Vec<Fixup>,
+    rel_info: Vec<RelEntry>,
+    /// References to external symbols, by name. The string table offset and
+    /// symbol index are filled in once the tables are built.
+    ext_ref: Vec<(String, RefEntry)>,
+    listing: Vec<ListEntry>,
+    opts: AsmOptions,
+    /// Branches which were out of range on a previous attempt, and are
+    /// emitted as an inverted branch over a `j`
+    relaxed: HashSet<(usize, usize)>,
+    /// Site of the instruction currently being emitted
+    site: (usize, usize),
+    /// `.set reorder`: fill delay slots after branches, jumps and loads
+    reorder: bool,
+    /// `.set noat`: the program is using `$at`, so pseudo-instructions
+    /// which need it are warned about
+    noat: bool,
+    /// Align `.half` and `.word` to their size. Turned off by `.align 0`
+    /// until the next section directive.
+    auto_align: bool,
+    /// Labels on lines of their own at the current location, which move
+    /// with it if it is aligned
+    bare_labels: Vec<String>,
+    warnings: Vec<AsmError>,
+    errors: Vec<AsmError>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new(&AsmOptions::default())
+    }
+}
+
+impl Assembler {
+    pub fn new(opts: &AsmOptions) -> Self {
+        Self {
+            sections: Default::default(),
+            uninit: [0; 2],
+            current: Location::TEXT,
+            symbols: vec![BTreeMap::new()],
+            globals: vec![BTreeSet::new()],
+            weak: vec![BTreeSet::new()],
+            forward: vec![HashSet::new()],
+            unit: 0,
+            fixups: vec![],
+            rel_info: vec![],
+            ext_ref: vec![],
+            listing: vec![],
+            opts: opts.clone(),
+            relaxed: HashSet::new(),
+            site: (0, 0),
+            reorder: true,
+            noat: false,
+            auto_align: true,
+            bare_labels: vec![],
+            warnings: vec![],
+            errors: vec![],
+        }
+    }
+
+    /// Assembles preprocessed source lines. The resulting object module is
+    /// built with `finish`.
+    pub fn assemble(&mut self, lines: Vec<SourceLine>) -> Result<(), Vec<AsmError>> {
+        self.assemble_files(vec![lines])
+    }
+
+    /// Assembles several preprocessed source files into a single module.
+    /// Symbols are local to the file which defines them, unless that file
+    /// declares them `.globl`.
+    ///
+    /// A line with an error is skipped and assembly carries on, so that every
+    /// error (up to `AsmOptions::max_errors`) is reported at once.
+    pub fn assemble_files(&mut self, files: Vec<Vec<SourceLine>>) -> Result<(), Vec<AsmError>> {
+        let mut parse_errors = vec![];
+        let files = files
+            .into_iter()
+            .map(|lines| {
+                lines
+                    .into_iter()
+                    .map(|l| {
+                        parse_line(l.clone(), &self.opts).unwrap_or_else(|e| {
+                            parse_errors.push(e);
+                            recover_labels(l)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let limit = match self.opts.max_errors {
+            0 => usize::MAX,
+            n => n,
+        };
+        loop {
+            self.errors = parse_errors.clone();
+            self.symbols = vec![BTreeMap::new(); files.len()];
+            self.globals = vec![BTreeSet::new(); files.len()];
+            self.weak = vec![BTreeSet::new(); files.len()];
+            self.forward = vec![HashSet::new(); files.len()];
+            let mut idx = 0;
+            for (unit, lines) in files.iter().enumerate() {
+                self.unit = unit;
+                self.current = Location::TEXT;
+                self.reorder = true;
+                self.noat = false;
+                self.auto_align = true;
+                self.bare_labels.clear();
+                for line in lines {
+                    self.site = (idx, 0);
+                    if let Err(e) = self.line(line) {
+                        self.errors.push(e);
+                    }
+                    idx += 1;
+                }
+            }
+            let too_far = self.resolve_fixups();
+            // report in source order rather than the order of the passes
+            let mut order = vec![];
+            for l in files.iter().flatten() {
+                if !order.contains(&&l.src.file) {
+                    order.push(&l.src.file);
+                }
+            }
+            let by_source = |e: &AsmError| (order.iter().position(|f| **f == e.file), e.line);
+            if !self.errors.is_empty() {
+                let mut errors = std::mem::take(&mut self.errors);
+                errors.sort_by_key(by_source);
+                errors.truncate(limit);
+                return Err(errors);
+            }
+            if too_far.is_empty() {
+                self.lint(&files);
+                self.warnings.sort_by_key(by_source);
+                return Ok(());
+            }
+
+            // relaxing a branch moves everything after it, so start over
+            // with the new set of relaxed branches
+            let relaxed = std::mem::take(&mut self.relaxed);
+            *self = Assembler {
+                relaxed: relaxed.into_iter().chain(too_far).collect(),
+                ..Assembler::new(&self.opts)
+            };
+        }
+    }
+
+    fn sect(&mut self) -> &mut Vec<u8> {
+        &mut self.sections[self.current as usize]
+    }
+
+    fn line(&mut self, line: &Line) -> Result<(), AsmError> {
+        // align before defining the label, so it points at the data
+        match &line.stmt {
+            Some(Stmt::Directive(Directive::Half(_))) => self.align_data(2),
+            Some(Stmt::Directive(Directive::Word(_))) => self.align_data(4),
+            _ => {}
+        }
+        let sect = self.current;
+        let start = self.sect().len() as u32;
+        let mut is_pseudo = false;
+
+        for label in &line.labels {
+            let offset = self.sect().len() as u32;
+            // keep going with the rest of the line, so the addresses after
+            // it are still right
+            if let Err(e) = self.define(label, SymValue::Label(self.current, offset), &line.src) {
+                self.errors.push(e);
+            }
+        }
+        match &line.stmt {
+            None => self.bare_labels.extend(line.labels.iter().cloned()),
+            Some(_) => self.bare_labels.clear(),
+        }
+
+        let src = &line.src;
+        match &line.stmt {
+            None => {}
+            Some(Stmt::Assign(name, expr)) => {
+                let value = expr
+                    .eval(&|s| self.lookup(self.unit, s))
+                    .map_err(|e| eval_error(src, None, e))?;
+                self.define(name, SymValue::Equate(value), src)?;
+            }
+            Some(Stmt::Directive(d)) => self.directive(d, src)?,
+            Some(Stmt::Instr {
+                mnemonic,
+                operands,
+                mnemonic_span,
+                operand_spans,
+            }) => {
+                if self.current != Location::TEXT {
+                    return Err(src.error_at(
+                        mnemonic_span.clone(),
+                        "instructions must be placed in the text section",
+                    ));
+                }
+                // span covering all of the operands, or the mnemonic if there
+                // are none
+                let span = match (operand_spans.first(), operand_spans.last()) {
+                    (Some(first), Some(last)) => first.start..last.end,
+                    _ => mnemonic_span.clone(),
+                };
+                let grammar = find_grammar(mnemonic, operands).map_err(|e| match e {
+                    GrammarError::UnknownMnemonic(_) => src.error_at(mnemonic_span.clone(), e),
+                    GrammarError::Operands(_) => src.error_at(span.clone(), e),
+                })?;
+                // a load or store whose displacement is an address (or may
+                // be, as it is not known yet) rather than a constant offset.
+                // `%lo(label)` is already the low half of one.
+                let addr_disp = match (grammar, operands.as_slice()) {
+                    (Grammar::LoadStoreOff, [Operand::Reg(rt), Operand::Disp(off, base)])
+                        if !matches!(off, Expr::Part(..))
+                            && !matches!(
+                                off.eval(&|s| self.lookup(self.unit, s)),
+                                Ok(Value::Abs(_))
+                            ) =>
+                    {
+                        Some((*rt, off, *base))
+                    }
+                    _ => None,
+                };
+                is_pseudo = grammar.is_pseudo() || addr_disp.is_some();
+                if mnemonic == "lea" {
+                    self.warn(
+                        Lint::Deprecated,
+                        src,
+                        Some(mnemonic_span.clone()),
+                        String::from("`lea` is deprecated, use `la`"),
+                    );
+                }
+                let insts = if let Some((rt, addr, base)) = addr_disp {
+                    pseudo::load_store_addr(mnemonic, rt, addr, base)
+                } else if is_pseudo {
+                    pseudo::expand(mnemonic, grammar, operands, &|e| {
+                        e.eval(&|s| self.lookup(self.unit, s)).ok()
+                    })
+                } else {
+                    encode(mnemonic, grammar, operands).map(|i| vec![i])
+                }
+                .map_err(|e| src.error_at(span.clone(), e))?;
+
+                let uses_at = |op: &Operand| matches!(op, Operand::Reg(1) | Operand::Disp(_, 1));
+                if self.noat
+                    && insts.iter().any(|i| uses_register(&i.inst, 1))
+                    && !operands.iter().any(uses_at)
+                {
+                    self.warn(
+                        Lint::Noat,
+                        src,
+                        Some(mnemonic_span.clone()),
+                        format!("`{}` uses $at after `.set noat`", mnemonic),
+                    );
+                }
+                let mut insts = insts;
+                if self.reorder && insts.last().is_some_and(|i| has_delay_slot(&i.inst)) {
+                    // nop added by assembler
+                    insts.push(Emit {
+                        inst: Instruction::R {
+                            rs: 0,
+                            rt: 0,
+                            rd: 0,
+                            shamt: 0,
+                            funct: FUNCT_SLL,
+                        },
+                        fixup: None,
+                    });
+                    is_pseudo = true;
+                }
+
+                // relaxed branches are listed like pseudo-instructions
+                is_pseudo |= (0..insts.len()).any(|i| self.relaxed.contains(&(self.site.0, i)));
+                for inst in insts {
+                    // point diagnostics for the fixup at the operand it came
+                    // from, if it is still recognisable after expansion
+                    let span = inst
+                        .fixup
+                        .as_ref()
+                        .and_then(|(_, e)| {
+                            operands.iter().position(|op| match op {
+                                Operand::Expr(x) | Operand::Disp(x, _) => x == e,
+                                Operand::Reg(_) => false,
+                            })
+                        })
+                        .map_or(span.clone(), |i| operand_spans[i].clone());
+                    self.emit(inst, src, &span);
+                }
+            }
+        }
+
+        let (offset, len) = if self.current == sect {
+            (start, self.sect().len() as u32 - start)
+        } else {
+            (self.sect().len() as u32, 0)
+        };
+        self.listing.push(ListEntry {
+            sect: self.current,
+            offset,
+            len,
+            src: line.src.clone(),
+            pseudo: is_pseudo,
+        });
+        Ok(())
+    }
+
+    fn directive(&mut self, d: &Directive, src: &SourceLine) -> Result<(), AsmError> {
+        match d {
+            Directive::Section(loc) => {
+                self.current = *loc;
+                self.auto_align = true;
+            }
+            Directive::Ascii(strings) => {
+                for s in strings {
+                    self.sect().extend_from_slice(s);
+                }
+            }
+            Directive::Byte(exprs) => self.data(exprs, 1, FixupKind::Byte, src),
+            Directive::Half(exprs) => self.data(exprs, 2, FixupKind::Half, src),
+            Directive::Word(exprs) => self.data(exprs, 4, FixupKind::Word, src),
+            Directive::Space(expr) => {
+                let n = self.abs(expr, src)?;
+                if n < 0 {
+                    return Err(src.error("`.space` size cannot be negative"));
+                }
+                let len = self.sect().len() + n as usize;
+                self.sect().resize(len, 0);
+            }
+            Directive::Align(expr) => {
+                let n = self.abs(expr, src)?;
+                if !(0..=16).contains(&n) {
+                    return Err(src.error(format!("invalid alignment {}", n)));
+                }
+                if n == 0 {
+                    self.auto_align = false;
+                }
+                let len = self.sect().len().next_multiple_of(1 << n);
+                self.sect().resize(len, 0);
+            }
+            Directive::Org(expr) => {
+                let target = match expr.eval(&|s| self.lookup(self.unit, s)) {
+                    Ok(Value::Abs(v)) => v,
+                    Ok(Value::Rel(sect, off)) if sect == self.current => off as i64,
+                    Ok(Value::Rel(..)) => {
+                        return Err(src.error("`.org` target is in another section"))
+                    }
+                    Err(e) => return Err(eval_error(src, None, e)),
+                };
+                let len = self.sect().len() as i64;
+                if target < len {
+                    return Err(src.error(format!(
+                        "`.org` cannot move the location counter backwards (from {:#x} to {:#x})",
+                        len, target
+                    )));
+                }
+                self.sect().resize(target as usize, 0);
+            }
+            Directive::Globl(name) => {
+                self.globals[self.unit].insert(name.clone());
+            }
+            Directive::Weak(name) => {
+                self.globals[self.unit].insert(name.clone());
+                self.weak[self.unit].insert(name.clone());
+            }
+            Directive::Set(opt) => match opt.as_str() {
+                "reorder" => self.reorder = true,
+                "noreorder" => self.reorder = false,
+                "at" => self.noat = false,
+                "noat" => self.noat = true,
+                _ => return Err(src.error(format!("unknown `.set` option `{}`", opt))),
+            },
+            Directive::Comm(name, size, align) | Directive::Lcomm(name, size, align) => {
+                let size = self.abs(size, src)?;
+                if size < 0 {
+                    return Err(src.error("`.comm` size cannot be negative"));
+                }
+                let align = match align {
+                    Some(e) => self.abs(e, src)?,
+                    None => 4,
+                };
+                if align <= 0 || align & (align - 1) != 0 {
+                    return Err(src.error(format!("invalid alignment {}", align)));
+                }
+
+                let sect = match size <= SMALL_DATA_SIZE {
+                    true => Location::SBSS,
+                    false => Location::BSS,
+                };
+                let len = &mut self.uninit[sect as usize - Location::SBSS as usize];
+                let offset = len.next_multiple_of(align as u32);
+                *len = offset + size as u32;
+                self.define(name, SymValue::Label(sect, offset), src)?;
+                if matches!(d, Directive::Comm(..)) {
+                    self.globals[self.unit].insert(name.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pads the current section to a multiple of `size` for a `.half` or
+    /// `.word`, unless turned off with `.align 0`. Labels just before the
+    /// directive are moved to the aligned location.
+    fn align_data(&mut self, size: usize) {
+        let len = self.sect().len();
+        let aligned = len.next_multiple_of(size);
+        if !self.auto_align || aligned == len {
+            return;
+        }
+        self.sect().resize(aligned, 0);
+        for name in &self.bare_labels {
+            let sym = self.symbols[self.unit].get_mut(name);
+            if let Some(Symbol {
+                value: SymValue::Label(sect, off),
+                ..
+            }) = sym
+            {
+                if *sect == self.current && *off as usize == len {
+                    *off = aligned as u32;
+                }
+            }
+        }
+    }
+
+    /// Emits `size` bytes for each expression, to be filled in later.
+    fn data(&mut self, exprs: &[Expr], size: usize, kind: FixupKind, src: &SourceLine) {
+        for e in exprs {
+            let offset = self.sect().len() as u32;
+            let len = self.sect().len() + size;
+            self.sect().resize(len, 0);
+            self.mark_forward(e);
+            self.fixups.push(Fixup {
+                sect: self.current,
+                offset,
+                kind,
+                expr: e.clone(),
+                src: src.clone(),
+                span: None,
+                unit: self.unit,
+                site: self.site,
+            });
+        }
+    }
+
+    fn emit(&mut self, inst: Emit, src: &SourceLine, span: &Range<usize>) {
+        let site = self.site;
+        self.site.1 += 1;
+        let insts = match self.relaxed.contains(&site) {
+            true => relax_branch(inst),
+            false => vec![inst],
+        };
+
+        for inst in insts {
+            let offset = self.sect().len() as u32;
+            let word: u32 = inst.inst.into();
+            self.sect().extend_from_slice(&word.to_be_bytes());
+            if let Some((kind, expr)) = inst.fixup {
+                self.mark_forward(&expr);
+                self.fixups.push(Fixup {
+                    sect: self.current,
+                    offset,
+                    kind,
+                    expr,
+                    src: src.clone(),
+                    span: Some(span.clone()),
+                    unit: self.unit,
+                    site,
+                });
+            }
+        }
+    }
+
+    /// Runs the analysis pass over the assembled program, adding anything it
+    /// finds to the warnings.
+    fn lint(&mut self, files: &[Vec<Line>]) {
+        if self.opts.lints.is_empty() {
+            return;
+        }
+        // the listing has one entry per source line, in order
+        let mut entries = self.listing.iter();
+        let units = files
+            .iter()
+            .map(|lines| {
+                lines
+                    .iter()
+                    .zip(entries.by_ref())
+                    .map(|(line, e)| LintLine {
+                        line,
+                        sect: e.sect,
+                        // data placed in the text is not analysed as code
+                        insts: match (e.sect, &line.stmt) {
+                            (Location::TEXT, Some(Stmt::Instr { .. })) => self.sections[0]
+                                [e.offset as usize..(e.offset + e.len) as usize]
+                                .chunks(4)
+                                .filter_map(|w| {
+                                    Instruction::try_from(u32::from_be_bytes(w.try_into().unwrap()))
+                                        .ok()
+                                })
+                                .collect(),
+                            _ => vec![],
+                        },
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let warnings = lint(&units, &self.globals, &self.opts.lints);
+        self.warnings.extend(warnings);
+    }
+
+    /// Records the symbols in `expr` which the current file has not defined
+    /// yet.
+    fn mark_forward(&mut self, expr: &Expr) {
+        let (symbols, forward) = (&self.symbols[self.unit], &mut self.forward[self.unit]);
+        expr.for_each_sym(&mut |s| {
+            if !symbols.contains_key(s) {
+                forward.insert(s.to_string());
+            }
+        });
+    }
+
+    /// Adds a symbol to the current file. Only symbols assigned with
+    /// `name = value` may be given a new value, and only with
+    /// `allow_redefinition`.
+    fn define(&mut self, name: &str, value: SymValue, src: &SourceLine) -> Result<(), AsmError> {
+        if let Some(prev) = self.symbols[self.unit].get(name) {
+            let both_equates = matches!(
+                (&prev.value, &value),
+                (SymValue::Equate(_), SymValue::Equate(_))
+            );
+            if !(both_equates && self.opts.allow_redefinition) {
+                let col = prev
+                    .src
+                    .find(name)
+                    .map_or(1, |r| prev.src.text[..r.start].chars().count() + 1);
+                return Err(error_near(
+                    src,
+                    src.find(name),
+                    format!(
+                        "`{}` is already defined at {}:{}:{}",
+                        name, prev.src.file, prev.src.line, col
+                    ),
+                )
+                .with_code("redefined"));
+            }
+        }
+        self.symbols[self.unit].insert(
+            name.to_string(),
+            Symbol {
+                value,
+                src: src.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Evaluates an expression which must be absolute and defined now.
+    fn abs(&self, expr: &Expr, src: &SourceLine) -> Result<i64, AsmError> {
+        match expr.eval(&|s| self.lookup(self.unit, s)) {
+            Ok(Value::Abs(v)) => Ok(v),
+            Ok(Value::Rel(..)) => Err(src.error("expected a constant value")),
+            Err(e) => Err(eval_error(src, None, e)),
+        }
+    }
+
+    /// Looks up a symbol as seen from the file `unit`: either defined in that
+    /// file, or declared global by the file defining it.
+    fn lookup(&self, unit: usize, name: &str) -> Option<Value> {
+        let sym = self.symbols[unit]
+            .get(name)
+            .or_else(|| self.lookup_global(name));
+        sym.map(|s| match s.value {
+            SymValue::Label(sect, off) => Value::Rel(sect, off),
+            SymValue::Equate(v) => v,
+        })
+    }
+
+    /// Finds a symbol exported by any of the files being assembled.
+    fn lookup_global(&self, name: &str) -> Option<&Symbol> {
+        (0..self.symbols.len())
+            .filter(|u| self.globals[*u].contains(name))
+            .find_map(|u| self.symbols[u].get(name))
+    }
+
+    /// Load addresses of each section, laid out the same way the simulator
+    /// loads a module: text and rdata where the layout puts them, and each
+    /// later section (up to bss) on the page following the end of the
+    /// previous one.
+    fn section_bases(&self) -> [u32; 6] {
+        let layout = &self.opts.layout;
+        let mut bases = [layout.text_start, layout.data_start, 0, 0, 0, 0];
+        for i in 2..6 {
+            let len = match i - 1 {
+                s @ 0..=3 => self.sections[s].len() as u32,
+                s => self.uninit[s - 4],
+            };
+            bases[i] = (bases[i - 1] + len).next_multiple_of(PAGE_SIZE);
+        }
+        bases
+    }
+
+    /// Patches every fixup now that all symbols are known, recording any
+    /// errors. Returns the sites of any branches which are out of range and
+    /// should be relaxed.
+    fn resolve_fixups(&mut self) -> Vec<(usize, usize)> {
+        let bases = self.section_bases();
+        let mut too_far = vec![];
+        let mut fixups = std::mem::take(&mut self.fixups).into_iter().peekable();
+        while let Some(mut f) = fixups.next() {
+            // `%hi(label)` followed by `%lo(label)` is relocated as a pair
+            if let (Expr::Part(AddrPart::Hi, hi), Some(next)) = (&f.expr, fixups.peek()) {
+                let paired = matches!(&next.expr, Expr::Part(AddrPart::Lo, lo) if lo == hi)
+                    && (next.sect, next.offset) == (f.sect, f.offset + 4)
+                    && !matches!(hi.eval(&|s| self.lookup(f.unit, s)), Ok(Value::Abs(_)));
+                if paired {
+                    f.kind = FixupKind::AddrPair(RelType::IMM3);
+                    f.expr = *hi.clone();
+                    fixups.next();
+                }
+            }
+            match self.resolve_fixup(f, &bases) {
+                Ok(Some(site)) => too_far.push(site),
+                Ok(None) => {}
+                Err(e) => self.errors.push(e),
+            }
+        }
+        too_far
+    }
+
+    /// Patches a single fixup. Returns its site if it is a branch which
+    /// should be relaxed.
+    fn resolve_fixup(
+        &mut self,
+        f: Fixup,
+        bases: &[u32; 6],
+    ) -> Result<Option<(usize, usize)>, AsmError> {
+        {
+            let value = match f.expr.eval(&|s| self.lookup(f.unit, s)) {
+                Ok(v) => v,
+                // an undefined symbol is assumed to be defined in another
+                // module, and left for the linker to fill in. Any constant
+                // offset from it is stored in the field for the linker to add
+                // the symbol's value to.
+                Err(EvalError::Undefined(s)) => {
+                    let addend = self.external_addend(&f, &s)?;
+                    self.external(&f, s)?;
+                    Value::Abs(addend)
+                }
+                Err(e) => return Err(eval_error(&f.src, f.span.clone(), e)),
+            };
+            let pc = bases[f.sect as usize] + f.offset;
+            let (v, sect) = match value {
+                Value::Abs(v) => (v, None),
+                // offsets before the start of a section have wrapped around
+                Value::Rel(sect, off) => {
+                    (bases[sect as usize].wrapping_add(off) as i64, Some(sect))
+                }
+            };
+
+            let err = |msg: String| error_near(&f.src, f.span.clone(), msg);
+            let mut patch = |off: u32, mask: u32, bits: u32| {
+                let data = &mut self.sections[f.sect as usize];
+                let idx = off as usize;
+                let word = u32::from_be_bytes(data[idx..idx + 4].try_into().unwrap());
+                let word = (word & !mask) | (bits & mask);
+                data[idx..idx + 4].copy_from_slice(&word.to_be_bytes());
+            };
+
+            match f.kind {
+                FixupKind::Imm { signed } => {
+                    let range = if signed { -0x8000..0x8000 } else { 0..0x10000 };
+                    let part = matches!(f.expr, Expr::Part(..));
+                    if sect.is_none() && !part && !range.contains(&v) {
+                        return Err(err(format!("immediate value {} out of range", v)));
+                    }
+                    patch(f.offset, 0xFFFF, v as u32);
+                    if sect.is_some() {
+                        self.rel_info.push(RelEntry {
+                            addr: f.offset,
+                            sect: f.sect,
+                            rel_info: RelType::IMM,
+                        });
+                    }
+                }
+                FixupKind::Shamt => {
+                    if !(0..32).contains(&v) || sect.is_some() {
+                        return Err(err(format!("invalid shift amount {}", v)));
+                    }
+                    patch(f.offset, 0x1F << 6, (v as u32) << 6);
+                }
+                FixupKind::Branch => {
+                    if sect.is_some_and(|s| s != f.sect) {
+                        return Err(err(String::from("branch target is in another section")));
+                    }
+                    let diff = v - (pc as i64 + 4);
+                    if diff % 4 != 0 {
+                        return Err(err(String::from("branch target is not word aligned")));
+                    }
+                    if !(-0x8000..0x8000).contains(&(diff >> 2)) {
+                        if self.opts.relax && !self.relaxed.contains(&f.site) {
+                            return Ok(Some(f.site));
+                        }
+                        return Err(err(format!(
+                            "branch target out of range ({} bytes away, limit is {}); \
+                             use --relax to rewrite long branches",
+                            diff, 0x20000
+                        )));
+                    }
+                    patch(f.offset, 0xFFFF, (diff >> 2) as u32);
+                }
+                FixupKind::Jump => {
+                    if v % 4 != 0 {
+                        return Err(err(String::from("jump target is not word aligned")));
+                    }
+                    patch(f.offset, 0x03FF_FFFF, (v as u32) >> 2);
+                    if sect.is_some() {
+                        self.rel_info.push(RelEntry {
+                            addr: f.offset,
+                            sect: f.sect,
+                            rel_info: RelType::JUMP,
+                        });
+                    }
+                }
+                FixupKind::AddrPair(kind) => {
+                    if !(-0x8000_0000..0x1_0000_0000).contains(&v) {
+                        return Err(err(format!("value {} does not fit in 32 bits", v)));
+                    }
+                    let v = v as u32;
+                    let hi = match kind {
+                        RelType::IMM3 => v.wrapping_add(0x8000) >> 16,
+                        _ => v >> 16,
+                    };
+                    patch(f.offset, 0xFFFF, hi);
+                    patch(f.offset + 4, 0xFFFF, v & 0xFFFF);
+                    if sect.is_some() {
+                        self.rel_info.push(RelEntry {
+                            addr: f.offset,
+                            sect: f.sect,
+                            rel_info: kind,
+                        });
+                    }
+                }
+                FixupKind::Word => {
+                    if !(-0x8000_0000..0x1_0000_0000).contains(&v) {
+                        self.warn(
+                            Lint::Range,
+                            &f.src,
+                            f.span.clone(),
+                            format!("value {} truncated to 32 bits ({:#x})", v, v as u32),
+                        );
+                    }
+                    let data = &mut self.sections[f.sect as usize];
+                    let idx = f.offset as usize;
+                    data[idx..idx + 4].copy_from_slice(&(v as u32).to_be_bytes());
+                    if sect.is_some() {
+                        self.rel_info.push(RelEntry {
+                            addr: f.offset,
+                            sect: f.sect,
+                            rel_info: RelType::WORD,
+                        });
+                    }
+                }
+                FixupKind::Half | FixupKind::Byte => {
+                    let size = if f.kind == FixupKind::Half { 2 } else { 1 };
+                    let bits = size * 8;
+                    if sect.is_some() || v < -(1 << (bits - 1)) || v >= (1 << bits) {
+                        return Err(err(format!("value {} does not fit in {} bits", v, bits)));
+                    }
+                    let data = &mut self.sections[f.sect as usize];
+                    let idx = f.offset as usize;
+                    let bytes = (v as u32).to_be_bytes();
+                    data[idx..idx + size].copy_from_slice(&bytes[4 - size..]);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the constant offset applied to the external symbol `name` by a
+    /// fixup's expression.
+    fn external_addend(&self, f: &Fixup, name: &str) -> Result<i64, AsmError> {
+        let (addend, neg) = match f.expr.split_external() {
+            Some((sym, None)) if sym == name => return Ok(0),
+            Some((sym, Some((op, addend)))) if sym == name => (addend, op == BinOp::Sub),
+            _ => {
+                return Err(error_near(
+                    &f.src,
+                    f.src.find(name).or(f.span.clone()),
+                    format!(
+                        "undefined symbol `{}` can only be offset by a constant",
+                        name
+                    ),
+                ))
+            }
+        };
+        match addend.eval(&|s| self.lookup(f.unit, s)) {
+            Ok(Value::Abs(v)) if neg => Ok(v.wrapping_neg()),
+            Ok(Value::Abs(v)) => Ok(v),
+            Ok(Value::Rel(..)) => Err(error_near(
+                &f.src,
+                f.span.clone(),
+                format!("external symbol `{}` cannot be offset by a label", name),
+            )),
+            Err(e) => Err(eval_error(&f.src, f.span.clone(), e)),
+        }
+    }
+
+    /// Records a reference to a symbol defined outside this module.
+    fn external(&mut self, f: &Fixup, name: String) -> Result<(), AsmError> {
+        let typ = match f.kind {
+            FixupKind::Imm { .. } => RefType::IMM,
+            FixupKind::Jump => RefType::JUMP,
+            FixupKind::AddrPair(RelType::IMM3) => RefType::IMM3,
+            FixupKind::AddrPair(_) => RefType::IMM2,
+            FixupKind::Word => RefType::WORD,
+            FixupKind::Half => RefType::HWORD,
+            FixupKind::Shamt | FixupKind::Branch | FixupKind::Byte => {
+                return Err(error_near(
+                    &f.src,
+                    f.src.find(&name).or(f.span.clone()),
+                    format!("external symbol `{}` cannot be used here", name),
+                ))
+            }
+        };
+        self.ext_ref.push((
+            name,
+            RefEntry {
+                addr: f.offset,
+                str_off: 0,
+                ref_info: RefInfo {
+                    ix: 0,
+                    unknown: RefUnknown::PLUS,
+                    typ,
+                    sect: f.sect,
+                },
+            },
+        ));
+        Ok(())
+    }
+
+    /// Records a warning, if its group is enabled.
+    fn warn(&mut self, lint: Lint, src: &SourceLine, span: Option<Range<usize>>, msg: String) {
+        if self.opts.lints.contains(&lint) {
+            self.warnings
+                .push(error_near(src, span, lint.message(msg)).with_code(lint.name()));
+        }
+    }
+
+    /// Warnings produced while assembling
+    pub fn warnings(&self) -> &[AsmError] {
+        &self.warnings
+    }
+
+    /// Renders a listing of the assembled program, showing the address and
+    /// contents produced by each source line. Pseudo-instructions are followed
+    /// by the real instructions they expanded to.
+    pub fn listing(&self) -> String {
+        let bases = self.section_bases();
+        let mut out = String::new();
+        let mut file = None;
+
+        for entry in &self.listing {
+            if file != Some(&entry.src.file) {
+                file = Some(&entry.src.file);
+                out.push_str(&format!("\n{}:\n", entry.src.file));
+            }
+            let addr = bases[entry.sect as usize] + entry.offset;
+            let data = &self.sections[entry.sect as usize]
+                [entry.offset as usize..(entry.offset + entry.len) as usize];
+            let mut chunks = data.chunks(4);
+
+            // pseudo-instructions list their words on the following lines
+            let first = match entry.pseudo {
+                true => String::new(),
+                false => chunks.next().map(hex).unwrap_or_default(),
+            };
+            out.push_str(&format!(
+                "{:08x} {:8} {:5}  {}\n",
+                addr, first, entry.src.line, entry.src.text
+            ));
+            if entry.pseudo {
+                for (i, word) in data.chunks(4).enumerate() {
+                    out.push_str(&format!(
+                        "{:08x} {:8}          {}\n",
+                        addr + i as u32 * 4,
+                        hex(word),
+                        disassemble(word)
+                    ));
+                }
+                continue;
+            }
+            // only the start of large blocks of data is shown
+            for (i, chunk) in chunks.enumerate() {
+                if i == 3 {
+                    out.push_str(&format!("{:8} ...\n", ""));
+                    break;
+                }
+                out.push_str(&format!(
+                    "{:08x} {:8}\n",
+                    addr + (i as u32 + 1) * 4,
+                    hex(chunk)
+                ));
+            }
+        }
+        out
+    }
+
+    /// Maps each line which emitted code back to its source file and line.
+    fn line_info(&self) -> LineInfo {
+        let mut info = LineInfo::default();
+        for e in &self.listing {
+            if e.sect != Location::TEXT || e.len == 0 {
+                continue;
+            }
+            let file = match info.files.iter().position(|f| *f == e.src.file) {
+                Some(i) => i,
+                None => {
+                    info.files.push(e.src.file.clone());
+                    info.files.len() - 1
+                }
+            };
+            info.lines.push(LineEntry {
+                offset: e.offset,
+                file: file as u32,
+                line: e.src.line as u32,
+            });
+        }
+        info
+    }
+
+    /// Builds the object module from the assembled sections and symbols.
+    pub fn finish(self) -> ObjectModule {
+        let bases = self.section_bases();
+        let mut symtab = vec![];
+        let mut strtab = StrTabBuilder::default();
+
+        let symbols = self
+            .symbols
+            .iter()
+            .enumerate()
+            .flat_map(|(unit, syms)| syms.iter().map(move |(name, sym)| (unit, name, sym)));
+        for (unit, name, sym) in symbols {
+            let (flags, val) = match sym.value {
+                SymValue::Label(sect, off) => {
+                    (sect as u32 | SYM_DEF | SYM_LBL, bases[sect as usize] + off)
+                }
+                SymValue::Equate(Value::Abs(v)) => {
+                    (Location::ABS as u32 | SYM_EQ | SYM_LIT, v as u32)
+                }
+                SymValue::Equate(Value::Rel(sect, off)) => {
+                    (sect as u32 | SYM_DEF | SYM_EQ, bases[sect as usize] + off)
+                }
+            };
+            let flags = match self.globals[unit].contains(name) {
+                true => flags | SYM_GLB,
+                false => flags,
+            };
+            let flags = match self.forward[unit].contains(name) {
+                true => flags | SYM_FORW,
+                false => flags,
+            };
+            let flags = match self.weak[unit].contains(name) {
+                true => flags | SYM_WEAK,
+                false => flags,
+            };
+            symtab.push(SymEntry {
+                flags,
+                val,
+                str_off: strtab.add(name),
+                ofid: 0,
+            });
+        }
+
+        // symbols which are referenced or declared global but never defined
+        // are external, and get a symbol table entry for the linker to match
+        // against other modules
+        let mut externs: HashMap<&str, (u16, u32)> = HashMap::new();
+        let undefined = self
+            .ext_ref
+            .iter()
+            .map(|(name, _)| name)
+            .chain(self.globals.iter().flatten())
+            .filter(|name| self.lookup_global(name).is_none());
+        for name in undefined {
+            if externs.contains_key(name.as_str()) {
+                continue;
+            }
+            let str_off = strtab.add(name);
+            externs.insert(name, (symtab.len() as u16, str_off));
+            // only referenced symbols were used before being defined
+            let flags = match self.forward.iter().any(|f| f.contains(name)) {
+                true => Location::EXT as u32 | SYM_GLB | SYM_FORW,
+                false => Location::EXT as u32 | SYM_GLB,
+            };
+            // a weak reference may be left undefined by the linker
+            let flags = match self.weak.iter().any(|w| w.contains(name)) {
+                true => flags | SYM_WEAK,
+                false => flags,
+            };
+            symtab.push(SymEntry {
+                flags,
+                val: 0,
+                str_off,
+                ofid: 0,
+            });
+        }
+
+        let ext_ref = self
+            .ext_ref
+            .iter()
+            .map(|(name, r)| {
+                let (ix, str_off) = externs[name.as_str()];
+                RefEntry {
+                    str_off,
+                    ref_info: RefInfo { ix, ..r.ref_info },
+                    ..*r
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let line_info = self.opts.line_info.then(|| self.line_info());
+        let mut rel_info = self.rel_info;
+        rel_info.sort_by_key(|r| (r.sect as u8, r.addr));
+
+        let strtab = strtab.finish();
+        let [text, rdata, data, sdata] = self.sections;
+        let head = ObjectHeader {
+            magic: 0xface,
+            version: 0x2cc6,
+            flags: 0,
+            entry: 0,
+            data: [
+                text.len() as u32,
+                rdata.len() as u32,
+                data.len() as u32,
+                sdata.len() as u32,
+                self.uninit[0],
+                self.uninit[1],
+                rel_info.len() as u32,
+                ext_ref.len() as u32,
+                symtab.len() as u32,
+                strtab.len() as u32,
+            ],
+        };
+
+        ObjectModule {
+            head,
+            text,
+            rdata,
+            data,
+            sdata,
+            rel_info,
+            ext_ref,
+            symtab,
+            strtab,
+            line_info,
+            core: None,
+        }
+    }
+}
+
+/// Whether an instruction is followed by a delay slot (branches and jumps) or
+/// its result is not available to the next instruction (loads).
+fn has_delay_slot(inst: &Instruction) -> bool {
+    match *inst {
+        Instruction::J { .. } => true,
+        Instruction::I { op, .. } => matches!(
+            op,
+            OP_BCOND
+                | OP_BEQ
+                | OP_BNE
+                | OP_BLEZ
+                | OP_BGTZ
+                | OP_LB
+                | OP_LH
+                | OP_LWL
+                | OP_LW
+                | OP_LBU
+                | OP_LHU
+                | OP_LWR
+        ),
+        Instruction::R { funct, .. } => matches!(funct, FUNCT_JR | FUNCT_JALR),
+    }
+}
+
+/// Whether an instruction reads or writes register `reg`.
+fn uses_register(inst: &Instruction, reg: u8) -> bool {
+    match *inst {
+        Instruction::J { .. } => false,
+        // the rt field of a bcond instruction selects the condition
+        Instruction::I {
+            op: OP_BCOND, rs, ..
+        } => rs == reg,
+        Instruction::I { rs, rt, .. } => rs == reg || rt == reg,
+        Instruction::R { rs, rt, rd, .. } => rs == reg || rt == reg || rd == reg,
+    }
+}
+
+/// Rewrites a conditional branch as the opposite branch skipping over a jump
+/// to the original target. Linking branches become a `jal`.
+fn relax_branch(branch: Emit) -> Vec<Emit> {
+    let (Some((FixupKind::Branch, target)), Instruction::I { op, rs, rt, .. }) =
+        (&branch.fixup, branch.inst)
+    else {
+        return vec![branch];
+    };
+    let (op, rt, jump) = match (op, rt) {
+        // always taken, so the jump alone will do
+        (OP_BEQ, _) if rs == rt => {
+            return vec![Emit {
+                inst: Instruction::J { op: OP_J, imm: 0 },
+                fixup: Some((FixupKind::Jump, target.clone())),
+            }]
+        }
+        (OP_BEQ, _) => (OP_BNE, rt, OP_J),
+        (OP_BNE, _) => (OP_BEQ, rt, OP_J),
+        (OP_BLEZ, _) => (OP_BGTZ, rt, OP_J),
+        (OP_BGTZ, _) => (OP_BLEZ, rt, OP_J),
+        (OP_BCOND, BCOND_BLTZ) => (OP_BCOND, BCOND_BGEZ, OP_J),
+        (OP_BCOND, BCOND_BGEZ) => (OP_BCOND, BCOND_BLTZ, OP_J),
+        (OP_BCOND, BCOND_BLTZAL) => (OP_BCOND, BCOND_BGEZ, OP_JAL),
+        (OP_BCOND, BCOND_BGEZAL) => (OP_BCOND, BCOND_BLTZ, OP_JAL),
+        _ => return vec![branch],
+    };
+    vec![
+        Emit {
+            inst: Instruction::I { op, rs, rt, imm: 1 },
+            fixup: None,
+        },
+        Emit {
+            inst: Instruction::J { op: jump, imm: 0 },
+            fixup: Some((FixupKind::Jump, target.clone())),
+        },
+    ]
+}
+
+/// Converts an evaluation error into a diagnostic, pointing at the offending
+/// symbol if there is one.
+fn eval_error(src: &SourceLine, span: Option<Range<usize>>, e: EvalError) -> AsmError {
+    match &e {
+        EvalError::Undefined(s) => error_near(src, src.find(s).or(span), &e).with_code("undefined"),
+        EvalError::Invalid(_) => error_near(src, span, e),
+    }
+}
+
+/// Creates an error pointing at `span` if known, or the whole line otherwise.
+fn error_near(src: &SourceLine, span: Option<Range<usize>>, msg: impl Display) -> AsmError {
+    match span {
+        Some(span) => src.error_at(span, msg),
+        None => src.error(msg),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn disassemble(word: &[u8]) -> String {
+    word.try_into()
+        .ok()
+        .map(u32::from_be_bytes)
+        .and_then(|w| Instruction::try_from(w).ok())
+        .map(|i| i.to_string())
+        .unwrap_or_default()
+}
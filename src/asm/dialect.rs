@@ -0,0 +1,72 @@
+use std::ops::Range;
+
+use clap::ValueEnum;
+
+use super::{macros::strip_comment, parse::keyword_span};
+
+/// Source syntax accepted by the assembler
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// The reference rasm assembler
+    #[default]
+    Rasm,
+    /// SPIM and MARS
+    Spim,
+}
+
+/// Mnemonics of floating point (coprocessor 1) instructions without a `.fmt`
+/// suffix.
+const FPU_MNEMONICS: [&str; 8] = [
+    "lwc1", "swc1", "ldc1", "sdc1", "mtc1", "mfc1", "bc1t", "bc1f",
+];
+
+/// Rewrites the SPIM/MARS only syntax on a line into its rasm equivalent.
+/// The result is the same length, so spans into it are also valid for the
+/// original line.
+pub fn spim_line(text: &str) -> Result<String, (Range<usize>, String)> {
+    let kw = keyword_span(text);
+    let mut out = text.to_string();
+    let blank =
+        |out: &mut String, r: Range<usize>| out.replace_range(r.clone(), &" ".repeat(r.len()));
+
+    match &text[kw.clone()] {
+        // there is no kernel mode, so kernel sections are ordinary ones
+        ".ktext" => out.replace_range(kw, " .text"),
+        ".kdata" => out.replace_range(kw, " .data"),
+        ".global" => out.replace_range(kw, " .globl"),
+        // `.eqv name, value` is `name = value`
+        ".eqv" => {
+            let comma = text[kw.end..]
+                .find(',')
+                .ok_or((kw.clone(), String::from("expected `.eqv name, value`")))?;
+            blank(&mut out, kw.clone());
+            out.replace_range(kw.end + comma..kw.end + comma + 1, "=");
+        }
+        // `.extern name, size` declares a global defined in another module;
+        // the size is only used by SPIM to place it in small data
+        ".extern" => {
+            out.replace_range(kw.clone(), " .globl");
+            let name = kw.end + (text[kw.end..].len() - text[kw.end..].trim_start().len());
+            let name_end = text[name..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .map_or(text.len(), |n| name + n);
+            blank(&mut out, name_end..strip_comment(text).len().max(name_end));
+        }
+        ".float" | ".double" => {
+            return Err((kw, String::from("floating point data is not supported")));
+        }
+        word if FPU_MNEMONICS.contains(&word) || text[kw.end..].starts_with('.') => {
+            let end = text[kw.end..]
+                .find(char::is_whitespace)
+                .map_or(text.len(), |n| kw.end + n);
+            return Err((
+                kw.start..end,
+                String::from(
+                    "floating point instructions are not supported (there is no coprocessor 1)",
+                ),
+            ));
+        }
+        _ => {}
+    }
+    Ok(out)
+}
@@ -0,0 +1,243 @@
+use std::fmt::Display;
+
+use super::{expr::Expr, parse::Operand, Grammar, GRAMMAR_MAP};
+use crate::common::{instruction::Arg, Instruction, RelType};
+
+/// How an expression operand is patched into the emitted word once its
+/// value is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixupKind {
+    /// 16 bit immediate field, checked against the signed or unsigned range
+    Imm {
+        signed: bool,
+    },
+    /// 5 bit shift amount
+    Shamt,
+    /// PC relative 16 bit branch offset
+    Branch,
+    /// 26 bit jump target
+    Jump,
+    /// Full 32 bit value split between the immediate of this instruction
+    /// (a `lui`) and the next one. `IMM2` pairs are completed with `ori`,
+    /// `IMM3` pairs with a sign extending instruction (`addiu`, loads, ...),
+    /// so the upper half is adjusted to compensate.
+    AddrPair(RelType),
+    Word,
+    Half,
+    Byte,
+}
+
+/// An instruction produced by the assembler, along with the expression (if
+/// any) which still needs to be patched into it.
+#[derive(Clone, Debug)]
+pub struct Emit {
+    pub inst: Instruction,
+    pub fixup: Option<(FixupKind, Expr)>,
+}
+
+impl Grammar {
+    /// Checks whether the operand list has the shape expected by this grammar
+    pub fn matches(&self, ops: &[Operand]) -> bool {
+        use Operand::{Disp as D, Expr as E, Reg as R};
+        match self {
+            Grammar::LoadStoreOff => matches!(ops, [R(_), D(..)]),
+            Grammar::ArithImm3 => matches!(ops, [R(_), R(_), E(_)]),
+            Grammar::ArithImm2 => matches!(ops, [R(_), E(_)]),
+            Grammar::ArithReg => matches!(ops, [R(_), R(_), R(_)]),
+            Grammar::DivMult => matches!(ops, [R(_), R(_)]),
+            Grammar::ArithMove => matches!(ops, [R(_)]),
+            Grammar::Shift => matches!(ops, [R(_), R(_), E(_)]),
+            Grammar::ShiftVar => matches!(ops, [R(_), R(_), R(_)]),
+            Grammar::Jump => matches!(ops, [E(_)]),
+            Grammar::JumpRegister => matches!(ops, [R(_)]),
+            Grammar::JumpRegister2 => matches!(ops, [R(_), R(_)]),
+            Grammar::BranchCmp => matches!(ops, [R(_), R(_), E(_)]),
+            Grammar::BranchCmpZero => matches!(ops, [R(_), E(_)]),
+            Grammar::None | Grammar::PNone => ops.is_empty(),
+            Grammar::Cop0Move => matches!(ops, [R(_), R(_)]),
+            Grammar::PLoadStoreAddr => matches!(ops, [R(_), E(_)]),
+            Grammar::PBranchCmpZero => matches!(ops, [R(_), E(_)]),
+            Grammar::PLoadStoreAbs => matches!(ops, [R(_), E(_)]),
+            Grammar::PLoadStoreRel => matches!(ops, [R(_), E(_)] | [R(_), D(..)]),
+            Grammar::PLoadStoreReg => matches!(ops, [R(_), R(_)]),
+            Grammar::PArithReg3 => matches!(ops, [R(_), R(_), R(_)]),
+            Grammar::PArithReg2 => matches!(ops, [R(_), R(_)]),
+            Grammar::PArithReg1 => matches!(ops, [R(_)]),
+            Grammar::PArithImm3 => matches!(ops, [R(_), R(_), E(_)]),
+            Grammar::PArithImm2 => matches!(ops, [R(_), E(_)]),
+            Grammar::PBranch1 => matches!(ops, [E(_)]),
+            Grammar::PBranch2 => matches!(ops, [R(_), E(_)]),
+            Grammar::PBranch3Reg => matches!(ops, [R(_), R(_), E(_)]),
+            Grammar::PBranch3Abs => matches!(ops, [R(_), E(_), E(_)]),
+        }
+    }
+
+    /// Operand syntax accepted by this grammar, for diagnostics
+    pub fn syntax(&self) -> &'static str {
+        match self {
+            Grammar::LoadStoreOff => "rt, expr(rs)",
+            Grammar::ArithImm3 => "rt, rs, imm",
+            Grammar::ArithImm2 => "rt, imm",
+            Grammar::ArithReg => "rd, rs, rt",
+            Grammar::DivMult => "rs, rt",
+            Grammar::ArithMove => "rs",
+            Grammar::Shift => "rd, rt, shamt",
+            Grammar::ShiftVar => "rd, rt, rs",
+            Grammar::Jump => "addr",
+            Grammar::JumpRegister => "rs",
+            Grammar::JumpRegister2 => "rs, rd",
+            Grammar::BranchCmp => "rs, rt, target",
+            Grammar::BranchCmpZero => "rs, target",
+            Grammar::None | Grammar::PNone => "",
+            Grammar::Cop0Move => "rt, rd",
+            Grammar::PLoadStoreAddr => "rt, addr",
+            Grammar::PBranchCmpZero => "rs, target",
+            Grammar::PLoadStoreAbs => "rd, imm",
+            Grammar::PLoadStoreRel => "rd, addr",
+            Grammar::PLoadStoreReg => "rd, rs",
+            Grammar::PArithReg3 => "rd, rt, rs",
+            Grammar::PArithReg2 => "rd, rs",
+            Grammar::PArithReg1 => "rd",
+            Grammar::PArithImm3 => "rd, rs, imm",
+            Grammar::PArithImm2 => "rd, imm",
+            Grammar::PBranch1 => "target",
+            Grammar::PBranch2 => "rs, target",
+            Grammar::PBranch3Reg => "rs, rt, target",
+            Grammar::PBranch3Abs => "rs, imm, target",
+        }
+    }
+
+    /// Number of operands taken by this grammar
+    pub fn arity(&self) -> usize {
+        match self.syntax() {
+            "" => 0,
+            s => s.split(',').count(),
+        }
+    }
+
+    /// Pseudo grammars are lowered by the expansion pass rather than being
+    /// encoded directly.
+    pub fn is_pseudo(&self) -> bool {
+        !matches!(
+            self,
+            Grammar::LoadStoreOff
+                | Grammar::ArithImm3
+                | Grammar::ArithImm2
+                | Grammar::ArithReg
+                | Grammar::DivMult
+                | Grammar::ArithMove
+                | Grammar::Shift
+                | Grammar::ShiftVar
+                | Grammar::Jump
+                | Grammar::JumpRegister
+                | Grammar::JumpRegister2
+                | Grammar::BranchCmp
+                | Grammar::BranchCmpZero
+                | Grammar::None
+                | Grammar::Cop0Move
+        )
+    }
+}
+
+/// Reasons an instruction could not be matched against `GRAMMAR_MAP`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GrammarError {
+    /// No instruction has this mnemonic
+    UnknownMnemonic(String),
+    /// The mnemonic exists, but none of its forms accept the given operands
+    Operands(String),
+}
+
+impl Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrammarError::UnknownMnemonic(s) | GrammarError::Operands(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Finds the grammar of `mnemonic` which accepts the given operands.
+pub fn find_grammar(mnemonic: &str, ops: &[Operand]) -> Result<&'static Grammar, GrammarError> {
+    let grammars = GRAMMAR_MAP
+        .get(mnemonic)
+        .ok_or(GrammarError::UnknownMnemonic(format!(
+            "unknown mnemonic `{}`",
+            mnemonic
+        )))?;
+    if let Some(g) = grammars.iter().find(|g| g.matches(ops)) {
+        return Ok(g);
+    }
+
+    let forms = grammars
+        .iter()
+        .map(|g| match g.syntax() {
+            "" => format!("`{}`", mnemonic),
+            s => format!("`{} {}`", mnemonic, s),
+        })
+        .collect::<Vec<_>>()
+        .join(" or ");
+    if grammars.iter().all(|g| g.arity() != ops.len()) {
+        let mut counts = grammars.iter().map(Grammar::arity).collect::<Vec<_>>();
+        counts.sort();
+        counts.dedup();
+        let counts = counts
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(" or ");
+        Err(GrammarError::Operands(format!(
+            "wrong operand count for `{}`: expected {}, found {} (usage: {})",
+            mnemonic,
+            counts,
+            ops.len(),
+            forms
+        )))
+    } else {
+        Err(GrammarError::Operands(format!(
+            "invalid operands for `{}` (usage: {})",
+            mnemonic, forms
+        )))
+    }
+}
+
+/// Encodes a real (non-pseudo) instruction.
+pub fn encode_real(mnemonic: &str, ops: &[Operand]) -> Result<Emit, String> {
+    let grammar = GRAMMAR_MAP
+        .get(mnemonic)
+        .and_then(|g| g.iter().find(|g| !g.is_pseudo() && g.matches(ops)))
+        .ok_or(format!("invalid operands for `{}`", mnemonic))?;
+    encode(mnemonic, grammar, ops)
+}
+
+/// Encodes a real instruction using the shared table in
+/// `common::instruction`. Expression operands are encoded as zero and
+/// returned as the fixup which fills them in.
+pub fn encode(mnemonic: &str, grammar: &Grammar, ops: &[Operand]) -> Result<Emit, String> {
+    let args = ops
+        .iter()
+        .map(|op| match op {
+            Operand::Reg(r) => Arg::Reg(*r),
+            Operand::Expr(_) => Arg::Imm(0),
+            Operand::Disp(_, r) => Arg::Disp(0, *r),
+        })
+        .collect::<Vec<_>>();
+    let inst = Instruction::encode(mnemonic, &args)?;
+
+    let signed = !matches!(mnemonic, "andi" | "ori" | "xori" | "lui");
+    let kind = match grammar {
+        Grammar::LoadStoreOff => Some(FixupKind::Imm { signed: true }),
+        Grammar::ArithImm3 | Grammar::ArithImm2 => Some(FixupKind::Imm { signed }),
+        Grammar::Shift => Some(FixupKind::Shamt),
+        Grammar::Jump => Some(FixupKind::Jump),
+        Grammar::BranchCmp | Grammar::BranchCmpZero => Some(FixupKind::Branch),
+        _ => None,
+    };
+    let expr = ops.iter().find_map(|op| match op {
+        Operand::Expr(e) | Operand::Disp(e, _) => Some(e.clone()),
+        Operand::Reg(_) => None,
+    });
+    Ok(Emit {
+        inst,
+        fixup: kind.zip(expr),
+    })
+}
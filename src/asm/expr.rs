@@ -0,0 +1,268 @@
+use std::fmt::Display;
+
+use pest::iterators::Pair;
+
+use super::{Rule, PRATT_PARSER};
+use crate::common::{parse_register, Location};
+
+/// Parsed (but not yet evaluated) assembler expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    Sym(String),
+    Reg(u8),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// `%hi(expr)` or `%lo(expr)`
+    Part(AddrPart, Box<Expr>),
+}
+
+/// Half of a 32 bit value, for building it with `lui` and a following
+/// instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrPart {
+    /// Upper 16 bits, adjusted for the lower half being sign extended
+    Hi,
+    /// Lower 16 bits
+    Lo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Pos,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+}
+
+/// Result of evaluating an expression. Relocatable values are addresses
+/// inside one of the module's sections, which the linker may move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Abs(i64),
+    Rel(Location, u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvalError {
+    /// The expression refers to a symbol which has not been defined (yet)
+    Undefined(String),
+    Invalid(String),
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::Undefined(s) => write!(f, "undefined symbol `{}`", s),
+            EvalError::Invalid(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Expr {
+    /// Builds an expression from an `expr` pair using the operator
+    /// precedence table in `PRATT_PARSER`.
+    pub fn from_pair(pair: Pair<Rule>) -> Result<Expr, String> {
+        PRATT_PARSER
+            .map_primary(|p| match p.as_rule() {
+                Rule::expr => Expr::from_pair(p),
+                Rule::number_literal => parse_number(p.as_str()).map(Expr::Num),
+                Rule::register => parse_register(p.as_str())
+                    .map(Expr::Reg)
+                    .ok_or(format!("invalid register `{}`", p.as_str())),
+                Rule::ident => Ok(Expr::Sym(p.as_str().to_string())),
+                Rule::addr_part => {
+                    let mut inner = p.into_inner();
+                    let part = match inner.next().unwrap().as_rule() {
+                        Rule::hi => AddrPart::Hi,
+                        _ => AddrPart::Lo,
+                    };
+                    let e = Expr::from_pair(inner.next().unwrap())?;
+                    Ok(Expr::Part(part, Box::new(e)))
+                }
+                r => unreachable!("unexpected rule in expression: {:?}", r),
+            })
+            .map_prefix(|op, rhs| {
+                let op = match op.as_rule() {
+                    Rule::neg => UnaryOp::Neg,
+                    Rule::pos => UnaryOp::Pos,
+                    Rule::not => UnaryOp::Not,
+                    _ => unreachable!(),
+                };
+                Ok(Expr::Unary(op, Box::new(rhs?)))
+            })
+            .map_infix(|lhs, op, rhs| {
+                let op = match op.as_rule() {
+                    Rule::add => BinOp::Add,
+                    Rule::sub => BinOp::Sub,
+                    Rule::mul => BinOp::Mul,
+                    Rule::div => BinOp::Div,
+                    Rule::r#mod => BinOp::Mod,
+                    Rule::and => BinOp::And,
+                    Rule::or => BinOp::Or,
+                    Rule::xor => BinOp::Xor,
+                    Rule::sll => BinOp::Sll,
+                    Rule::srl => BinOp::Srl,
+                    Rule::sra => BinOp::Sra,
+                    _ => unreachable!(),
+                };
+                Ok(Expr::Binary(op, Box::new(lhs?), Box::new(rhs?)))
+            })
+            .parse(pair.into_inner())
+    }
+
+    /// Evaluates the expression, looking up symbols with `lookup`.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<Value>) -> Result<Value, EvalError> {
+        match self {
+            Expr::Num(n) => Ok(Value::Abs(*n)),
+            Expr::Sym(s) => lookup(s).ok_or(EvalError::Undefined(s.clone())),
+            Expr::Reg(_) => Err(EvalError::Invalid(String::from(
+                "register used where a value was expected",
+            ))),
+            Expr::Unary(UnaryOp::Pos, e) => e.eval(lookup),
+            Expr::Unary(op, e) => {
+                let v = match e.eval(lookup)? {
+                    Value::Abs(v) => v,
+                    Value::Rel(..) => return Err(relocatable_err()),
+                };
+                Ok(Value::Abs(match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Pos => v,
+                    UnaryOp::Not => !v,
+                }))
+            }
+            Expr::Part(part, e) => match e.eval(lookup)? {
+                Value::Abs(v) => Ok(Value::Abs(match part {
+                    AddrPart::Hi => (v.wrapping_add(0x8000) >> 16) & 0xFFFF,
+                    AddrPart::Lo => v & 0xFFFF,
+                })),
+                Value::Rel(..) => Err(EvalError::Invalid(String::from(
+                    "`%hi` of a label must be followed by an instruction using `%lo` of the same label",
+                ))),
+            },
+            Expr::Binary(op, l, r) => {
+                // a label may be offset by a constant, and the distance
+                // between two labels in the same section is a constant
+                let (l, r) = match (op, l.eval(lookup)?, r.eval(lookup)?) {
+                    (_, Value::Abs(l), Value::Abs(r)) => (l, r),
+                    (BinOp::Add, Value::Rel(s, off), Value::Abs(n))
+                    | (BinOp::Add, Value::Abs(n), Value::Rel(s, off)) => {
+                        return Ok(Value::Rel(s, (off as i64).wrapping_add(n) as u32))
+                    }
+                    (BinOp::Sub, Value::Rel(s, off), Value::Abs(n)) => {
+                        return Ok(Value::Rel(s, (off as i64).wrapping_sub(n) as u32))
+                    }
+                    (BinOp::Sub, Value::Rel(s1, a), Value::Rel(s2, b)) if s1 == s2 => {
+                        return Ok(Value::Abs(a as i64 - b as i64))
+                    }
+                    _ => return Err(relocatable_err()),
+                };
+                Ok(Value::Abs(match op {
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                    BinOp::Div => l
+                        .checked_div(r)
+                        .ok_or(EvalError::Invalid(String::from("division by zero")))?,
+                    BinOp::Mod => l
+                        .checked_rem(r)
+                        .ok_or(EvalError::Invalid(String::from("division by zero")))?,
+                    BinOp::And => l & r,
+                    BinOp::Or => l | r,
+                    BinOp::Xor => l ^ r,
+                    BinOp::Sll => ((l as u32) << (r & 0x1F)) as i64,
+                    BinOp::Srl => ((l as u32) >> (r & 0x1F)) as i64,
+                    BinOp::Sra => ((l as i32) >> (r & 0x1F)) as i64,
+                }))
+            }
+        }
+    }
+
+    /// The expression with each register replaced by its value, given by
+    /// `value`, for evaluating it against a running program
+    pub fn with_registers(&self, value: &dyn Fn(u8) -> i64) -> Expr {
+        match self {
+            Expr::Reg(r) => Expr::Num(value(*r)),
+            Expr::Num(_) | Expr::Sym(_) => self.clone(),
+            Expr::Unary(op, e) => Expr::Unary(*op, Box::new(e.with_registers(value))),
+            Expr::Part(part, e) => Expr::Part(*part, Box::new(e.with_registers(value))),
+            Expr::Binary(op, l, r) => Expr::Binary(
+                *op,
+                Box::new(l.with_registers(value)),
+                Box::new(r.with_registers(value)),
+            ),
+        }
+    }
+
+    /// Splits an expression referring to an external symbol into the symbol
+    /// and a constant addend, i.e. `sym`, `sym + expr`, `expr + sym` or
+    /// `sym - expr`.
+    pub fn split_external(&self) -> Option<(&str, Option<(BinOp, &Expr)>)> {
+        match self {
+            Expr::Sym(s) => Some((s, None)),
+            Expr::Binary(op @ (BinOp::Add | BinOp::Sub), l, r) => match (l.as_ref(), r.as_ref()) {
+                (Expr::Sym(s), e) => Some((s, Some((*op, e)))),
+                (e, Expr::Sym(s)) if *op == BinOp::Add => Some((s, Some((*op, e)))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Calls `f` with the name of every symbol the expression refers to.
+    pub fn for_each_sym<'a>(&'a self, f: &mut dyn FnMut(&'a str)) {
+        match self {
+            Expr::Sym(s) => f(s),
+            Expr::Num(_) | Expr::Reg(_) => {}
+            Expr::Unary(_, e) | Expr::Part(_, e) => e.for_each_sym(f),
+            Expr::Binary(_, l, r) => {
+                l.for_each_sym(f);
+                r.for_each_sym(f);
+            }
+        }
+    }
+
+    /// Returns the symbol name if this expression is a lone symbol.
+    pub fn as_sym(&self) -> Option<&str> {
+        match self {
+            Expr::Sym(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+fn relocatable_err() -> EvalError {
+    EvalError::Invalid(String::from(
+        "labels may only be offset by a constant, or subtracted from a label in the same section",
+    ))
+}
+
+/// Parses a hex (`0x`), octal (`0o`), binary (`0b`) or decimal literal.
+/// Underscores may be used as digit separators in the prefixed forms.
+pub fn parse_number(s: &str) -> Result<i64, String> {
+    let (digits, radix) = if let Some(d) = s.strip_prefix("0x") {
+        (d, 16)
+    } else if let Some(d) = s.strip_prefix("0o") {
+        (d, 8)
+    } else if let Some(d) = s.strip_prefix("0b") {
+        (d, 2)
+    } else {
+        (s, 10)
+    };
+    i64::from_str_radix(&digits.replace('_', ""), radix)
+        .map_err(|e| format!("invalid number literal `{}`: {}", s, e))
+}
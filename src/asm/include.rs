@@ -0,0 +1,86 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{
+    macros::{blank_block_comments, directive_args, strip_comment},
+    AsmError, SourceLine,
+};
+
+/// Splices the contents of every `.include "file"` directive into the source,
+/// recursively. Paths are resolved relative to the directory of the file
+/// containing the directive, and including a file which is already being
+/// included (directly or indirectly) is an error.
+pub fn resolve_includes(file: &Path, input: &str) -> Result<Vec<SourceLine>, AsmError> {
+    let mut stack = vec![canonical(file)];
+    let mut out = vec![];
+    splice(
+        file,
+        SourceLine::split(&file.to_string_lossy(), input),
+        &mut stack,
+        &mut out,
+    )?;
+    Ok(out)
+}
+
+fn splice(
+    file: &Path,
+    lines: Vec<SourceLine>,
+    stack: &mut Vec<PathBuf>,
+    out: &mut Vec<SourceLine>,
+) -> Result<(), AsmError> {
+    for line in blank_block_comments(lines)? {
+        let code = strip_comment(&line.text).trim();
+        let Some(arg) = directive_args(code, ".include") else {
+            out.push(line);
+            continue;
+        };
+
+        let name = arg
+            .strip_prefix('"')
+            .and_then(|a| a.strip_suffix('"'))
+            .ok_or_else(|| line.error("`.include` expects a quoted file name"))?;
+        let path = file.parent().unwrap_or(Path::new("")).join(name);
+        let key = canonical(&path);
+
+        if let Some(idx) = stack.iter().position(|p| *p == key) {
+            let cycle = stack[idx..]
+                .iter()
+                .chain([&key])
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(line.error(format!("include cycle detected: {}", cycle)));
+        }
+
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            line.error(format!(
+                "failed to read included file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        let site = Arc::new(line);
+        let included = SourceLine::split(&path.to_string_lossy(), &contents)
+            .into_iter()
+            .map(|l| SourceLine {
+                included_from: Some(site.clone()),
+                ..l
+            })
+            .collect();
+
+        stack.push(key);
+        splice(&path, included, stack, out)?;
+        stack.pop();
+    }
+    Ok(())
+}
+
+/// Canonicalizes a path for cycle detection, falling back to the path as
+/// given if it does not exist (the read will report the error).
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
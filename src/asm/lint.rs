@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Display,
+};
+
+use super::{
+    expr::Expr,
+    parse::{Directive, Line, Operand, Stmt},
+    AsmError,
+};
+use crate::common::{instruction::opcodes::*, register_name, Instruction, Location};
+
+/// Groups of warnings, which can be turned on and off individually with
+/// `-W`. The first four are found by the analysis pass run after a program
+/// assembles, the rest while assembling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A label which is never referenced and not exported
+    UnusedLabel,
+    /// Code following an unconditional jump which no label leads to
+    Unreachable,
+    /// An instruction whose only effect is writing `$zero`
+    ZeroWrite,
+    /// A register written and then overwritten without being read between
+    DeadWrite,
+    /// A value truncated to fit the space it is stored in
+    Range,
+    /// A pseudo-instruction kept only for old sources
+    Deprecated,
+    /// A pseudo-instruction using `$at` after `.set noat`
+    Noat,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 7] = [
+        Lint::UnusedLabel,
+        Lint::Unreachable,
+        Lint::ZeroWrite,
+        Lint::DeadWrite,
+        Lint::Range,
+        Lint::Deprecated,
+        Lint::Noat,
+    ];
+
+    /// Name used to select the group with `-W`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "unused-label",
+            Lint::Unreachable => "unreachable",
+            Lint::ZeroWrite => "zero-write",
+            Lint::DeadWrite => "dead-write",
+            Lint::Range => "range",
+            Lint::Deprecated => "deprecated",
+            Lint::Noat => "noat",
+        }
+    }
+
+    /// Formats a warning message in this group
+    pub fn message(&self, msg: impl Display) -> String {
+        format!("warning: {} [-W{}]", msg, self.name())
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|l| l.name() == name)
+    }
+}
+
+/// A source line together with the instructions it assembled to.
+pub struct LintLine<'a> {
+    pub line: &'a Line,
+    pub sect: Location,
+    pub insts: Vec<Instruction>,
+}
+
+/// Looks for likely mistakes in an assembled program. `units` holds the
+/// lines of each source file, and `globals` the names each one exports.
+pub fn lint(
+    units: &[Vec<LintLine>],
+    globals: &[BTreeSet<String>],
+    enabled: &HashSet<Lint>,
+) -> Vec<AsmError> {
+    let mut warnings = vec![];
+    for (lines, globals) in units.iter().zip(globals) {
+        let mut found = vec![];
+        if enabled.contains(&Lint::UnusedLabel) {
+            unused_labels(lines, globals, &mut found);
+        }
+        if enabled.contains(&Lint::Unreachable) {
+            unreachable(lines, &mut found);
+        }
+        if enabled.contains(&Lint::ZeroWrite) {
+            zero_writes(lines, &mut found);
+        }
+        if enabled.contains(&Lint::DeadWrite) {
+            dead_writes(lines, &mut found);
+        }
+        found.sort_by_key(|w| w.line);
+        warnings.append(&mut found);
+    }
+    warnings
+}
+
+fn warn(l: &LintLine, lint: Lint, msg: String) -> AsmError {
+    l.line.src.error(lint.message(msg)).with_code(lint.name())
+}
+
+fn unused_labels(lines: &[LintLine], globals: &BTreeSet<String>, out: &mut Vec<AsmError>) {
+    let mut used = HashSet::new();
+    let mut mark = |e: &Expr| {
+        e.for_each_sym(&mut |s| {
+            used.insert(s.to_string());
+        })
+    };
+    for l in lines {
+        match &l.line.stmt {
+            Some(Stmt::Assign(_, e)) => mark(e),
+            Some(Stmt::Instr { operands, .. }) => {
+                for op in operands {
+                    if let Operand::Expr(e) | Operand::Disp(e, _) = op {
+                        mark(e);
+                    }
+                }
+            }
+            Some(Stmt::Directive(d)) => match d {
+                Directive::Byte(es) | Directive::Half(es) | Directive::Word(es) => {
+                    es.iter().for_each(&mut mark)
+                }
+                Directive::Space(e) | Directive::Align(e) | Directive::Org(e) => mark(e),
+                Directive::Comm(_, size, align) | Directive::Lcomm(_, size, align) => {
+                    mark(size);
+                    align.iter().for_each(&mut mark);
+                }
+                _ => {}
+            },
+            None => {}
+        }
+    }
+
+    for l in lines {
+        for label in &l.line.labels {
+            // the program's entry point is found by name
+            if !used.contains(label) && !globals.contains(label) && label != "main" {
+                out.push(warn(
+                    l,
+                    Lint::UnusedLabel,
+                    format!("label `{}` is never used", label),
+                ));
+            }
+        }
+    }
+}
+
+fn unreachable(lines: &[LintLine], out: &mut Vec<AsmError>) {
+    // no label since the last unconditional jump, and whether that has
+    // already been reported
+    let (mut dead, mut warned) = (false, false);
+    for l in lines.iter().filter(|l| l.sect == Location::TEXT) {
+        if !l.line.labels.is_empty() {
+            (dead, warned) = (false, false);
+        }
+        // nops are allowed, as they may fill a delay slot
+        let Some(last) = l.insts.iter().rev().find(|i| !is_nop(i)) else {
+            continue;
+        };
+        if dead && !warned {
+            out.push(warn(
+                l,
+                Lint::Unreachable,
+                String::from("unreachable code after unconditional jump"),
+            ));
+            warned = true;
+        }
+        dead |= is_unconditional(last);
+    }
+}
+
+fn zero_writes(lines: &[LintLine], out: &mut Vec<AsmError>) {
+    for l in lines {
+        if l.insts.iter().any(|i| !is_nop(i) && dest(i) == Some(0)) {
+            out.push(warn(
+                l,
+                Lint::ZeroWrite,
+                String::from("write to `$zero` has no effect"),
+            ));
+        }
+    }
+}
+
+fn dead_writes(lines: &[LintLine], out: &mut Vec<AsmError>) {
+    // line which last wrote each register, while it has not been read since
+    let mut pending: [Option<&LintLine>; 32] = [None; 32];
+    for l in lines.iter().filter(|l| l.sect == Location::TEXT) {
+        if !l.line.labels.is_empty() {
+            pending = [None; 32];
+        }
+        for i in &l.insts {
+            if is_barrier(i) {
+                pending = [None; 32];
+                continue;
+            }
+            for r in sources(i).into_iter().flatten() {
+                pending[r as usize] = None;
+            }
+            match dest(i) {
+                Some(0) | None => {}
+                Some(r) => {
+                    if let Some(prev) = pending[r as usize] {
+                        // a pseudo-instruction may write a register twice
+                        if !std::ptr::eq(prev, l) {
+                            out.push(warn(
+                                prev,
+                                Lint::DeadWrite,
+                                format!(
+                                    "value written to `${}` is overwritten before it is used",
+                                    register_name(r)
+                                ),
+                            ));
+                        }
+                    }
+                    pending[r as usize] = Some(l);
+                }
+            }
+            if is_branch(i) {
+                pending = [None; 32];
+            }
+        }
+    }
+}
+
+fn is_nop(i: &Instruction) -> bool {
+    u32::from(*i) == 0
+}
+
+/// `j`, `jr` and branches which are always taken (`b`)
+fn is_unconditional(i: &Instruction) -> bool {
+    match *i {
+        Instruction::J { op, .. } => op == OP_J,
+        Instruction::R { funct, .. } => funct == FUNCT_JR,
+        Instruction::I { op, rs, rt, .. } => op == OP_BEQ && rs == rt,
+    }
+}
+
+/// Any instruction which may transfer control
+fn is_branch(i: &Instruction) -> bool {
+    match *i {
+        Instruction::J { .. } => true,
+        Instruction::R { funct, .. } => matches!(funct, FUNCT_JR | FUNCT_JALR),
+        Instruction::I { op, .. } => matches!(op, OP_BCOND | OP_BEQ | OP_BNE | OP_BLEZ | OP_BGTZ),
+    }
+}
+
+/// Instructions which may read any register
+fn is_barrier(i: &Instruction) -> bool {
+    matches!(
+        *i,
+        Instruction::R {
+            funct: FUNCT_SYSCALL | FUNCT_BREAK,
+            ..
+        }
+    )
+}
+
+/// Register written by an instruction
+fn dest(i: &Instruction) -> Option<u8> {
+    match *i {
+        Instruction::J { op, .. } => (op == OP_JAL).then_some(31),
+        Instruction::R { funct, rd, .. } => match funct {
+            FUNCT_SLL..=FUNCT_SRAV | FUNCT_JALR | FUNCT_MFHI | FUNCT_MFLO => Some(rd),
+            FUNCT_ADD..=FUNCT_SLTU => Some(rd),
+            _ => None,
+        },
+        Instruction::I { op, rt, .. } => match op {
+            OP_ADDI..=OP_LUI | OP_LB..=OP_LWR => Some(rt),
+            _ => None,
+        },
+    }
+}
+
+/// Registers read by an instruction
+fn sources(i: &Instruction) -> [Option<u8>; 2] {
+    match *i {
+        Instruction::J { .. } => [None, None],
+        Instruction::R { funct, rs, rt, .. } => match funct {
+            FUNCT_SLL..=FUNCT_SRA => [Some(rt), None],
+            FUNCT_JR | FUNCT_JALR | FUNCT_MTHI | FUNCT_MTLO => [Some(rs), None],
+            FUNCT_MFHI | FUNCT_MFLO => [None, None],
+            _ => [Some(rs), Some(rt)],
+        },
+        Instruction::I { op, rs, rt, .. } => match op {
+            OP_BEQ | OP_BNE => [Some(rs), Some(rt)],
+            // lwl and lwr merge into the old value of rt
+            OP_LWL | OP_LWR => [Some(rs), Some(rt)],
+            OP_SB..=OP_SWR => [Some(rs), Some(rt)],
+            OP_LUI => [None, None],
+            _ => [Some(rs), None],
+        },
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use super::{AsmError, SourceLine};
+
+/// Maximum depth of nested macro invocations. Macros which (directly or
+/// indirectly) invoke themselves will hit this limit instead of looping.
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+/// A single formal parameter of a macro, with an optional default value used
+/// when the invocation does not supply one.
+#[derive(Clone, Debug)]
+pub struct MacroParam {
+    pub name: String,
+    pub default: Option<String>,
+}
+
+/// A macro defined with `.macro name params...` / `.endm`.
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<MacroParam>,
+    pub body: Vec<SourceLine>,
+}
+
+/// Textual macro expander. Definitions are removed from the output, and each
+/// invocation is replaced by the macro body with parameters substituted.
+///
+/// Inside a body, `\name` is replaced by the value of parameter `name`, and
+/// `\@` is replaced by a counter unique to each invocation, which allows
+/// macros to define local labels (e.g. `loop\@:`) without colliding.
+///
+/// Arguments may be given positionally (`push $t0, 4`) or by name
+/// (`push off=4, reg=$t0`), but positional arguments cannot follow named ones.
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    counter: usize,
+}
+
+impl Default for MacroExpander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MacroExpander {
+    pub fn new() -> Self {
+        Self {
+            macros: HashMap::new(),
+            counter: 0,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    pub fn expand(&mut self, lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, AsmError> {
+        let mut out = vec![];
+        self.expand_into(lines, 0, &mut out)?;
+        Ok(out)
+    }
+
+    fn expand_into(
+        &mut self,
+        lines: Vec<SourceLine>,
+        depth: usize,
+        out: &mut Vec<SourceLine>,
+    ) -> Result<(), AsmError> {
+        let mut iter = lines.into_iter();
+        while let Some(line) = iter.next() {
+            let code = strip_comment(&line.text).trim();
+
+            if let Some(rest) = directive_args(code, ".macro") {
+                let m = self.define(&line, rest, &mut iter)?;
+                self.macros.insert(m.name.clone(), m);
+                continue;
+            }
+            if directive_args(code, ".endm").is_some() {
+                return Err(line.error("`.endm` without matching `.macro`"));
+            }
+
+            let (labels, rest) = split_labels(code);
+            let name = rest.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+            let Some(m) = self.macros.get(name).cloned() else {
+                out.push(line);
+                continue;
+            };
+
+            if depth >= MAX_MACRO_DEPTH {
+                return Err(line.error(format!(
+                    "macro `{}` exceeded the maximum expansion depth of {}",
+                    name, MAX_MACRO_DEPTH
+                )));
+            }
+
+            // labels preceding the invocation mark the start of the expansion
+            if !labels.is_empty() {
+                out.push(SourceLine {
+                    text: labels.to_string(),
+                    ..line.clone()
+                });
+            }
+
+            let values = bind_args(&m, &line, rest[name.len()..].trim())?;
+            self.counter += 1;
+            let body = m
+                .body
+                .iter()
+                .map(|l| SourceLine {
+                    text: substitute(&l.text, &values, self.counter),
+                    ..line.clone()
+                })
+                .collect();
+            self.expand_into(body, depth + 1, out)?;
+        }
+        Ok(())
+    }
+
+    /// Parses a macro header and collects its body up to the matching `.endm`.
+    /// Nested definitions are kept in the body and defined when it expands.
+    fn define(
+        &self,
+        start: &SourceLine,
+        header: &str,
+        lines: &mut impl Iterator<Item = SourceLine>,
+    ) -> Result<Macro, AsmError> {
+        let mut parts = header
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty());
+        let name = match parts.next() {
+            Some(n) if is_ident(n) => n.to_string(),
+            Some(n) => return Err(start.error(format!("invalid macro name `{}`", n))),
+            None => return Err(start.error("`.macro` requires a name")),
+        };
+
+        let mut params: Vec<MacroParam> = vec![];
+        for p in parts {
+            let (pname, default) = match p.split_once('=') {
+                Some((n, d)) => (n, Some(d.to_string())),
+                None => (p, None),
+            };
+            if !is_ident(pname) {
+                return Err(start.error(format!("invalid macro parameter `{}`", pname)));
+            }
+            if params.iter().any(|q| q.name == pname) {
+                return Err(start.error(format!("duplicate macro parameter `{}`", pname)));
+            }
+            params.push(MacroParam {
+                name: pname.to_string(),
+                default,
+            });
+        }
+
+        let mut body = vec![];
+        let mut nesting = 0;
+        for line in lines.by_ref() {
+            let code = strip_comment(&line.text).trim();
+            if directive_args(code, ".macro").is_some() {
+                nesting += 1;
+            } else if directive_args(code, ".endm").is_some() {
+                if nesting == 0 {
+                    return Ok(Macro { name, params, body });
+                }
+                nesting -= 1;
+            }
+            body.push(line);
+        }
+
+        Err(start.error(format!("macro `{}` is missing `.endm`", name)))
+    }
+}
+
+/// Matches the arguments of an invocation against the macro's parameters,
+/// returning the value for each parameter in declaration order.
+fn bind_args(m: &Macro, line: &SourceLine, args: &str) -> Result<Vec<(String, String)>, AsmError> {
+    let mut values: Vec<Option<String>> = m.params.iter().map(|p| p.default.clone()).collect();
+    let mut named = false;
+    let mut pos = 0;
+
+    for arg in split_args(args) {
+        let named_param = arg.split_once('=').and_then(|(n, v)| {
+            let idx = m.params.iter().position(|p| p.name == n.trim())?;
+            Some((idx, v.trim()))
+        });
+        match named_param {
+            Some((idx, v)) => {
+                named = true;
+                values[idx] = Some(v.to_string());
+            }
+            None if named => {
+                return Err(line.error(format!(
+                    "positional argument `{}` follows named arguments in invocation of `{}`",
+                    arg, m.name
+                )))
+            }
+            None => {
+                if pos >= m.params.len() {
+                    return Err(line.error(format!(
+                        "too many arguments to macro `{}` (expected at most {})",
+                        m.name,
+                        m.params.len()
+                    )));
+                }
+                values[pos] = Some(arg);
+                pos += 1;
+            }
+        }
+    }
+
+    m.params
+        .iter()
+        .zip(values)
+        .map(|(p, v)| match v {
+            Some(v) => Ok((p.name.clone(), v)),
+            None => Err(line.error(format!(
+                "missing value for parameter `{}` of macro `{}`",
+                p.name, m.name
+            ))),
+        })
+        .collect()
+}
+
+/// Replaces `\param` and `\@` references in one line of a macro body.
+fn substitute(text: &str, values: &[(String, String)], counter: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('\\') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(r) = rest.strip_prefix('@') {
+            out.push_str(&counter.to_string());
+            rest = r;
+            continue;
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        match values.iter().find(|(n, _)| *n == rest[..len]) {
+            Some((_, v)) => {
+                out.push_str(v);
+                rest = &rest[len..];
+            }
+            None => out.push('\\'),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Splits an argument list on top-level commas, ignoring commas inside
+/// parentheses or string literals.
+fn split_args(args: &str) -> Vec<String> {
+    let mut out = vec![];
+    let mut cur = String::new();
+    let mut parens = 0;
+    let mut in_str = false;
+    let mut escaped = false;
+    for c in args.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            '(' if !in_str => parens += 1,
+            ')' if !in_str => parens -= 1,
+            ',' if !in_str && parens == 0 => {
+                out.push(cur.trim().to_string());
+                cur.clear();
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(c);
+    }
+    if !cur.trim().is_empty() || !out.is_empty() {
+        out.push(cur.trim().to_string());
+    }
+    out
+}
+
+/// Removes a trailing `#` or `;` comment, ignoring those characters inside
+/// strings.
+pub(super) fn strip_comment(text: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (idx, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            '#' | ';' if !in_str => return &text[..idx],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Replaces `/* ... */` comments, which may span several lines, with spaces
+/// so the columns of the code around them are unchanged.
+pub(super) fn blank_block_comments(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, AsmError> {
+    // line the comment being blanked was opened on
+    let mut open: Option<(SourceLine, usize)> = None;
+    let mut out = Vec::with_capacity(lines.len());
+    for mut line in lines {
+        let mut text = String::with_capacity(line.text.len());
+        let mut chars = line.text.chars().peekable();
+        let (mut in_str, mut escaped) = (false, false);
+        while let Some(c) = chars.next() {
+            if open.is_some() {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    text.push_str("  ");
+                    open = None;
+                } else {
+                    text.push_str(&" ".repeat(c.len_utf8()));
+                }
+                continue;
+            }
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_str => escaped = true,
+                '"' => in_str = !in_str,
+                '/' if !in_str && chars.peek() == Some(&'*') => {
+                    chars.next();
+                    open = Some((line.clone(), text.len()));
+                    text.push_str("  ");
+                    continue;
+                }
+                // the rest of the line is a comment already
+                '#' | ';' if !in_str => {
+                    text.push(c);
+                    text.extend(chars.by_ref());
+                    break;
+                }
+                _ => {}
+            }
+            text.push(c);
+        }
+        line.text = text;
+        out.push(line);
+    }
+    match open {
+        Some((line, col)) => Err(line.error_at(col..col + 2, "unterminated `/*` comment")),
+        None => Ok(out),
+    }
+}
+
+/// If `code` is the directive `name`, returns the text following it.
+pub(super) fn directive_args<'a>(code: &'a str, name: &str) -> Option<&'a str> {
+    let rest = code.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// Splits leading `label:` definitions off of a line of code.
+fn split_labels(code: &str) -> (&str, &str) {
+    let mut end = 0;
+    loop {
+        let rest = code[end..].trim_start();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len > 0 && is_ident(&rest[..len]) && rest[len..].starts_with(':') {
+            end = code.len() - rest.len() + len + 1;
+        } else {
+            return (code[..end].trim(), rest);
+        }
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -1,13 +1,45 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use clap::{Args, ValueEnum};
 
 use lazy_static::lazy_static;
 use pest::{
+    error::LineColLocation,
+    iterators::Pair,
     pratt_parser::{Assoc, Op, PrattParser},
-    Parser, Token,
+    Parser,
 };
 use pest_derive::Parser;
 
-use crate::common::{Error, Instruction};
+use crate::{
+    common::{Error, Instruction, Location, ObjectModule},
+    sim::{Layout, LayoutArgs, PAGE_SIZE},
+};
+
+pub mod assemble;
+pub mod dialect;
+pub mod encode;
+pub mod expr;
+pub mod include;
+pub mod lint;
+pub mod macros;
+pub mod parse;
+pub mod pseudo;
+
+use dialect::Dialect;
+use lint::Lint;
 
 #[derive(Parser)]
 #[grammar = "asm/mips.pest"]
@@ -42,7 +74,11 @@ pub enum Grammar {
     BranchCmpZero,
     /// op
     None,
+    /// op rt, rd (a coprocessor register)
+    Cop0Move,
 
+    /// op
+    PNone,
     /// op rt, addr
     PLoadStoreAddr,
     /// op rs, target
@@ -105,8 +141,8 @@ lazy_static! {
             ("bgtz", vec![Grammar::BranchCmpZero]),
             ("addiu", vec![Grammar::ArithImm3]),
             ("addi", vec![Grammar::ArithImm3]),
-            ("sltiu", vec![Grammar::ArithImm2]),
-            ("slti", vec![Grammar::ArithImm2]),
+            ("sltiu", vec![Grammar::ArithImm3]),
+            ("slti", vec![Grammar::ArithImm3]),
             ("andi", vec![Grammar::ArithImm3]),
             ("ori", vec![Grammar::ArithImm3]),
             ("xori", vec![Grammar::ArithImm3]),
@@ -145,6 +181,7 @@ lazy_static! {
             ("sub", vec![Grammar::ArithReg]),
             ("and", vec![Grammar::ArithReg]),
             ("or", vec![Grammar::ArithReg]),
+            ("xor", vec![Grammar::ArithReg]),
             ("nor", vec![Grammar::ArithReg]),
             ("sltu", vec![Grammar::ArithReg]),
             ("slt", vec![Grammar::ArithReg]),
@@ -152,9 +189,11 @@ lazy_static! {
             ("bgezal", vec![Grammar::BranchCmpZero]),
             ("bltz", vec![Grammar::BranchCmpZero]),
             ("bgez", vec![Grammar::BranchCmpZero]),
+            ("mfc0", vec![Grammar::Cop0Move]),
+            ("mtc0", vec![Grammar::Cop0Move]),
+            ("rfe", vec![Grammar::None]),
 
-            ("beqz", vec![Grammar::PBranchCmpZero]),
-            ("bnez", vec![Grammar::PBranchCmpZero]),
+            ("nop", vec![Grammar::PNone]),
             ("li", vec![Grammar::PLoadStoreAbs]),
             ("la", vec![Grammar::PLoadStoreRel]),
             ("lea", vec![Grammar::PLoadStoreRel]),
@@ -163,22 +202,21 @@ lazy_static! {
             ("neg", vec![Grammar::PArithReg1, Grammar::PArithReg2]),
             ("negu", vec![Grammar::PArithReg1, Grammar::PArithReg2]),
             ("not", vec![Grammar::PArithReg1, Grammar::PArithReg2]),
-            ("rem", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("remu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("rol", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("ror", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("mul", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("mulo", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("mulou", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("seq", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sge", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sgeu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sgt", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sgtu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sle", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sleu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("sne", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm2]),
-            ("div", vec![Grammar::PArithReg3, Grammar::PArithImm3]),
+            ("rem", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("remu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("rol", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("ror", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("mul", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("mulo", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("mulou", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("seq", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sge", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sgeu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sgt", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sgtu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sle", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sleu", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
+            ("sne", vec![Grammar::PArithReg2, Grammar::PArithReg3, Grammar::PArithImm2, Grammar::PArithImm3]),
             ("b", vec![Grammar::PBranch1]),
             ("bal", vec![Grammar::PBranch1]),
             ("beqz", vec![Grammar::PBranch2]),
@@ -195,51 +233,697 @@ lazy_static! {
     };
 }
 
-pub fn dbg_parse(input: String, rule: Rule) -> Result<(), pest::error::Error<Rule>> {
-    let mut tokens = MIPSParser::parse(rule, &input)?.tokens().peekable();
-    let mut depth = 0;
-    let chars = input.chars().collect::<Vec<_>>();
-    while let Some(tok) = tokens.next() {
-        if let Some(tok2) = tokens.peek() {
-            match (tok, tok2) {
-                (
-                    Token::Start { rule, pos: start },
-                    Token::End {
-                        rule: rule2,
-                        pos: end,
-                    },
-                ) => {
-                    println!(
-                        "{: >width$}{:?} [{}]",
-                        "",
-                        rule,
-                        chars[start.pos()..end.pos()]
-                            .iter()
-                            .collect::<String>()
-                            .trim(),
-                        width = (depth * 2)
-                    );
-                    depth += 1;
-                }
-                (Token::Start { rule, .. }, _) => {
-                    println!("{: >width$}{:?}", "", rule, width = (depth * 2));
-                    depth += 1;
+/// A single line of assembly source, tagged with the file and line number it
+/// originally came from so that diagnostics survive preprocessing.
+#[derive(Clone, Debug)]
+pub struct SourceLine {
+    pub text: String,
+    pub file: String,
+    pub line: usize,
+    /// The `.include` directive which pulled this line in, if any.
+    pub included_from: Option<Arc<SourceLine>>,
+}
+
+impl SourceLine {
+    /// Splits a source file into lines, numbered from 1.
+    pub fn split(file: &str, input: &str) -> Vec<SourceLine> {
+        input
+            .lines()
+            .enumerate()
+            .map(|(idx, text)| SourceLine {
+                text: text.to_string(),
+                file: file.to_string(),
+                line: idx + 1,
+                included_from: None,
+            })
+            .collect()
+    }
+
+    /// Creates an assembler error pointing at the code on this line.
+    pub fn error(&self, msg: impl std::fmt::Display) -> AsmError {
+        let code = macros::strip_comment(&self.text).trim_end();
+        let start = code.len() - code.trim_start().len();
+        self.error_at(start..code.len(), msg)
+    }
+
+    /// Creates an assembler error pointing at the byte range `span` of this
+    /// line. The diagnostic shows the offending line with the span underlined.
+    pub fn error_at(&self, span: Range<usize>, msg: impl std::fmt::Display) -> AsmError {
+        let start = span.start.min(self.text.len());
+        let end = span.end.clamp(start, self.text.len());
+        let col = self.text[..start].chars().count() + 1;
+
+        // keep tabs in the padding so the carets line up with the source
+        let pad = self.text[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let carets = "^".repeat(self.text[start..end].chars().count().max(1));
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        let message = msg.to_string();
+        let mut rendered = format!(
+            "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+            self.file, self.line, col, message, gutter, self.line, self.text, gutter, pad, carets
+        );
+        let mut site = &self.included_from;
+        while let Some(l) = site {
+            rendered.push_str(&format!("\n  included from {}:{}", l.file, l.line));
+            site = &l.included_from;
+        }
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            column: col,
+            code: "error",
+            message,
+            rendered,
+        }
+    }
+
+    /// Finds `word` on this line as a whole identifier, ignoring comments.
+    pub fn find(&self, word: &str) -> Option<Range<usize>> {
+        let code = macros::strip_comment(&self.text);
+        let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        code.match_indices(word)
+            .map(|(idx, _)| idx..idx + word.len())
+            .find(|r| !code[..r.start].ends_with(is_ident) && !code[r.end..].starts_with(is_ident))
+    }
+}
+
+/// Runs the textual preprocessing passes (include resolution, then macro
+/// expansion) over a source file, producing the lines handed to the parser.
+pub fn preprocess(file: &str, input: &str) -> Result<Vec<SourceLine>, AsmError> {
+    let lines = include::resolve_includes(Path::new(file), input)?;
+    macros::MacroExpander::new().expand(lines)
+}
+
+/// An error produced while assembling, pointing at the source line
+/// responsible.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    /// Line number, starting from 1
+    pub line: usize,
+    /// Column number in characters, starting from 1
+    pub column: usize,
+    /// Short name for the kind of problem, for tools: `syntax`, `undefined`,
+    /// `redefined` or `error`, or the group of a warning
+    pub code: &'static str,
+    /// Description of the problem, without any location information
+    pub message: String,
+    /// Full diagnostic including the source line and include chain
+    rendered: String,
+}
+
+impl AsmError {
+    pub fn with_code(self, code: &'static str) -> Self {
+        AsmError { code, ..self }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        Lint::from_name(self.code).is_some()
+    }
+
+    /// The diagnostic as a JSON object, for `--diagnostics json`
+    pub fn to_json(&self) -> String {
+        // warnings are tagged with their group for people, which tools get
+        // from the code instead
+        let tag = format!(" [-W{}]", self.code);
+        let message = match self.is_warning() {
+            true => self
+                .message
+                .strip_prefix("warning: ")
+                .and_then(|m| m.strip_suffix(tag.as_str()))
+                .unwrap_or(&self.message),
+            false => &self.message,
+        };
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{}}}",
+            json_str(&self.file),
+            self.line,
+            self.column,
+            if self.is_warning() { "warning" } else { "error" },
+            self.code,
+            json_str(message)
+        )
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rendered)
+    }
+}
+
+impl From<AsmError> for Error {
+    fn from(e: AsmError) -> Self {
+        Error::AssemblerError(e.rendered)
+    }
+}
+
+/// Options controlling how source is assembled.
+#[derive(Clone, Debug)]
+pub struct AsmOptions {
+    /// Name of the source file, used in diagnostics and to resolve
+    /// `.include` paths
+    pub file_name: String,
+    /// Rewrite conditional branches whose target is out of range into a
+    /// branch around a jump, rather than failing
+    pub relax: bool,
+    /// Guarantee identical output for identical input. Symbols and
+    /// relocations are always emitted in a stable order and the object
+    /// format has no timestamp fields, so this currently changes nothing;
+    /// it exists so build scripts can ask for reproducible output explicitly.
+    pub deterministic: bool,
+    /// Emit a line info section mapping text offsets to source lines
+    pub line_info: bool,
+    /// Analysis warnings to report
+    pub lints: HashSet<Lint>,
+    /// Accept mnemonics and directives in any case, e.g. `ADD` or `.WORD`
+    pub ignore_case: bool,
+    /// Stop reporting errors after this many, or never if 0
+    pub max_errors: usize,
+    /// Source syntax to accept
+    pub dialect: Dialect,
+    /// Allow symbols assigned with `name = value` to be reassigned
+    pub allow_redefinition: bool,
+    /// Where the program will be loaded
+    pub layout: Layout,
+}
+
+impl Default for AsmOptions {
+    fn default() -> Self {
+        Self {
+            file_name: String::from("<input>"),
+            relax: false,
+            deterministic: false,
+            line_info: false,
+            lints: HashSet::from(Lint::ALL),
+            ignore_case: false,
+            max_errors: 20,
+            dialect: Dialect::Rasm,
+            allow_redefinition: false,
+            layout: Layout::default(),
+        }
+    }
+}
+
+/// Assembles a source program held in memory into an object module.
+pub fn assemble(source: &str, opts: &AsmOptions) -> Result<ObjectModule, Vec<AsmError>> {
+    let mut asm = assemble::Assembler::new(opts);
+    asm.assemble(preprocess(&opts.file_name, source).map_err(|e| vec![e])?)?;
+    Ok(asm.finish())
+}
+
+/// Kind of file written by the assembler
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// R2K object module
+    Obj,
+    /// Flat image of the text section, with no header
+    Bin,
+}
+
+/// How diagnostics are printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticFormat {
+    /// Messages showing the source line, on standard error
+    Text,
+    /// A JSON array of objects with file, line, column, severity, code and
+    /// message fields, on standard output
+    Json,
+}
+
+#[derive(Args, Clone)]
+#[command(about = "Assemble one or more MIPS source files into object modules.
+Each input produces an object module of the same name with the extension .obj,
+unless --combine is given
+")]
+pub struct AsmArgs {
+    #[arg(
+        short = 'l',
+        long = "listing",
+        help = "Write a listing file showing the address and encoding of each source line"
+    )]
+    listing: bool,
+    #[arg(
+        long = "relax",
+        help = "Rewrite conditional branches whose target is out of range into a branch around a jump"
+    )]
+    relax: bool,
+    #[arg(
+        long = "deterministic",
+        help = "Produce byte-identical output for identical input (rtool objects contain no timestamps, so this is always the case)"
+    )]
+    deterministic: bool,
+    #[arg(
+        short = 'g',
+        long = "debug",
+        help = "Include a line info section so the simulator can show source lines"
+    )]
+    debug: bool,
+    #[arg(
+        long = "combine",
+        help = "Assemble all inputs into a single object module, named after the first input. Labels are local to their file unless declared .globl"
+    )]
+    combine: bool,
+    #[arg(
+        long = "format",
+        value_enum,
+        default_value_t = OutputFormat::Obj,
+        help = "Output format. `bin` writes the text section as a flat image (.bin) to be loaded at the text start (0x00400000 by default)"
+    )]
+    format: OutputFormat,
+    #[arg(
+        long = "bin-data",
+        help = "With --format bin, also write the rdata, data and sdata sections (.data.bin) as laid out from the data start (0x10000000 by default)"
+    )]
+    bin_data: bool,
+    #[arg(
+        short = 'W',
+        value_name = "[no-]WARNING",
+        help = "Enable or disable (with no-) a group of warnings: unused-label, unreachable, zero-write, dead-write, range, deprecated, noat or all. All are enabled by default"
+    )]
+    warnings: Vec<String>,
+    #[arg(
+        long = "werror",
+        help = "Treat warnings as errors: no output is written for a file with warnings, and the exit status is nonzero"
+    )]
+    werror: bool,
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        help = "Number of files to assemble at once. Defaults to the number of CPUs"
+    )]
+    jobs: Option<usize>,
+    #[arg(
+        short = 'i',
+        long = "ignore-case",
+        help = "Accept mnemonics and directives in any case (ADD, Li, .WORD). Labels and symbols stay case sensitive"
+    )]
+    ignore_case: bool,
+    #[arg(
+        long = "max-errors",
+        default_value_t = 20,
+        help = "Stop after reporting this many errors in a file (0 for no limit)"
+    )]
+    max_errors: usize,
+    #[arg(
+        long = "dialect",
+        value_enum,
+        default_value_t = Dialect::Rasm,
+        help = "Source syntax. `spim` also accepts SPIM/MARS directives (.ktext, .kdata, .eqv, .extern, .global)"
+    )]
+    dialect: Dialect,
+    #[arg(
+        long = "allow-redefinition",
+        help = "Allow symbols assigned with `name = value` to be reassigned. Labels can never be redefined"
+    )]
+    allow_redefinition: bool,
+    #[arg(
+        long = "report",
+        help = "Print the size of each section and the symbol and relocation counts of each output"
+    )]
+    report: bool,
+    #[arg(
+        short = 'o',
+        value_name = "OUTPUT",
+        help = "Write the object module (or binary image) here, or to standard output for -. Defaults to the input name with the extension .obj (or .bin). Only allowed with a single input or --combine"
+    )]
+    output: Option<String>,
+    #[arg(
+        long = "check",
+        help = "Check the input for errors without writing any output"
+    )]
+    check: bool,
+    #[arg(
+        long = "diagnostics",
+        value_enum,
+        default_value_t = DiagnosticFormat::Text,
+        help = "Format of errors and warnings"
+    )]
+    diagnostics: DiagnosticFormat,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    #[arg(required = true)]
+    files: Vec<String>,
+}
+
+pub fn asm(args: &AsmArgs) {
+    if args.output.is_some() && !args.combine && args.files.len() > 1 {
+        eprintln!("-o can only be used with a single input file, or with --combine");
+        std::process::exit(1);
+    }
+    let mut lints = HashSet::from(Lint::ALL);
+    for w in &args.warnings {
+        let (on, name) = match w.strip_prefix("no-") {
+            Some(name) => (false, name),
+            None => (true, w.as_str()),
+        };
+        let group = match name {
+            "all" => Lint::ALL.to_vec(),
+            _ => match Lint::from_name(name) {
+                Some(lint) => vec![lint],
+                None => {
+                    eprintln!("unknown warning group `{}`", name);
+                    std::process::exit(1);
                 }
-                _ => {
-                    depth -= 1;
+            },
+        };
+        for lint in group {
+            match on {
+                true => lints.insert(lint),
+                false => lints.remove(&lint),
+            };
+        }
+    }
+    let layout = args.layout.layout().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let opts = |file: &str| AsmOptions {
+        file_name: file.to_string(),
+        relax: args.relax,
+        deterministic: args.deterministic,
+        line_info: args.debug,
+        lints: lints.clone(),
+        ignore_case: args.ignore_case,
+        max_errors: args.max_errors,
+        dialect: args.dialect,
+        allow_redefinition: args.allow_redefinition,
+        layout,
+    };
+    let load = |file: &String| {
+        let src = fs::read_to_string(file).unwrap_or_else(|e| {
+            eprintln!("cannot read `{}`: {}", file, e);
+            std::process::exit(1);
+        });
+        preprocess(file, &src).map_err(|e| vec![e])
+    };
+
+    let mut failed = false;
+    let mut json = vec![];
+    if args.combine {
+        let mut asm = assemble::Assembler::new(&opts(&args.files[0]));
+        let result = args
+            .files
+            .iter()
+            .map(load)
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|files| asm.assemble_files(files));
+        failed |= complete(asm, result, &args.files[0], args, &mut json);
+    } else {
+        let jobs = args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let results = parallel_map(&args.files, jobs, |file| {
+            let mut asm = assemble::Assembler::new(&opts(file));
+            let result = load(file).and_then(|lines| asm.assemble(lines));
+            (asm, result)
+        });
+        // diagnostics are reported in the order the files were given
+        for (file, (asm, result)) in args.files.iter().zip(results) {
+            failed |= complete(asm, result, file, args, &mut json);
+        }
+    }
+    if args.diagnostics == DiagnosticFormat::Json {
+        println!("[{}]", json.join(",\n"));
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Reports the diagnostics from assembling `file`, either printing them or
+/// adding them to `json`, and writes its output if it succeeded (and is not
+/// just being checked). Returns whether it failed.
+fn complete(
+    asm: assemble::Assembler,
+    result: Result<(), Vec<AsmError>>,
+    file: &str,
+    args: &AsmArgs,
+    json: &mut Vec<String>,
+) -> bool {
+    let text = args.diagnostics == DiagnosticFormat::Text;
+    let mut report = |diags: &[AsmError]| match text {
+        true => diags.iter().for_each(|d| eprintln!("{}", d)),
+        false => json.extend(diags.iter().map(AsmError::to_json)),
+    };
+    report(asm.warnings());
+    match result {
+        Ok(()) if args.werror && !asm.warnings().is_empty() => {
+            if text {
+                eprintln!("{}: warnings treated as errors (--werror)", file);
+            }
+            true
+        }
+        Ok(()) if args.check => false,
+        Ok(()) => write_output(asm, file, args).is_err(),
+        Err(errors) => {
+            report(&errors);
+            if text && errors.len() == args.max_errors {
+                eprintln!("stopping after {} errors", args.max_errors);
+            }
+            true
+        }
+    }
+}
+
+/// Applies `f` to each item using up to `jobs` threads, returning the
+/// results in the same order as the items.
+fn parallel_map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let r = f(item);
+                results.lock().unwrap()[i] = Some(r);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Writes the object module or binary image (and listing, if requested) for
+/// an assembled program alongside the source file `file`.
+fn write_output(asm: assemble::Assembler, file: &str, args: &AsmArgs) -> Result<(), ()> {
+    let write = |path: &Path, contents: &[u8]| {
+        match path == Path::new("-") {
+            true => io::stdout().write_all(contents),
+            false => fs::write(path, contents),
+        }
+        .map_err(|e| eprintln!("cannot write `{}`: {}", path.display(), e))
+    };
+    if args.listing {
+        write(
+            &Path::new(file).with_extension("lst"),
+            asm.listing().as_bytes(),
+        )?;
+    }
+
+    let om = asm.finish();
+    // the header is also the size report
+    let h = om.head.data;
+    let out = match (&args.output, args.format) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, OutputFormat::Obj) => Path::new(file).with_extension("obj"),
+        (None, OutputFormat::Bin) => Path::new(file).with_extension("bin"),
+    };
+    let same_file = |input: &String| {
+        fs::canonicalize(input)
+            .ok()
+            .is_some_and(|i| fs::canonicalize(&out).is_ok_and(|o| i == o))
+    };
+    if args.files.iter().any(same_file) {
+        eprintln!(
+            "{}: refusing to overwrite input file {}",
+            file,
+            out.display()
+        );
+        return Err(());
+    }
+    match args.format {
+        OutputFormat::Obj => write(&out, &om.to_vec_u8())?,
+        OutputFormat::Bin => {
+            // there is no linker to fill in external references later
+            if !om.ext_ref.is_empty() {
+                let names = om
+                    .symtab
+                    .iter()
+                    .filter(|s| s.flags & 0xF == Location::EXT as u32)
+                    .filter_map(|s| om.get_str_entry(s.str_off as usize))
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                eprintln!(
+                    "{}: cannot write a binary image with undefined symbols: {}",
+                    file,
+                    names.join(", ")
+                );
+                return Err(());
+            }
+            write(&out, &om.text)?;
+            if args.bin_data {
+                let mut image = vec![];
+                for sect in [&om.rdata, &om.data, &om.sdata] {
+                    if !sect.is_empty() {
+                        image.resize(image.len().next_multiple_of(PAGE_SIZE as usize), 0);
+                        image.extend_from_slice(sect);
+                    }
                 }
+                let path = match out == Path::new("-") {
+                    true => Path::new(file).with_extension("data.bin"),
+                    false => out.with_extension("data.bin"),
+                };
+                write(&path, &image)?;
             }
         }
     }
+    if args.report {
+        let report = format!(
+            "{}: text {} rdata {} data {} sdata {} sbss {} bss {} bytes; {} symbols, {} relocations, {} references",
+            out.display(),
+            h[0],
+            h[1],
+            h[2],
+            h[3],
+            h[4],
+            h[5],
+            h[8],
+            h[6],
+            h[7]
+        );
+        // keep the report out of an object written to standard output
+        match out == Path::new("-") {
+            true => eprintln!("{}", report),
+            false => println!("{}", report),
+        }
+    }
     Ok(())
 }
 
-pub fn dbg_parse_2(input: String) -> Result<(), pest::error::Error<Rule>> {
-    for l in input.lines() {
-        let mut l = l.to_string();
-        l.push('\n');
-        dbg_parse(l, Rule::line)?
+#[derive(Args, Clone)]
+#[command(
+    about = "Print the parse tree of each line of a MIPS source file, after
+includes and macros have been expanded
+"
+)]
+pub struct ParseArgs {
+    #[arg(long = "json", help = "Print the tree as JSON, for use by other tools")]
+    json: bool,
+    file: String,
+}
+
+pub fn parse(args: &ParseArgs) {
+    let src = fs::read_to_string(&args.file).unwrap_or_else(|e| {
+        eprintln!("cannot read `{}`: {}", args.file, e);
+        std::process::exit(1);
+    });
+    let lines = preprocess(&args.file, &src).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let mut failed = false;
+    let mut json = vec![];
+    for l in &lines {
+        let tree = match MIPSParser::parse(Rule::line, &l.text) {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                failed = true;
+                let (LineColLocation::Pos((_, col)) | LineColLocation::Span((_, col), _)) =
+                    e.line_col;
+                match args.json {
+                    true => json.push(format!(
+                        "{{\"file\":{},\"line\":{},\"column\":{},\"error\":{}}}",
+                        json_str(&l.file),
+                        l.line,
+                        col,
+                        json_str(&e.variant.message())
+                    )),
+                    false => eprintln!("{}:{}:{}: {}", l.file, l.line, col, e.variant.message()),
+                }
+                continue;
+            }
+        };
+        // the `line` rule itself, and the end of input, say nothing
+        let pairs = tree
+            .flat_map(|p| p.into_inner())
+            .filter(|p| p.as_rule() != Rule::EOI);
+        match args.json {
+            true => json.push(format!(
+                "{{\"file\":{},\"line\":{},\"tree\":[{}]}}",
+                json_str(&l.file),
+                l.line,
+                pairs.map(pair_json).collect::<Vec<_>>().join(",")
+            )),
+            false => {
+                println!("{}:{}: {}", l.file, l.line, l.text.trim());
+                pairs.for_each(|p| print_pair(p, 1));
+            }
+        }
+    }
+    if args.json {
+        println!("[{}]", json.join(",\n"));
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Prints a parse tree, one rule per line. Rules without children are
+/// followed by the text they matched.
+fn print_pair(pair: Pair<Rule>, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let mut inner = pair.clone().into_inner().peekable();
+    match inner.peek() {
+        None => println!("{}{:?} [{}]", indent, pair.as_rule(), pair.as_str().trim()),
+        Some(_) => {
+            println!("{}{:?}", indent, pair.as_rule());
+            inner.for_each(|p| print_pair(p, depth + 1));
+        }
     }
+}
 
-    Ok(())
+fn pair_json(pair: Pair<Rule>) -> String {
+    let span = pair.as_span();
+    format!(
+        "{{\"rule\":\"{:?}\",\"start\":{},\"end\":{},\"text\":{},\"children\":[{}]}}",
+        pair.as_rule(),
+        span.start(),
+        span.end(),
+        json_str(span.as_str()),
+        pair.into_inner()
+            .map(pair_json)
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Quotes a string for JSON output.
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
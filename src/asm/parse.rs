@@ -0,0 +1,391 @@
+use std::ops::Range;
+
+use pest::{
+    error::{ErrorVariant, InputLocation},
+    iterators::Pair,
+    Parser,
+};
+
+use super::{
+    dialect::{spim_line, Dialect},
+    expr::Expr,
+    macros::strip_comment,
+    AsmError, AsmOptions, MIPSParser, Rule, SourceLine, GRAMMAR_MAP,
+};
+use crate::common::{parse_register, Location};
+
+/// One parsed line of source: any number of labels followed by an optional
+/// statement.
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub labels: Vec<String>,
+    pub stmt: Option<Stmt>,
+    pub src: SourceLine,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    /// `name = expr`
+    Assign(String, Expr),
+    Directive(Directive),
+    Instr {
+        mnemonic: String,
+        operands: Vec<Operand>,
+        /// Byte range of the mnemonic within the source line
+        mnemonic_span: Range<usize>,
+        /// Byte range of each operand within the source line
+        operand_spans: Vec<Range<usize>>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum Directive {
+    /// `.text`, `.rdata`, `.data` or `.sdata`
+    Section(Location),
+    /// `.ascii` / `.asciiz`, with the terminating NUL already appended for
+    /// the latter
+    Ascii(Vec<Vec<u8>>),
+    Byte(Vec<Expr>),
+    Half(Vec<Expr>),
+    Word(Vec<Expr>),
+    /// `.comm name, size[, align]`: a global reserved in (s)bss
+    Comm(String, Expr, Option<Expr>),
+    /// `.lcomm name, size[, align]`: as `.comm`, but local to the file
+    Lcomm(String, Expr, Option<Expr>),
+    Space(Expr),
+    Align(Expr),
+    /// `.org offset`: pads the section with zeros up to `offset` (or a label
+    /// in the section)
+    Org(Expr),
+    Globl(String),
+    /// `.weak name`: global, but may be overridden by another module's
+    /// definition
+    Weak(String),
+    /// `.set option`
+    Set(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum Operand {
+    Reg(u8),
+    Expr(Expr),
+    /// `expr($reg)`, with an omitted offset parsed as 0
+    Disp(Expr, u8),
+}
+
+/// Parses one line of (preprocessed) source, in the syntax selected by
+/// `opts`.
+pub fn parse_line(src: SourceLine, opts: &AsmOptions) -> Result<Line, AsmError> {
+    let mut text = match opts.ignore_case {
+        true => fold_keyword(&src.text),
+        false => src.text.clone(),
+    };
+    if opts.dialect == Dialect::Spim {
+        text = spim_line(&text).map_err(|(span, msg)| src.error_at(span, msg))?;
+    }
+    let mut pairs = MIPSParser::parse(Rule::line, &text).map_err(|e| syntax_error(&src, e))?;
+
+    let mut labels = vec![];
+    let mut stmt = None;
+    for pair in pairs.next().unwrap().into_inner() {
+        match pair.as_rule() {
+            Rule::label => labels.push(pair.into_inner().as_str().to_string()),
+            Rule::assign => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().unwrap().as_str().to_string();
+                let value = inner.next().unwrap();
+                let expr = match value.as_rule() {
+                    Rule::register => Expr::Reg(
+                        register(value.as_str()).map_err(|e| src.error_at(span(&value), e))?,
+                    ),
+                    _ => {
+                        let span = span(&value);
+                        Expr::from_pair(value).map_err(|e| src.error_at(span, e))?
+                    }
+                };
+                stmt = Some(Stmt::Assign(name, expr));
+            }
+            Rule::directive => {
+                let span = span(&pair);
+                stmt = Some(Stmt::Directive(
+                    parse_directive(pair).map_err(|e| src.error_at(span, e))?,
+                ))
+            }
+            Rule::instr => {
+                let mut inner = pair.into_inner();
+                let mnemonic = inner.next().unwrap();
+                let mnemonic_span = span(&mnemonic);
+                let mut operands = vec![];
+                let mut operand_spans = vec![];
+                for op in inner.next().unwrap().into_inner() {
+                    let span = span(&op);
+                    operands.push(parse_operand(op).map_err(|e| src.error_at(span.clone(), e))?);
+                    operand_spans.push(span);
+                }
+                stmt = Some(Stmt::Instr {
+                    mnemonic: mnemonic.as_str().to_string(),
+                    operands,
+                    mnemonic_span,
+                    operand_spans,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Line { labels, stmt, src })
+}
+
+/// Keeps just the labels of a line which failed to parse, so that references
+/// to them do not cause more errors.
+pub fn recover_labels(src: SourceLine) -> Line {
+    let mut labels = vec![];
+    let mut rest = src.text.trim_start();
+    while let Some(label) = MIPSParser::parse(Rule::label, rest)
+        .ok()
+        .and_then(|mut pairs| pairs.next())
+    {
+        labels.push(label.clone().into_inner().as_str().to_string());
+        rest = rest[label.as_str().len()..].trim_start();
+    }
+    Line {
+        labels,
+        stmt: None,
+        src,
+    }
+}
+
+/// Lowercases the mnemonic or directive name of a line, leaving labels and
+/// symbols (which are case sensitive) alone. The result is the same length,
+/// so spans into it are also valid for the original line.
+fn fold_keyword(text: &str) -> String {
+    let kw = keyword_span(text);
+    let word = text[kw.clone()].to_ascii_lowercase();
+    let mut out = text.to_string();
+    if word.starts_with('.') || GRAMMAR_MAP.contains_key(word.as_str()) {
+        out.replace_range(kw, &word);
+    }
+    out
+}
+
+/// Finds the mnemonic or directive name (including its `.`) of a line,
+/// skipping over any labels.
+pub fn keyword_span(text: &str) -> Range<usize> {
+    let word_end = |i: usize| {
+        text[i..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(text.len(), |n| i + n)
+    };
+    let skip_space = |i: usize| text.len() - text[i..].trim_start().len();
+
+    let mut start = skip_space(0);
+    loop {
+        let end = word_end(start);
+        if end == start || !text[end..].starts_with(':') {
+            break;
+        }
+        start = skip_space(end + 1);
+    }
+    match text[start..].starts_with('.') {
+        true => start..word_end(start + 1),
+        false => start..word_end(start),
+    }
+}
+
+/// Builds a diagnostic from a pest error, describing what was expected in
+/// terms of the source rather than grammar rule names.
+fn syntax_error(src: &SourceLine, e: pest::error::Error<Rule>) -> AsmError {
+    // the grammar only accepts known mnemonics, so an unknown one shows up
+    // as a syntax error at the start of the statement
+    let code = strip_comment(&src.text);
+    let stmt = code.find(':').map_or(code, |idx| &code[idx + 1..]);
+    let word = stmt
+        .trim_start()
+        .split(|c: char| c.is_whitespace())
+        .next()
+        .unwrap_or("");
+    let is_ident = word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    let rest = stmt.trim_start()[word.len()..].trim_start();
+    if is_ident && !rest.starts_with('=') && !GRAMMAR_MAP.contains_key(word.to_lowercase().as_str())
+    {
+        if let Some(span) = src.find(word) {
+            return src
+                .error_at(span, format!("unknown mnemonic `{}`", word))
+                .with_code("syntax");
+        }
+    }
+
+    let span = match e.location {
+        InputLocation::Pos(p) => p..p + 1,
+        InputLocation::Span((start, end)) => start..end,
+    };
+    let msg = match &e.variant {
+        ErrorVariant::ParsingError { positives, .. } => {
+            let mut expected = positives.iter().map(describe_rule).collect::<Vec<_>>();
+            expected.dedup();
+            match expected.as_slice() {
+                [] => String::from("syntax error"),
+                [one] => format!("syntax error: expected {}", one),
+                [init @ .., last] => {
+                    format!("syntax error: expected {} or {}", init.join(", "), last)
+                }
+            }
+        }
+        ErrorVariant::CustomError { message } => format!("syntax error: {}", message),
+    };
+    src.error_at(span, msg).with_code("syntax")
+}
+
+/// Human readable name of a grammar rule, for syntax errors
+fn describe_rule(rule: &Rule) -> String {
+    match rule {
+        Rule::EOI => String::from("end of line"),
+        Rule::add
+        | Rule::sub
+        | Rule::mul
+        | Rule::div
+        | Rule::r#mod
+        | Rule::and
+        | Rule::or
+        | Rule::xor
+        | Rule::sll
+        | Rule::srl
+        | Rule::sra => String::from("an operator"),
+        Rule::expr | Rule::number_literal | Rule::ident | Rule::addr_part => {
+            String::from("an expression")
+        }
+        Rule::register => String::from("a register"),
+        Rule::mnemonic => String::from("an instruction"),
+        Rule::separator => String::from("`,`"),
+        Rule::comment => String::from("a comment"),
+        Rule::assign => String::from("an assignment"),
+        Rule::text
+        | Rule::rdata
+        | Rule::data
+        | Rule::sdata
+        | Rule::ascii
+        | Rule::asciiz
+        | Rule::byte
+        | Rule::half
+        | Rule::word
+        | Rule::comm
+        | Rule::lcomm
+        | Rule::space
+        | Rule::align
+        | Rule::org
+        | Rule::globl
+        | Rule::weak
+        | Rule::set => format!("`.{:?}`", rule),
+        r => format!("{:?}", r).replace('_', " "),
+    }
+}
+
+fn register(s: &str) -> Result<u8, String> {
+    parse_register(s).ok_or(format!("invalid register `{}`", s))
+}
+
+fn span(pair: &Pair<Rule>) -> Range<usize> {
+    pair.as_span().start()..pair.as_span().end()
+}
+
+fn parse_operand(pair: Pair<Rule>) -> Result<Operand, String> {
+    match pair.as_rule() {
+        Rule::displacement => {
+            let mut inner = pair.into_inner().collect::<Vec<_>>();
+            let reg = register(inner.pop().unwrap().as_str())?;
+            let off = match inner.pop() {
+                Some(e) => Expr::from_pair(e)?,
+                None => Expr::Num(0),
+            };
+            Ok(Operand::Disp(off, reg))
+        }
+        Rule::expr => match Expr::from_pair(pair)? {
+            Expr::Reg(r) => Ok(Operand::Reg(r)),
+            e => Ok(Operand::Expr(e)),
+        },
+        r => unreachable!("unexpected operand rule {:?}", r),
+    }
+}
+
+fn parse_directive(pair: Pair<Rule>) -> Result<Directive, String> {
+    let mut inner = pair.into_inner();
+    let kind = inner.next().unwrap();
+    let exprs = |inner: pest::iterators::Pairs<Rule>| {
+        inner.map(Expr::from_pair).collect::<Result<Vec<_>, _>>()
+    };
+    Ok(match kind.as_rule() {
+        Rule::text => Directive::Section(Location::TEXT),
+        Rule::rdata => Directive::Section(Location::RDATA),
+        Rule::data => Directive::Section(Location::DATA),
+        Rule::sdata => Directive::Section(Location::SDATA),
+        Rule::ascii | Rule::asciiz => {
+            let zero = kind.as_rule() == Rule::asciiz;
+            let strings = inner
+                .map(|s| {
+                    let mut bytes = unescape(s.into_inner().next().unwrap().as_str())?;
+                    if zero {
+                        bytes.push(0);
+                    }
+                    Ok(bytes)
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            Directive::Ascii(strings)
+        }
+        Rule::byte => Directive::Byte(exprs(inner)?),
+        Rule::half => Directive::Half(exprs(inner)?),
+        Rule::word => Directive::Word(exprs(inner)?),
+        Rule::comm | Rule::lcomm => {
+            let name = inner.next().unwrap().as_str().to_string();
+            let size = Expr::from_pair(inner.next().unwrap())?;
+            let align = inner.next().map(Expr::from_pair).transpose()?;
+            if kind.as_rule() == Rule::comm {
+                Directive::Comm(name, size, align)
+            } else {
+                Directive::Lcomm(name, size, align)
+            }
+        }
+        Rule::space => Directive::Space(Expr::from_pair(inner.next().unwrap())?),
+        Rule::align => Directive::Align(Expr::from_pair(inner.next().unwrap())?),
+        Rule::org => Directive::Org(Expr::from_pair(inner.next().unwrap())?),
+        Rule::globl => Directive::Globl(inner.next().unwrap().as_str().to_string()),
+        Rule::weak => Directive::Weak(inner.next().unwrap().as_str().to_string()),
+        Rule::set => Directive::Set(inner.next().unwrap().as_str().to_string()),
+        r => unreachable!("unexpected directive rule {:?}", r),
+    })
+}
+
+/// Resolves escape sequences in the body of a string literal.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push(b'\n'),
+            Some('t') => out.push(b'\t'),
+            Some('r') => out.push(b'\r'),
+            Some('b') => out.push(0x08),
+            Some('f') => out.push(0x0c),
+            Some('0') => out.push(0),
+            Some('u') => {
+                let hex = chars.by_ref().take(4).collect::<String>();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(format!("invalid unicode escape `\\u{}`", hex))?;
+                let mut buf = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            Some(c) => out.push(c as u8),
+            None => return Err(String::from("unterminated escape sequence")),
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,309 @@
+use super::{
+    encode::{encode_real, Emit, FixupKind},
+    expr::{Expr, Value},
+    parse::Operand,
+    Grammar,
+};
+use crate::common::{instruction::opcodes::FUNCT_BREAK, Instruction, RelType};
+
+const ZERO: u8 = 0;
+const AT: u8 = 1;
+
+fn reg(r: u8) -> Operand {
+    Operand::Reg(r)
+}
+
+fn num(n: i64) -> Operand {
+    Operand::Expr(Expr::Num(n))
+}
+
+/// Lowers a pseudo instruction into one or more real instructions. `$at` is
+/// used as a scratch register where the expansion needs one.
+///
+/// `value` evaluates an operand with the symbols known so far, returning
+/// `None` if it cannot be evaluated yet; it is used to pick the shortest
+/// expansion of `li` when the constant is already known.
+pub fn expand(
+    mnemonic: &str,
+    grammar: &Grammar,
+    ops: &[Operand],
+    value: &dyn Fn(&Expr) -> Option<Value>,
+) -> Result<Vec<Emit>, String> {
+    use Operand::{Disp as D, Expr as E, Reg as R};
+
+    match (grammar, ops) {
+        (Grammar::PNone, []) => match mnemonic {
+            "nop" => Ok(vec![encode_real("sll", &[reg(ZERO), reg(ZERO), num(0)])?]),
+            _ => Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+        },
+        (Grammar::PLoadStoreAbs, [R(rd), E(imm)]) => load_imm(*rd, imm, value),
+        (Grammar::PLoadStoreRel, [R(rd), E(addr)]) => load_addr(*rd, addr, RelType::IMM2, "ori"),
+        (Grammar::PLoadStoreRel, [R(rd), D(off, rs)]) => Ok(vec![encode_real(
+            "addiu",
+            &[reg(*rd), reg(*rs), Operand::Expr(off.clone())],
+        )?]),
+        (Grammar::PLoadStoreReg, [R(rd), R(rs)]) => {
+            Ok(vec![encode_real("addu", &[reg(*rd), reg(*rs), reg(ZERO)])?])
+        }
+        // lw $t0, label -> lui $at, %hi(label); lw $t0, %lo(label)($at)
+        (Grammar::PLoadStoreAddr, [R(rt), E(addr)]) => {
+            let mut insts = load_addr(AT, addr, RelType::IMM3, "addiu")?;
+            insts[1] = encode_real(mnemonic, &[reg(*rt), Operand::Disp(Expr::Num(0), AT)])?;
+            insts[1].fixup = None;
+            Ok(insts)
+        }
+        (Grammar::PArithReg1, [R(rd)]) => unary(mnemonic, *rd, *rd),
+        (Grammar::PArithReg2, [R(rd), R(rs)]) => match mnemonic {
+            "abs" | "neg" | "negu" | "not" => unary(mnemonic, *rd, *rs),
+            _ => binary(mnemonic, *rd, *rd, *rs),
+        },
+        (Grammar::PArithReg3, [R(rd), R(rs), R(rt)]) => binary(mnemonic, *rd, *rs, *rt),
+        (Grammar::PArithImm2, [R(rd), E(imm)]) => binary_imm(mnemonic, *rd, *rd, imm, value),
+        (Grammar::PArithImm3, [R(rd), R(rs), E(imm)]) => binary_imm(mnemonic, *rd, *rs, imm, value),
+        (Grammar::PBranch1, [E(target)]) => Ok(vec![match mnemonic {
+            "b" => encode_real("beq", &[reg(ZERO), reg(ZERO), E(target.clone())])?,
+            "bal" => encode_real("bgezal", &[reg(ZERO), E(target.clone())])?,
+            _ => return Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+        }]),
+        (Grammar::PBranchCmpZero | Grammar::PBranch2, [R(rs), E(target)]) => {
+            let real = match mnemonic {
+                "beqz" => "beq",
+                "bnez" => "bne",
+                _ => return Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+            };
+            Ok(vec![encode_real(
+                real,
+                &[reg(*rs), reg(ZERO), E(target.clone())],
+            )?])
+        }
+        (Grammar::PBranch3Reg, [R(rs), R(rt), E(target)]) => branch_cmp(mnemonic, *rs, *rt, target),
+        (Grammar::PBranch3Abs, [R(rs), E(imm), E(target)]) => {
+            let mut insts = load_imm(AT, imm, value)?;
+            insts.extend(branch_cmp(mnemonic, *rs, AT, target)?);
+            Ok(insts)
+        }
+        _ => Err(format!("invalid operands for `{}`", mnemonic)),
+    }
+}
+
+/// `li`: uses a single instruction when the value is already known to fit in
+/// 16 bits, otherwise a `lui`/`ori` pair through `$at`.
+fn load_imm(
+    rd: u8,
+    imm: &Expr,
+    value: &dyn Fn(&Expr) -> Option<Value>,
+) -> Result<Vec<Emit>, String> {
+    match value(imm) {
+        Some(Value::Abs(v)) if (-0x8000..0x8000).contains(&v) => {
+            Ok(vec![encode_real("addiu", &[reg(rd), reg(ZERO), num(v)])?])
+        }
+        Some(Value::Abs(v)) if (0..0x10000).contains(&v) => {
+            Ok(vec![encode_real("ori", &[reg(rd), reg(ZERO), num(v)])?])
+        }
+        _ => load_addr(rd, imm, RelType::IMM2, "ori"),
+    }
+}
+
+/// A load or store from an address plus a base register, rather than a
+/// constant offset from one:
+/// `lw $t0, label+4($t1)` -> `lui $at, hi; addiu $at, $at, lo;
+/// addu $at, $at, $t1; lw $t0, 0($at)`. With `$zero` as the base, the low
+/// half goes directly in the load or store instead.
+pub fn load_store_addr(mnemonic: &str, rt: u8, addr: &Expr, base: u8) -> Result<Vec<Emit>, String> {
+    let mut insts = load_addr(AT, addr, RelType::IMM3, "addiu")?;
+    let mut mem = encode_real(mnemonic, &[reg(rt), Operand::Disp(Expr::Num(0), AT)])?;
+    mem.fixup = None;
+    match base {
+        ZERO => insts[1] = mem,
+        _ => {
+            insts.push(encode_real("addu", &[reg(AT), reg(AT), reg(base)])?);
+            insts.push(mem);
+        }
+    }
+    Ok(insts)
+}
+
+/// Materializes a full 32 bit value with `lui $at, hi` followed by
+/// `lo_op rd, $at, lo`. The pair is patched (and relocated, if needed) as a
+/// unit.
+fn load_addr(rd: u8, addr: &Expr, kind: RelType, lo_op: &str) -> Result<Vec<Emit>, String> {
+    let mut hi = encode_real("lui", &[reg(AT), num(0)])?;
+    hi.fixup = Some((FixupKind::AddrPair(kind), addr.clone()));
+    let mut lo = encode_real(lo_op, &[reg(rd), reg(AT), num(0)])?;
+    lo.fixup = None;
+    Ok(vec![hi, lo])
+}
+
+fn unary(mnemonic: &str, rd: u8, rs: u8) -> Result<Vec<Emit>, String> {
+    Ok(match mnemonic {
+        // $at = sign mask, rd = (rs ^ mask) - mask
+        "abs" => vec![
+            encode_real("sra", &[reg(AT), reg(rs), num(31)])?,
+            encode_real("xor", &[reg(rd), reg(rs), reg(AT)])?,
+            encode_real("subu", &[reg(rd), reg(rd), reg(AT)])?,
+        ],
+        "neg" => vec![encode_real("sub", &[reg(rd), reg(ZERO), reg(rs)])?],
+        "negu" => vec![encode_real("subu", &[reg(rd), reg(ZERO), reg(rs)])?],
+        "not" => vec![encode_real("nor", &[reg(rd), reg(rs), reg(ZERO)])?],
+        _ => return Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+    })
+}
+
+fn binary(mnemonic: &str, rd: u8, rs: u8, rt: u8) -> Result<Vec<Emit>, String> {
+    let real = |m: &str, ops: &[Operand]| encode_real(m, ops);
+    Ok(match mnemonic {
+        "div" | "divu" => vec![
+            real(mnemonic, &[reg(rs), reg(rt)])?,
+            real("mflo", &[reg(rd)])?,
+        ],
+        "rem" | "remu" => vec![
+            real(
+                if mnemonic == "rem" { "div" } else { "divu" },
+                &[reg(rs), reg(rt)],
+            )?,
+            real("mfhi", &[reg(rd)])?,
+        ],
+        "mul" => vec![
+            real("mult", &[reg(rs), reg(rt)])?,
+            real("mflo", &[reg(rd)])?,
+        ],
+        // trap with break 6 (overflow) when the high word isn't the sign
+        // extension of the low word
+        "mulo" => vec![
+            real("mult", &[reg(rs), reg(rt)])?,
+            real("mfhi", &[reg(AT)])?,
+            real("mflo", &[reg(rd)])?,
+            real("sra", &[reg(rd), reg(rd), num(31)])?,
+            skip_if("beq", AT, rd)?,
+            break_code(6),
+            real("mflo", &[reg(rd)])?,
+        ],
+        "mulou" => vec![
+            real("multu", &[reg(rs), reg(rt)])?,
+            real("mfhi", &[reg(AT)])?,
+            skip_if("beq", AT, ZERO)?,
+            break_code(6),
+            real("mflo", &[reg(rd)])?,
+        ],
+        "rol" => vec![
+            real("subu", &[reg(AT), reg(ZERO), reg(rt)])?,
+            real("srlv", &[reg(AT), reg(rs), reg(AT)])?,
+            real("sllv", &[reg(rd), reg(rs), reg(rt)])?,
+            real("or", &[reg(rd), reg(rd), reg(AT)])?,
+        ],
+        "ror" => vec![
+            real("subu", &[reg(AT), reg(ZERO), reg(rt)])?,
+            real("sllv", &[reg(AT), reg(rs), reg(AT)])?,
+            real("srlv", &[reg(rd), reg(rs), reg(rt)])?,
+            real("or", &[reg(rd), reg(rd), reg(AT)])?,
+        ],
+        "seq" => vec![
+            real("xor", &[reg(rd), reg(rs), reg(rt)])?,
+            real("sltiu", &[reg(rd), reg(rd), num(1)])?,
+        ],
+        "sne" => vec![
+            real("xor", &[reg(rd), reg(rs), reg(rt)])?,
+            real("sltu", &[reg(rd), reg(ZERO), reg(rd)])?,
+        ],
+        "sge" | "sgeu" => vec![
+            real(set_op(mnemonic), &[reg(rd), reg(rs), reg(rt)])?,
+            real("xori", &[reg(rd), reg(rd), num(1)])?,
+        ],
+        "sgt" | "sgtu" => vec![real(set_op(mnemonic), &[reg(rd), reg(rt), reg(rs)])?],
+        "sle" | "sleu" => vec![
+            real(set_op(mnemonic), &[reg(rd), reg(rt), reg(rs)])?,
+            real("xori", &[reg(rd), reg(rd), num(1)])?,
+        ],
+        _ => return Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+    })
+}
+
+fn binary_imm(
+    mnemonic: &str,
+    rd: u8,
+    rs: u8,
+    imm: &Expr,
+    value: &dyn Fn(&Expr) -> Option<Value>,
+) -> Result<Vec<Emit>, String> {
+    match mnemonic {
+        // rotates by a constant don't need the amount in a register
+        "rol" | "ror" => {
+            let (first, second) = if mnemonic == "rol" {
+                ("sll", "srl")
+            } else {
+                ("srl", "sll")
+            };
+            let amt = match value(imm) {
+                Some(Value::Abs(v)) => v & 0x1F,
+                _ => return Err(format!("`{}` requires a constant rotate amount", mnemonic)),
+            };
+            Ok(vec![
+                encode_real(first, &[reg(AT), reg(rs), num(amt)])?,
+                encode_real(second, &[reg(rd), reg(rs), num((32 - amt) & 0x1F)])?,
+                encode_real("or", &[reg(rd), reg(rd), reg(AT)])?,
+            ])
+        }
+        _ => {
+            let mut insts = load_imm(AT, imm, value)?;
+            insts.extend(binary(mnemonic, rd, rs, AT)?);
+            Ok(insts)
+        }
+    }
+}
+
+fn branch_cmp(mnemonic: &str, rs: u8, rt: u8, target: &Expr) -> Result<Vec<Emit>, String> {
+    let target = Operand::Expr(target.clone());
+    // (compare operands swapped, branch if the comparison is true)
+    let (swap, taken) = match mnemonic {
+        "blt" | "bltu" => (false, true),
+        "bge" | "bgeu" => (false, false),
+        "bgt" | "bgtu" => (true, true),
+        "ble" | "bleu" => (true, false),
+        _ => return Err(format!("unknown pseudo instruction `{}`", mnemonic)),
+    };
+    let (a, b) = if swap { (rt, rs) } else { (rs, rt) };
+    let slt = if mnemonic.ends_with('u') {
+        "sltu"
+    } else {
+        "slt"
+    };
+    Ok(vec![
+        encode_real(slt, &[reg(AT), reg(a), reg(b)])?,
+        encode_real(
+            if taken { "bne" } else { "beq" },
+            &[reg(AT), reg(ZERO), target],
+        )?,
+    ])
+}
+
+fn set_op(mnemonic: &str) -> &'static str {
+    if mnemonic.ends_with('u') {
+        "sltu"
+    } else {
+        "slt"
+    }
+}
+
+/// Branch over the following instruction.
+fn skip_if(mnemonic: &str, rs: u8, rt: u8) -> Result<Emit, String> {
+    let mut e = encode_real(mnemonic, &[reg(rs), reg(rt), num(0)])?;
+    if let Instruction::I { imm, .. } = &mut e.inst {
+        *imm = 1;
+    }
+    e.fixup = None;
+    Ok(e)
+}
+
+/// `break` with a (small) code, which is carried in the shamt field.
+fn break_code(code: u8) -> Emit {
+    Emit {
+        inst: Instruction::R {
+            rs: 0,
+            rt: 0,
+            rd: 0,
+            shamt: code,
+            funct: FUNCT_BREAK,
+        },
+        fixup: None,
+    }
+}
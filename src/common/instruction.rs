@@ -14,6 +14,9 @@ pub mod opcodes {
     pub const FUNCT_SRAV: u8 = 0o07;
     pub const FUNCT_JR: u8 = 0o10;
     pub const FUNCT_JALR: u8 = 0o11;
+    /// MIPS32r2 only, with `--isa mips32r2`
+    pub const FUNCT_MOVZ: u8 = 0o12;
+    pub const FUNCT_MOVN: u8 = 0o13;
     pub const FUNCT_SYSCALL: u8 = 0o14;
     pub const FUNCT_BREAK: u8 = 0o15;
     pub const FUNCT_MFHI: u8 = 0o20;
@@ -34,6 +37,12 @@ pub mod opcodes {
     pub const FUNCT_NOR: u8 = 0o47;
     pub const FUNCT_SLT: u8 = 0o52;
     pub const FUNCT_SLTU: u8 = 0o53;
+    pub const FUNCT_TGE: u8 = 0o60;
+    pub const FUNCT_TGEU: u8 = 0o61;
+    pub const FUNCT_TLT: u8 = 0o62;
+    pub const FUNCT_TLTU: u8 = 0o63;
+    pub const FUNCT_TEQ: u8 = 0o64;
+    pub const FUNCT_TNE: u8 = 0o66;
 
     pub const OP_FUNCT: u8 = 0o00;
     pub const OP_BCOND: u8 = 0o01;
@@ -51,6 +60,10 @@ pub mod opcodes {
     pub const OP_ORI: u8 = 0o15;
     pub const OP_XORI: u8 = 0o16;
     pub const OP_LUI: u8 = 0o17;
+    pub const OP_COP0: u8 = 0o20;
+    /// MIPS32r2 only, with `--isa mips32r2`
+    pub const OP_SPECIAL2: u8 = 0o34;
+    pub const OP_SPECIAL3: u8 = 0o37;
     pub const OP_LB: u8 = 0o40;
     pub const OP_LH: u8 = 0o41;
     pub const OP_LWL: u8 = 0o42;
@@ -64,10 +77,40 @@ pub mod opcodes {
     pub const OP_SW: u8 = 0o53;
     pub const OP_SWR: u8 = 0o56;
 
+    /// Coprocessor operations, in the rs field of an `OP_COP0` instruction
+    pub const COP_MF: u8 = 0o00;
+    pub const COP_MT: u8 = 0o04;
+    pub const COP_CO: u8 = 0o20;
+    /// funct field of a `COP_CO` operation
+    pub const CP0_RFE: u8 = 0o20;
+
+    /// CP0 register numbers
+    pub const CP0_STATUS: u8 = 12;
+    pub const CP0_CAUSE: u8 = 13;
+    pub const CP0_EPC: u8 = 14;
+
+    /// funct field of an `OP_SPECIAL2` instruction
+    pub const SPECIAL2_MUL: u8 = 0o02;
+    pub const SPECIAL2_CLZ: u8 = 0o40;
+    pub const SPECIAL2_CLO: u8 = 0o41;
+    /// funct field of an `OP_SPECIAL3` instruction
+    pub const SPECIAL3_EXT: u8 = 0o00;
+    pub const SPECIAL3_INS: u8 = 0o04;
+    pub const SPECIAL3_BSHFL: u8 = 0o40;
+    /// shamt field of a `SPECIAL3_BSHFL` instruction
+    pub const BSHFL_SEB: u8 = 0o20;
+    pub const BSHFL_SEH: u8 = 0o30;
+
     pub const BCOND_BLTZ: u8 = 0o00;
     pub const BCOND_BGEZ: u8 = 0o01;
     pub const BCOND_BLTZAL: u8 = 0o20;
     pub const BCOND_BGEZAL: u8 = 0o21;
+    pub const BCOND_TGEI: u8 = 0o10;
+    pub const BCOND_TGEIU: u8 = 0o11;
+    pub const BCOND_TLTI: u8 = 0o12;
+    pub const BCOND_TLTIU: u8 = 0o13;
+    pub const BCOND_TEQI: u8 = 0o14;
+    pub const BCOND_TNEI: u8 = 0o16;
 
     pub const SYSCALL_PRINT_INT: u32 = 1;
     pub const SYSCALL_PRINT_STRING: u32 = 4;
@@ -84,6 +127,47 @@ pub mod opcodes {
     pub const SYSCALL_EXIT2: u32 = 17;
     pub const SYSCALL_SNAP: u32 = 18;
     pub const SYSCALL_RSNAP: u32 = 19;
+    pub const SYSCALL_TIME: u32 = 30;
+    pub const SYSCALL_SLEEP: u32 = 32;
+    pub const SYSCALL_SET_SEED: u32 = 40;
+    pub const SYSCALL_RANDOM_INT: u32 = 41;
+    pub const SYSCALL_RANDOM_RANGE: u32 = 42;
+    /// MARS only, with `--syscalls mars`
+    pub const SYSCALL_PRINT_HEX: u32 = 34;
+    pub const SYSCALL_PRINT_BINARY: u32 = 35;
+    pub const SYSCALL_PRINT_UNSIGNED: u32 = 36;
+
+    /// The syscalls the simulator carries out itself, which cannot be given
+    /// to a custom handler
+    pub const BUILTIN_SYSCALLS: &[u32] = &[
+        SYSCALL_PRINT_INT,
+        SYSCALL_PRINT_STRING,
+        SYSCALL_READ_INT,
+        SYSCALL_READ_STRING,
+        SYSCALL_SBRK,
+        SYSCALL_EXIT,
+        SYSCALL_PRINT_CHAR,
+        SYSCALL_READ_CHAR,
+        SYSCALL_OPEN,
+        SYSCALL_READ,
+        SYSCALL_WRITE,
+        SYSCALL_CLOSE,
+        SYSCALL_EXIT2,
+        SYSCALL_SNAP,
+        SYSCALL_RSNAP,
+        SYSCALL_TIME,
+        SYSCALL_SLEEP,
+        SYSCALL_SET_SEED,
+        SYSCALL_RANDOM_INT,
+        SYSCALL_RANDOM_RANGE,
+    ];
+
+    /// The syscalls carried out only with `--syscalls mars`
+    pub const MARS_SYSCALLS: &[u32] = &[
+        SYSCALL_PRINT_HEX,
+        SYSCALL_PRINT_BINARY,
+        SYSCALL_PRINT_UNSIGNED,
+    ];
 }
 
 /// Extracts a bitfield from a 32-bit number, idx 0 is the highest order bit.
@@ -114,6 +198,8 @@ impl TryFrom<u32> for Instruction {
                     0o00 | 0o02..=0o04 | 0o06..=0o07 => {}
                     // jump
                     0o10 | 0o11 => {}
+                    // conditional move
+                    0o12 | 0o13 => {}
                     // syscall / brk
                     0o14 | 0o15 => {}
                     // hi / lo reg operands
@@ -124,6 +210,8 @@ impl TryFrom<u32> for Instruction {
                     0o40..=0o47 => {}
                     // set cond
                     0o52..=0o53 => {}
+                    // trap
+                    0o60..=0o64 | 0o66 => {}
                     _ => {
                         return Err(Error::InstructionParseError(format!(
                             "Illegal funct {:06b}",
@@ -144,8 +232,50 @@ impl TryFrom<u32> for Instruction {
                 op: opcode as u8,
                 imm: imm_j,
             }),
-            /* I type instruction */
-            0o04..=0o17 | 0o40..=0o46 | 0o50..=0o53 | 0o56 => Ok(Instruction::I {
+            /* I type branch or trap, with the condition in rt */
+            0o01 => match rt {
+                0o00 | 0o01 | 0o20 | 0o21 | 0o10..=0o14 | 0o16 => Ok(Instruction::I {
+                    op: opcode as u8,
+                    rs: rs as u8,
+                    rt: rt as u8,
+                    imm: imm_i as u16,
+                }),
+                _ => Err(Error::InstructionParseError(format!(
+                    "Illegal condition {:05b}",
+                    rt
+                ))),
+            },
+            /* MIPS32r2 R type instructions, kept as I type with rd, shamt
+             * and funct in imm, as there is no opcode field in R type */
+            0o34 | 0o37 => {
+                let known = match opcode {
+                    0o34 => matches!(funct, 0o02 | 0o40 | 0o41),
+                    _ => match funct {
+                        // ext, where pos + size may not pass the word
+                        0o00 => shamt + rd < 32,
+                        // ins, where the msb may not be below the lsb
+                        0o04 => rd >= shamt,
+                        // seb / seh
+                        0o40 => matches!(shamt, 0o20 | 0o30),
+                        _ => false,
+                    },
+                };
+                if !known {
+                    return Err(Error::InstructionParseError(format!(
+                        "Illegal funct {:06b} for opcode {}",
+                        funct, opcode
+                    )));
+                }
+                Ok(Instruction::I {
+                    op: opcode as u8,
+                    rs: rs as u8,
+                    rt: rt as u8,
+                    imm: imm_i as u16,
+                })
+            }
+            /* I type instruction. For coprocessor 0 the operation is in rs,
+             * and the CP0 register (or funct for `rfe`) in imm */
+            0o04..=0o17 | 0o20 | 0o40..=0o46 | 0o50..=0o53 | 0o56 => Ok(Instruction::I {
                 op: opcode as u8,
                 rs: rs as u8,
                 rt: rt as u8,
@@ -183,6 +313,352 @@ impl From<Instruction> for u32 {
     }
 }
 
+/// Which fields identify an instruction, for encoding it from its mnemonic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// `OP_FUNCT` with the given funct field
+    R(u8),
+    /// The given opcode, with operands in the rs, rt and imm fields
+    I(u8),
+    /// `OP_BCOND` with the given condition in the rt field
+    Bcond(u8),
+    /// The given opcode, with a 26 bit target
+    J(u8),
+    /// `OP_COP0` with the given operation in the rs field
+    Cop0(u8),
+    /// `OP_SPECIAL2` with the given funct field
+    Special2(u8),
+    /// `OP_SPECIAL3` with the given funct field, other than `SPECIAL3_BSHFL`
+    Special3(u8),
+    /// `OP_SPECIAL3` with funct `SPECIAL3_BSHFL` and the given shamt field
+    Bshfl(u8),
+}
+
+/// An operand of a real instruction, with any expression already evaluated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arg {
+    Reg(u8),
+    /// Immediate, shift amount, branch offset or jump target (in words).
+    /// Truncated to the width of the field it is stored in.
+    Imm(u32),
+    /// `imm(reg)`
+    Disp(u32, u8),
+}
+
+/// Mnemonic of every real instruction and how it is encoded. This is the
+/// inverse of the decoder in `TryFrom<u32>`.
+pub const ENCODINGS: [(&str, Encoding); 82] = {
+    use opcodes::*;
+    use Encoding::*;
+    [
+        ("sll", R(FUNCT_SLL)),
+        ("srl", R(FUNCT_SRL)),
+        ("sra", R(FUNCT_SRA)),
+        ("sllv", R(FUNCT_SLLV)),
+        ("srlv", R(FUNCT_SRLV)),
+        ("srav", R(FUNCT_SRAV)),
+        ("jr", R(FUNCT_JR)),
+        ("jalr", R(FUNCT_JALR)),
+        ("syscall", R(FUNCT_SYSCALL)),
+        ("break", R(FUNCT_BREAK)),
+        ("mfhi", R(FUNCT_MFHI)),
+        ("mthi", R(FUNCT_MTHI)),
+        ("mflo", R(FUNCT_MFLO)),
+        ("mtlo", R(FUNCT_MTLO)),
+        ("mult", R(FUNCT_MULT)),
+        ("multu", R(FUNCT_MULTU)),
+        ("div", R(FUNCT_DIV)),
+        ("divu", R(FUNCT_DIVU)),
+        ("add", R(FUNCT_ADD)),
+        ("addu", R(FUNCT_ADDU)),
+        ("sub", R(FUNCT_SUB)),
+        ("subu", R(FUNCT_SUBU)),
+        ("and", R(FUNCT_AND)),
+        ("or", R(FUNCT_OR)),
+        ("xor", R(FUNCT_XOR)),
+        ("nor", R(FUNCT_NOR)),
+        ("slt", R(FUNCT_SLT)),
+        ("sltu", R(FUNCT_SLTU)),
+        ("bltz", Bcond(BCOND_BLTZ)),
+        ("bgez", Bcond(BCOND_BGEZ)),
+        ("bltzal", Bcond(BCOND_BLTZAL)),
+        ("bgezal", Bcond(BCOND_BGEZAL)),
+        ("j", J(OP_J)),
+        ("jal", J(OP_JAL)),
+        ("beq", I(OP_BEQ)),
+        ("bne", I(OP_BNE)),
+        ("blez", I(OP_BLEZ)),
+        ("bgtz", I(OP_BGTZ)),
+        ("addi", I(OP_ADDI)),
+        ("addiu", I(OP_ADDIU)),
+        ("slti", I(OP_SLTI)),
+        ("sltiu", I(OP_SLTIU)),
+        ("andi", I(OP_ANDI)),
+        ("ori", I(OP_ORI)),
+        ("xori", I(OP_XORI)),
+        ("lui", I(OP_LUI)),
+        ("lb", I(OP_LB)),
+        ("lh", I(OP_LH)),
+        ("lwl", I(OP_LWL)),
+        ("lw", I(OP_LW)),
+        ("lbu", I(OP_LBU)),
+        ("lhu", I(OP_LHU)),
+        ("lwr", I(OP_LWR)),
+        ("sb", I(OP_SB)),
+        ("sh", I(OP_SH)),
+        ("swl", I(OP_SWL)),
+        ("sw", I(OP_SW)),
+        ("swr", I(OP_SWR)),
+        ("mfc0", Cop0(COP_MF)),
+        ("mtc0", Cop0(COP_MT)),
+        ("rfe", Cop0(COP_CO)),
+        ("movz", R(FUNCT_MOVZ)),
+        ("movn", R(FUNCT_MOVN)),
+        ("mul", Special2(SPECIAL2_MUL)),
+        ("clz", Special2(SPECIAL2_CLZ)),
+        ("clo", Special2(SPECIAL2_CLO)),
+        ("ext", Special3(SPECIAL3_EXT)),
+        ("ins", Special3(SPECIAL3_INS)),
+        ("seb", Bshfl(BSHFL_SEB)),
+        ("seh", Bshfl(BSHFL_SEH)),
+        ("tge", R(FUNCT_TGE)),
+        ("tgeu", R(FUNCT_TGEU)),
+        ("tlt", R(FUNCT_TLT)),
+        ("tltu", R(FUNCT_TLTU)),
+        ("teq", R(FUNCT_TEQ)),
+        ("tne", R(FUNCT_TNE)),
+        ("tgei", Bcond(BCOND_TGEI)),
+        ("tgeiu", Bcond(BCOND_TGEIU)),
+        ("tlti", Bcond(BCOND_TLTI)),
+        ("tltiu", Bcond(BCOND_TLTIU)),
+        ("teqi", Bcond(BCOND_TEQI)),
+        ("tnei", Bcond(BCOND_TNEI)),
+    ]
+};
+
+impl Encoding {
+    /// Whether this is a conditional branch, rather than any other
+    /// instruction which tests a condition, such as a trap
+    pub fn is_branch(&self) -> bool {
+        use opcodes::*;
+        matches!(
+            self,
+            Encoding::I(OP_BEQ..=OP_BGTZ)
+                | Encoding::Bcond(BCOND_BLTZ | BCOND_BGEZ | BCOND_BLTZAL | BCOND_BGEZAL)
+        )
+    }
+}
+
+/// Looks up the mnemonic of an encoding, if it is a known instruction.
+pub fn mnemonic(enc: Encoding) -> Option<&'static str> {
+    ENCODINGS.iter().find(|(_, e)| *e == enc).map(|(m, _)| *m)
+}
+
+/// Looks up how the real instruction `mnemonic` is encoded.
+pub fn encoding(mnemonic: &str) -> Option<Encoding> {
+    ENCODINGS
+        .iter()
+        .find(|(m, _)| *m == mnemonic)
+        .map(|(_, e)| *e)
+}
+
+impl Instruction {
+    /// The code of a `break`, which fills the 20 bits between the opcode and
+    /// the funct field
+    pub fn break_code(&self) -> Option<u32> {
+        match *self {
+            Instruction::R {
+                rs,
+                rt,
+                rd,
+                shamt,
+                funct: opcodes::FUNCT_BREAK,
+            } => Some((rs as u32) << 15 | (rt as u32) << 10 | (rd as u32) << 5 | shamt as u32),
+            _ => None,
+        }
+    }
+
+    /// The code of a conditional trap, which fills the 10 bits between rt and
+    /// the funct field. The immediate forms have no code, so it is 0.
+    pub fn trap_code(&self) -> Option<u32> {
+        use opcodes::*;
+        match *self {
+            Instruction::R {
+                rd,
+                shamt,
+                funct: FUNCT_TGE..=FUNCT_TEQ | FUNCT_TNE,
+                ..
+            } => Some((rd as u32) << 5 | shamt as u32),
+            Instruction::I {
+                op: OP_BCOND,
+                rt: BCOND_TGEI..=BCOND_TEQI | BCOND_TNEI,
+                ..
+            } => Some(0),
+            _ => None,
+        }
+    }
+
+    /// The rd, shamt and funct fields of an `OP_SPECIAL2` or `OP_SPECIAL3`
+    /// instruction, which are kept in its imm
+    pub fn special_fields(&self) -> Option<(u8, u8, u8)> {
+        use opcodes::*;
+        match *self {
+            Instruction::I {
+                op: OP_SPECIAL2 | OP_SPECIAL3,
+                imm,
+                ..
+            } => Some((
+                (imm >> 11) as u8 & 0x1f,
+                (imm >> 6) as u8 & 0x1f,
+                imm as u8 & 0x3f,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Whether this instruction was added by MIPS32r2, and so is only run
+    /// with `--isa mips32r2`
+    pub fn is_mips32r2(&self) -> bool {
+        use opcodes::*;
+        matches!(
+            self,
+            Instruction::R {
+                funct: FUNCT_MOVZ | FUNCT_MOVN,
+                ..
+            } | Instruction::I {
+                op: OP_SPECIAL2 | OP_SPECIAL3,
+                ..
+            }
+        )
+    }
+
+    /// The fields which identify this instruction
+    pub fn encoding(&self) -> Encoding {
+        use opcodes::*;
+        match *self {
+            Instruction::R { funct, .. } => Encoding::R(funct),
+            Instruction::I {
+                op: OP_SPECIAL2, ..
+            } => Encoding::Special2(self.special_fields().unwrap().2),
+            Instruction::I {
+                op: OP_SPECIAL3, ..
+            } => match self.special_fields().unwrap() {
+                (_, shamt, SPECIAL3_BSHFL) => Encoding::Bshfl(shamt),
+                (_, _, funct) => Encoding::Special3(funct),
+            },
+            Instruction::I {
+                op: OP_BCOND, rt, ..
+            } => Encoding::Bcond(rt),
+            Instruction::I {
+                op: OP_COP0, rs, ..
+            } => Encoding::Cop0(rs),
+            Instruction::I { op, .. } => Encoding::I(op),
+            Instruction::J { op, .. } => Encoding::J(op),
+        }
+    }
+
+    /// Builds a real instruction from its mnemonic and operands, given in
+    /// the order they are written in assembly.
+    pub fn encode(mnemonic: &str, args: &[Arg]) -> Result<Instruction, String> {
+        use opcodes::*;
+        use Arg::{Disp, Imm, Reg};
+
+        let enc = encoding(mnemonic).ok_or(format!("unknown instruction `{}`", mnemonic))?;
+        let r = |rs, rt, rd, shamt, funct| Instruction::R {
+            rs,
+            rt,
+            rd,
+            shamt,
+            funct,
+        };
+        let i = |op, rs, rt, imm: u32| Instruction::I {
+            op,
+            rs,
+            rt,
+            imm: imm as u16,
+        };
+        let special = |op, rs, rt, rd: u32, shamt: u32, funct: u8| {
+            i(
+                op,
+                rs,
+                rt,
+                (rd & 0x1f) << 11 | (shamt & 0x1f) << 6 | funct as u32,
+            )
+        };
+
+        Ok(match (enc, args) {
+            (
+                Encoding::R(f @ (FUNCT_SLL | FUNCT_SRL | FUNCT_SRA)),
+                &[Reg(rd), Reg(rt), Imm(sa)],
+            ) => r(0, rt, rd, (sa & 0x1f) as u8, f),
+            (
+                Encoding::R(f @ (FUNCT_SLLV | FUNCT_SRLV | FUNCT_SRAV)),
+                &[Reg(rd), Reg(rt), Reg(rs)],
+            ) => r(rs, rt, rd, 0, f),
+            (Encoding::R(f @ FUNCT_JR), &[Reg(rs)]) => r(rs, 0, 0, 0, f),
+            // jalr links into $ra unless told otherwise
+            (Encoding::R(f @ FUNCT_JALR), &[Reg(rs)]) => r(rs, 0, 31, 0, f),
+            (Encoding::R(f @ FUNCT_JALR), &[Reg(rs), Reg(rd)]) => r(rs, 0, rd, 0, f),
+            (Encoding::R(f @ (FUNCT_SYSCALL | FUNCT_BREAK)), &[]) => r(0, 0, 0, 0, f),
+            (Encoding::R(f @ (FUNCT_MFHI | FUNCT_MFLO)), &[Reg(rd)]) => r(0, 0, rd, 0, f),
+            (Encoding::R(f @ (FUNCT_MTHI | FUNCT_MTLO)), &[Reg(rs)]) => r(rs, 0, 0, 0, f),
+            (Encoding::R(f @ FUNCT_MULT..=FUNCT_DIVU), &[Reg(rs), Reg(rt)]) => r(rs, rt, 0, 0, f),
+            (Encoding::R(f @ FUNCT_ADD..=FUNCT_SLTU), &[Reg(rd), Reg(rs), Reg(rt)]) => {
+                r(rs, rt, rd, 0, f)
+            }
+            (Encoding::R(f @ (FUNCT_MOVZ | FUNCT_MOVN)), &[Reg(rd), Reg(rs), Reg(rt)]) => {
+                r(rs, rt, rd, 0, f)
+            }
+            (Encoding::R(f @ (FUNCT_TGE..=FUNCT_TEQ | FUNCT_TNE)), &[Reg(rs), Reg(rt)]) => {
+                r(rs, rt, 0, 0, f)
+            }
+            (
+                Encoding::R(f @ (FUNCT_TGE..=FUNCT_TEQ | FUNCT_TNE)),
+                &[Reg(rs), Reg(rt), Imm(code)],
+            ) => r(rs, rt, (code >> 5) as u8 & 0x1f, code as u8 & 0x1f, f),
+            (Encoding::Special2(f @ SPECIAL2_MUL), &[Reg(rd), Reg(rs), Reg(rt)]) => {
+                special(OP_SPECIAL2, rs, rt, rd as u32, 0, f)
+            }
+            // clz and clo give rd in rt as well
+            (Encoding::Special2(f @ (SPECIAL2_CLZ | SPECIAL2_CLO)), &[Reg(rd), Reg(rs)]) => {
+                special(OP_SPECIAL2, rs, rd, rd as u32, 0, f)
+            }
+            (Encoding::Special3(f @ SPECIAL3_EXT), &[Reg(rt), Reg(rs), Imm(pos), Imm(size)])
+                if size > 0 && pos + size <= 32 =>
+            {
+                special(OP_SPECIAL3, rs, rt, size - 1, pos, f)
+            }
+            (Encoding::Special3(f @ SPECIAL3_INS), &[Reg(rt), Reg(rs), Imm(pos), Imm(size)])
+                if size > 0 && pos + size <= 32 =>
+            {
+                special(OP_SPECIAL3, rs, rt, pos + size - 1, pos, f)
+            }
+            (Encoding::Bshfl(sa), &[Reg(rd), Reg(rt)]) => {
+                special(OP_SPECIAL3, 0, rt, rd as u32, sa as u32, SPECIAL3_BSHFL)
+            }
+            (Encoding::I(op @ (OP_BEQ | OP_BNE)), &[Reg(rs), Reg(rt), Imm(off)]) => {
+                i(op, rs, rt, off)
+            }
+            (Encoding::I(op @ (OP_BLEZ | OP_BGTZ)), &[Reg(rs), Imm(off)]) => i(op, rs, 0, off),
+            (Encoding::Bcond(cond), &[Reg(rs), Imm(off)]) => i(OP_BCOND, rs, cond, off),
+            (Encoding::I(op @ OP_LB..=OP_SWR), &[Reg(rt), Disp(off, rs)]) => i(op, rs, rt, off),
+            (Encoding::I(op @ OP_ADDI..=OP_XORI), &[Reg(rt), Reg(rs), Imm(imm)]) => {
+                i(op, rs, rt, imm)
+            }
+            (Encoding::I(op @ OP_ADDI..=OP_LUI), &[Reg(rt), Imm(imm)]) => i(op, 0, rt, imm),
+            (Encoding::Cop0(cop @ (COP_MF | COP_MT)), &[Reg(rt), Reg(rd)]) => {
+                i(OP_COP0, cop, rt, (rd as u32) << 11)
+            }
+            (Encoding::Cop0(cop @ COP_CO), &[]) => i(OP_COP0, cop, 0, CP0_RFE as u32),
+            (Encoding::J(op), [Imm(target)]) => Instruction::J {
+                op,
+                imm: target & 0x03FF_FFFF,
+            },
+            _ => return Err(format!("invalid operands for `{}`", mnemonic)),
+        })
+    }
+}
+
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use opcodes::*;
@@ -198,7 +674,47 @@ impl Display for Instruction {
                 imm << 2
             ),
             Instruction::I { op, rs, rt, imm } => match *op {
+                OP_SPECIAL2 | OP_SPECIAL3 => {
+                    let (rd, shamt, funct) = self.special_fields().unwrap();
+                    let (rs, rt, rd) = (register_name(*rs), register_name(*rt), register_name(rd));
+                    match (*op, funct) {
+                        (OP_SPECIAL2, SPECIAL2_MUL) => write!(f, "mul ${}, ${}, ${}", rd, rs, rt),
+                        (OP_SPECIAL2, SPECIAL2_CLZ) => write!(f, "clz ${}, ${}", rd, rs),
+                        (OP_SPECIAL2, _) => write!(f, "clo ${}, ${}", rd, rs),
+                        (_, SPECIAL3_BSHFL) => match shamt {
+                            BSHFL_SEB => write!(f, "seb ${}, ${}", rd, rt),
+                            _ => write!(f, "seh ${}, ${}", rd, rt),
+                        },
+                        (_, SPECIAL3_EXT) => {
+                            let size = (imm >> 11 & 0x1f) + 1;
+                            write!(f, "ext ${}, ${}, {}, {}", rt, rs, shamt, size)
+                        }
+                        (_, _) => {
+                            let size = (imm >> 11 & 0x1f) + 1 - shamt as u16;
+                            write!(f, "ins ${}, ${}, {}, {}", rt, rs, shamt, size)
+                        }
+                    }
+                }
+                OP_COP0 => match *rs {
+                    COP_MF => write!(f, "mfc0 ${}, ${}", register_name(*rt), imm >> 11),
+                    COP_MT => write!(f, "mtc0 ${}, ${}", register_name(*rt), imm >> 11),
+                    _ => write!(f, "rfe"),
+                },
                 OP_LUI => write!(f, "lui ${}, 0x{:04x}", register_name(*rt), imm),
+                OP_BCOND if self.trap_code().is_some() => write!(
+                    f,
+                    "{} ${}, {}",
+                    match *rt {
+                        BCOND_TGEI => "tgei",
+                        BCOND_TGEIU => "tgeiu",
+                        BCOND_TLTI => "tlti",
+                        BCOND_TLTIU => "tltiu",
+                        BCOND_TEQI => "teqi",
+                        _ => "tnei",
+                    },
+                    register_name(*rs),
+                    *imm as i16
+                ),
                 _ => write!(
                     f,
                     "{} ${}, ${}, 0x{:04x}",
@@ -263,11 +779,34 @@ impl Display for Instruction {
                 FUNCT_JR => write!(f, "jr ${}", register_name(*rs)),
                 FUNCT_JALR => write!(f, "jalr ${}, ${}", register_name(*rs), register_name(*rd)),
                 FUNCT_SYSCALL => write!(f, "syscall"),
-                FUNCT_BREAK => write!(f, "break"),
+                FUNCT_BREAK => match self.break_code() {
+                    Some(0) | None => write!(f, "break"),
+                    Some(code) => write!(f, "break 0x{:x}", code),
+                },
                 FUNCT_MFHI => write!(f, "mfhi ${}", register_name(*rd)),
                 FUNCT_MFLO => write!(f, "mflo ${}", register_name(*rd)),
                 FUNCT_MTHI => write!(f, "mthi ${}", register_name(*rs)),
                 FUNCT_MTLO => write!(f, "mtlo ${}", register_name(*rs)),
+                FUNCT_TGE..=FUNCT_TEQ | FUNCT_TNE => {
+                    write!(
+                        f,
+                        "{} ${}, ${}",
+                        match *funct {
+                            FUNCT_TGE => "tge",
+                            FUNCT_TGEU => "tgeu",
+                            FUNCT_TLT => "tlt",
+                            FUNCT_TLTU => "tltu",
+                            FUNCT_TEQ => "teq",
+                            _ => "tne",
+                        },
+                        register_name(*rs),
+                        register_name(*rt)
+                    )?;
+                    match self.trap_code() {
+                        Some(0) | None => Ok(()),
+                        Some(code) => write!(f, ", {}", code),
+                    }
+                }
                 0o30..=0o33 => write!(
                     f,
                     "{} ${}, ${}",
@@ -295,6 +834,8 @@ impl Display for Instruction {
                         FUNCT_NOR => "nor",
                         FUNCT_SLT => "slt",
                         FUNCT_SLTU => "sltu",
+                        FUNCT_MOVZ => "movz",
+                        FUNCT_MOVN => "movn",
                         _ => unreachable!(),
                     },
                     register_name(*rd),
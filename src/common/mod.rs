@@ -44,6 +44,31 @@ pub fn register_name(reg: u8) -> &'static str {
     }
 }
 
+/// Parses a register, given either by number (`$8`) or by name (`$t0`). The
+/// leading `$` is optional. `$s8` is accepted as an alias of `$fp`.
+pub fn parse_register(s: &str) -> Option<u8> {
+    let name = s.strip_prefix('$').unwrap_or(s).to_ascii_lowercase();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return name.parse::<u8>().ok().filter(|n| *n < 32);
+    }
+    match name.as_str() {
+        "s8" => Some(30),
+        "" => None,
+        _ => (0..32).find(|r| register_name(*r) == name),
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InstructionParseError(s) => write!(f, "instruction parse error: {}", s),
+            Error::MemoryAccessError(s) => write!(f, "memory access error: {}", s),
+            Error::UnhandledException(s) => write!(f, "unhandled exception: {}", s),
+            Error::AssemblerError(s) => write!(f, "{}", s),
+        }
+    }
+}
+
 impl Display for Location {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -123,6 +148,9 @@ pub fn flags_string(flags: u32) -> String {
     if has_all_flags(flags, SYM_LIT) {
         s.push_str("LIT ");
     }
+    if has_all_flags(flags, SYM_WEAK) {
+        s.push_str("WEAK ");
+    }
     if !has_any_flags(flags, SYM_DEF | SYM_LIT) {
         s.push_str("UNDEF ");
     }
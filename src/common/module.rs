@@ -7,9 +7,10 @@ use std::{
 use lazy_static::lazy_static;
 
 use super::{
-    flags_string,
+    flags_string, register_name,
     types::{ObjectHeader, ObjectModule},
-    Location, RefInfo, RefUnknown, SymEntry,
+    Archive, ArchiveMember, ArchiveSymbol, CoreInfo, CorePage, LineEntry, LineInfo, Location,
+    RefInfo, RefUnknown, StrTabBuilder, SymEntry, CORE_EXEC, CORE_WRITE, SYM_DEF, SYM_GLB, SYM_LIT,
 };
 use crate::common::{RefEntry, RefType, RelEntry, RelType};
 
@@ -130,6 +131,8 @@ lazy_static! {
         strtab: (0..0x8000)
             .flat_map(|e| format!("{:015b}", e).bytes().chain([0]).collect::<Vec<_>>())
             .collect(),
+        line_info: None,
+        core: None,
     };
 }
 
@@ -326,7 +329,19 @@ impl ObjectModule {
         }
 
         // TODO: mod tab
-        // println!("Remaining bytes in object file: {}", bytes.count());
+        let mut rest = bytes.copied().collect::<Vec<_>>();
+        let line_info = match rest.starts_with(&LineInfo::MAGIC.to_be_bytes()) {
+            true => {
+                let info = LineInfo::from_bytes(&rest)?;
+                rest.drain(..info.to_bytes().len());
+                Some(info)
+            }
+            false => None,
+        };
+        let core = match rest.starts_with(&CoreInfo::MAGIC.to_be_bytes()) {
+            true => Some(CoreInfo::from_bytes(&rest)?),
+            false => None,
+        };
 
         Ok(ObjectModule {
             head,
@@ -338,6 +353,8 @@ impl ObjectModule {
             ext_ref,
             symtab,
             strtab,
+            line_info,
+            core,
         })
     }
 
@@ -447,6 +464,53 @@ impl ObjectModule {
         Ok(())
     }
 
+    pub fn print_lines(&self) {
+        if let Some(info) = &self.line_info {
+            println!("line info: {} entries", info.lines.len());
+            for l in &info.lines {
+                println!(
+                    " line: text {:08x} {}:{}",
+                    l.offset, info.files[l.file as usize], l.line
+                );
+            }
+        }
+    }
+
+    pub fn print_core(&self) {
+        if let Some(core) = &self.core {
+            println!("core: {}", core.reason);
+            println!(" pc {:08x} hi {:08x} lo {:08x}", core.pc, core.hi, core.lo);
+            for (i, chunk) in core.reg.chunks(4).enumerate() {
+                print!(" ");
+                for (j, r) in chunk.iter().enumerate() {
+                    print!(
+                        " {:>5} {:08x}",
+                        format!("${}", register_name((4 * i + j) as u8)),
+                        r
+                    );
+                }
+                println!();
+            }
+            println!("pages: {} of {} bytes", core.pages.len(), core.page_size);
+            for page in &core.pages {
+                println!(
+                    " page: addr {:08x} {}{}",
+                    page.addr,
+                    if page.flags & CORE_WRITE != 0 {
+                        "W"
+                    } else {
+                        "-"
+                    },
+                    if page.flags & CORE_EXEC != 0 {
+                        "X"
+                    } else {
+                        "-"
+                    },
+                );
+            }
+        }
+    }
+
     pub fn get_str_entry(&self, offset: usize) -> Option<CString> {
         // check that string is the first string or immediately follows a NUL byte
         if offset != 0
@@ -484,10 +548,284 @@ impl ObjectModule {
             buf.extend_from_slice(&sym.to_bytes());
         }
         buf.extend_from_slice(self.strtab.as_slice());
+        if let Some(info) = self.line_info {
+            buf.extend_from_slice(&info.to_bytes());
+        }
+        if let Some(core) = self.core {
+            buf.extend_from_slice(&core.to_bytes());
+        }
         buf
     }
 }
 
+impl StrTabBuilder {
+    /// Returns the offset of `s` in the table, adding it (NUL terminated)
+    /// if it is not there already.
+    pub fn add(&mut self, s: &str) -> u32 {
+        if let Some(off) = self.offsets.get(s) {
+            return *off;
+        }
+        let off = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        self.offsets.insert(s.to_string(), off);
+        off
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl LineInfo {
+    pub const MAGIC: u32 = 0x4c49_4e45;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&Self::MAGIC.to_be_bytes());
+        buf.extend_from_slice(&(self.files.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&(self.lines.len() as u32).to_be_bytes());
+        for f in &self.files {
+            buf.extend_from_slice(f.as_bytes());
+            buf.push(0);
+        }
+        for l in &self.lines {
+            buf.extend_from_slice(&l.offset.to_be_bytes());
+            buf.extend_from_slice(&l.file.to_be_bytes());
+            buf.extend_from_slice(&l.line.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let err = || String::from("Reached end of data while parsing line info");
+        let word = |i: usize| -> Result<u32, String> {
+            data.get(i..i + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(err)
+        };
+        let (nfiles, nlines) = (word(4)?, word(8)?);
+
+        let mut pos = 12;
+        let mut files = vec![];
+        for _ in 0..nfiles {
+            let len = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or_else(err)?;
+            files.push(String::from_utf8_lossy(&data[pos..pos + len]).into_owned());
+            pos += len + 1;
+        }
+
+        let mut lines = vec![];
+        for _ in 0..nlines {
+            let entry = LineEntry {
+                offset: word(pos)?,
+                file: word(pos + 4)?,
+                line: word(pos + 8)?,
+            };
+            if entry.file >= nfiles {
+                return Err(format!("Invalid file index {} in line info", entry.file));
+            }
+            lines.push(entry);
+            pos += 12;
+        }
+        Ok(LineInfo { files, lines })
+    }
+
+    /// Finds the source file and line which produced the word at `offset`
+    /// in the text section.
+    pub fn lookup(&self, offset: u32) -> Option<(&str, u32)> {
+        let i = self.lines.partition_point(|l| l.offset <= offset);
+        let l = self.lines.get(i.checked_sub(1)?)?;
+        Some((&self.files[l.file as usize], l.line))
+    }
+}
+
+impl CoreInfo {
+    pub const MAGIC: u32 = 0x434f_5245;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        let words = [Self::MAGIC, self.pc, self.hi, self.lo]
+            .into_iter()
+            .chain(self.reg);
+        for w in words {
+            buf.extend_from_slice(&w.to_be_bytes());
+        }
+        buf.extend_from_slice(self.reason.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&self.page_size.to_be_bytes());
+        buf.extend_from_slice(&(self.pages.len() as u32).to_be_bytes());
+        for page in &self.pages {
+            buf.extend_from_slice(&page.addr.to_be_bytes());
+            buf.extend_from_slice(&page.flags.to_be_bytes());
+            buf.extend_from_slice(&page.bytes);
+        }
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let err = || String::from("Reached end of data while parsing core");
+        let word = |i: usize| -> Result<u32, String> {
+            data.get(i..i + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(err)
+        };
+        let mut reg = [0; 32];
+        for (i, r) in reg.iter_mut().enumerate() {
+            *r = word(16 + 4 * i)?;
+        }
+
+        let mut pos = 16 + 4 * 32;
+        let len = data
+            .get(pos..)
+            .and_then(|rest| rest.iter().position(|b| *b == 0))
+            .ok_or_else(err)?;
+        let reason = String::from_utf8_lossy(&data[pos..pos + len]).into_owned();
+        pos += len + 1;
+
+        let (page_size, count) = (word(pos)?, word(pos + 4)?);
+        pos += 8;
+        let mut pages = vec![];
+        for _ in 0..count {
+            let (addr, flags) = (word(pos)?, word(pos + 4)?);
+            let bytes = data
+                .get(pos + 8..pos + 8 + page_size as usize)
+                .ok_or_else(err)?;
+            pages.push(CorePage {
+                addr,
+                flags,
+                bytes: bytes.to_vec(),
+            });
+            pos += 8 + page_size as usize;
+        }
+        Ok(CoreInfo {
+            pc: word(4)?,
+            hi: word(8)?,
+            lo: word(12)?,
+            reg,
+            reason,
+            page_size,
+            pages,
+        })
+    }
+}
+
+impl Archive {
+    pub const MAGIC: u32 = 0x524c_4942;
+
+    /// Collects `members` into an archive, indexing the global symbols each
+    /// one defines
+    pub fn new(members: Vec<ArchiveMember>) -> Self {
+        let index = members
+            .iter()
+            .enumerate()
+            .flat_map(|(i, m)| {
+                m.module
+                    .symtab
+                    .iter()
+                    .filter(|s| s.has_flags(SYM_GLB) && s.has_any_flag(SYM_DEF | SYM_LIT))
+                    .filter_map(|s| m.module.get_str_entry(s.str_off as usize))
+                    .map(move |name| ArchiveSymbol {
+                        name: name.to_string_lossy().into_owned(),
+                        member: i as u32,
+                    })
+            })
+            .collect();
+        Archive { members, index }
+    }
+
+    pub fn is_archive(data: &[u8]) -> bool {
+        data.starts_with(&Self::MAGIC.to_be_bytes())
+    }
+
+    /// The first member which defines the global symbol `name`
+    pub fn member_for(&self, name: &str) -> Option<usize> {
+        self.index
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.member as usize)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for w in [
+            Self::MAGIC,
+            self.members.len() as u32,
+            self.index.len() as u32,
+        ] {
+            buf.extend_from_slice(&w.to_be_bytes());
+        }
+        for sym in &self.index {
+            buf.extend_from_slice(sym.name.as_bytes());
+            buf.push(0);
+            buf.extend_from_slice(&sym.member.to_be_bytes());
+        }
+        for member in &self.members {
+            let bytes = member.module.clone().to_vec_u8();
+            buf.extend_from_slice(member.name.as_bytes());
+            buf.push(0);
+            buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if !Self::is_archive(data) {
+            return Err(String::from("Not an archive"));
+        }
+        let err = || String::from("Reached end of data while parsing archive");
+        let word = |i: usize| -> Result<u32, String> {
+            data.get(i..i + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(err)
+        };
+        let string = |i: usize| -> Result<String, String> {
+            let len = data
+                .get(i..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or_else(err)?;
+            Ok(String::from_utf8_lossy(&data[i..i + len]).into_owned())
+        };
+
+        let (members, symbols) = (word(4)?, word(8)?);
+        let mut pos = 12;
+        let mut index = vec![];
+        for _ in 0..symbols {
+            let name = string(pos)?;
+            pos += name.len() + 1;
+            let member = word(pos)?;
+            if member >= members {
+                return Err(format!("Index entry `{}` names member {}", name, member));
+            }
+            index.push(ArchiveSymbol { name, member });
+            pos += 4;
+        }
+
+        let mut archive = Archive {
+            members: vec![],
+            index,
+        };
+        for _ in 0..members {
+            let name = string(pos)?;
+            pos += name.len() + 1;
+            let len = word(pos)? as usize;
+            pos += 4;
+            let bytes = data.get(pos..pos + len).ok_or_else(err)?;
+            if len < 52 {
+                return Err(format!("Member `{}` is not an object module", name));
+            }
+            let module = ObjectModule::from_slice_u8(bytes)
+                .map_err(|e| format!("Member `{}`: {}", name, e))?;
+            archive.members.push(ArchiveMember { name, module });
+            pos += len;
+        }
+        Ok(archive)
+    }
+}
+
 impl RelEntry {
     pub fn to_bytes(&self) -> [u8; 8] {
         let mut buf = [0; 8];
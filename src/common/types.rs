@@ -1,4 +1,4 @@
-use std::{ffi::CString, fmt::Display};
+use std::{collections::HashMap, ffi::CString, fmt::Display};
 
 /// Unified error type across all rtool subcommands
 #[derive(Clone, Debug)]
@@ -6,6 +6,7 @@ pub enum Error {
     InstructionParseError(String),
     MemoryAccessError(String),
     UnhandledException(String),
+    AssemblerError(String),
 }
 
 /// Intermediate instruction representation allowing easy conversion to and
@@ -46,6 +47,10 @@ pub struct ObjectHeader {
     pub(crate) data: [u32; 10],
 }
 
+/// Set in `ObjectHeader::flags` by the linker once every reference in a
+/// module is resolved, marking it executable
+pub const FL_EXEC: u32 = 0x0000_0001;
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct ObjectModule {
     pub(crate) head: ObjectHeader,
@@ -57,9 +62,109 @@ pub struct ObjectModule {
     pub(crate) ext_ref: Vec<RefEntry>,
     pub(crate) symtab: Vec<SymEntry>,
     pub(crate) strtab: Vec<u8>,
+    /// Debug line information, stored after the string table when present
+    pub(crate) line_info: Option<LineInfo>,
+    /// The state of the program when it crashed, in a core file
+    pub(crate) core: Option<CoreInfo>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Builds a module's string table, storing each distinct string once.
+#[derive(Clone, Debug, Default)]
+pub struct StrTabBuilder {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) offsets: HashMap<String, u32>,
+}
+
+/// Maps offsets in the text section to the source line they were assembled
+/// from.
+///
+/// ```text
+/// u32 magic ("LINE"), u32 file count, u32 entry count,
+/// file names (NUL terminated), entries (u32 offset, u32 file, u32 line)
+/// ```
+///
+/// All integers are big endian. Entries are sorted by offset, and each one
+/// covers the text up to the next.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+/// The registers and memory of a program which terminated abnormally,
+/// written by `rtool run --core` after the line info (if any) of a copy of
+/// the program's module.
+///
+/// ```text
+/// u32 magic ("CORE"), u32 pc, u32 hi, u32 lo, 32 u32 registers,
+/// why it terminated (NUL terminated), u32 page size, u32 page count,
+/// pages (u32 address, u32 flags, page bytes)
+/// ```
+///
+/// All integers are big endian. A page's flags are `CORE_WRITE` and
+/// `CORE_EXEC`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoreInfo {
+    pub pc: u32,
+    pub hi: u32,
+    pub lo: u32,
+    pub reg: [u32; 32],
+    pub reason: String,
+    pub page_size: u32,
+    pub pages: Vec<CorePage>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorePage {
+    pub addr: u32,
+    pub flags: u32,
+    pub bytes: Vec<u8>,
+}
+
+pub const CORE_WRITE: u32 = 0x1;
+pub const CORE_EXEC: u32 = 0x2;
+
+/// Object modules collected into one library file, written by `rtool ar`.
+/// rlink only takes the members which define symbols the other modules
+/// refer to but do not define.
+///
+/// ```text
+/// u32 magic ("RLIB"), u32 member count, u32 index count,
+/// index entries (symbol name NUL terminated, u32 member),
+/// members (name NUL terminated, u32 length, module bytes)
+/// ```
+///
+/// All integers are big endian. The index lists every global symbol defined
+/// by each member.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Archive {
+    pub members: Vec<ArchiveMember>,
+    pub index: Vec<ArchiveSymbol>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub module: ObjectModule,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveSymbol {
+    pub name: String,
+    /// index into `Archive::members`
+    pub member: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineEntry {
+    /// offset into the text section
+    pub offset: u32,
+    /// index into `LineInfo::files`
+    pub file: u32,
+    pub line: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Location {
     TEXT = 0,
     RDATA = 1,
@@ -102,6 +207,9 @@ pub const SYM_SML: u32 = 0x0000_8000;
 pub const SYM_ADJ: u32 = 0x0001_0000;
 pub const SYM_DISC: u32 = 0x0002_0000;
 pub const SYM_LIT: u32 = 0x0004_0000;
+/// Not part of the reference format: set by `.weak`, marking a symbol which
+/// the linker should replace with a non-weak definition of the same name
+pub const SYM_WEAK: u32 = 0x0008_0000;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct RelEntry {
@@ -142,7 +250,7 @@ pub enum RefType {
     IMM3 = 6,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RelType {
     IMM = 1,
     IMM2 = 2,
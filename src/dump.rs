@@ -9,10 +9,17 @@ use crate::common::ObjectModule;
 If no flags are specified, prints all information about all files
 ")]
 pub struct DumpArgs {
+    #[arg(
+        short = 'c',
+        help = "Dump the registers and pages of a core file (if present)"
+    )]
+    core: bool,
     #[arg(short = 'd', help = "Dump the contents of the data section")]
     data: bool,
     #[arg(short = 'f', help = "Dump the contents of the reference list")]
     reference: bool,
+    #[arg(short = 'g', help = "Dump the debug line info (if present)")]
+    lines: bool,
     #[arg(short = 'l', help = "Dump the contents of the relocation list")]
     relocation: bool,
     #[arg(
@@ -33,14 +40,16 @@ pub struct DumpArgs {
 
 pub fn dump(args: &DumpArgs) {
     // if no flags specified, print everything
-    let all = !(args.data
+    let all = !(args.core
+        || args.data
         || args.reference
         || args.relocation
         || args.modtab
         || args.rdata
         || args.sdata
         || args.text
-        || args.symtab);
+        || args.symtab
+        || args.lines);
     let oms = args
         .files
         .iter()
@@ -81,5 +90,11 @@ pub fn dump(args: &DumpArgs) {
         if all || args.symtab {
             om.print_sym();
         }
+        if all || args.lines {
+            om.print_lines();
+        }
+        if all || args.core {
+            om.print_core();
+        }
     }
 }
@@ -1,5 +1,7 @@
 #![allow(unused)]
 
+/// Collects object modules into library archives for rlink
+pub mod ar;
 /// Assembler functionality. Parses a given source file and produces a MIPS
 /// object file, which can then be linked into an executable with rlink.
 pub mod asm;
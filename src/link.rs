@@ -1,29 +1,104 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
 use lazy_static::lazy_static;
 
 use clap::Args;
 
-use crate::common::{ObjectHeader, ObjectModule, RefEntry, RelEntry, SymEntry};
+use crate::{
+    common::{
+        instruction::opcodes::*, Archive, Instruction, LineInfo, Location, ObjectHeader,
+        ObjectModule, RefEntry, RefInfo, RefType, RefUnknown, RelEntry, RelType, StrTabBuilder,
+        SymEntry, FL_EXEC, SYM_DEF, SYM_GLB, SYM_LBL, SYM_LIT, SYM_WEAK,
+    },
+    sim::{Layout, LayoutArgs, PAGE_SIZE, TEXT_START},
+};
+
+/// Symbol the program starts at, defined by the startup module
+const ENTRY: &str = "__r2k__entry__";
 
 lazy_static! {
-    static ref r2k_startup_obj: ObjectModule = ObjectModule {
-        head: ObjectHeader {
-            magic: 0xface,
-            version: 0x2cc6,
-            flags: 0x00000000,
-            entry: 0x00000000,
-            data: [
-                0x00000000, 0x00000000, 0x00000008, 0x00000000, 0x00000000, 0x00000000, 0x00000000,
-                0x00000000, 0x00000000, 0x00000000,
-            ]
-        },
-        text: vec![],
-        rdata: vec![],
-        data: vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-        sdata: vec![],
-        rel_info: vec![],
-        ext_ref: vec![],
-        symtab: vec![],
-        strtab: vec![],
+    /// Calls `main`, then exits once it returns. The simulator passes argc,
+    /// argv and envp to it in `$a0` to `$a2`, so they are left as they are.
+    static ref r2k_startup_obj: ObjectModule = {
+        let text = [
+            Instruction::J { op: OP_JAL, imm: 0 },
+            Instruction::I {
+                op: OP_ORI,
+                rs: 0,
+                rt: 2,
+                imm: SYSCALL_EXIT as u16,
+            },
+            Instruction::R {
+                rs: 0,
+                rt: 0,
+                rd: 0,
+                shamt: 0,
+                funct: FUNCT_SYSCALL,
+            },
+        ]
+        .into_iter()
+        .flat_map(|i| u32::from(i).to_be_bytes())
+        .collect::<Vec<_>>();
+        let mut strtab = StrTabBuilder::default();
+        let symtab = vec![
+            SymEntry {
+                flags: Location::TEXT as u32 | SYM_DEF | SYM_LBL | SYM_GLB,
+                val: TEXT_START,
+                str_off: strtab.add(ENTRY),
+                ofid: 0,
+            },
+            SymEntry {
+                flags: Location::EXT as u32 | SYM_GLB,
+                val: 0,
+                str_off: strtab.add("main"),
+                ofid: 0,
+            },
+        ];
+        let ext_ref = vec![RefEntry {
+            addr: 0,
+            str_off: symtab[1].str_off,
+            ref_info: RefInfo {
+                ix: 1,
+                unknown: RefUnknown::PLUS,
+                typ: RefType::JUMP,
+                sect: Location::TEXT,
+            },
+        }];
+        let strtab = strtab.finish();
+        ObjectModule {
+            head: ObjectHeader {
+                magic: 0xface,
+                version: 0x2cc6,
+                flags: 0x00000000,
+                entry: 0x00000000,
+                data: [
+                    text.len() as u32,
+                    0x00000000,
+                    0x00000008,
+                    0x00000000,
+                    0x00000000,
+                    0x00000000,
+                    0x00000000,
+                    ext_ref.len() as u32,
+                    symtab.len() as u32,
+                    strtab.len() as u32,
+                ],
+            },
+            text,
+            rdata: vec![],
+            data: vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            sdata: vec![],
+            rel_info: vec![],
+            ext_ref,
+            symtab,
+            strtab,
+            line_info: None,
+            core: None,
+        }
     };
 }
 
@@ -48,9 +123,653 @@ pub struct LinkerArgs {
         help = "Use the specified file as the startup routine. By default an internal object is used"
     )]
     startup: Option<String>,
+    #[arg(
+        short = 'e',
+        value_name = "SYMBOL",
+        conflicts_with = "startup",
+        help = "Start the program at SYMBOL, a global label in the text section, instead of the startup routine, which is then not linked. The default output name is determined by the module defining SYMBOL."
+    )]
+    entry: Option<String>,
+    #[arg(
+        long,
+        help = "When more than one module defines a global symbol, use the first definition (in the order the files are given) instead of failing."
+    )]
+    keep_first: bool,
+    #[command(flatten)]
+    layout: LayoutArgs,
     files: Vec<String>,
 }
 
+/// A module being linked, and where its sections are placed
+struct Input {
+    /// The file it was read from, for diagnostics and the load map
+    name: String,
+    module: ObjectModule,
+    /// Where each section from text to bss was placed when the module was
+    /// assembled
+    old: [u32; 6],
+    /// The offset of each section in the same section of the linked program
+    offset: [u32; 6],
+    /// Where each section is placed in the linked program
+    new: [u32; 6],
+}
+
+impl Input {
+    fn sizes(&self) -> [u32; 6] {
+        self.module.head.data[..6].try_into().unwrap()
+    }
+
+    /// Moves an address in one of the module's sections to where that
+    /// section is in the linked program. Addresses outside them (including
+    /// absolute values) are left as they are.
+    fn relocate(&self, addr: u32) -> u32 {
+        let sizes = self.sizes();
+        let inside = (0..6).find(|s| (self.old[*s]..self.old[*s] + sizes[*s]).contains(&addr));
+        // a label may be at the very end of its section
+        let sect = inside.or_else(|| (0..6).find(|s| self.old[*s] + sizes[*s] == addr));
+        match sect {
+            Some(s) => addr - self.old[s] + self.new[s],
+            None => addr,
+        }
+    }
+
+    /// Describes the location `addr` in the section `sect`, along with the
+    /// label it follows, as in `a.obj: TEXT 0x00000024 <main+0x18>`
+    fn describe(&self, sect: Location, addr: u32) -> String {
+        let m = &self.module;
+        let at = self.old[sect as usize] + addr;
+        let label = m
+            .symtab
+            .iter()
+            .filter(|s| is_reloc(s) && sym_loc(s) == sect && s.val <= at)
+            .max_by_key(|s| s.val)
+            .map(|s| match at - s.val {
+                0 => format!(" <{}>", sym_name(m, s.str_off)),
+                off => format!(" <{}+0x{:x}>", sym_name(m, s.str_off), off),
+            });
+        format!(
+            "{}: {} 0x{:08x}{}",
+            self.name,
+            sect,
+            addr,
+            label.unwrap_or_default()
+        )
+    }
+
+    /// Relocates the low halfword of an address, the rest of which is not
+    /// known, taking it to be in the first section it could be in
+    fn relocate_low(&self, low: u32) -> u32 {
+        let sizes = self.sizes();
+        (0..6)
+            .map(|s| {
+                let addr = (self.old[s] & 0xFFFF_0000) | low;
+                match addr < self.old[s] {
+                    true => addr.wrapping_add(0x10000),
+                    false => addr,
+                }
+            })
+            .enumerate()
+            .find(|(s, addr)| (self.old[*s]..=self.old[*s] + sizes[*s]).contains(addr))
+            .map_or(low, |(_, addr)| self.relocate(addr))
+    }
+}
+
+/// A global symbol defined by one of the modules
+#[derive(Clone, Copy)]
+struct Global {
+    val: u32,
+    /// Whether its value is an address, which moves with the program
+    reloc: bool,
+    weak: bool,
+    /// Index of the module defining it
+    input: usize,
+}
+
+/// Load addresses of each section of a module with sections of `sizes`,
+/// laid out as the assembler and simulator do: text and rdata where the
+/// layout puts them, and each later section on the page following the end
+/// of the previous one.
+fn section_bases(layout: &Layout, sizes: [u32; 6]) -> [u32; 6] {
+    let mut bases = [layout.text_start, layout.data_start, 0, 0, 0, 0];
+    for i in 2..6 {
+        bases[i] = (bases[i - 1] + sizes[i - 1]).next_multiple_of(PAGE_SIZE);
+    }
+    bases
+}
+
+fn read_word(data: &[u8], off: u32) -> u32 {
+    let idx = off as usize;
+    u32::from_be_bytes(data[idx..idx + 4].try_into().unwrap())
+}
+
+fn write_word(data: &mut [u8], off: u32, word: u32) {
+    let idx = off as usize;
+    data[idx..idx + 4].copy_from_slice(&word.to_be_bytes());
+}
+
+/// Replaces the bits of the word at `off` selected by `mask`
+fn patch(data: &mut [u8], off: u32, mask: u32, bits: u32) {
+    let word = read_word(data, off);
+    write_word(data, off, (word & !mask) | (bits & mask));
+}
+
+/// Reads the value held by a field of type `typ` at `off`, of an
+/// instruction or data at `pc`
+fn read_field(data: &[u8], off: u32, pc: u32, typ: RefType) -> u32 {
+    match typ {
+        RefType::IMM => read_word(data, off) & 0xFFFF,
+        RefType::HWORD => {
+            let idx = off as usize;
+            u16::from_be_bytes(data[idx..idx + 2].try_into().unwrap()) as i16 as u32
+        }
+        RefType::IMM2 => (read_word(data, off) << 16) | (read_word(data, off + 4) & 0xFFFF),
+        RefType::IMM3 => {
+            let lo = read_word(data, off + 4) as i16 as u32;
+            (read_word(data, off) << 16).wrapping_add(lo)
+        }
+        RefType::WORD => read_word(data, off),
+        RefType::JUMP => (pc & 0xF000_0000) | ((read_word(data, off) & 0x03FF_FFFF) << 2),
+    }
+}
+
+/// Stores `val` in a field of type `typ` at `off`
+fn write_field(data: &mut [u8], off: u32, typ: RefType, val: u32) {
+    match typ {
+        RefType::IMM => patch(data, off, 0xFFFF, val),
+        RefType::HWORD => {
+            let idx = off as usize;
+            data[idx..idx + 2].copy_from_slice(&(val as u16).to_be_bytes());
+        }
+        RefType::IMM2 => {
+            patch(data, off, 0xFFFF, val >> 16);
+            patch(data, off + 4, 0xFFFF, val);
+        }
+        RefType::IMM3 => {
+            patch(data, off, 0xFFFF, val.wrapping_add(0x8000) >> 16);
+            patch(data, off + 4, 0xFFFF, val);
+        }
+        RefType::WORD => write_word(data, off, val),
+        RefType::JUMP => patch(data, off, 0x03FF_FFFF, val >> 2),
+    }
+}
+
+fn rel_field(rel: RelType) -> RefType {
+    match rel {
+        RelType::IMM => RefType::IMM,
+        RelType::IMM2 => RefType::IMM2,
+        RelType::IMM3 => RefType::IMM3,
+        RelType::WORD => RefType::WORD,
+        RelType::JUMP => RefType::JUMP,
+    }
+}
+
+/// The relocation which moves a field of type `typ` with the program, if it
+/// can be relocated
+fn field_rel(typ: RefType) -> Option<RelType> {
+    match typ {
+        RefType::IMM => Some(RelType::IMM),
+        RefType::IMM2 => Some(RelType::IMM2),
+        RefType::IMM3 => Some(RelType::IMM3),
+        RefType::WORD => Some(RelType::WORD),
+        RefType::JUMP => Some(RelType::JUMP),
+        RefType::HWORD => None,
+    }
+}
+
+enum Loaded {
+    Module(Box<ObjectModule>),
+    Archive(Archive),
+}
+
+fn load(path: &str) -> Result<Loaded, String> {
+    let data = fs::read(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+    if Archive::is_archive(&data) {
+        return Archive::from_bytes(&data)
+            .map(Loaded::Archive)
+            .map_err(|e| format!("`{}`: {}", path, e));
+    }
+    if data.len() < 52 {
+        return Err(format!("`{}` is not an object module", path));
+    }
+    ObjectModule::from_slice_u8(&data)
+        .map(|m| Loaded::Module(Box::new(m)))
+        .map_err(|e| format!("`{}`: {}", path, e))
+}
+
+/// Adds the archive members which define symbols the modules refer to but
+/// do not define, until none are left which an archive can supply. Members
+/// are taken from the first archive (in the order given) defining the
+/// symbol, and only once each.
+fn pull_members(modules: &mut Vec<(String, ObjectModule)>, archives: &[(String, Archive)]) {
+    let mut pulled = HashSet::new();
+    loop {
+        let defined = modules
+            .iter()
+            .flat_map(|(_, m)| {
+                m.symtab
+                    .iter()
+                    .filter(|s| s.has_flags(SYM_GLB) && is_defined(s))
+                    .map(|s| sym_name(m, s.str_off))
+            })
+            .collect::<HashSet<_>>();
+        // weak references are left unresolved rather than pulling a member in
+        let undefined = modules
+            .iter()
+            .flat_map(|(_, m)| {
+                let weak = m
+                    .symtab
+                    .iter()
+                    .filter(|s| !is_defined(s) && s.has_flags(SYM_WEAK))
+                    .map(|s| sym_name(m, s.str_off))
+                    .collect::<HashSet<_>>();
+                m.ext_ref
+                    .iter()
+                    .map(|r| sym_name(m, r.str_off))
+                    .filter(move |n| !weak.contains(n))
+            })
+            .filter(|n| !defined.contains(n))
+            .collect::<BTreeSet<_>>();
+        let wanted = undefined
+            .iter()
+            .filter_map(|n| {
+                archives
+                    .iter()
+                    .enumerate()
+                    .find_map(|(a, (_, ar))| ar.member_for(n).map(|m| (a, m)))
+            })
+            .filter(|w| pulled.insert(*w))
+            .collect::<BTreeSet<_>>();
+        if wanted.is_empty() {
+            return;
+        }
+        for (a, m) in wanted {
+            let (path, archive) = &archives[a];
+            let member = &archive.members[m];
+            modules.push((format!("{}({})", path, member.name), member.module.clone()));
+        }
+    }
+}
+
+fn sym_name(module: &ObjectModule, str_off: u32) -> String {
+    module
+        .get_str_entry(str_off as usize)
+        .map_or(String::from("?"), |n| n.to_string_lossy().into_owned())
+}
+
+fn sym_loc(sym: &SymEntry) -> Location {
+    ((sym.flags & 0xF) as u8).try_into().unwrap()
+}
+
+/// Whether a symbol is defined, rather than referring to another module
+fn is_defined(sym: &SymEntry) -> bool {
+    sym.has_any_flag(SYM_DEF | SYM_LIT)
+}
+
+/// Whether a defined symbol's value is an address in one of its module's
+/// sections
+fn is_reloc(sym: &SymEntry) -> bool {
+    sym.has_flags(SYM_DEF) && (sym_loc(sym) as u8) <= Location::BSS as u8
+}
+
+/// Places the sections of every module one after the other, in the order
+/// they are given
+fn place(inputs: &mut [Input], layout: &Layout) -> [u32; 6] {
+    let mut sizes = [0u32; 6];
+    for input in inputs.iter_mut() {
+        for (s, size) in input.sizes().into_iter().enumerate() {
+            let align = if s == 0 { 4 } else { 8 };
+            input.offset[s] = sizes[s].next_multiple_of(align);
+            sizes[s] = input.offset[s] + size;
+        }
+    }
+    let bases = section_bases(layout, sizes);
+    for input in inputs.iter_mut() {
+        input.new = std::array::from_fn(|s| bases[s] + input.offset[s]);
+    }
+    sizes
+}
+
+/// Every global symbol defined by the modules, by name. A weak definition is
+/// only used if there is no other. Two modules defining the same symbol is an
+/// error, unless `keep_first` is given, when the first definition is used.
+fn globals(inputs: &[Input], keep_first: bool) -> Result<HashMap<String, Global>, String> {
+    let mut globals: HashMap<String, Global> = HashMap::new();
+    let mut duplicates = vec![];
+    for (idx, input) in inputs.iter().enumerate() {
+        let m = &input.module;
+        for sym in m.symtab.iter().filter(|s| s.has_flags(SYM_GLB)) {
+            if !is_defined(sym) {
+                continue;
+            }
+            let reloc = is_reloc(sym);
+            let global = Global {
+                val: match reloc {
+                    true => input.relocate(sym.val),
+                    false => sym.val,
+                },
+                reloc,
+                weak: sym.has_flags(SYM_WEAK),
+                input: idx,
+            };
+            let name = sym_name(m, sym.str_off);
+            match globals.get(&name) {
+                Some(prev) if !prev.weak && !global.weak && !keep_first => {
+                    duplicates.push(format!(
+                        "\n  `{}` in {} (0x{:08x}) and {} (0x{:08x})",
+                        name, inputs[prev.input].name, prev.val, input.name, global.val
+                    ));
+                }
+                Some(prev) if !prev.weak || global.weak => {}
+                _ => {
+                    globals.insert(name, global);
+                }
+            }
+        }
+    }
+    match duplicates.is_empty() {
+        true => Ok(globals),
+        false => Err(format!(
+            "symbols defined more than once (--keep-first uses the first definition):{}",
+            duplicates.concat()
+        )),
+    }
+}
+
+/// Links the startup module (unless another entry point is given) and
+/// `args.files` into one executable module, along with the members of any
+/// archives among them which are needed
+fn link_modules(args: &LinkerArgs) -> Result<(ObjectModule, Vec<Input>), String> {
+    let layout = args.layout.layout()?;
+    let mut modules = vec![];
+    match (&args.startup, &args.entry) {
+        (_, Some(_)) => {}
+        (Some(path), None) => match load(path)? {
+            Loaded::Module(m) => modules.push((path.clone(), *m)),
+            Loaded::Archive(_) => return Err(format!("`{}`: startup cannot be an archive", path)),
+        },
+        (None, None) => modules.push((String::from("<startup>"), r2k_startup_obj.clone())),
+    }
+    let mut archives = vec![];
+    for f in &args.files {
+        match load(f)? {
+            Loaded::Module(m) => modules.push((f.clone(), *m)),
+            Loaded::Archive(a) => archives.push((f.clone(), a)),
+        }
+    }
+    pull_members(&mut modules, &archives);
+    let mut inputs = modules
+        .into_iter()
+        .map(|(name, module)| {
+            let sizes = module.head.data[..6].try_into().unwrap();
+            Input {
+                name,
+                old: section_bases(&layout, sizes),
+                module,
+                offset: [0; 6],
+                new: [0; 6],
+            }
+        })
+        .collect::<Vec<_>>();
+    let sizes = place(&mut inputs, &layout);
+    let globals = globals(&inputs, args.keep_first)?;
+
+    let mut sections: [Vec<u8>; 4] = std::array::from_fn(|s| vec![0; sizes[s] as usize]);
+    for input in &inputs {
+        let m = &input.module;
+        for (s, data) in [&m.text, &m.rdata, &m.data, &m.sdata]
+            .into_iter()
+            .enumerate()
+        {
+            let start = input.offset[s] as usize;
+            sections[s][start..start + data.len()].copy_from_slice(data);
+        }
+    }
+
+    // addresses the modules were assembled to use are moved to where their
+    // sections now are
+    let mut rel_info = vec![];
+    for input in &inputs {
+        for rel in &input.module.rel_info {
+            let s = rel.sect as usize;
+            let off = input.offset[s] + rel.addr;
+            let data = &mut sections[s];
+            let typ = rel_field(rel.rel_info);
+            let val = match rel.rel_info {
+                RelType::IMM => input.relocate_low(read_field(data, off, 0, typ)),
+                _ => input.relocate(read_field(data, off, input.old[s] + rel.addr, typ)),
+            };
+            write_field(data, off, typ, val);
+            rel_info.push(RelEntry { addr: off, ..*rel });
+        }
+    }
+
+    // references to other modules are filled in with the value of the
+    // symbol, plus what is already in the field
+    let mut unresolved: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for input in &inputs {
+        let m = &input.module;
+        for r in &m.ext_ref {
+            let s = r.ref_info.sect as usize;
+            let off = input.offset[s] + r.addr;
+            let name = sym_name(m, r.str_off);
+            let weak = m
+                .symtab
+                .get(r.ref_info.ix as usize)
+                .is_some_and(|sym| sym.has_flags(SYM_WEAK));
+            let global = match globals.get(&name) {
+                Some(g) => *g,
+                // a weak reference may be left undefined, as 0
+                None if weak => Global {
+                    val: 0,
+                    reloc: false,
+                    weak: true,
+                    input: 0,
+                },
+                None => {
+                    unresolved
+                        .entry(name)
+                        .or_default()
+                        .push(input.describe(r.ref_info.sect, r.addr));
+                    continue;
+                }
+            };
+            let data = &mut sections[s];
+            let typ = r.ref_info.typ;
+            let addend = read_field(data, off, input.new[s] + r.addr, typ);
+            let addend = match typ {
+                // the jump field holds the offset alone, not an address
+                RefType::JUMP => addend & 0x0FFF_FFFF,
+                RefType::IMM => addend as u16 as i16 as u32,
+                _ => addend,
+            };
+            let val = match r.ref_info.unknown {
+                RefUnknown::PLUS => global.val.wrapping_add(addend),
+                RefUnknown::EQ => global.val,
+                RefUnknown::MINUS => addend.wrapping_sub(global.val),
+            };
+            write_field(data, off, typ, val);
+            if let (true, Some(rel)) = (global.reloc, field_rel(typ)) {
+                rel_info.push(RelEntry {
+                    addr: off,
+                    sect: r.ref_info.sect,
+                    rel_info: rel,
+                });
+            }
+        }
+    }
+    if !unresolved.is_empty() {
+        let mut msg = String::from("undefined symbols:");
+        for (name, sites) in unresolved {
+            msg.push_str(&format!("\n  `{}`, referenced from:", name));
+            for site in sites {
+                msg.push_str(&format!("\n    {}", site));
+            }
+        }
+        return Err(msg);
+    }
+    rel_info.sort_by_key(|r| (r.sect as u8, r.addr));
+
+    // every defined symbol is kept, for the load map and debugging
+    let mut symtab = vec![];
+    let mut strtab = StrTabBuilder::default();
+    for (ofid, input) in inputs.iter().enumerate() {
+        let m = &input.module;
+        for sym in m.symtab.iter().filter(|s| is_defined(s)) {
+            symtab.push(SymEntry {
+                val: match is_reloc(sym) {
+                    true => input.relocate(sym.val),
+                    false => sym.val,
+                },
+                str_off: strtab.add(&sym_name(m, sym.str_off)),
+                ofid: ofid as u16,
+                ..*sym
+            });
+        }
+    }
+    let name = args.entry.as_deref().unwrap_or(ENTRY);
+    let text = section_bases(&layout, sizes)[0];
+    let entry = match globals.get(name) {
+        Some(g) if g.reloc && (text..text + sizes[0]).contains(&g.val) => g.val,
+        _ => {
+            return Err(format!(
+                "`{}` is not a global label in the text, so the program has no entry point",
+                name
+            ))
+        }
+    };
+
+    let line_info = inputs
+        .iter()
+        .any(|i| i.module.line_info.is_some())
+        .then(|| merge_lines(&inputs));
+
+    let strtab = strtab.finish();
+    let [text, rdata, data, sdata] = sections;
+    let head = ObjectHeader {
+        magic: 0xface,
+        version: 0x2cc6,
+        flags: FL_EXEC,
+        entry,
+        data: [
+            sizes[0],
+            sizes[1],
+            sizes[2],
+            sizes[3],
+            sizes[4],
+            sizes[5],
+            rel_info.len() as u32,
+            0,
+            symtab.len() as u32,
+            strtab.len() as u32,
+        ],
+    };
+    let module = ObjectModule {
+        head,
+        text,
+        rdata,
+        data,
+        sdata,
+        rel_info,
+        ext_ref: vec![],
+        symtab,
+        strtab,
+        line_info,
+        core: None,
+    };
+    Ok((module, inputs))
+}
+
+/// The line info of every module which has it, moved to where its text is
+fn merge_lines(inputs: &[Input]) -> LineInfo {
+    let mut info = LineInfo::default();
+    for input in inputs {
+        let Some(lines) = &input.module.line_info else {
+            continue;
+        };
+        let files = lines
+            .files
+            .iter()
+            .map(|f| match info.files.iter().position(|g| g == f) {
+                Some(i) => i as u32,
+                None => {
+                    info.files.push(f.clone());
+                    (info.files.len() - 1) as u32
+                }
+            })
+            .collect::<Vec<_>>();
+        for l in &lines.lines {
+            info.lines.push(crate::common::LineEntry {
+                offset: l.offset + input.offset[0],
+                file: files[l.file as usize],
+                line: l.line,
+            });
+        }
+    }
+    info
+}
+
+/// Prints where each module's sections were placed, and the address of
+/// every symbol they define
+fn print_load_map(module: &ObjectModule, inputs: &[Input]) {
+    const SECTIONS: [&str; 6] = ["text", "rdata", "data", "sdata", "sbss", "bss"];
+    println!("Load map:");
+    for (ofid, input) in inputs.iter().enumerate() {
+        println!("{}:", input.name);
+        for (s, size) in input.sizes().into_iter().enumerate() {
+            if size > 0 {
+                println!(
+                    "  {:<6} 0x{:08x} ({} bytes)",
+                    SECTIONS[s], input.new[s], size
+                );
+            }
+        }
+        let mut syms = module
+            .symtab
+            .iter()
+            .filter(|s| s.ofid as usize == ofid && is_reloc(s))
+            .map(|s| (s.val, sym_name(module, s.str_off), s.has_flags(SYM_GLB)))
+            .collect::<Vec<_>>();
+        syms.sort();
+        for (val, name, global) in syms {
+            println!(
+                "    0x{:08x} {} {}",
+                val,
+                if global { "G" } else { "L" },
+                name
+            );
+        }
+    }
+}
+
 pub fn link(args: &LinkerArgs) {
-    todo!();
+    let (module, inputs) = link_modules(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if args.load_map {
+        print_load_map(&module, &inputs);
+    }
+
+    // named after the module defining main (or the entry point), by default
+    let start = args.entry.as_deref().unwrap_or("main");
+    let out = args.out.clone().unwrap_or_else(|| {
+        inputs
+            .iter()
+            .skip(args.entry.is_none() as usize)
+            .find(|i| {
+                i.module.symtab.iter().any(|s| {
+                    s.has_flags(SYM_GLB) && is_defined(s) && sym_name(&i.module, s.str_off) == start
+                })
+            })
+            .map_or(String::from("r.out"), |i| {
+                Path::new(&i.name)
+                    .with_extension("out")
+                    .to_string_lossy()
+                    .into_owned()
+            })
+    });
+    if let Err(e) = fs::write(&out, module.to_vec_u8()) {
+        eprintln!("cannot write `{}`: {}", out, e);
+        std::process::exit(1);
+    }
 }
@@ -3,6 +3,8 @@ use std::fs;
 use clap::{Parser, Subcommand};
 
 use rtool::{
+    ar::{ar, ArArgs},
+    asm::{asm, parse, AsmArgs, ParseArgs},
     dump::{dump, DumpArgs},
     link::{link, LinkerArgs},
     sim::{sim, SimArgs},
@@ -18,8 +20,11 @@ struct Cli {
 
 #[derive(Subcommand, Clone)]
 enum Commands {
+    Ar(ArArgs),
+    Asm(AsmArgs),
     Dump(DumpArgs),
     Link(LinkerArgs),
+    Parse(ParseArgs),
     Run(SimArgs),
 }
 
@@ -33,16 +38,14 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
+        Commands::Ar(args) => ar(&args),
+        Commands::Asm(args) => asm(&args),
         Commands::Dump(args) => dump(&args),
         Commands::Link(args) => link(&args),
-        Commands::Run(args) => sim(&args),
+        Commands::Parse(args) => parse(&args),
+        Commands::Run(args) => std::process::exit(sim(&args)),
     }
 
-    /*
-    let in_file = fs::read_to_string("./src/examples/asm/colony.asm").unwrap();
-    rtool::asm::dbg_parse(in_file, Rule::program).unwrap();
-    */
-
     /*
     let in_obj = fs::read("./data/Projects/proj1/colony.out").unwrap();
     let obj = rtool::common::ObjectModule::from_slice_u8(in_obj.as_slice()).unwrap();
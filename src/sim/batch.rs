@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    config::{self, Entry},
+    Exec, SimArgs,
+};
+use crate::common::ObjectModule;
+
+/// One program run by `--batch`, read from a manifest of
+///
+/// ```toml
+/// [[program]]
+/// name = "sort"          # defaults to the file
+/// file = "sort.out"      # the executable
+/// args = ["-n", "10"]    # its arguments; defaults to none
+/// stdin = "sort.in"      # its input; defaults to --stdin or --stdin-str
+/// output = "sort.txt"    # where to write its output; defaults to nowhere
+/// max_inst = 1000000     # as -i
+/// timeout = 10           # as --timeout
+/// livelock = 2           # as --livelock
+/// ```
+///
+/// tables, with paths relative to the manifest.
+#[derive(Default)]
+struct Program {
+    name: Option<String>,
+    file: Option<String>,
+    args: Vec<String>,
+    stdin: Option<String>,
+    output: Option<String>,
+    max_inst: Option<u32>,
+    timeout: Option<u32>,
+    livelock: Option<u32>,
+}
+
+/// How a program's run ended
+struct Outcome {
+    /// How it stopped, e.g. `exit 0` or `livelock`
+    status: String,
+    /// Whether it exited with status 0
+    ok: bool,
+    steps: u64,
+    time: Duration,
+}
+
+/// Reads a manifest, as described for `Program`
+fn load_programs(path: &str) -> Result<Vec<Program>, String> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let relative = |p: String| dir.join(p).to_string_lossy().into_owned();
+    let mut programs: Vec<Program> = vec![];
+    for (num, entry) in config::read(path)? {
+        let err = |msg: &str| format!("{}:{}: {}", path, num, msg);
+        let (key, val) = match entry {
+            Entry::ArrayTable(name) if name == "program" => {
+                programs.push(Program::default());
+                continue;
+            }
+            Entry::Pair(key, val) => (key, val),
+            _ => return Err(err("expected `[[program]]` or `key = value`")),
+        };
+        let program = programs
+            .last_mut()
+            .ok_or_else(|| err("expected `[[program]]` first"))?;
+        let quoted = || config::string(&val).ok_or_else(|| err("expected a quoted string"));
+        let number = || {
+            val.parse::<u32>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| err("expected a number above 0"))
+        };
+        match key.as_str() {
+            "name" => program.name = Some(quoted()?),
+            "file" => program.file = Some(quoted()?),
+            "args" => {
+                program.args =
+                    config::strings(&val).ok_or_else(|| err("expected a list of quoted strings"))?
+            }
+            "stdin" => program.stdin = Some(relative(quoted()?)),
+            "output" => program.output = Some(relative(quoted()?)),
+            "max_inst" => program.max_inst = Some(number()?),
+            "timeout" => program.timeout = Some(number()?),
+            "livelock" => program.livelock = Some(number()?),
+            key => return Err(err(&format!("unknown key `{}`", key))),
+        }
+    }
+
+    for (idx, program) in programs.iter_mut().enumerate() {
+        let Some(file) = program.file.take() else {
+            return Err(format!("{}: program {} has no `file`", path, idx + 1));
+        };
+        program.name.get_or_insert_with(|| file.clone());
+        program.file = Some(relative(file));
+    }
+    Ok(programs)
+}
+
+/// Runs each program of the manifest given by `--batch`, `--jobs` at a time,
+/// then prints how each one ended, returning the status rtool should exit
+/// with: 0 if every program exited with status 0, otherwise 1. Options
+/// besides those of the manifest apply to every program.
+pub fn run_batch(args: &SimArgs, path: &str) -> i32 {
+    let programs = match load_programs(path) {
+        Ok(programs) => programs,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new((0..programs.len()).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..args.jobs.clamp(1, programs.len().max(1)) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(program) = programs.get(idx) else {
+                    break;
+                };
+                let outcome = run_program(args, program);
+                outcomes.lock().unwrap()[idx] = Some(outcome);
+            });
+        }
+    });
+
+    let width = programs
+        .iter()
+        .filter_map(|p| p.name.as_ref().map(|n| n.len()))
+        .max()
+        .unwrap_or(0)
+        .max("program".len());
+    println!(
+        "{:<width$}  {:<20} {:>12} {:>10}",
+        "program",
+        "status",
+        "instructions",
+        "time",
+        width = width
+    );
+    let mut ok = 0;
+    for (program, outcome) in programs.iter().zip(outcomes.into_inner().unwrap()) {
+        let name = program.name.as_deref().unwrap_or_default();
+        match outcome.unwrap() {
+            Ok(outcome) => {
+                ok += outcome.ok as usize;
+                println!(
+                    "{:<width$}  {:<20} {:>12} {:>9.3}s",
+                    name,
+                    outcome.status,
+                    outcome.steps,
+                    outcome.time.as_secs_f64(),
+                    width = width
+                );
+            }
+            Err(e) => println!("{:<width$}  not run: {}", name, e, width = width),
+        }
+    }
+    println!("{} of {} programs exited with status 0", ok, programs.len());
+    (ok != programs.len()) as i32
+}
+
+/// Runs one program, returning how it ended, or why it could not be run
+fn run_program(args: &SimArgs, program: &Program) -> Result<Outcome, String> {
+    let file = program.file.clone().unwrap_or_default();
+    let bytes = fs::read(&file).map_err(|e| format!("cannot read `{}`: {}", file, e))?;
+    let module = ObjectModule::from_slice_u8(&bytes)?;
+    let mut args = args.clone();
+    args.file = file;
+    args.program_args = program.args.clone();
+    if let Some(max_inst) = program.max_inst {
+        args.max_inst = max_inst;
+    }
+    if program.timeout.is_some() {
+        args.timeout = program.timeout;
+    }
+    if program.livelock.is_some() {
+        args.livelock = program.livelock;
+    }
+
+    let mut exec = Exec::new(module, &args)?;
+    exec.capture_output();
+    let input = match &program.stdin {
+        Some(path) => Some(fs::read(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?),
+        None => args.stdin_input()?,
+    };
+    if let Some(input) = input {
+        exec.set_stdin(&input);
+    }
+    let start = Instant::now();
+    let _ = exec.run();
+    let time = start.elapsed();
+
+    if let Some(path) = &program.output {
+        fs::write(path, exec.take_stdout())
+            .map_err(|e| format!("cannot write `{}`: {}", path, e))?;
+    }
+    Ok(Outcome {
+        status: exec.stop_reason(),
+        ok: exec.exit_code() == Some(0),
+        steps: exec.steps(),
+        time,
+    })
+}
@@ -0,0 +1,252 @@
+use std::fmt::Display;
+
+/// The geometry of a simulated cache, given to `--icache`, `--dcache` and
+/// `--l2cache` as `SIZE:ASSOC:LINE` in bytes, with an optional `K` suffix on
+/// the size.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    size: u32,
+    assoc: u32,
+    line: u32,
+}
+
+impl CacheConfig {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("invalid cache `{}`, expected SIZE:ASSOC:LINE", spec);
+        let fields = spec.split(':').collect::<Vec<_>>();
+        let [size, assoc, line] = fields[..] else {
+            return Err(bad());
+        };
+        let size = match size.strip_suffix(['k', 'K']) {
+            Some(kb) => kb.parse::<u32>().ok().and_then(|kb| kb.checked_mul(1024)),
+            None => size.parse().ok(),
+        }
+        .ok_or_else(bad)?;
+        let assoc = assoc.parse::<u32>().map_err(|_| bad())?;
+        let line = line.parse::<u32>().map_err(|_| bad())?;
+
+        if ![size, assoc, line].iter().all(|n| n.is_power_of_two()) {
+            return Err(format!(
+                "invalid cache `{}`: sizes must be powers of two",
+                spec
+            ));
+        }
+        if line < 4 || size < assoc * line {
+            return Err(format!(
+                "invalid cache `{}`: lines must be at least a word, and fit in the cache",
+                spec
+            ));
+        }
+        Ok(Self { size, assoc, line })
+    }
+
+    fn sets(&self) -> u32 {
+        self.size / (self.assoc * self.line)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    tag: u32,
+    dirty: bool,
+    /// When the line was last accessed, for LRU replacement
+    used: u64,
+}
+
+/// What an access to a cache did
+struct Access {
+    hit: bool,
+    /// The address of a dirty line evicted to make room, which must be
+    /// written back to the next level
+    writeback: Option<u32>,
+}
+
+/// A set-associative, write-back, write-allocate cache with LRU replacement.
+/// Only the addresses accessed are simulated; the data always comes from
+/// `Memory`.
+#[derive(Clone)]
+pub struct Cache {
+    name: &'static str,
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    read_hits: u64,
+    read_misses: u64,
+    write_hits: u64,
+    write_misses: u64,
+    writebacks: u64,
+}
+
+impl Cache {
+    fn new(name: &'static str, config: CacheConfig) -> Self {
+        Self {
+            name,
+            config,
+            sets: vec![vec![]; config.sets() as usize],
+            clock: 0,
+            read_hits: 0,
+            read_misses: 0,
+            write_hits: 0,
+            write_misses: 0,
+            writebacks: 0,
+        }
+    }
+
+    fn access(&mut self, addr: u32, write: bool) -> Access {
+        self.clock += 1;
+        let block = addr / self.config.line;
+        let nsets = self.config.sets();
+        let (set, tag) = ((block % nsets) as usize, block / nsets);
+        let set_lines = &mut self.sets[set];
+
+        let hit = match set_lines.iter_mut().find(|l| l.tag == tag) {
+            Some(line) => {
+                line.used = self.clock;
+                line.dirty |= write;
+                true
+            }
+            None => false,
+        };
+        match (hit, write) {
+            (true, false) => self.read_hits += 1,
+            (true, true) => self.write_hits += 1,
+            (false, false) => self.read_misses += 1,
+            (false, true) => self.write_misses += 1,
+        }
+        if hit {
+            return Access {
+                hit,
+                writeback: None,
+            };
+        }
+
+        let mut writeback = None;
+        if set_lines.len() == self.config.assoc as usize {
+            let lru = (0..set_lines.len())
+                .min_by_key(|i| set_lines[*i].used)
+                .unwrap();
+            let victim = set_lines.swap_remove(lru);
+            if victim.dirty {
+                self.writebacks += 1;
+                writeback = Some((victim.tag * nsets + set as u32) * self.config.line);
+            }
+        }
+        set_lines.push(Line {
+            tag,
+            dirty: write,
+            used: self.clock,
+        });
+        Access { hit, writeback }
+    }
+}
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rate = |misses: u64, hits: u64| match misses + hits {
+            0 => 0.0,
+            n => misses as f64 * 100.0 / n as f64,
+        };
+        let c = &self.config;
+        writeln!(
+            f,
+            "  {} ({} bytes, {}-way, {} byte lines, {} sets):",
+            self.name,
+            c.size,
+            c.assoc,
+            c.line,
+            c.sets()
+        )?;
+        writeln!(
+            f,
+            "    {:<12}{:>12}{:>12}{:>10}",
+            "", "hits", "misses", "miss rate"
+        )?;
+        let mut row = |name: &str, hits: u64, misses: u64| {
+            writeln!(
+                f,
+                "    {:<12}{:>12}{:>12}{:>9.2}%",
+                name,
+                hits,
+                misses,
+                rate(misses, hits)
+            )
+        };
+        row("reads", self.read_hits, self.read_misses)?;
+        row("writes", self.write_hits, self.write_misses)?;
+        row(
+            "total",
+            self.read_hits + self.write_hits,
+            self.read_misses + self.write_misses,
+        )?;
+        writeln!(f, "    {:<12}{:>12}", "writebacks", self.writebacks)
+    }
+}
+
+/// The simulated cache hierarchy: split first level instruction and data
+/// caches, either of which may be left out, backed by an optional unified
+/// second level cache which sees their misses and writebacks.
+#[derive(Clone)]
+pub struct Caches {
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    l2: Option<Cache>,
+}
+
+impl Caches {
+    /// The hierarchy given by the command line, or None if there are no
+    /// caches
+    pub fn new(
+        icache: Option<CacheConfig>,
+        dcache: Option<CacheConfig>,
+        l2: Option<CacheConfig>,
+    ) -> Option<Self> {
+        if icache.is_none() && dcache.is_none() && l2.is_none() {
+            return None;
+        }
+        Some(Self {
+            icache: icache.map(|c| Cache::new("I-cache", c)),
+            dcache: dcache.map(|c| Cache::new("D-cache", c)),
+            l2: l2.map(|c| Cache::new("L2 cache", c)),
+        })
+    }
+
+    /// Accesses the first level cache given, passing a miss on to the
+    /// second level
+    fn access(l1: &mut Option<Cache>, l2: &mut Option<Cache>, addr: u32, write: bool) {
+        let Some(l1) = l1 else {
+            if let Some(l2) = l2 {
+                l2.access(addr, write);
+            }
+            return;
+        };
+        let access = l1.access(addr, write);
+        if let Some(l2) = l2 {
+            if let Some(victim) = access.writeback {
+                l2.access(victim, true);
+            }
+            if !access.hit {
+                l2.access(addr, false);
+            }
+        }
+    }
+
+    /// Counts an instruction fetch from `pc`
+    pub fn fetch(&mut self, pc: u32) {
+        Self::access(&mut self.icache, &mut self.l2, pc, false);
+    }
+
+    /// Counts a load from, or a store to, `addr`
+    pub fn data(&mut self, addr: u32, write: bool) {
+        Self::access(&mut self.dcache, &mut self.l2, addr, write);
+    }
+}
+
+impl Display for Caches {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "\nCaches:")?;
+        for cache in [&self.icache, &self.dcache, &self.l2].into_iter().flatten() {
+            write!(f, "{}", cache)?;
+        }
+        Ok(())
+    }
+}
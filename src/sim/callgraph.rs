@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::symbols::Symbols;
+use crate::common::{instruction::opcodes::*, Instruction};
+
+/// A call which has not returned yet
+#[derive(Clone)]
+struct Frame {
+    /// The value the callee returns to, as saved in the link register
+    ret: u32,
+    /// The call stack node of the caller
+    caller: usize,
+}
+
+/// A shadow call stack, following `jal`, `jalr` and `jr` to count the
+/// instructions executed with each call stack. Kept when `--profile` or
+/// `--call-graph` is given.
+#[derive(Clone)]
+pub struct CallGraph {
+    /// Every function called, along with the entry point
+    functions: Vec<u32>,
+    /// Call stacks seen so far, as a tree: each node is its caller's node
+    /// and the function called. Node 0 is the entry point.
+    nodes: Vec<(usize, u32)>,
+    children: HashMap<(usize, u32), usize>,
+    /// Instructions executed with each call stack
+    node_counts: Vec<u64>,
+    /// Calls made from one function to another
+    calls: BTreeMap<(u32, u32), u64>,
+    node: usize,
+    frames: Vec<Frame>,
+}
+
+/// Call and instruction counts of one function
+#[derive(Default)]
+struct Totals {
+    calls: u64,
+    /// Instructions executed in the function itself
+    exclusive: u64,
+    /// Instructions executed in the function and everything it called
+    inclusive: u64,
+}
+
+impl CallGraph {
+    pub fn new(entry: u32) -> Self {
+        Self {
+            functions: vec![entry],
+            nodes: vec![(0, entry)],
+            children: HashMap::new(),
+            node_counts: vec![0],
+            calls: BTreeMap::new(),
+            node: 0,
+            frames: vec![],
+        }
+    }
+
+    /// Counts `inst`, executed at `pc`, which continues execution at `next`
+    pub fn record(&mut self, pc: u32, inst: Instruction, next: u32) {
+        self.node_counts[self.node] += 1;
+
+        match inst {
+            // the simulator links the address of the call itself, and jalr
+            // continues after the address it is given
+            Instruction::J { op: OP_JAL, .. } => self.call(pc, next),
+            Instruction::R {
+                funct: FUNCT_JALR, ..
+            } => self.call(pc, next.wrapping_sub(4)),
+            Instruction::R {
+                funct: FUNCT_JR, ..
+            } => self.ret(next.wrapping_sub(4)),
+            _ => {}
+        }
+    }
+
+    fn call(&mut self, ret: u32, func: u32) {
+        if let Err(idx) = self.functions.binary_search(&func) {
+            self.functions.insert(idx, func);
+        }
+        let caller = self.node;
+        *self.calls.entry((self.nodes[caller].1, func)).or_default() += 1;
+        self.frames.push(Frame { ret, caller });
+        let next_id = self.nodes.len();
+        self.node = *self.children.entry((caller, func)).or_insert(next_id);
+        if self.node == next_id {
+            self.nodes.push((caller, func));
+            self.node_counts.push(0);
+        }
+    }
+
+    /// Returns to `ret`, if that is where a call on the stack returns to.
+    /// Other jumps through registers are not returns.
+    fn ret(&mut self, ret: u32) {
+        if let Some(depth) = self.frames.iter().rposition(|f| f.ret == ret) {
+            self.node = self.frames[depth].caller;
+            self.frames.truncate(depth);
+        }
+    }
+
+    /// The calls which have not returned, as the addresses they were made
+    /// from, innermost first
+    pub fn backtrace(&self) -> Vec<u32> {
+        self.frames.iter().rev().map(|f| f.ret).collect()
+    }
+
+    /// The function containing `addr`: the nearest one called at or before
+    /// it
+    pub fn function_of(&self, addr: u32) -> u32 {
+        let idx = self.functions.partition_point(|f| *f <= addr);
+        self.functions[idx.saturating_sub(1)]
+    }
+
+    pub fn name(func: u32, symbols: &Symbols) -> String {
+        symbols
+            .describe(func, true)
+            .unwrap_or_else(|| format!("0x{:08x}", func))
+    }
+
+    /// Call and instruction counts by function. Instructions executed in a
+    /// recursive call are only counted once towards the inclusive total.
+    fn totals(&self) -> BTreeMap<u32, Totals> {
+        // callers always come before their callees in `nodes`
+        let mut subtree = self.node_counts.clone();
+        for (node, (caller, _)) in self.nodes.iter().enumerate().skip(1).rev() {
+            subtree[*caller] += subtree[node];
+        }
+
+        let mut totals = BTreeMap::<u32, Totals>::new();
+        totals.entry(self.nodes[0].1).or_default().calls = 1;
+        for ((_, callee), n) in &self.calls {
+            totals.entry(*callee).or_default().calls += n;
+        }
+        for (node, (_, func)) in self.nodes.iter().enumerate() {
+            let totals = totals.entry(*func).or_default();
+            totals.exclusive += self.node_counts[node];
+            let mut at = node;
+            let recursive = loop {
+                if at == 0 {
+                    break false;
+                }
+                at = self.nodes[at].0;
+                if self.nodes[at].1 == *func {
+                    break true;
+                }
+            };
+            if !recursive {
+                totals.inclusive += subtree[node];
+            }
+        }
+        totals
+    }
+
+    /// The call graph report: call and instruction counts for each function,
+    /// then the number of calls between each pair of functions
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut totals = self.totals().into_iter().collect::<Vec<_>>();
+        totals.sort_by_key(|(func, t)| (std::cmp::Reverse(t.inclusive), *func));
+
+        let mut out = format!(
+            "\nCall graph:\n  {:<30}{:>8}{:>12}{:>12}\n",
+            "function", "calls", "exclusive", "inclusive"
+        );
+        for (func, t) in totals {
+            out.push_str(&format!(
+                "  {:<30}{:>8}{:>12}{:>12}\n",
+                Self::name(func, symbols),
+                t.calls,
+                t.exclusive,
+                t.inclusive
+            ));
+        }
+
+        out.push_str("\nCalls:\n");
+        for ((caller, callee), n) in &self.calls {
+            let edge = format!(
+                "{} -> {}",
+                Self::name(*caller, symbols),
+                Self::name(*callee, symbols)
+            );
+            out.push_str(&format!("  {:<50}{:>8}\n", edge, n));
+        }
+        out
+    }
+
+    /// The call graph in the DOT language, with each function labelled with
+    /// its inclusive and exclusive instruction counts and each edge with the
+    /// number of calls made
+    pub fn dot(&self, symbols: &Symbols) -> String {
+        let mut out = String::from("digraph calls {\n    node [shape=box];\n");
+        for (func, t) in self.totals() {
+            out.push_str(&format!(
+                "    \"0x{:08x}\" [label=\"{}\\n{} calls\\n{} inclusive\\n{} exclusive\"];\n",
+                func,
+                Self::name(func, symbols).escape_default(),
+                t.calls,
+                t.inclusive,
+                t.exclusive
+            ));
+        }
+        for ((caller, callee), n) in &self.calls {
+            out.push_str(&format!(
+                "    \"0x{:08x}\" -> \"0x{:08x}\" [label=\"{}\"];\n",
+                caller, callee, n
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Instruction counts by call stack, one `caller;callee count` line per
+    /// stack, as taken by flamegraph tools
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+        for (node, n) in self.node_counts.iter().enumerate() {
+            if *n == 0 {
+                continue;
+            }
+            let mut stack = vec![];
+            let mut at = node;
+            loop {
+                let (caller, func) = self.nodes[at];
+                stack.push(Self::name(func, symbols));
+                if at == 0 {
+                    break;
+                }
+                at = caller;
+            }
+            stack.reverse();
+            out.push_str(&format!("{} {}\n", stack.join(";"), n));
+        }
+        out
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom},
+};
+
+/// Start of a file written by `--checkpoint` or rbug's `save`
+pub const MAGIC: &[u8] = b"R2KCKPT1";
+
+/// How a file the program has open was opened, so that it can be opened
+/// again when a checkpoint is resumed
+#[derive(Clone)]
+pub struct FileInfo {
+    pub path: String,
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+}
+
+impl FileInfo {
+    /// Opens the file again, at `offset`. It is neither created nor
+    /// truncated, as it was when first opened.
+    pub fn reopen(&self, offset: u64) -> Result<File, String> {
+        let mut file = OpenOptions::new()
+            .read(self.read)
+            .write(self.write && !self.append)
+            .append(self.append)
+            .open(&self.path)
+            .map_err(|e| format!("cannot reopen `{}`: {}", self.path, e))?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| format!("cannot reopen `{}`: {}", self.path, e))?;
+        Ok(file)
+    }
+}
+
+/// Builds a checkpoint of big-endian words and length-prefixed bytes
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self {
+            buf: MAGIC.to_vec(),
+        }
+    }
+
+    pub fn word(&mut self, w: u32) {
+        self.buf.extend_from_slice(&w.to_be_bytes());
+    }
+
+    pub fn long(&mut self, l: u64) {
+        self.buf.extend_from_slice(&l.to_be_bytes());
+    }
+
+    /// Bytes whose length is written before them
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.word(b.len() as u32);
+        self.buf.extend_from_slice(b);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back what a `Writer` wrote, in the same order
+pub struct Reader<'d> {
+    data: &'d [u8],
+}
+
+impl<'d> Reader<'d> {
+    pub fn new(data: &'d [u8]) -> Result<Self, String> {
+        match data.strip_prefix(MAGIC) {
+            Some(data) => Ok(Self { data }),
+            None => Err(String::from("not a checkpoint")),
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'d [u8], String> {
+        if self.data.len() < n {
+            return Err(String::from("the checkpoint is cut short"));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn word(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn long(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'d [u8], String> {
+        let len = self.word()? as usize;
+        self.take(len)
+    }
+
+    /// Checks that everything has been read
+    pub fn finish(self) -> Result<(), String> {
+        match self.data.is_empty() {
+            true => Ok(()),
+            false => Err(String::from("the checkpoint has extra data at its end")),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use lazy_static::lazy_static;
+use pest::{
+    iterators::Pair,
+    pratt_parser::{Assoc, Op, PrattParser},
+    Parser,
+};
+
+use super::Exec;
+use crate::{
+    asm::{
+        expr::{Expr, Value},
+        MIPSParser, Rule,
+    },
+    common::parse_register,
+};
+
+lazy_static! {
+    static ref PRATT_PARSER: PrattParser<Rule> = PrattParser::new()
+        .op(Op::infix(Rule::log_or, Assoc::Left))
+        .op(Op::infix(Rule::log_and, Assoc::Left))
+        .op(Op::infix(Rule::cmp_eq, Assoc::Left)
+            | Op::infix(Rule::cmp_ne, Assoc::Left)
+            | Op::infix(Rule::cmp_le, Assoc::Left)
+            | Op::infix(Rule::cmp_ge, Assoc::Left)
+            | Op::infix(Rule::cmp_lt, Assoc::Left)
+            | Op::infix(Rule::cmp_gt, Assoc::Left));
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CondOp {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    /// An assembler expression, in which registers stand for their values,
+    /// and names which are not symbols may be registers without the `$`
+    Expr(Expr),
+    /// `word(expr)`, `half(expr)` or `byte(expr)`: memory at an address
+    Load(Width, Expr),
+    Binary(CondOp, Box<Node>, Box<Node>),
+}
+
+/// The condition of a breakpoint, which is only stopped at when it holds,
+/// e.g. `$t0 == 5 && word(sp + 8) != 0`. Operands are assembler
+/// expressions, or memory loaded with `word`, `half` or `byte`; they are
+/// compared as signed 32 bit numbers, and any other than 0 is true.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    text: String,
+    root: Node,
+}
+
+fn build(pair: Pair<Rule>) -> Result<Node, String> {
+    PRATT_PARSER
+        .map_primary(|p| match p.as_rule() {
+            Rule::cond => build(p),
+            Rule::expr => Expr::from_pair(p).map(Node::Expr),
+            Rule::load => {
+                let mut inner = p.into_inner();
+                let width = match inner.next().unwrap().as_str() {
+                    "byte" => Width::Byte,
+                    "half" => Width::Half,
+                    _ => Width::Word,
+                };
+                Ok(Node::Load(width, Expr::from_pair(inner.next().unwrap())?))
+            }
+            r => unreachable!("unexpected rule in condition: {:?}", r),
+        })
+        .map_infix(|lhs, op, rhs| {
+            let op = match op.as_rule() {
+                Rule::log_and => CondOp::And,
+                Rule::log_or => CondOp::Or,
+                Rule::cmp_eq => CondOp::Eq,
+                Rule::cmp_ne => CondOp::Ne,
+                Rule::cmp_le => CondOp::Le,
+                Rule::cmp_ge => CondOp::Ge,
+                Rule::cmp_lt => CondOp::Lt,
+                _ => CondOp::Gt,
+            };
+            Ok(Node::Binary(op, Box::new(lhs?), Box::new(rhs?)))
+        })
+        .parse(pair.into_inner())
+}
+
+/// Evaluates an expression against the program's registers and symbols
+fn value(exec: &Exec, expr: &Expr) -> Result<i32, String> {
+    let regs = exec.registers();
+    let expr = expr.with_registers(&|r| regs[r as usize] as i64);
+    let lookup = |name: &str| {
+        let value = match name {
+            "pc" => exec.pc(),
+            "hi" => exec.hi(),
+            "lo" => exec.lo(),
+            _ => exec
+                .resolve(name)
+                .ok()
+                .or_else(|| parse_register(name).map(|r| regs[r as usize]))?,
+        };
+        Some(Value::Abs(value as i64))
+    };
+    match expr.eval(&lookup).map_err(|e| e.to_string())? {
+        Value::Abs(v) => Ok(v as i32),
+        Value::Rel(..) => unreachable!("symbols are looked up as addresses"),
+    }
+}
+
+impl Node {
+    fn eval(&self, exec: &Exec) -> Result<i32, String> {
+        match self {
+            Node::Expr(e) => value(exec, e),
+            Node::Load(width, e) => {
+                let addr = value(exec, e)? as u32;
+                let loaded = match width {
+                    Width::Byte => exec.read_byte(addr).map(|b| b as i32),
+                    Width::Half => exec.read_half(addr).map(|h| h as i32),
+                    Width::Word => exec.read_word(addr).map(|w| w as i32),
+                };
+                loaded.map_err(|_| format!("cannot read memory at 0x{:08x}", addr))
+            }
+            Node::Binary(CondOp::And, l, r) => {
+                Ok((l.eval(exec)? != 0 && r.eval(exec)? != 0) as i32)
+            }
+            Node::Binary(CondOp::Or, l, r) => Ok((l.eval(exec)? != 0 || r.eval(exec)? != 0) as i32),
+            Node::Binary(op, l, r) => {
+                let (l, r) = (l.eval(exec)?, r.eval(exec)?);
+                Ok(match op {
+                    CondOp::Eq => l == r,
+                    CondOp::Ne => l != r,
+                    CondOp::Le => l <= r,
+                    CondOp::Ge => l >= r,
+                    CondOp::Lt => l < r,
+                    _ => l > r,
+                } as i32)
+            }
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut pairs = MIPSParser::parse(Rule::condition, text)
+            .map_err(|e| format!("invalid condition `{}`: {}", text, e.variant.message()))?;
+        let cond = pairs.next().unwrap().into_inner().next().unwrap();
+        Ok(Self {
+            text: text.to_string(),
+            root: build(cond)?,
+        })
+    }
+
+    /// Whether the condition holds for the program as it is now
+    pub fn holds(&self, exec: &Exec) -> Result<bool, String> {
+        Ok(self.root.eval(exec)? != 0)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+
+/// A line of a config file, other than a blank line or comment
+pub enum Entry {
+    /// `[name]`
+    Table(String),
+    /// `[[name]]`, another table of an array of them
+    ArrayTable(String),
+    /// `key = value`, with the value as written
+    Pair(String, String),
+}
+
+/// Reads the subset of TOML the manifests and configs rtool takes are
+/// written in: tables, arrays of tables and `key = value` lines, with `#`
+/// comments outside of strings. Each entry is given with its line number,
+/// and values are left for `string`, `strings` or the caller to parse.
+pub fn read(path: &str) -> Result<Vec<(usize, Entry)>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+    let mut entries = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let name = |l: &str| l.trim().to_string();
+        let entry = if let Some(l) = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]")) {
+            Entry::ArrayTable(name(l))
+        } else if let Some(l) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            Entry::Table(name(l))
+        } else {
+            let (key, val) = line.split_once('=').ok_or_else(|| {
+                format!("{}:{}: expected a table or `key = value`", path, idx + 1)
+            })?;
+            Entry::Pair(name(key), name(val))
+        };
+        entries.push((idx + 1, entry));
+    }
+    Ok(entries)
+}
+
+/// `line` up to any `#` which is not in a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// The string `val` starts with, in double quotes (with `\"`, `\\`, `\n`,
+/// `\t` and `\r` escapes) or single quotes (without), and what follows it
+fn quoted(val: &str) -> Option<(String, &str)> {
+    if let Some(rest) = val.strip_prefix('\'') {
+        let (s, rest) = rest.split_once('\'')?;
+        return Some((s.to_string(), rest));
+    }
+    let mut chars = val.strip_prefix('"')?.char_indices();
+    let mut s = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((s, &val[i + 2..])),
+            '\\' => s.push(match chars.next()?.1 {
+                '"' => '"',
+                '\\' => '\\',
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                _ => return None,
+            }),
+            c => s.push(c),
+        }
+    }
+    None
+}
+
+/// A value which is a quoted string
+pub fn string(val: &str) -> Option<String> {
+    match quoted(val)? {
+        (s, "") => Some(s),
+        _ => None,
+    }
+}
+
+/// A value which is a list of quoted strings, in brackets
+pub fn strings(val: &str) -> Option<Vec<String>> {
+    let mut rest = val.strip_prefix('[')?.strip_suffix(']')?.trim();
+    let mut list = vec![];
+    while !rest.is_empty() {
+        let (item, after) = quoted(rest)?;
+        list.push(item);
+        rest = after.trim_start();
+        rest = match rest.strip_prefix(',') {
+            Some(after) => after.trim_start(),
+            None if rest.is_empty() => rest,
+            None => return None,
+        };
+    }
+    Some(list)
+}
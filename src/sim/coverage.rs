@@ -0,0 +1,113 @@
+use super::{mem::Endian, symbols::Symbols, Layout};
+use crate::common::{instruction::opcodes::OP_JAL, Instruction, ObjectModule, SYM_DEF, SYM_GLB};
+
+/// Which instructions in the text segment have been executed, kept when
+/// `--coverage` is given.
+#[derive(Clone)]
+pub struct Coverage {
+    text_start: u32,
+    executed: Vec<bool>,
+    /// The entry point, global text symbols and every address called by a
+    /// `jal`, in order. Each function is taken to run until the next one
+    /// starts.
+    functions: Vec<u32>,
+}
+
+impl Coverage {
+    /// Prepares to track the execution of the module's text segment
+    pub fn new(module: &ObjectModule, entry: u32, layout: &Layout, endian: Endian) -> Self {
+        let text_start = layout.text_start;
+        let text = &module.text;
+        let globals = module
+            .symtab
+            .iter()
+            .filter(|s| s.has_flags(SYM_GLB | SYM_DEF))
+            .map(|s| s.val)
+            .filter(|val| (text_start..layout.data_start).contains(val));
+        let mut functions = text
+            .chunks_exact(4)
+            .filter_map(|w| Instruction::try_from(endian.word(w.try_into().unwrap())).ok())
+            .filter_map(|inst| match inst {
+                Instruction::J { op: OP_JAL, imm } => Some(imm << 2),
+                _ => None,
+            })
+            .chain(globals)
+            .chain([entry])
+            .collect::<Vec<_>>();
+        functions.sort();
+        functions.dedup();
+        Self {
+            text_start,
+            executed: vec![false; text.len() / 4],
+            functions,
+        }
+    }
+
+    pub fn record(&mut self, pc: u32) {
+        if let Some(idx) = pc
+            .checked_sub(self.text_start)
+            .map(|off| (off / 4) as usize)
+        {
+            if let Some(executed) = self.executed.get_mut(idx) {
+                *executed = true;
+            }
+        }
+    }
+
+    /// The coverage report: the proportion of instructions executed overall
+    /// and in each function, then every instruction which was not executed,
+    /// disassembled by `disassemble`
+    pub fn report(&self, symbols: &Symbols, disassemble: impl Fn(u32) -> String) -> String {
+        let addr = |idx: usize| self.text_start + idx as u32 * 4;
+        let pct = |n: usize, total: usize| match total {
+            0 => 0.0,
+            t => n as f64 * 100.0 / t as f64,
+        };
+        let executed = self.executed.iter().filter(|e| **e).count();
+        let total = self.executed.len();
+
+        let mut out = format!(
+            "\nCoverage: {} of {} instructions executed ({:.2}%)\n",
+            executed,
+            total,
+            pct(executed, total)
+        );
+        // instructions before the first function are counted in it
+        let mut starts = self.functions.clone();
+        starts[0] = starts[0].min(self.text_start);
+        for (i, start) in starts.iter().enumerate() {
+            let first = ((start - self.text_start) / 4) as usize;
+            let end = starts
+                .get(i + 1)
+                .map_or(total, |next| ((next - self.text_start) / 4) as usize);
+            let Some(words) = self.executed.get(first..end.min(total)) else {
+                continue;
+            };
+            let n = words.iter().filter(|e| **e).count();
+            let name = symbols
+                .describe(self.functions[i], true)
+                .unwrap_or_else(|| format!("0x{:08x}", self.functions[i]));
+            out.push_str(&format!(
+                "  {:<30}{:>6}/{:<6} {:>6.2}%\n",
+                name,
+                n,
+                words.len(),
+                pct(n, words.len())
+            ));
+        }
+
+        if executed < total {
+            out.push_str("\nNot executed:\n");
+            let mut last = None;
+            for (idx, _) in self.executed.iter().enumerate().filter(|(_, e)| !**e) {
+                // separate runs of unexecuted instructions
+                if last.is_some_and(|last| last + 1 != idx) {
+                    out.push_str("  ...\n");
+                }
+                out.push_str(&format!("  {}\n", disassemble(addr(idx))));
+                last = Some(idx);
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write,
+};
+
+use super::{condition::Condition, host, tui::Tui, Exec, SimArgs};
+use crate::common::parse_register;
+
+/// Most snapshots kept; older ones are dropped, limiting how far back the
+/// program can be reversed to `MAX_SNAPSHOTS * --snapshot-interval` steps.
+const MAX_SNAPSHOTS: usize = 64;
+
+const HELP: &str = "commands:
+  s, step [N]             execute N instructions (default 1)
+  c, continue             run until a breakpoint or the program ends
+  rs, reverse-step [N]    go back N instructions (default 1)
+  rc, reverse-continue    go back to the last time a breakpoint was reached
+  b, break ADDR|SYM [if COND]
+                          set a breakpoint, which with a condition such as
+                          `$t0 == 5 && word(sp + 8) != 0` only stops when it holds
+  d, delete ADDR|SYM      remove a breakpoint
+  r, regs                 print the registers
+  bt, backtrace           print the calls leading to the current instruction
+  w, watch EXPR           show the value of EXPR after each command: a register,
+                          pc, hi, lo, or the word at [ADDR|SYM]
+  uw, unwatch N           remove watch expression N
+  m, memory ADDR|SYM      show the memory at ADDR
+  x ADDR|SYM[..END]       dump the memory from ADDR up to END, or 64 bytes of it
+  save FILE               save the program's state to FILE, as --checkpoint does
+  restore FILE            carry on from the state saved in FILE
+  h, help                 print this message
+  q, quit                 stop debugging
+Ctrl-C stops a running program and returns to the prompt.";
+
+/// Snapshots of the program taken every `interval` steps as it runs, so the
+/// debugger can go back in time: an earlier step is reached by restoring the
+/// closest snapshot before it and executing forwards again.
+struct History<'a> {
+    interval: u64,
+    snapshots: VecDeque<Exec<'a>>,
+    /// The pc of every step executed since the oldest snapshot
+    log: VecDeque<(u64, u32)>,
+}
+
+impl<'a> History<'a> {
+    /// Records that `exec` is about to execute a step
+    fn record(&mut self, exec: &Exec<'a>) {
+        let step = exec.steps();
+        if step.is_multiple_of(self.interval)
+            && self.snapshots.back().map(Exec::steps) != Some(step)
+        {
+            self.snapshots.push_back(exec.clone());
+            if self.snapshots.len() > MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+                let oldest = self.snapshots[0].steps();
+                while self.log.front().is_some_and(|(s, _)| *s < oldest) {
+                    self.log.pop_front();
+                }
+            }
+        }
+        self.log.push_back((step, exec.pc()));
+    }
+
+    /// Rewinds `exec` to `target` steps, or as close to it as the history
+    /// goes. Returns false if the history does not reach back that far.
+    fn rewind(&mut self, exec: &mut Exec<'a>, target: u64) -> bool {
+        let Some(snap) = self.snapshots.iter().rev().find(|s| s.steps() <= target) else {
+            if let Some(oldest) = self.snapshots.front().cloned() {
+                self.rewind(exec, oldest.steps());
+            }
+            return false;
+        };
+        exec.restore(snap);
+
+        // what happens from here on will be recorded again
+        while self.snapshots.back().is_some_and(|s| s.steps() > target) {
+            self.snapshots.pop_back();
+        }
+        while self.log.back().is_some_and(|(s, _)| *s >= target) {
+            self.log.pop_back();
+        }
+
+        exec.set_quiet(true);
+        while exec.steps() < target {
+            self.record(exec);
+            if exec.step().is_err() && !exec.resume_trap() {
+                break;
+            }
+        }
+        exec.set_quiet(false);
+        true
+    }
+}
+
+/// The value of a watch expression: a register, `pc`, `hi`, `lo`, or the
+/// word at an address or symbol in brackets
+fn evaluate(exec: &Exec, expr: &str) -> Result<u32, String> {
+    if let Some(addr) = expr.strip_prefix('[').and_then(|e| e.strip_suffix(']')) {
+        let addr = exec.resolve(addr.trim())?;
+        return exec
+            .read_word(addr)
+            .map_err(|_| format!("0x{:08x} is not mapped", addr));
+    }
+    match expr {
+        "pc" => Ok(exec.pc()),
+        "hi" => Ok(exec.hi()),
+        "lo" => Ok(exec.lo()),
+        _ => parse_register(expr)
+            .map(|r| exec.registers()[r as usize])
+            .ok_or_else(|| format!("invalid expression `{}`", expr)),
+    }
+}
+
+/// The simulator debugger, rbug (`-d`). Reads commands from stdin.
+pub struct Debugger<'a> {
+    exec: Exec<'a>,
+    /// Breakpoints by address, along with their conditions
+    breakpoints: BTreeMap<u32, Option<Condition>>,
+    history: History<'a>,
+    watches: Vec<String>,
+    /// The full-screen view, with `--tui`
+    tui: Option<Tui>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(mut exec: Exec<'a>, args: &SimArgs) -> Self {
+        let tui = args.tui.then(|| Tui::new(&exec)).and_then(|tui| match tui {
+            Ok(tui) => {
+                exec.capture_output();
+                Some(tui)
+            }
+            Err(e) => {
+                eprintln!("cannot start the full-screen view: {}", e);
+                None
+            }
+        });
+        Self {
+            exec,
+            breakpoints: BTreeMap::new(),
+            history: History {
+                interval: args.snapshot_interval.max(1),
+                snapshots: VecDeque::new(),
+                log: VecDeque::new(),
+            },
+            watches: vec![],
+            tui,
+        }
+    }
+
+    /// Prints a message, or adds it to the message pane of the full-screen
+    /// view
+    fn say(&mut self, msg: &str) {
+        match &mut self.tui {
+            Some(tui) => tui.say(msg),
+            None => println!("{}", msg),
+        }
+    }
+
+    pub fn run(mut self) -> Exec<'a> {
+        // Ctrl-C stops the program rather than rtool
+        host::catch_interrupts();
+        self.show();
+        loop {
+            if self.tui.is_none() {
+                print!("(rbug) ");
+                std::io::stdout().flush().unwrap();
+            }
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            // only interrupts the command about to run
+            host::take_interrupt();
+            let mut words = line.split_whitespace();
+            let Some(cmd) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+            let count = || arg.map_or(Ok(1), |n| n.parse::<u64>());
+            match cmd {
+                "s" | "step" => match count() {
+                    Ok(n) => {
+                        for _ in 0..n {
+                            if !self.step() {
+                                break;
+                            }
+                        }
+                        self.show();
+                    }
+                    Err(_) => self.say(&format!("invalid count `{}`", arg.unwrap())),
+                },
+                "c" | "continue" => {
+                    while self.step() && !self.at_breakpoint() {}
+                    self.show();
+                }
+                "rs" | "reverse-step" => match count() {
+                    Ok(n) => {
+                        let target = self.exec.steps().saturating_sub(n);
+                        if !self.history.rewind(&mut self.exec, target) {
+                            self.say("reached the start of the recorded history");
+                        }
+                        self.show();
+                    }
+                    Err(_) => self.say(&format!("invalid count `{}`", arg.unwrap())),
+                },
+                "rc" | "reverse-continue" => {
+                    let now = self.exec.steps();
+                    let reached = self
+                        .history
+                        .log
+                        .iter()
+                        .rev()
+                        .filter(|(s, pc)| *s < now && self.breakpoints.contains_key(pc))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    // a condition can only be checked by going back to see
+                    let mut hit = false;
+                    for (step, _) in reached {
+                        self.history.rewind(&mut self.exec, step);
+                        if self.at_breakpoint() {
+                            hit = true;
+                            break;
+                        }
+                    }
+                    if !hit {
+                        let oldest = self.history.snapshots.front().map_or(now, Exec::steps);
+                        self.history.rewind(&mut self.exec, oldest);
+                        self.say("reached the start of the recorded history");
+                    }
+                    self.show();
+                }
+                "b" | "break" | "d" | "delete" => match arg.map(|a| self.exec.resolve(a)) {
+                    Some(Ok(addr)) if cmd.starts_with('b') => {
+                        let rest = words.collect::<Vec<_>>();
+                        let cond = match rest.split_first() {
+                            None => Ok(None),
+                            Some((&"if", cond)) if !cond.is_empty() => {
+                                Condition::parse(&cond.join(" ")).map(Some)
+                            }
+                            Some(_) => Err(format!("usage: {} ADDR|SYM [if COND]", cmd)),
+                        };
+                        match cond {
+                            Ok(cond) => {
+                                let mut msg =
+                                    format!("breakpoint at {}", self.exec.disassemble(addr));
+                                if let Some(cond) = &cond {
+                                    msg += &format!(" if {}", cond.text());
+                                }
+                                self.breakpoints.insert(addr, cond);
+                                self.say(&msg);
+                            }
+                            Err(e) => self.say(&e),
+                        }
+                    }
+                    Some(Ok(addr)) => {
+                        if self.breakpoints.remove(&addr).is_none() {
+                            self.say(&format!("no breakpoint at 0x{:08x}", addr));
+                        }
+                    }
+                    Some(Err(e)) => self.say(&e),
+                    None => self.say(&format!("usage: {} ADDR|SYM", cmd)),
+                },
+                "r" | "regs" => self.say(&self.exec.dump_registers()),
+                "bt" | "backtrace" => self.say(self.exec.backtrace().trim()),
+                "w" | "watch" => {
+                    let expr = words.fold(arg.unwrap_or("").to_string(), |e, w| e + w);
+                    if expr.is_empty() {
+                        self.say(&format!("usage: {} EXPR", cmd));
+                    } else if let Err(e) = evaluate(&self.exec, &expr) {
+                        self.say(&e);
+                    } else {
+                        self.watches.push(expr);
+                    }
+                    self.show();
+                }
+                "uw" | "unwatch" => {
+                    match arg.and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) if (1..=self.watches.len()).contains(&n) => {
+                            self.watches.remove(n - 1);
+                        }
+                        _ => self.say(&format!("usage: {} N, for one of the watches", cmd)),
+                    }
+                    self.show();
+                }
+                "m" | "memory" => match arg.map(|a| self.exec.resolve(a)) {
+                    Some(Ok(addr)) => match &mut self.tui {
+                        Some(tui) => {
+                            tui.memory = addr;
+                            self.show();
+                        }
+                        None => print!("{}", self.exec.dump_memory(addr, addr + 64)),
+                    },
+                    Some(Err(e)) => self.say(&e),
+                    None => self.say(&format!("usage: {} ADDR|SYM", cmd)),
+                },
+                "x" => {
+                    let range = arg.map(|a| match a.split_once("..") {
+                        Some((start, end)) => {
+                            Ok((self.exec.resolve(start)?, self.exec.resolve(end)?))
+                        }
+                        None => self
+                            .exec
+                            .resolve(a)
+                            .map(|start| (start, start.saturating_add(64))),
+                    });
+                    match range {
+                        Some(Ok((start, end))) => {
+                            self.say(self.exec.dump_memory(start, end).trim_end())
+                        }
+                        Some(Err(e)) => self.say(&e),
+                        None => self.say("usage: x ADDR|SYM[..END]"),
+                    }
+                }
+                "save" => match arg.map(|path| self.exec.save_checkpoint(path)) {
+                    Some(Ok(())) => self.say(&format!("saved to `{}`", arg.unwrap())),
+                    Some(Err(e)) => self.say(&e),
+                    None => self.say("usage: save FILE"),
+                },
+                "restore" => match arg.map(|path| self.exec.load_checkpoint(path)) {
+                    Some(Ok(())) => {
+                        // the history is of another run
+                        self.history.snapshots.clear();
+                        self.history.log.clear();
+                        self.show();
+                    }
+                    Some(Err(e)) => self.say(&e),
+                    None => self.say("usage: restore FILE"),
+                },
+                "h" | "help" => self.say(HELP),
+                "q" | "quit" => break,
+                _ => self.say(&format!("unknown command `{}`, try `help`", cmd)),
+            }
+        }
+        self.exec
+    }
+
+    /// Whether the program has reached a breakpoint whose condition, if it
+    /// has one, holds. A condition which cannot be evaluated stops it too.
+    fn at_breakpoint(&mut self) -> bool {
+        let pc = self.exec.pc();
+        let Some(cond) = self.breakpoints.get(&pc) else {
+            return false;
+        };
+        match cond.as_ref().map(|c| c.holds(&self.exec)) {
+            None | Some(Ok(true)) => true,
+            Some(Ok(false)) => false,
+            Some(Err(e)) => {
+                self.say(&format!(
+                    "cannot check the breakpoint at 0x{:08x}: {}",
+                    pc, e
+                ));
+                true
+            }
+        }
+    }
+
+    /// Executes one instruction, returning false if the program has stopped
+    /// or Ctrl-C has been pressed
+    fn step(&mut self) -> bool {
+        if self.exec.terminated() {
+            self.say("the program has terminated");
+            return false;
+        }
+        if host::take_interrupt() {
+            self.say(&format!("interrupted at 0x{:08x}", self.exec.pc()));
+            return false;
+        }
+        self.history.record(&self.exec);
+        let pc = self.exec.pc();
+        match self.exec.step() {
+            Ok(()) => true,
+            // a `break 0` in the program stops like a breakpoint
+            Err(_) if self.exec.resume_trap() => {
+                self.say(&format!("stopped at `break 0` at 0x{:08x}", pc));
+                false
+            }
+            Err(e) => {
+                self.say(&e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Prints the next instruction to execute and the watch expressions, or
+    /// redraws the full-screen view
+    fn show(&mut self) {
+        let watches = self
+            .watches
+            .iter()
+            .map(|w| (w.clone(), evaluate(&self.exec, w)))
+            .collect::<Vec<_>>();
+        if let Some(tui) = &mut self.tui {
+            tui.draw(&mut self.exec, &self.breakpoints, &watches);
+            return;
+        }
+        println!(
+            "[{}] {}",
+            self.exec.steps(),
+            self.exec.disassemble(self.exec.pc())
+        );
+        for (idx, (expr, value)) in watches.iter().enumerate() {
+            match value {
+                Ok(v) => println!("  {}: {} = 0x{:08x} ({})", idx + 1, expr, v, *v as i32),
+                Err(e) => println!("  {}: {} = {}", idx + 1, expr, e),
+            }
+        }
+    }
+}
@@ -1,22 +1,104 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs::File,
-    io::{Read, Write},
-    os::unix::fs::OpenOptionsExt,
+    io::{Read, Seek, Write},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use super::{mem::Memory, SimArgs, EMPTY_ARGS, PAGE_SIZE};
+use clap::ValueEnum;
+
+use super::{
+    cache::Caches,
+    callgraph::CallGraph,
+    checkpoint::{self, FileInfo},
+    coverage::Coverage,
+    format::DumpFormat,
+    heatmap::Heatmap,
+    host,
+    livelock::Livelock,
+    mem::{Memory, Page},
+    predict::Predictor,
+    profile::Profile,
+    random::Random,
+    recent::Recent,
+    record::{Input, InputKind},
+    stats::Stats,
+    symbols::Symbols,
+    timing::{Timing, TimingConfig},
+    trace::{Regs, TraceFormat, Tracer},
+    SimArgs, EMPTY_ARGS, PAGE_BITS, PAGE_MASK, PAGE_SIZE,
+};
 use crate::{
-    common::{Error, Instruction, ObjectModule},
-    sim::{Register, DATA_START, STACK_START},
+    common::{
+        CoreInfo, CorePage, Error, Instruction, ObjectModule, CORE_EXEC, CORE_WRITE, SYM_DEF,
+        SYM_GLB,
+    },
+    sim::{Layout, Register, StackDirection},
 };
 
+/// Global symbol which, when defined by the program, is jumped to instead of
+/// terminating on an exception
+const EXCEPTION_HANDLER: &str = "__r2k__exception__";
+
+/// File descriptors for the host's standard streams, which are always open.
+/// Files opened by the program are numbered from 3.
+const STDIN_FD: u32 = 0;
+const STDOUT_FD: u32 = 1;
+const STDERR_FD: u32 = 2;
+
+/// Start of a file written by the `snap` syscall
+const SNAP_MAGIC: &[u8] = b"R2KSNAP1";
+
+/// ExcCode values reported in the Cause register
+const EXC_ADEL: u32 = 4;
+const EXC_ADES: u32 = 5;
+const EXC_BP: u32 = 9;
+const EXC_OV: u32 = 12;
+const EXC_TR: u32 = 13;
+
+/// How many instructions are run between checks of the clock for
+/// `--timeout`, which is too slow to read after every one
+const TIMEOUT_INTERVAL: u64 = 1 << 16;
+
 #[derive(Clone, Copy)]
-struct ExecCtx {
-    reg: [u32; 32],
-    pc: u32,
-    hi: u32,
-    lo: u32,
+pub(super) struct ExecCtx {
+    pub(super) reg: [u32; 32],
+    pub(super) pc: u32,
+    pub(super) hi: u32,
+    pub(super) lo: u32,
+    cp0: Cp0,
+}
+
+/// The coprocessor 0 registers visible to a program's exception handler
+#[derive(Clone, Copy, Default)]
+struct Cp0 {
+    status: u32,
+    cause: u32,
+    epc: u32,
+}
+
+impl Cp0 {
+    fn read(&self, reg: u8) -> u32 {
+        use crate::common::instruction::opcodes::*;
+        match reg {
+            CP0_STATUS => self.status,
+            CP0_CAUSE => self.cause,
+            CP0_EPC => self.epc,
+            _ => 0,
+        }
+    }
+
+    /// Cause is set by the processor, so writes to it are ignored
+    fn write(&mut self, reg: u8, val: u32) {
+        use crate::common::instruction::opcodes::*;
+        match reg {
+            CP0_STATUS => self.status = val,
+            CP0_EPC => self.epc = val,
+            _ => {}
+        }
+    }
 }
 
 pub struct Exec<'a> {
@@ -25,21 +107,191 @@ pub struct Exec<'a> {
     heap_start: u32,
     heap_size: u32,
     heap_next_page: u32,
+    /// The program break: the end of the heap as far as the program is
+    /// concerned. Pages up to `heap_next_page` are mapped.
+    brk: u32,
     exn: Option<Exception>,
+    /// Address of the program's exception handler, if it has one
+    handler: Option<u32>,
     files: HashMap<u32, File>,
+    /// How each of `files` was opened, so that checkpoints can open them
+    /// again
+    file_info: HashMap<u32, FileInfo>,
     next_fd: u32,
+    /// Where the program exits when execution reaches it, which is the end
+    /// of its text with `--syscalls mars`
+    text_end: Option<u32>,
+    /// Handlers for syscalls the simulator does not carry out itself
+    syscalls: HashMap<u32, SyscallHandler<'a>>,
+    /// The random number generators, by the id the program gives them
+    random: HashMap<u32, Random>,
+    /// What `$k0` and `$k1` are clobbered with, when `--clobber-seed` is
+    /// given
+    clobber: Option<Random>,
+    /// Instruction counts, kept when `-p` is given
+    stats: Option<Stats>,
+    /// Instruction counts by address, when `--profile` is given
+    profile: Option<Profile>,
+    /// The shadow call stack, when `--profile` or `--call-graph` is given
+    calls: Option<CallGraph>,
+    /// Which instructions have been executed, when `--coverage` is given
+    coverage: Option<Coverage>,
+    /// Watches for the program getting stuck, when `--livelock` is given
+    livelock: Option<Livelock>,
+    /// Loads and stores by address, when `--heatmap` is given
+    heatmap: Option<Heatmap>,
+    /// Simulated cycle counts, when `--timing` is given
+    timing: Option<Timing>,
+    /// Where the segments of the program are in memory
+    layout: Layout,
+    /// The simulated caches, when any are given
+    caches: Option<Caches>,
+    /// Branch prediction statistics, when `--predictor` is given
+    predictor: Option<Predictor>,
+    symbols: Symbols,
+    /// Prints each instruction, when `-t` is given
+    tracer: Option<Tracer>,
+    /// The last instructions executed
+    recent: Recent,
+    /// Number of instructions executed
+    steps: u64,
+    /// The number of steps at which `-i` stops the program
+    inst_limit: Option<u64>,
+    /// When `--timeout` stops the program, set once it starts running
+    deadline: Option<Instant>,
+    /// Where `--until` stops the program, until it first gets there
+    until: Option<u32>,
+    /// Every nondeterministic input the program has received
+    inputs: Vec<Input>,
+    /// Input to hand out again instead of reading it from the host, when
+    /// re-executing from a snapshot or replaying a recording
+    replay: VecDeque<Input>,
+    /// Suppresses program output, when re-executing from a snapshot
+    quiet: bool,
+    /// Input for the program to read instead of the host's stdin
+    stdin: Option<VecDeque<u8>>,
+    /// Output written by the program, when it is kept rather than written to
+    /// the host's stdout and stderr
+    stdout: Option<Vec<u8>>,
+    stderr: Option<Vec<u8>>,
+    /// Read-only pages decoded when first executed, by virtual page number,
+    /// so that each instruction is only decoded once
+    icache: HashMap<u32, DecodedPage>,
+    /// The page last executed from, which is usually the next one as well
+    last_page: Option<(u32, DecodedPage)>,
     args: &'a SimArgs,
 }
 
+/// Which numbers and conventions the syscalls follow, given by `--syscalls`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SyscallConvention {
+    /// rsim's
+    #[default]
+    R2k,
+    /// Those of the MARS simulator: integers are printed signed and read with
+    /// a sign, read_char returns in $v0, read_string only adds the newline if
+    /// it fits, open takes MARS's flags, 34 to 36 print integers in hex,
+    /// binary and unsigned, and running off the end of the text segment
+    /// exits the program.
+    Mars,
+}
+
+/// Which instruction set the program is run as, given by `--isa`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Isa {
+    /// MIPS I, as the R2000 runs
+    #[default]
+    Mips1,
+    /// MIPS32 release 2, which adds three-operand mul, clz and clo, movz and
+    /// movn, seb and seh, and ext and ins, as gcc commonly emits
+    #[value(name = "mips32r2")]
+    Mips32r2,
+}
+
+/// Carries out a syscall for the program, returning why it failed if the
+/// program should be terminated
+pub type SyscallHandler<'a> = Rc<dyn Fn(&mut Exec<'a>) -> Result<(), String> + 'a>;
+
+/// The instructions in a page, indexed by word. Words which are not valid
+/// instructions are left to be decoded (and fail to) when executed.
+type DecodedPage = Arc<[Option<Instruction>]>;
+
 #[derive(Clone)]
 enum Exception {
     Syscall(u32),
+    /// `break` with its 20 bit code. `break 0` is the trap rbug stops at.
     Break(u32),
+    /// A conditional trap whose condition held, with its 10 bit code
+    Trap(u32),
     DivideByZero,
     Overflow,
     Memory(Error),
     Exit(u32),
     Timer,
+    /// A syscall which no handler carries out, or whose handler failed
+    BadSyscall(String),
+    /// The program ran `--livelock` million instructions without making
+    /// progress, looping between the two addresses
+    Livelock(u32, u32),
+    /// The program ran the number of instructions allowed by `-i`
+    InstLimit,
+    /// The program ran for the number of seconds allowed by `--timeout`
+    Timeout,
+    /// The program reached the address given by `--until`
+    Until,
+    /// The program was loaded from a core file by `--debug-core`, having
+    /// terminated for this reason
+    Core(String),
+}
+
+impl Exception {
+    /// The ExcCode reported to a handler for this exception, raised by `inst`,
+    /// or None if it always terminates the program
+    fn code(&self, inst: Instruction) -> Option<u32> {
+        use crate::common::instruction::opcodes::*;
+        match self {
+            Exception::Memory(_) => match inst {
+                Instruction::I {
+                    op: OP_SB..=OP_SWR, ..
+                } => Some(EXC_ADES),
+                _ => Some(EXC_ADEL),
+            },
+            Exception::Overflow => Some(EXC_OV),
+            Exception::DivideByZero | Exception::Trap(_) => Some(EXC_TR),
+            // the debugger's, not the program's
+            Exception::Break(0) => None,
+            Exception::Break(_) => Some(EXC_BP),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that a module is marked executable, which rlink does once every
+/// reference is resolved, describing why not if it isn't
+fn check_executable(module: &ObjectModule) -> Result<(), String> {
+    if module.head.flags & 0x3 != 0 && module.ext_ref.is_empty() {
+        return Ok(());
+    }
+    let mut msg = String::from("not an executable module");
+    if module.ext_ref.is_empty() {
+        msg.push_str(", as it has no entry point");
+    } else {
+        msg.push_str(match module.ext_ref.len() {
+            1 => ", as a reference is unresolved:",
+            _ => ", as references are unresolved:",
+        });
+        for r in &module.ext_ref {
+            let name = module
+                .get_str_entry(r.str_off as usize)
+                .map_or(String::from("?"), |n| n.to_string_lossy().into_owned());
+            msg.push_str(&format!(
+                "\n  {} at 0x{:08x} in {}",
+                name, r.addr, r.ref_info.sect
+            ));
+        }
+    }
+    msg.push_str("\n(-x runs it anyway)");
+    Err(msg)
 }
 
 impl<'a> Clone for Exec<'a> {
@@ -50,9 +302,41 @@ impl<'a> Clone for Exec<'a> {
             heap_start: self.heap_start,
             heap_size: self.heap_size,
             heap_next_page: self.heap_next_page,
+            brk: self.brk,
             exn: self.exn.clone(),
+            handler: self.handler,
             files: HashMap::new(),
+            file_info: HashMap::new(),
             next_fd: 33,
+            text_end: self.text_end,
+            syscalls: self.syscalls.clone(),
+            random: self.random.clone(),
+            clobber: self.clobber.clone(),
+            stats: self.stats.clone(),
+            profile: self.profile.clone(),
+            calls: self.calls.clone(),
+            coverage: self.coverage.clone(),
+            livelock: self.livelock.clone(),
+            heatmap: self.heatmap.clone(),
+            timing: self.timing.clone(),
+            layout: self.layout,
+            caches: self.caches.clone(),
+            predictor: self.predictor.clone(),
+            symbols: self.symbols.clone(),
+            tracer: self.tracer.clone(),
+            recent: self.recent.clone(),
+            steps: self.steps,
+            inst_limit: self.inst_limit,
+            deadline: self.deadline,
+            until: self.until,
+            inputs: self.inputs.clone(),
+            replay: self.replay.clone(),
+            quiet: self.quiet,
+            stdin: self.stdin.clone(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            icache: self.icache.clone(),
+            last_page: self.last_page.clone(),
             args: self.args,
         }
     }
@@ -95,7 +379,7 @@ impl<'a> Exec<'a> {
                     self.ctx.pc = self.ctx.reg[rs as usize];
                 }
                 FUNCT_SYSCALL => self.raise_exn(Exception::Syscall(0)),
-                FUNCT_BREAK => self.raise_exn(Exception::Syscall(0)),
+                FUNCT_BREAK => self.raise_exn(Exception::Break(i.break_code().unwrap())),
                 FUNCT_MFHI => self.ctx.reg[rd as usize] = self.ctx.hi,
                 FUNCT_MTHI => self.ctx.hi = self.ctx.reg[rs as usize],
                 FUNCT_MFLO => self.ctx.reg[rd as usize] = self.ctx.lo,
@@ -188,6 +472,31 @@ impl<'a> Exec<'a> {
                             0
                         }
                 }
+                FUNCT_TGE..=FUNCT_TEQ | FUNCT_TNE => {
+                    let a = self.ctx.reg[rs as usize];
+                    let b = self.ctx.reg[rt as usize];
+                    let trap = match funct {
+                        FUNCT_TGE => a as i32 >= b as i32,
+                        FUNCT_TGEU => a >= b,
+                        FUNCT_TLT => (a as i32) < b as i32,
+                        FUNCT_TLTU => a < b,
+                        FUNCT_TEQ => a == b,
+                        _ => a != b,
+                    };
+                    if trap {
+                        self.raise_exn(Exception::Trap(i.trap_code().unwrap()))
+                    }
+                }
+                FUNCT_MOVZ => {
+                    if self.ctx.reg[rt as usize] == 0 {
+                        self.ctx.reg[rd as usize] = self.ctx.reg[rs as usize]
+                    }
+                }
+                FUNCT_MOVN => {
+                    if self.ctx.reg[rt as usize] != 0 {
+                        self.ctx.reg[rd as usize] = self.ctx.reg[rs as usize]
+                    }
+                }
                 _ => unreachable!(),
             },
             Instruction::I { op, rs, rt, imm } => match op {
@@ -214,6 +523,22 @@ impl<'a> Exec<'a> {
                             self.ctx.pc = (self.ctx.pc as i32 + ((imm as i16 as i32) << 2)) as u32
                         }
                     }
+                    BCOND_TGEI..=BCOND_TEQI | BCOND_TNEI => {
+                        let a = self.ctx.reg[rs as usize];
+                        let b = imm as i16 as i32;
+                        let trap = match rt {
+                            BCOND_TGEI => a as i32 >= b,
+                            // compared unsigned, after sign extending
+                            BCOND_TGEIU => a >= b as u32,
+                            BCOND_TLTI => (a as i32) < b,
+                            BCOND_TLTIU => a < b as u32,
+                            BCOND_TEQI => a as i32 == b,
+                            _ => a as i32 != b,
+                        };
+                        if trap {
+                            self.raise_exn(Exception::Trap(0))
+                        }
+                    }
                     _ => unreachable!(),
                 },
                 OP_BEQ => {
@@ -337,7 +662,51 @@ impl<'a> Exec<'a> {
                 OP_SWR => {
                     todo!()
                 }
-                0o20..=0o23 => {
+                OP_SPECIAL2 | OP_SPECIAL3 => {
+                    let (rd, shamt, funct) = i.special_fields().unwrap();
+                    let a = self.ctx.reg[rs as usize];
+                    let b = self.ctx.reg[rt as usize];
+                    match (op, funct) {
+                        (OP_SPECIAL2, SPECIAL2_MUL) => {
+                            self.ctx.reg[rd as usize] = a.wrapping_mul(b)
+                        }
+                        (OP_SPECIAL2, SPECIAL2_CLZ) => {
+                            self.ctx.reg[rd as usize] = a.leading_zeros()
+                        }
+                        (OP_SPECIAL2, SPECIAL2_CLO) => self.ctx.reg[rd as usize] = a.leading_ones(),
+                        (_, SPECIAL3_BSHFL) => {
+                            self.ctx.reg[rd as usize] = match shamt {
+                                BSHFL_SEB => b as i8 as u32,
+                                _ => b as i16 as u32,
+                            }
+                        }
+                        (_, SPECIAL3_EXT) => {
+                            // rd is the size less one, shamt the position
+                            let mask = (1u64 << (rd + 1)) - 1;
+                            self.ctx.reg[rt as usize] = ((a >> shamt) as u64 & mask) as u32
+                        }
+                        (_, SPECIAL3_INS) => {
+                            // rd is the most significant bit, shamt the least
+                            let mask = (((1u64 << (rd + 1)) - 1) as u32) & !((1 << shamt) - 1);
+                            self.ctx.reg[rt as usize] = (b & !mask) | ((a << shamt) & mask)
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                OP_COP0 => match rs {
+                    COP_MF => self.ctx.reg[rt as usize] = self.ctx.cp0.read((imm >> 11) as u8),
+                    COP_MT => self
+                        .ctx
+                        .cp0
+                        .write((imm >> 11) as u8, self.ctx.reg[rt as usize]),
+                    COP_CO if imm as u8 & 0x3F == CP0_RFE => {
+                        // pop the interrupt enable / kernel mode stack
+                        let status = self.ctx.cp0.status;
+                        self.ctx.cp0.status = (status & !0xF) | ((status >> 2) & 0xF);
+                    }
+                    _ => panic!("Unimplemented coprocessor instruction"),
+                },
+                0o21..=0o23 => {
                     panic!("Unimplemented coprocessor instruction")
                 }
                 0o60..=0o63 => {
@@ -349,10 +718,10 @@ impl<'a> Exec<'a> {
                 _ => unreachable!(),
             },
             Instruction::J { op, imm } => match op {
-                OP_J => self.ctx.pc = ((self.ctx.pc & 0xF0000000) | (imm << 2)) - 4,
+                OP_J => self.ctx.pc = ((self.ctx.pc & 0xF0000000) | (imm << 2)).wrapping_sub(4),
                 OP_JAL => {
                     self.ctx.reg[31] = self.ctx.pc;
-                    self.ctx.pc = (self.ctx.pc & 0xF0000000 | (imm << 2)) - 4
+                    self.ctx.pc = (self.ctx.pc & 0xF0000000 | (imm << 2)).wrapping_sub(4)
                 }
                 _ => unreachable!(),
             },
@@ -360,7 +729,10 @@ impl<'a> Exec<'a> {
     }
 
     fn raise_exn(&mut self, exn: Exception) {
-        if !self.args.no_kern_clobber {
+        if let Some(clobber) = &mut self.clobber {
+            self.ctx.reg[Register::K0 as usize] = clobber.next_u32();
+            self.ctx.reg[Register::K1 as usize] = clobber.next_u32();
+        } else if !self.args.no_kern_clobber {
             const MASK: u32 = 0b10000000000000000000000001100010;
             if self.ctx.reg[Register::K0 as usize] & 1 > 0 {
                 self.ctx.reg[Register::K0 as usize] ^= MASK;
@@ -373,36 +745,49 @@ impl<'a> Exec<'a> {
         }
 
         match exn {
-            Exception::Syscall(v) | Exception::Break(v) => self.syscall(v),
+            Exception::Syscall(v) => self.syscall(v),
             // stores exn to be checked before executing next instruction
             e => self.exn = Some(e),
         }
     }
 
+    /// Transfers control to the program's exception handler, as the R2000
+    /// does: EPC holds the faulting instruction and the mode bits of Status
+    /// are pushed. As with `$ra` after a `jal`, a handler returning with
+    /// `jr` to EPC resumes after the faulting instruction.
+    fn enter_handler(&mut self, handler: u32, code: u32) {
+        let cp0 = &mut self.ctx.cp0;
+        cp0.epc = self.ctx.pc;
+        cp0.cause = code << 2;
+        cp0.status = (cp0.status & !0x3F) | ((cp0.status << 2) & 0x3C);
+        self.exn = None;
+        // step() advances past this
+        self.ctx.pc = handler.wrapping_sub(4);
+    }
+
     fn syscall(&mut self, _imm: u32) {
         use crate::common::instruction::opcodes::*;
 
-        match self.ctx.reg[Register::V0 as usize] {
+        let code = self.ctx.reg[Register::V0 as usize];
+        if self.args.syscalls == SyscallConvention::Mars && self.mars_syscall(code) {
+            return;
+        }
+        match code {
             // print_int
             SYSCALL_PRINT_INT => {
-                print!("{}", self.ctx.reg[Register::A0 as usize]);
-                std::io::stdout().flush().unwrap();
+                self.print(&self.ctx.reg[Register::A0 as usize].to_string());
             }
             // print_string(buf)
             SYSCALL_PRINT_STRING => {
                 let mut a = self.ctx.reg[Register::A0 as usize];
                 match self.read_string(a) {
-                    Ok(s) => {
-                        print!("{}", s);
-                        std::io::stdout().flush();
-                    }
+                    Ok(s) => self.print(&s),
                     Err(e) => self.exn = Some(Exception::Memory(e)),
                 }
             }
             // read_int
             SYSCALL_READ_INT => {
-                let mut line = String::new();
-                std::io::stdin().read_line(&mut line).unwrap();
+                let mut line = self.read_line();
                 line = line.chars().take_while(|c| c.is_ascii_digit()).collect();
                 match line.parse::<i32>() {
                     Ok(i) => {
@@ -416,8 +801,7 @@ impl<'a> Exec<'a> {
             }
             // read_string(buf, len)
             SYSCALL_READ_STRING => {
-                let mut line = String::new();
-                std::io::stdin().read_line(&mut line).unwrap();
+                let line = self.read_line();
                 let bytes = line.as_bytes();
                 let mut buf_addr = self.ctx.reg[Register::A0 as usize];
                 self.ctx.reg[Register::V0 as usize] = buf_addr;
@@ -443,17 +827,14 @@ impl<'a> Exec<'a> {
                     self.ctx.reg[Register::V0 as usize] = 0;
                 }
             }
-            // sbrk(amt)
+            // sbrk(amt): moves the break by a signed amount, returning the old
+            // break, or -1 if it would leave the heap
             SYSCALL_SBRK => {
-                self.ctx.reg[Register::V0 as usize] = self.heap_start;
-                if self.ctx.reg[Register::A0 as usize] != 0 {
-                    let new_pages = (self.ctx.reg[4] + PAGE_SIZE - 1) / PAGE_SIZE;
-                    for _ in 0..new_pages {
-                        self.mem.alloc_page(self.heap_next_page, true, false);
-                        self.heap_next_page += PAGE_SIZE;
-                    }
-                    self.heap_size += new_pages * PAGE_SIZE;
-                }
+                let amt = self.ctx.reg[Register::A0 as usize] as i32;
+                self.ctx.reg[Register::V0 as usize] = match self.sbrk(amt) {
+                    Some(old) => old,
+                    None => -1i32 as u32,
+                };
                 self.ctx.reg[Register::V1 as usize] = self.heap_size;
             }
             // exit()
@@ -462,18 +843,14 @@ impl<'a> Exec<'a> {
             }
             // print_char(char)
             SYSCALL_PRINT_CHAR => {
-                print!("{}", char::from(self.ctx.reg[Register::A0 as usize] as u8));
-                std::io::stdout().flush().unwrap();
+                self.print(&char::from(self.ctx.reg[Register::A0 as usize] as u8).to_string());
             }
             // read_char()
             SYSCALL_READ_CHAR => {
-                let mut byte = [0u8];
-                std::io::stdin().read_exact(&mut byte);
-                self.ctx.reg[Register::A0 as usize] = byte[0] as u32;
+                self.ctx.reg[Register::A0 as usize] = self.read_char() as u32;
             }
             // open(name, flags, mode)
             SYSCALL_OPEN => {
-                let mut opts = std::fs::OpenOptions::new();
                 let name = match self.read_string(self.ctx.reg[Register::A0 as usize]) {
                     Ok(s) => s,
                     Err(e) => {
@@ -483,27 +860,17 @@ impl<'a> Exec<'a> {
                 };
                 let flags = self.ctx.reg[Register::A1 as usize];
                 let mode = self.ctx.reg[Register::A2 as usize];
-                opts.mode(mode);
-                if flags == 0 {
-                    opts.read(true);
-                }
-                if flags & 0x1 != 0 {
-                    opts.write(true);
-                }
-                if flags & 0x2 != 0 {
-                    opts.read(true);
-                    opts.write(true);
-                }
-                if flags & 0x100 != 0 {
-                    opts.create(true);
-                }
-                if flags & 0x1000 != 0 {
-                    opts.truncate(true);
-                }
-                opts.custom_flags(flags as i32);
+                let opts = host::open_options(flags, mode);
+                let info = FileInfo {
+                    path: name.clone(),
+                    read: flags & 0x3 != 0x1,
+                    write: flags & 0x3 != 0,
+                    append: flags & 0x8 != 0,
+                };
                 match opts.open(name) {
                     Ok(f) => {
                         self.files.insert(self.next_fd, f);
+                        self.file_info.insert(self.next_fd, info);
                         self.ctx.reg[Register::V0 as usize] = self.next_fd;
                         self.next_fd += 1;
                     }
@@ -514,59 +881,73 @@ impl<'a> Exec<'a> {
             }
             // read(fd, buf, len)
             SYSCALL_READ => {
-                if let Some(f) = self.files.get_mut(&self.ctx.reg[Register::A0 as usize]) {
-                    let mut buf: Vec<u8> =
-                        Vec::with_capacity(self.ctx.reg[Register::A2 as usize] as usize);
-                    let read = match f.read(buf.as_mut_slice()) {
-                        Ok(amt) => amt,
-                        Err(_) => {
-                            self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
-                            return;
-                        }
+                let fd = self.ctx.reg[Register::A0 as usize];
+                let len = self.ctx.reg[Register::A2 as usize] as usize;
+                if fd != STDIN_FD && !self.files.contains_key(&fd) {
+                    self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
+                    return;
+                }
+                let buf = self.input(InputKind::Read, |exec| {
+                    let mut buf = vec![0; len];
+                    let read = match fd {
+                        STDIN_FD => exec.read_stdin(&mut buf).ok()?,
+                        _ => exec.files.get_mut(&fd)?.read(&mut buf).ok()?,
                     };
-                    self.ctx.reg[Register::V0 as usize] = read as u32;
-                    for (off, b) in buf.iter().enumerate().take(read) {
+                    buf.truncate(read);
+                    Some(buf)
+                });
+                let Some(buf) = buf else {
+                    self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
+                    return;
+                };
+                self.ctx.reg[Register::V0 as usize] = buf.len() as u32;
+                for (off, b) in buf.iter().enumerate() {
+                    match self
+                        .mem
+                        .write_byte(self.ctx.reg[Register::A1 as usize] + off as u32, *b)
+                    {
+                        Ok(_) => {}
+                        Err(e) => {
+                            self.exn = Some(Exception::Memory(e));
+                            break;
+                        }
+                    }
+                }
+            }
+            // write(fd, buf, len)
+            SYSCALL_WRITE => {
+                let fd = self.ctx.reg[Register::A0 as usize];
+                if fd > STDERR_FD && !self.files.contains_key(&fd) {
+                    self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
+                    return;
+                }
+                let mut buf: Vec<u8> =
+                    Vec::with_capacity(self.ctx.reg[Register::A2 as usize] as usize);
+                for off in 0..self.ctx.reg[6] as usize {
+                    buf.push(
                         match self
                             .mem
-                            .write_byte(self.ctx.reg[Register::A1 as usize] + off as u32, *b)
+                            .read_byte(self.ctx.reg[Register::A1 as usize] + off as u32)
                         {
-                            Ok(_) => {}
+                            Ok(b) => b,
                             Err(e) => {
                                 self.exn = Some(Exception::Memory(e));
-                                break;
+                                return;
                             }
-                        }
-                    }
-                } else {
-                    self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
+                        },
+                    )
+                }
+                let res = match fd {
+                    STDIN_FD => Err(std::io::ErrorKind::Unsupported.into()),
+                    STDOUT_FD | STDERR_FD => self.write_stream(fd, &buf).map(|_| buf.len()),
+                    _ => self.files.get_mut(&fd).unwrap().write(buf.as_slice()),
+                };
+                match res {
+                    Ok(amt) => self.ctx.reg[Register::V0 as usize] = amt as u32,
+                    Err(e) => self.ctx.reg[Register::V0 as usize] = -1i32 as u32,
                 }
             }
-            // write(fd, buf, len)
-            SYSCALL_WRITE => {
-                if let Some(f) = self.files.get_mut(&self.ctx.reg[Register::A0 as usize]) {
-                    let mut buf: Vec<u8> =
-                        Vec::with_capacity(self.ctx.reg[Register::A2 as usize] as usize);
-                    for off in 0..self.ctx.reg[6] as usize {
-                        buf.push(
-                            match self
-                                .mem
-                                .read_byte(self.ctx.reg[Register::A1 as usize] + off as u32)
-                            {
-                                Ok(b) => b,
-                                Err(e) => {
-                                    self.exn = Some(Exception::Memory(e));
-                                    break;
-                                }
-                            },
-                        )
-                    }
-                    match f.write(buf.as_slice()) {
-                        Ok(amt) => self.ctx.reg[Register::V0 as usize] = amt as u32,
-                        Err(e) => self.ctx.reg[Register::V0 as usize] = -1i32 as u32,
-                    }
-                }
-            }
-            // close(fd)
+            // close(fd), which does nothing for the standard streams
             SYSCALL_CLOSE => {
                 if self
                     .files
@@ -574,14 +955,636 @@ impl<'a> Exec<'a> {
                 {
                     // causes the file to be dropped, which closes the fd
                     self.files.remove(&self.ctx.reg[Register::A0 as usize]);
+                    self.file_info.remove(&self.ctx.reg[Register::A0 as usize]);
                 }
             }
             // exit2(code)
             SYSCALL_EXIT2 => self.exn = Some(Exception::Exit(self.ctx.reg[Register::A0 as usize])),
-            _ => unreachable!(),
+            // snap(name): returns 0 after saving, or 1 when resumed by rsnap
+            SYSCALL_SNAP => {
+                let res = self
+                    .read_string(self.ctx.reg[Register::A0 as usize])
+                    .map(|name| self.save_snap(&name));
+                self.ctx.reg[Register::V0 as usize] = match res {
+                    Ok(Ok(())) => 0,
+                    Ok(Err(_)) => -1i32 as u32,
+                    Err(e) => return self.exn = Some(Exception::Memory(e)),
+                };
+            }
+            // rsnap(name): does not return on success
+            SYSCALL_RSNAP => {
+                let res = self
+                    .read_string(self.ctx.reg[Register::A0 as usize])
+                    .map(|name| self.load_snap(&name));
+                match res {
+                    Ok(Ok(())) => self.ctx.reg[Register::V0 as usize] = 1,
+                    Ok(Err(_)) => self.ctx.reg[Register::V0 as usize] = -1i32 as u32,
+                    Err(e) => self.exn = Some(Exception::Memory(e)),
+                }
+            }
+            // time(): milliseconds since the epoch, low word in $a0 and high in $a1
+            SYSCALL_TIME => {
+                let ms = self.clock();
+                self.ctx.reg[Register::A0 as usize] = ms as u32;
+                self.ctx.reg[Register::A1 as usize] = (ms >> 32) as u32;
+            }
+            // sleep(ms), which does not sleep when re-executing from a snapshot
+            SYSCALL_SLEEP => {
+                if !self.quiet {
+                    let ms = self.ctx.reg[Register::A0 as usize];
+                    std::thread::sleep(std::time::Duration::from_millis(ms as u64));
+                }
+            }
+            // set_seed(id, seed)
+            SYSCALL_SET_SEED => {
+                let id = self.ctx.reg[Register::A0 as usize];
+                let seed = self.ctx.reg[Register::A1 as usize];
+                self.random.insert(id, Random::new(seed as u64));
+            }
+            // random_int(id): any int, in $a0
+            SYSCALL_RANDOM_INT => {
+                let id = self.ctx.reg[Register::A0 as usize];
+                self.ctx.reg[Register::A0 as usize] = self.random(id).next_u32();
+            }
+            // random_range(id, bound): an int from 0 up to bound, in $a0
+            SYSCALL_RANDOM_RANGE => {
+                let id = self.ctx.reg[Register::A0 as usize];
+                let bound = self.ctx.reg[Register::A1 as usize] as i32;
+                if bound <= 0 {
+                    self.exn = Some(Exception::BadSyscall(format!(
+                        "random_range: bound {} is not positive",
+                        bound
+                    )));
+                    return;
+                }
+                self.ctx.reg[Register::A0 as usize] = self.random(id).below(bound as u32);
+            }
+            code => match self.syscalls.get(&code).cloned() {
+                Some(handler) => {
+                    if let Err(e) = handler(self) {
+                        self.exn = Some(Exception::BadSyscall(format!("syscall {}: {}", code, e)));
+                    }
+                }
+                None => self.exn = Some(Exception::BadSyscall(format!("unknown syscall {}", code))),
+            },
         }
     }
 
+    /// Carries out a syscall the MARS way, if it differs from rsim's,
+    /// returning whether it did
+    fn mars_syscall(&mut self, code: u32) -> bool {
+        use crate::common::instruction::opcodes::*;
+
+        let a0 = self.ctx.reg[Register::A0 as usize];
+        match code {
+            SYSCALL_PRINT_INT => self.print(&(a0 as i32).to_string()),
+            SYSCALL_PRINT_HEX => self.print(&format!("0x{:08x}", a0)),
+            SYSCALL_PRINT_BINARY => self.print(&format!("{:032b}", a0)),
+            SYSCALL_PRINT_UNSIGNED => self.print(&a0.to_string()),
+            // read_int: a signed integer, terminating the program if there is none
+            SYSCALL_READ_INT => {
+                let line = self.read_line();
+                match line.trim().parse::<i32>() {
+                    Ok(i) => self.ctx.reg[Register::V0 as usize] = i as u32,
+                    Err(_) => {
+                        self.exn = Some(Exception::BadSyscall(format!(
+                            "read_int: invalid integer input `{}`",
+                            line.trim()
+                        )))
+                    }
+                }
+            }
+            // read_string(buf, len): at most len - 1 characters, including the
+            // newline if there is room, then a NUL
+            SYSCALL_READ_STRING => {
+                let len = self.ctx.reg[Register::A1 as usize] as i32;
+                if len < 1 {
+                    return true;
+                }
+                let line = self.read_line();
+                let bytes = line.as_bytes();
+                let bytes = &bytes[..bytes.len().min(len as usize - 1)];
+                for (addr, b) in (a0..).zip(bytes.iter().chain([&0])) {
+                    if let Err(e) = self.mem.write_byte(addr, *b) {
+                        self.exn = Some(Exception::Memory(e));
+                        break;
+                    }
+                }
+            }
+            SYSCALL_READ_CHAR => self.ctx.reg[Register::V0 as usize] = self.read_char() as u32,
+            // open(name, flags): flags are 0 to read, 1 to write and 9 to append
+            SYSCALL_OPEN => {
+                let mut opts = std::fs::OpenOptions::new();
+                let flags = self.ctx.reg[Register::A1 as usize];
+                match flags {
+                    0 => opts.read(true),
+                    1 => opts.write(true).create(true).truncate(true),
+                    9 => opts.append(true).create(true),
+                    _ => {
+                        self.ctx.reg[Register::V0 as usize] = -1i32 as u32;
+                        return true;
+                    }
+                };
+                let name = match self.read_string(a0) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        self.exn = Some(Exception::Memory(e));
+                        return true;
+                    }
+                };
+                let info = FileInfo {
+                    path: name.clone(),
+                    read: flags == 0,
+                    write: flags != 0,
+                    append: flags == 9,
+                };
+                self.ctx.reg[Register::V0 as usize] = match opts.open(name) {
+                    Ok(f) => {
+                        self.files.insert(self.next_fd, f);
+                        self.file_info.insert(self.next_fd, info);
+                        self.next_fd += 1;
+                        self.next_fd - 1
+                    }
+                    Err(_) => -1i32 as u32,
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Milliseconds since the epoch, from the host clock
+    fn clock(&mut self) -> u64 {
+        let bytes = self.input(InputKind::Clock, |_| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()?;
+            Some((now.as_millis() as u64).to_le_bytes().to_vec())
+        });
+        bytes
+            .and_then(|b| b.try_into().ok())
+            .map_or(0, u64::from_le_bytes)
+    }
+
+    /// The random number generator with the given id, which is seeded from
+    /// `--seed` if it is given, otherwise from the clock, if the program
+    /// has not seeded it
+    fn random(&mut self, id: u32) -> &mut Random {
+        if !self.random.contains_key(&id) {
+            let seed = match self.args.seed {
+                Some(seed) => seed ^ ((id as u64) << 32),
+                None => self.clock() ^ id as u64,
+            };
+            self.random.insert(id, Random::new(seed));
+        }
+        self.random.get_mut(&id).unwrap()
+    }
+
+    /// Has `handler` carry out syscall `code`, in place of any handler given
+    /// for it before. The builtin syscalls cannot be replaced.
+    pub fn register_syscall(
+        &mut self,
+        code: u32,
+        handler: impl Fn(&mut Exec<'a>) -> Result<(), String> + 'a,
+    ) -> Result<(), String> {
+        use crate::common::instruction::opcodes::{BUILTIN_SYSCALLS, MARS_SYSCALLS};
+
+        if BUILTIN_SYSCALLS.contains(&code)
+            || (self.args.syscalls == SyscallConvention::Mars && MARS_SYSCALLS.contains(&code))
+        {
+            return Err(format!("syscall {} is builtin", code));
+        }
+        self.syscalls.insert(code, Rc::new(handler));
+        Ok(())
+    }
+
+    /// Moves the program break, mapping pages as it passes over them. Pages
+    /// are left mapped when the break moves back down.
+    fn sbrk(&mut self, amt: i32) -> Option<u32> {
+        let old = self.brk;
+        let new = old.checked_add_signed(amt)?;
+        if new < self.heap_start {
+            return None;
+        }
+        while self.heap_next_page < new {
+            let p = self.mem.alloc_page(self.heap_next_page, true, false)?;
+            p.0.fill(self.args.bss_val);
+            self.heap_next_page += self.mem.page_size();
+            self.heap_size += self.mem.page_size();
+        }
+        self.brk = new;
+        Some(old)
+    }
+
+    /// Saves the registers and writable memory to a file, for `rsnap`. The
+    /// saved pc is that of the `snap` syscall, so restoring resumes after it.
+    fn save_snap(&self, path: &str) -> std::io::Result<()> {
+        let mut buf = SNAP_MAGIC.to_vec();
+        let words = [self.ctx.pc, self.ctx.hi, self.ctx.lo]
+            .into_iter()
+            .chain(self.ctx.reg)
+            .chain([
+                self.heap_start,
+                self.heap_size,
+                self.heap_next_page,
+                self.brk,
+            ]);
+        for w in words {
+            buf.extend_from_slice(&w.to_be_bytes());
+        }
+        let pages = self.mem.writable_pages();
+        buf.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        for (addr, page) in pages {
+            buf.extend_from_slice(&addr.to_be_bytes());
+            buf.extend_from_slice(&page.0);
+        }
+        std::fs::write(path, buf)
+    }
+
+    /// Restores a file written by `save_snap`
+    fn load_snap(&mut self, path: &str) -> std::io::Result<()> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid snapshot");
+        let data = std::fs::read(path)?;
+        let body = data.strip_prefix(SNAP_MAGIC).ok_or_else(invalid)?;
+        let mut words = body
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()));
+        let mut word = || words.next().ok_or_else(invalid);
+        let (pc, hi, lo) = (word()?, word()?, word()?);
+        let mut reg = [0; 32];
+        for r in reg.iter_mut() {
+            *r = word()?;
+        }
+        let (heap_start, heap_size, heap_next_page, brk) = (word()?, word()?, word()?, word()?);
+        let count = word()? as usize;
+
+        let header = SNAP_MAGIC.len() + 4 * (3 + 32 + 4 + 1);
+        let entry = 4 + self.mem.page_size() as usize;
+        if data.len() != header + count * entry {
+            return Err(invalid());
+        }
+        for chunk in data[header..].chunks_exact(entry) {
+            let addr = u32::from_be_bytes(chunk[..4].try_into().unwrap());
+            self.mem.restore_page(addr, Page(chunk[4..].into()));
+        }
+        self.ctx.pc = pc;
+        self.ctx.hi = hi;
+        self.ctx.lo = lo;
+        self.ctx.reg = reg;
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        self.heap_next_page = heap_next_page;
+        self.brk = brk;
+        Ok(())
+    }
+
+    /// A hash of the text segment, which a checkpoint is checked against to
+    /// be sure it is resumed by the same program
+    fn text_hash(&self) -> u64 {
+        (self.layout.text_start..self.layout.data_start)
+            .map_while(|addr| self.mem.read_byte(addr).ok())
+            .fold(0xcbf29ce484222325, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    /// Saves everything about the program to a file, to be resumed later by
+    /// `load_checkpoint`: the registers, writable memory, heap, random
+    /// number generators, and the files it has open along with their
+    /// offsets. A program stopped by `-i` may be saved and resumed; one which
+    /// has terminated otherwise may not.
+    pub fn save_checkpoint(&self, path: &str) -> Result<(), String> {
+        if self
+            .exn
+            .as_ref()
+            .is_some_and(|e| !matches!(e, Exception::InstLimit))
+        {
+            return Err(String::from("the program has terminated"));
+        }
+        let mut w = checkpoint::Writer::new();
+        w.long(self.text_hash());
+        let cp0 = &self.ctx.cp0;
+        let words = [self.ctx.pc, self.ctx.hi, self.ctx.lo]
+            .into_iter()
+            .chain(self.ctx.reg)
+            .chain([cp0.status, cp0.cause, cp0.epc])
+            .chain([
+                self.heap_start,
+                self.heap_size,
+                self.heap_next_page,
+                self.brk,
+                self.next_fd,
+            ]);
+        for word in words {
+            w.word(word);
+        }
+        w.long(self.steps);
+
+        w.word(self.random.len() as u32);
+        for (id, random) in &self.random {
+            w.word(*id);
+            w.long(random.state());
+        }
+        w.long(self.clobber.as_ref().map_or(0, |c| c.state()));
+
+        w.word(self.files.len() as u32);
+        for (fd, mut file) in &self.files {
+            let info = &self.file_info[fd];
+            let offset = file
+                .stream_position()
+                .map_err(|e| format!("cannot save `{}`: {}", info.path, e))?;
+            w.word(*fd);
+            w.word(info.read as u32 | (info.write as u32) << 1 | (info.append as u32) << 2);
+            w.long(offset);
+            w.bytes(info.path.as_bytes());
+        }
+
+        let pages = self.mem.writable_pages();
+        w.word(pages.len() as u32);
+        for (addr, page) in pages {
+            w.word(addr);
+            w.bytes(&page.0);
+        }
+        std::fs::write(path, w.finish()).map_err(|e| format!("cannot write `{}`: {}", path, e))
+    }
+
+    /// Resumes the program from a file written by `save_checkpoint`, which
+    /// must have been saved by the same program
+    pub fn load_checkpoint(&mut self, path: &str) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+        let err = |e: String| format!("`{}`: {}", path, e);
+        let mut r = checkpoint::Reader::new(&data).map_err(err)?;
+        if r.long().map_err(err)? != self.text_hash() {
+            return Err(err(String::from("the checkpoint is of another program")));
+        }
+        let mut words = [0; 3 + 32 + 3 + 5];
+        for word in words.iter_mut() {
+            *word = r.word().map_err(err)?;
+        }
+        let steps = r.long().map_err(err)?;
+
+        let mut random = HashMap::new();
+        for _ in 0..r.word().map_err(err)? {
+            let id = r.word().map_err(err)?;
+            random.insert(id, Random::from_state(r.long().map_err(err)?));
+        }
+        let clobber = r.long().map_err(err)?;
+
+        let mut files = HashMap::new();
+        let mut file_info = HashMap::new();
+        for _ in 0..r.word().map_err(err)? {
+            let fd = r.word().map_err(err)?;
+            let flags = r.word().map_err(err)?;
+            let offset = r.long().map_err(err)?;
+            let info = FileInfo {
+                path: String::from_utf8_lossy(r.bytes().map_err(err)?).into_owned(),
+                read: flags & 1 != 0,
+                write: flags & 2 != 0,
+                append: flags & 4 != 0,
+            };
+            files.insert(fd, info.reopen(offset)?);
+            file_info.insert(fd, info);
+        }
+
+        let mut pages = vec![];
+        for _ in 0..r.word().map_err(err)? {
+            let addr = r.word().map_err(err)?;
+            let page = r.bytes().map_err(err)?;
+            if page.len() != self.mem.page_size() as usize {
+                return Err(err(String::from(
+                    "the checkpoint has a page of the wrong size (was it saved with another --page-size?)",
+                )));
+            }
+            pages.push((addr, Page(page.into())));
+        }
+        r.finish().map_err(err)?;
+
+        for (addr, page) in pages {
+            self.mem.restore_page(addr, page);
+        }
+        let [pc, hi, lo, rest @ ..] = words;
+        let (reg, rest) = rest.split_at(32);
+        let [status, cause, epc, heap_start, heap_size, heap_next_page, brk, next_fd] =
+            rest.try_into().unwrap();
+        self.ctx.pc = pc;
+        self.ctx.hi = hi;
+        self.ctx.lo = lo;
+        self.ctx.reg = reg.try_into().unwrap();
+        self.ctx.cp0 = Cp0 { status, cause, epc };
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+        self.heap_next_page = heap_next_page;
+        self.brk = brk;
+        self.next_fd = next_fd;
+        self.random = random;
+        if self.clobber.is_some() || clobber != 0 {
+            self.clobber = Some(Random::from_state(clobber));
+        }
+        self.files = files;
+        self.file_info = file_info;
+        self.exn = None;
+        // -i counts the instructions executed after resuming
+        self.inst_limit = self.inst_limit.map(|_| steps + self.args.max_inst as u64);
+        self.steps = steps;
+        Ok(())
+    }
+
+    /// The registers and every mapped page, for the core file written when
+    /// the program terminates abnormally, because of `reason`
+    pub fn core_info(&self, reason: &str) -> CoreInfo {
+        let pages = self
+            .mem
+            .mapped_pages()
+            .into_iter()
+            .map(|(addr, page)| CorePage {
+                addr,
+                flags: match self.mem.is_writable(addr) {
+                    true => CORE_WRITE,
+                    false => 0,
+                } | match self.mem.is_executable(addr) {
+                    true => CORE_EXEC,
+                    false => 0,
+                },
+                bytes: page.0.to_vec(),
+            })
+            .collect();
+        CoreInfo {
+            pc: self.ctx.pc,
+            hi: self.ctx.hi,
+            lo: self.ctx.lo,
+            reg: self.ctx.reg,
+            reason: reason.to_string(),
+            page_size: self.mem.page_size(),
+            pages,
+        }
+    }
+
+    /// Puts the program in the state saved in a core file, to be inspected
+    /// but not run any further
+    pub fn load_core(&mut self, core: &CoreInfo) -> Result<(), String> {
+        if core.page_size != self.mem.page_size() {
+            return Err(format!(
+                "the core has pages of {} bytes; run with --page-size {}",
+                core.page_size, core.page_size
+            ));
+        }
+        for page in &core.pages {
+            self.mem
+                .restore_page(page.addr, Page(page.bytes.as_slice().into()));
+        }
+        self.ctx.pc = core.pc;
+        self.ctx.hi = core.hi;
+        self.ctx.lo = core.lo;
+        self.ctx.reg = core.reg;
+        self.exn = Some(Exception::Core(core.reason.clone()));
+        Ok(())
+    }
+
+    /// The last instructions executed, for when the program terminates
+    /// abnormally
+    pub fn recent(&self) -> String {
+        self.recent.report(&self.mem, &self.symbols, &self.ctx.reg)
+    }
+
+    /// Whether the program was stopped before it finished, by `-i`, so that
+    /// it may be resumed from a checkpoint
+    pub fn interrupted(&self) -> bool {
+        matches!(self.exn, Some(Exception::InstLimit))
+    }
+
+    /// Writes program output to stdout
+    fn print(&mut self, s: &str) {
+        self.write_stream(STDOUT_FD, s.as_bytes()).unwrap();
+    }
+
+    /// Writes program output to stdout or stderr, or keeps it if output is
+    /// being captured
+    fn write_stream(&mut self, fd: u32, buf: &[u8]) -> std::io::Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        let captured = match fd {
+            STDOUT_FD => &mut self.stdout,
+            _ => &mut self.stderr,
+        };
+        if let Some(captured) = captured {
+            captured.extend_from_slice(buf);
+            return Ok(());
+        }
+        match fd {
+            STDOUT_FD => {
+                let mut out = std::io::stdout();
+                out.write_all(buf).and_then(|_| out.flush())
+            }
+            _ => std::io::stderr().write_all(buf),
+        }
+    }
+
+    /// Reads program input from the input given by `set_stdin`, if any,
+    /// otherwise from the host's stdin
+    fn read_stdin(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.stdin {
+            Some(input) => {
+                let n = buf.len().min(input.len());
+                for (b, i) in buf.iter_mut().zip(input.drain(..n)) {
+                    *b = i;
+                }
+                Ok(n)
+            }
+            None => std::io::stdin().read(buf),
+        }
+    }
+
+    /// Gives the program `input` to read instead of the host's stdin
+    pub fn set_stdin(&mut self, input: &[u8]) {
+        self.stdin = Some(input.iter().copied().collect());
+    }
+
+    /// Keeps the program's output, to be taken with `take_stdout` and
+    /// `take_stderr`, instead of writing it to the host's stdout and stderr
+    pub fn capture_output(&mut self) {
+        self.stdout.get_or_insert_with(Vec::new);
+        self.stderr.get_or_insert_with(Vec::new);
+    }
+
+    /// The output the program has written to stdout since this was last
+    /// called, if it is being captured
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.stdout.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// The output the program has written to stderr since this was last
+    /// called, if it is being captured
+    pub fn take_stderr(&mut self) -> Vec<u8> {
+        self.stderr.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Receives an input of the given kind, from the replayed inputs if
+    /// there are any left, otherwise using `read`. Either way it is logged.
+    fn input(
+        &mut self,
+        kind: InputKind,
+        read: impl FnOnce(&mut Self) -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let bytes = match self.replay.pop_front() {
+            Some(input) if input.kind == kind => input.bytes,
+            Some(input) => {
+                eprintln!(
+                    "replay diverged at step {}: expected {:?} input, program asked for {:?}",
+                    self.steps, input.kind, kind
+                );
+                self.replay.clear();
+                read(self)
+            }
+            None => read(self),
+        };
+        self.inputs.push(Input {
+            step: self.steps,
+            kind,
+            bytes: bytes.clone(),
+        });
+        bytes
+    }
+
+    /// Reads a line of program input from stdin
+    fn read_line(&mut self) -> String {
+        let line = self.input(InputKind::Line, |exec| {
+            let Some(input) = &mut exec.stdin else {
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line).ok()?;
+                return Some(line.into_bytes());
+            };
+            let len = input
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(input.len(), |nl| nl + 1);
+            Some(input.drain(..len).collect())
+        });
+        String::from_utf8_lossy(&line.unwrap_or_default()).into_owned()
+    }
+
+    /// Reads a single byte of program input from stdin
+    fn read_char(&mut self) -> u8 {
+        let byte = self.input(InputKind::Byte, |exec| {
+            let mut byte = [0u8];
+            match exec.read_stdin(&mut byte).ok()? {
+                0 => None,
+                _ => Some(byte.to_vec()),
+            }
+        });
+        byte.and_then(|b| b.first().copied()).unwrap_or(0)
+    }
+
+    /// Inputs received so far, for `--record`
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    /// Queues up recorded inputs to be received instead of reading the host's
+    pub fn replay(&mut self, inputs: Vec<Input>) {
+        self.replay.extend(inputs);
+    }
+
     fn read_string(&self, mut addr: u32) -> Result<String, Error> {
         let mut bytes: Vec<u8> = vec![];
         loop {
@@ -602,108 +1605,894 @@ impl<'a> Exec<'a> {
                 pc: 0,
                 hi: 0,
                 lo: 0,
+                cp0: Cp0::default(),
             },
-            mem: Memory::new(),
+            mem: Memory::new(PAGE_BITS),
             exn: None,
+            handler: None,
             files: HashMap::new(),
+            file_info: HashMap::new(),
             next_fd: 3,
+            text_end: None,
+            syscalls: HashMap::new(),
+            random: HashMap::new(),
+            clobber: None,
+            stats: None,
+            profile: None,
+            calls: None,
+            coverage: None,
+            livelock: None,
+            heatmap: None,
+            timing: None,
+            layout: Layout::default(),
+            caches: None,
+            predictor: None,
+            symbols: Symbols::default(),
+            tracer: None,
+            recent: Recent::new(),
+            steps: 0,
+            inst_limit: None,
+            deadline: None,
+            until: None,
+            inputs: vec![],
+            replay: VecDeque::new(),
+            quiet: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            icache: HashMap::new(),
+            last_page: None,
             heap_next_page: 0,
             heap_size: 0,
             heap_start: 0,
+            brk: 0,
             args: &EMPTY_ARGS,
         }
     }
 
-    pub fn new(module: ObjectModule, args: &'a SimArgs) -> Option<Self> {
+    pub fn new(module: ObjectModule, args: &'a SimArgs) -> Result<Self, String> {
         let mut ctx = ExecCtx {
             reg: [0; 32],
             pc: 0,
             hi: 0,
             lo: 0,
+            cp0: Cp0::default(),
         };
-        if module.head.flags & 0x3 == 0 {
-            return None; // module has no entry point
+        if !args.force_exec {
+            check_executable(&module)?;
         }
-        ctx.pc = module.head.entry;
-        // __r2k__startup__obj__ reads a bit above the stack pointer, so move it down a bit
-        ctx.reg[Register::SP as usize] = STACK_START - 0x1000;
-        ctx.reg[Register::FP as usize] = STACK_START;
-        ctx.reg[Register::GP as usize] = DATA_START;
+        let layout = args.layout.layout()?;
+        // a module forced to run without an entry point starts at its text
+        ctx.pc = match module.head.entry {
+            0 => layout.text_start,
+            entry => entry,
+        };
+        ctx.reg[Register::FP as usize] = layout.stack_start;
+        ctx.reg[Register::GP as usize] = layout.data_start;
 
-        if !args.no_kern_clobber {
+        let mut clobber = args.clobber_seed.map(Random::new);
+        if let Some(clobber) = &mut clobber {
+            ctx.reg[Register::K0 as usize] = clobber.next_u32();
+            ctx.reg[Register::K1 as usize] = clobber.next_u32();
+        } else if !args.no_kern_clobber {
             ctx.reg[Register::K0 as usize] = 0x00000000;
             ctx.reg[Register::K1 as usize] = 0xFFFFFFFF;
         }
 
-        let mem = Memory::new_from_object(module, args);
+        let handler = module
+            .symtab
+            .iter()
+            .find(|s| {
+                s.has_flags(SYM_GLB | SYM_DEF)
+                    && module
+                        .get_str_entry(s.str_off as usize)
+                        .is_some_and(|n| n.as_bytes() == EXCEPTION_HANDLER.as_bytes())
+            })
+            .map(|s| s.val);
 
-        println!(
-            "Creating new Execution ctx with entrypoint @ 0x{:08x}",
-            ctx.pc
-        );
+        let symbols = Symbols::from_module(&module);
+        let until = args
+            .until
+            .as_deref()
+            .map(|s| symbols.resolve(s))
+            .transpose()?;
+        let tracing = args.trace
+            || args.trace_file.is_some()
+            || args.trace_from.is_some()
+            || args.trace_to.is_some()
+            || args.trace_format != TraceFormat::Text;
+        let tracer = match tracing {
+            true => Some(Tracer::new(args, &symbols)?),
+            false => None,
+        };
+        let coverage = (args.coverage || args.coverage_file.is_some())
+            .then(|| Coverage::new(&module, ctx.pc, &layout, args.endian));
+        let timing = match &args.timing_config {
+            Some(path) => Some(Timing::new(TimingConfig::load(path)?)),
+            None => args.timing.then(|| Timing::new(TimingConfig::default())),
+        };
+        let profile = args.profile || args.profile_folded.is_some();
+        let call_graph = args.call_graph || args.call_graph_dot.is_some();
+        let text_end = (args.syscalls == SyscallConvention::Mars)
+            .then(|| layout.text_start + module.text.len() as u32);
+        let (mut mem, heap_start, heap_end) = Memory::new_from_object(module, args, &layout)?;
 
-        Some(Self {
+        // the program is its own first argument
+        let argv = [&args.file]
+            .into_iter()
+            .chain(&args.program_args)
+            .cloned()
+            .collect::<Vec<_>>();
+        let envp = match args.no_env {
+            true => vec![],
+            false => std::env::vars_os()
+                .map(|(key, val)| format!("{}={}", key.to_string_lossy(), val.to_string_lossy()))
+                .collect(),
+        };
+        let sp = mem.place_args(&layout, &argv, &envp)?;
+        ctx.reg[Register::SP as usize] = sp;
+        // __r2k__startup__obj__ finds argc and argv above the stack pointer,
+        // and main is passed them in $a0 to $a2 as well
+        let argc_at = match layout.stack_direction {
+            StackDirection::Down => sp,
+            StackDirection::Up => layout.stack_start,
+        };
+        ctx.reg[Register::A0 as usize] = argv.len() as u32;
+        ctx.reg[Register::A1 as usize] = argc_at + 4;
+        ctx.reg[Register::A2 as usize] = argc_at + 4 * (argv.len() as u32 + 2);
+
+        let mut exec = Self {
             ctx,
             mem,
-            heap_start: 0,
-            heap_size: 0,
-            heap_next_page: 0,
+            heap_start,
+            heap_size: heap_end - heap_start,
+            heap_next_page: heap_end,
+            brk: heap_start,
             exn: None,
+            handler,
             files: HashMap::new(),
+            file_info: HashMap::new(),
             next_fd: 3,
+            text_end,
+            syscalls: HashMap::new(),
+            random: HashMap::new(),
+            clobber,
+            stats: args.inst_stats.then(Stats::default),
+            profile: profile.then(|| Profile::new(&layout)),
+            calls: (profile || call_graph).then(|| CallGraph::new(ctx.pc)),
+            coverage,
+            livelock: args
+                .livelock
+                .map(|millions| Livelock::new(millions as u64 * 1_000_000)),
+            heatmap: (args.heatmap || args.heatmap_bucket.is_some())
+                .then(|| Heatmap::new(args.heatmap_bucket.unwrap_or(64))),
+            timing,
+            layout,
+            caches: Caches::new(args.icache, args.dcache, args.l2cache),
+            predictor: args
+                .predictor
+                .map(|kind| Predictor::new(kind, args.btb_size)),
+            symbols,
+            tracer,
+            recent: Recent::new(),
+            steps: 0,
+            inst_limit: (args.max_inst != 0).then_some(args.max_inst as u64),
+            deadline: None,
+            until,
+            inputs: vec![],
+            replay: VecDeque::new(),
+            quiet: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            icache: HashMap::new(),
+            last_page: None,
             args,
-        })
+        };
+        for plugin in &args.syscall_plugin {
+            let plugin = plugin.clone();
+            exec.register_syscall(plugin.number(), move |exec| plugin.call(exec))?;
+        }
+        Ok(exec)
     }
 
-    pub fn run(mut self) -> Result<(), Error> {
-        loop {
-            self.step()?;
+    pub fn pc(&self) -> u32 {
+        self.ctx.pc
+    }
+
+    /// Moves execution to `pc`
+    pub fn set_pc(&mut self, pc: u32) {
+        self.ctx.pc = pc;
+    }
+
+    pub fn reg(&self, reg: Register) -> u32 {
+        self.ctx.reg[reg as usize]
+    }
+
+    /// Sets a register, other than `$zero`, which stays 0
+    pub fn set_reg(&mut self, reg: Register, value: u32) {
+        if reg != Register::ZERO {
+            self.ctx.reg[reg as usize] = value;
         }
-        Ok(())
+    }
+
+    /// The general purpose registers, by number
+    pub fn registers(&self) -> &[u32; 32] {
+        &self.ctx.reg
+    }
+
+    pub fn hi(&self) -> u32 {
+        self.ctx.hi
+    }
+
+    pub fn lo(&self) -> u32 {
+        self.ctx.lo
+    }
+
+    pub fn read_word(&self, addr: u32) -> Result<u32, Error> {
+        self.mem.read_word(addr)
+    }
+
+    pub fn read_half(&self, addr: u32) -> Result<u16, Error> {
+        self.mem.read_half(addr)
+    }
+
+    pub fn read_byte(&self, addr: u32) -> Result<u8, Error> {
+        self.mem.read_byte(addr)
+    }
+
+    /// Writes to memory as a store would, so only writable pages can be
+    /// written
+    pub fn write_word(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        self.mem.write_word(addr, value)
+    }
+
+    pub fn write_byte(&mut self, addr: u32, value: u8) -> Result<(), Error> {
+        self.mem.write_byte(addr, value)
+    }
+
+    /// Passes over the instruction at pc without executing it, as if it did
+    /// nothing
+    pub(super) fn skip(&mut self) {
+        self.ctx.pc = self.ctx.pc.wrapping_add(4);
+        self.steps += 1;
+    }
+
+    /// Lets the program carry on from the `--until` address it stopped at,
+    /// returning false if it stopped for any other reason
+    pub(super) fn resume_until(&mut self) -> bool {
+        if !self.reached_until() {
+            return false;
+        }
+        self.exn = None;
+        true
+    }
+
+    /// Passes over the `break 0` the program stopped at, returning false if
+    /// it stopped for any other reason
+    pub(super) fn resume_trap(&mut self) -> bool {
+        if !matches!(self.exn, Some(Exception::Break(0))) {
+            return false;
+        }
+        self.exn = None;
+        self.skip();
+        true
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Whether the program has stopped, either by exiting or on an exception
+    pub fn terminated(&self) -> bool {
+        self.exn.is_some()
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Rewinds to `snapshot`, a copy of this program taken earlier. Input read
+    /// since then is queued up to be read again, so that execution from the
+    /// snapshot repeats itself. Open files are not restored.
+    pub fn restore(&mut self, snapshot: &Exec<'a>) {
+        let mut replay = self
+            .inputs
+            .iter()
+            .filter(|i| i.step >= snapshot.steps)
+            .cloned()
+            .collect::<VecDeque<_>>();
+        replay.extend(self.replay.drain(..));
+        let files = std::mem::take(&mut self.files);
+        let file_info = std::mem::take(&mut self.file_info);
+        let next_fd = self.next_fd;
+        *self = snapshot.clone();
+        self.replay = replay;
+        self.files = files;
+        self.file_info = file_info;
+        self.next_fd = next_fd;
+    }
+
+    /// The registers, as shown by the debugger and post-mortem dump
+    pub fn dump_registers(&self) -> String {
+        DumpFormat::new(self.args).registers(&self.ctx.reg, self.ctx.pc, self.ctx.hi, self.ctx.lo)
+    }
+
+    /// The memory in `start..end`, as shown by the debugger and post-mortem
+    /// dump
+    pub fn dump_memory(&self, start: u32, end: u32) -> String {
+        DumpFormat::new(self.args).memory(&self.mem, start, end)
+    }
+
+    /// Disassembles the instruction at `addr`
+    pub fn disassemble(&self, addr: u32) -> String {
+        match self.mem.read_word(addr).map(Instruction::try_from) {
+            Ok(Ok(inst)) => {
+                let name = self.symbols.describe(addr, true);
+                let name = name.map_or(String::new(), |n| format!(" <{}>", n));
+                format!("0x{:08x}{}: {}", addr, name, inst)
+            }
+            _ => format!("0x{:08x}: <invalid>", addr),
+        }
+    }
+
+    /// The calls leading to the current instruction, innermost first. They
+    /// come from the shadow call stack when one is kept, and are otherwise
+    /// guessed by looking for return addresses in `$ra` and on the stack.
+    pub fn backtrace(&self) -> String {
+        let pc = self.ctx.pc;
+        let (sites, exact) = match &self.calls {
+            Some(calls) => (calls.backtrace(), true),
+            None => (self.scan_backtrace(), false),
+        };
+        let mut out = String::from("\nBacktrace:\n");
+        for (depth, addr) in std::iter::once(pc).chain(sites).enumerate() {
+            out.push_str(&format!("  #{:<3} {}\n", depth, self.disassemble(addr)));
+        }
+        if !exact {
+            out.push_str("  (found by scanning the stack, so callers may be missing or stale;\n");
+            out.push_str("  --call-graph keeps an exact call stack)\n");
+        }
+        out
+    }
+
+    /// Call sites which might have led to the current instruction: `$ra`
+    /// and words on the stack which hold the address of a `jal` or `jalr`.
+    /// A `jal` is only taken as a caller if it calls a function starting
+    /// shortly before the frame found inside it.
+    fn scan_backtrace(&self) -> Vec<u32> {
+        use crate::common::instruction::opcodes::*;
+        /// Furthest a frame is taken to be from the start of its function
+        const MAX_FUNCTION: u32 = 0x10000;
+        /// Most stack words looked at
+        const MAX_SCAN: u32 = 0x10000;
+
+        let sp = self.ctx.reg[Register::SP as usize];
+        let stack = (0..MAX_SCAN).map_while(|i| match self.layout.stack_direction {
+            StackDirection::Down => sp
+                .checked_add(4 * i)
+                .filter(|a| *a <= self.layout.stack_start),
+            StackDirection::Up => sp
+                .checked_sub(4 * i)
+                .filter(|a| *a >= self.layout.stack_start),
+        });
+        let candidates = std::iter::once(self.ctx.reg[Register::RA as usize])
+            .chain(stack.filter_map(|addr| self.mem.read_word(addr).ok()));
+
+        let mut sites = vec![];
+        let mut inner = self.ctx.pc;
+        for site in candidates {
+            if site % 4 != 0 || sites.last() == Some(&site) {
+                continue;
+            }
+            let called = match self.mem.read_word(site).map(Instruction::try_from) {
+                Ok(Ok(Instruction::J { op: OP_JAL, imm })) => {
+                    let func = (site & 0xF0000000) | (imm << 2);
+                    func <= inner && inner - func < MAX_FUNCTION
+                }
+                Ok(Ok(Instruction::R {
+                    funct: FUNCT_JALR, ..
+                })) => true,
+                _ => false,
+            };
+            if called {
+                sites.push(site);
+                inner = site;
+            }
+        }
+        sites
+    }
+
+    /// Parses an address given as a number or a symbol name
+    pub fn resolve(&self, s: &str) -> Result<u32, String> {
+        self.symbols.resolve(s)
+    }
+
+    /// Whether the program terminated itself through an exit syscall
+    /// A few words on why the program stopped, for reports
+    pub(super) fn stop_reason(&self) -> String {
+        let reason = match &self.exn {
+            None => "running",
+            Some(Exception::Exit(code)) => return format!("exit {}", code),
+            Some(Exception::Break(code)) => return format!("break {}", code),
+            Some(Exception::Trap(code)) => return format!("trap {}", code),
+            Some(Exception::Livelock(..)) => "livelock",
+            Some(Exception::InstLimit) => "instruction limit",
+            Some(Exception::Timeout) => "timeout",
+            Some(Exception::Until) => "until",
+            Some(Exception::Core(_)) => "core",
+            Some(Exception::Memory(_)) => "memory fault",
+            Some(Exception::Overflow) => "overflow",
+            Some(Exception::DivideByZero) => "divide by zero",
+            Some(Exception::BadSyscall(_)) => "bad syscall",
+            Some(Exception::Syscall(_) | Exception::Timer) => "exception",
+        };
+        reason.to_string()
+    }
+
+    /// Whether the program stopped at the address given by `--until`
+    pub fn reached_until(&self) -> bool {
+        matches!(self.exn, Some(Exception::Until))
+    }
+
+    /// Whether `--timeout` stopped the program
+    pub fn timed_out(&self) -> bool {
+        matches!(self.exn, Some(Exception::Timeout))
+    }
+
+    pub fn exited(&self) -> bool {
+        matches!(self.exn, Some(Exception::Exit(_)))
+    }
+
+    /// The code the program passed to `exit2`, or 0 for `exit`, if it has
+    /// exited
+    pub fn exit_code(&self) -> Option<u32> {
+        match self.exn {
+            Some(Exception::Exit(code)) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Registers along with the data segment, heap and stack, as printed
+    /// after the program terminates
+    pub fn dump(&self) -> String {
+        let fmt = DumpFormat::new(self.args);
+        let sp = self.ctx.reg[Register::SP as usize];
+        format!(
+            "\nRegisters:\n{}\nData:\n{}\nStack:\n{}",
+            fmt.registers(&self.ctx.reg, self.ctx.pc, self.ctx.hi, self.ctx.lo),
+            fmt.memory(&self.mem, self.layout.data_start, self.heap_next_page),
+            match self.layout.stack_direction {
+                StackDirection::Down =>
+                    fmt.memory(&self.mem, sp & !0xF, self.layout.stack_start + 4),
+                StackDirection::Up => fmt.memory(&self.mem, self.layout.stack_start & !0xF, sp + 4),
+            },
+        )
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.deadline = self
+            .args
+            .timeout
+            .map(|secs| Instant::now() + Duration::from_secs(secs as u64));
+        // statistics and tracing are per instruction, which only the
+        // interpreter keeps track of
+        #[cfg(feature = "threaded")]
+        if self.args.threaded
+            && self.stats.is_none()
+            && self.profile.is_none()
+            && self.calls.is_none()
+            && self.coverage.is_none()
+            && self.timing.is_none()
+            && self.caches.is_none()
+            && self.predictor.is_none()
+            && self.tracer.is_none()
+            && self.text_end.is_none()
+            && self.livelock.is_none()
+            && self.heatmap.is_none()
+            && self.inst_limit.is_none()
+            && self.deadline.is_none()
+            && self.until.is_none()
+        {
+            return super::threaded::run(self);
+        }
+        let res = loop {
+            if let Err(e) = self.step() {
+                break Err(e);
+            }
+        };
+        if let Some(stats) = &self.stats {
+            eprint!("{}", stats);
+        }
+        if let Some(timing) = &self.timing {
+            eprint!("{}", timing);
+        }
+        if let Some(caches) = &self.caches {
+            eprint!("{}", caches);
+        }
+        if let Some(predictor) = &self.predictor {
+            eprint!("{}", predictor.report(|addr| self.disassemble(addr)));
+        }
+        self.report_profile();
+        self.report_call_graph();
+        self.report_coverage();
+        if let Some(heatmap) = &self.heatmap {
+            eprint!(
+                "{}",
+                heatmap.report(&self.symbols, |addr| self.segment(addr))
+            );
+        }
+        res
+    }
+
+    /// Prints the `--profile` report, and writes the folded call stacks if
+    /// asked to
+    fn report_profile(&self) {
+        let (Some(profile), Some(calls)) = (&self.profile, &self.calls) else {
+            return;
+        };
+        eprint!(
+            "{}",
+            profile.report(calls, &self.symbols, |addr| self.disassemble(addr))
+        );
+        if let Some(path) = &self.args.profile_folded {
+            if let Err(e) = std::fs::write(path, calls.folded(&self.symbols)) {
+                eprintln!("cannot write `{}`: {}", path, e);
+            }
+        }
+    }
+
+    /// Prints the `--call-graph` report, and writes the graph in DOT if asked
+    /// to
+    fn report_call_graph(&self) {
+        let Some(calls) = &self.calls else {
+            return;
+        };
+        if self.args.call_graph {
+            eprint!("{}", calls.report(&self.symbols));
+        }
+        if let Some(path) = &self.args.call_graph_dot {
+            if let Err(e) = std::fs::write(path, calls.dot(&self.symbols)) {
+                eprintln!("cannot write `{}`: {}", path, e);
+            }
+        }
+    }
+
+    /// Prints the `--coverage` report, or writes it to the file given
+    fn report_coverage(&self) {
+        let Some(coverage) = &self.coverage else {
+            return;
+        };
+        let report = coverage.report(&self.symbols, |addr| self.disassemble(addr));
+        match &self.args.coverage_file {
+            Some(path) => {
+                if let Err(e) = std::fs::write(path, report.trim_start()) {
+                    eprintln!("cannot write `{}`: {}", path, e);
+                }
+            }
+            None => eprint!("{}", report),
+        }
+    }
+
+    /// The registers, memory and instruction count, for the threaded backend
+    #[cfg(feature = "threaded")]
+    pub(super) fn machine(&mut self) -> (&mut ExecCtx, &mut Memory, &mut u64) {
+        (&mut self.ctx, &mut self.mem, &mut self.steps)
+    }
+
+    fn regs(&self) -> Regs {
+        Regs {
+            gpr: self.ctx.reg,
+            hi: self.ctx.hi,
+            lo: self.ctx.lo,
+        }
+    }
+
+    /// Counts the fetch of `inst` and any load or store it makes in the
+    /// simulated caches
+    #[cold]
+    fn access_caches(&mut self, inst: Instruction) {
+        use crate::common::instruction::opcodes::*;
+        let addr = self.mem_addr(inst);
+        let Some(caches) = &mut self.caches else {
+            return;
+        };
+        caches.fetch(self.ctx.pc);
+        if let (Some(addr), Instruction::I { op, .. }) = (addr, inst) {
+            caches.data(addr, op >= OP_SB);
+        }
+    }
+
+    /// The address accessed by a load or store, before it is executed
+    fn mem_addr(&self, inst: Instruction) -> Option<u32> {
+        use crate::common::instruction::opcodes::*;
+        match inst {
+            Instruction::I {
+                op: OP_LB..=OP_SWR,
+                rs,
+                imm,
+                ..
+            } => Some(self.ctx.reg[rs as usize].wrapping_add(imm as i16 as u32)),
+            _ => None,
+        }
+    }
+
+    /// The part of memory `addr` lies in, for diagnostics
+    fn segment(&self, addr: u32) -> &'static str {
+        match addr {
+            _ if !self.mem.is_mapped(addr) => "unmapped",
+            _ if addr < self.layout.data_start => "TEXT",
+            _ if addr < self.heap_start => "DATA",
+            _ if addr < self.heap_next_page => "HEAP",
+            _ => "STACK",
+        }
+    }
+
+    /// An address along with the symbol it falls within and its segment.
+    /// Only text and data are described by symbol, as nothing else has any.
+    fn locate(&self, addr: u32) -> String {
+        let segment = self.segment(addr);
+        let symbol = match segment {
+            "TEXT" | "DATA" => self.symbols.describe(addr, true),
+            _ => None,
+        };
+        match symbol {
+            Some(name) => format!("0x{:08x} <{}> ({})", addr, name, segment),
+            None => format!("0x{:08x} ({})", addr, segment),
+        }
+    }
+
+    /// Describes a memory exception raised at `pc`, by `inst` accessing
+    /// `addr` if known
+    fn memory_fault(
+        &self,
+        e: &str,
+        pc: u32,
+        inst: Option<Instruction>,
+        addr: Option<u32>,
+    ) -> String {
+        let mut msg = format!("Memory exception: {}\n  pc:      {}", e, self.locate(pc));
+        if let Some(inst) = inst {
+            msg.push_str(&format!(": {}", inst));
+        }
+        if let Some(addr) = addr {
+            msg.push_str(&format!("\n  address: {}", self.locate(addr)));
+        }
+        msg
+    }
+
+    /// Decodes the read-only page with the given number, or returns None if
+    /// it is writable (or unmapped) and so must be decoded as it executes
+    fn decoded_page(&mut self, page: u32) -> Option<DecodedPage> {
+        if let Some(decoded) = self.icache.get(&page) {
+            return Some(decoded.clone());
+        }
+        let base = page << PAGE_BITS;
+        if !self.mem.is_mapped(base) || self.mem.is_writable(base) {
+            return None;
+        }
+        let decoded: DecodedPage = (base..base + PAGE_SIZE)
+            .step_by(4)
+            .map(|addr| self.decode(self.mem.read_word(addr).ok()?).ok())
+            .collect();
+        self.icache.insert(page, decoded.clone());
+        Some(decoded)
+    }
+
+    /// Decodes `word`, which must be an instruction of the `--isa` given
+    fn decode(&self, word: u32) -> Result<Instruction, Error> {
+        let inst = Instruction::try_from(word)?;
+        if inst.is_mips32r2() && self.args.isa != Isa::Mips32r2 {
+            return Err(Error::InstructionParseError(format!(
+                "`{}` is a MIPS32r2 instruction (--isa mips32r2 runs it)",
+                inst
+            )));
+        }
+        Ok(inst)
+    }
+
+    /// The instruction at pc if it has been decoded already, which is
+    /// usually the case
+    #[inline]
+    fn cached(&self, pc: u32) -> Option<Instruction> {
+        match &self.last_page {
+            Some((page, decoded)) if *page == pc >> PAGE_BITS && pc.is_multiple_of(4) => {
+                decoded[(pc & PAGE_MASK) as usize / 4]
+            }
+            _ => None,
+        }
+    }
+
+    /// The instruction at pc, decoding its page if it has not been already
+    #[inline(never)]
+    fn fetch(&mut self) -> Result<Instruction, Error> {
+        let pc = self.ctx.pc;
+        let page = pc >> PAGE_BITS;
+        if self.last_page.as_ref().is_none_or(|(p, _)| *p != page) {
+            self.last_page = self.decoded_page(page).map(|decoded| (page, decoded));
+            if let Some(inst) = self.cached(pc) {
+                return Ok(inst);
+            }
+        }
+        match self.mem.read_word(pc) {
+            Ok(i) => self.decode(i),
+            Err(Error::MemoryAccessError(e)) => Err(Error::UnhandledException(format!(
+                "Unhandled Exception: {}",
+                self.memory_fault(&e, pc, None, None)
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Traces the instruction about to be executed
+    #[cold]
+    fn trace(&mut self, inst: Instruction) {
+        let word = self.mem.read_word(self.ctx.pc).unwrap_or_default();
+        let mem_addr = self.mem_addr(inst);
+        let regs = self.regs();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(self.ctx.pc, word, inst, mem_addr, regs, &self.symbols);
+        }
+    }
+
+    /// Finishes tracing the instruction just executed
+    #[cold]
+    fn retire(&mut self) {
+        let regs = self.regs();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.retire(regs);
+        }
+    }
+
+    /// The error the program terminates with after `inst` at `pc` raised an
+    /// exception which was not handled
+    #[cold]
+    fn unhandled(&self, e: &Exception, pc: u32, inst: Instruction) -> Error {
+        Error::UnhandledException(format!(
+            "Unhandled Exception: {}",
+            match e {
+                Exception::Timer => {
+                    String::from("Unimplemented!?")
+                }
+                Exception::Overflow => {
+                    String::from("Overflow exception")
+                }
+                Exception::Exit(code) => {
+                    format!("Exit with code {}", code)
+                }
+                Exception::Syscall(operand) => {
+                    format!("Syscall with operand {}", operand)
+                }
+                Exception::DivideByZero => {
+                    String::from("Divide by zero")
+                }
+                Exception::Memory(Error::MemoryAccessError(e)) => {
+                    // a faulting instruction doesn't write any registers, so
+                    // the address is as it was before executing it
+                    self.memory_fault(e, pc, Some(inst), self.mem_addr(inst))
+                }
+                Exception::Memory(_) => unreachable!(),
+                Exception::Break(0) => String::from("Breakpoint trap (break 0)"),
+                Exception::Break(code) => {
+                    format!("Break with code {} (0x{:x})", code, code)
+                }
+                Exception::Trap(code) => {
+                    format!("Trap with code {} (0x{:x})", code, code)
+                }
+                Exception::BadSyscall(msg) => msg.clone(),
+                Exception::Livelock(low, high) => format!(
+                    "Livelock: {} instructions without a syscall or touching new memory\n  \
+                     looping: {} to {}",
+                    self.livelock.as_ref().map_or(0, |l| l.limit()),
+                    self.locate(*low),
+                    self.locate(*high)
+                ),
+                Exception::InstLimit => {
+                    format!("Instruction limit of {} reached", self.args.max_inst)
+                }
+                Exception::Timeout => format!(
+                    "Timeout of {}s reached after {} instructions",
+                    self.args.timeout.unwrap_or(0),
+                    self.steps
+                ),
+                Exception::Until => format!(
+                    "Reached {} after {} instructions",
+                    self.locate(pc),
+                    self.steps
+                ),
+                Exception::Core(reason) => reason.clone(),
+            }
+        ))
     }
 
     pub fn step(&mut self) -> Result<(), Error> {
-        let i = self.mem.read_word(self.ctx.pc)?;
-        let inst: Instruction = i.try_into()?;
-        if self.args.trace {
-            eprintln!("pc @ 0x{:08x}: 0x{:08x} -> {}", self.ctx.pc, i, inst);
+        let inst = match self.cached(self.ctx.pc) {
+            Some(inst) => inst,
+            None => self.fetch()?,
+        };
+        if self.until == Some(self.ctx.pc) {
+            self.until = None;
+            self.exn = Some(Exception::Until);
+            return Err(self.unhandled(&Exception::Until, self.ctx.pc, inst));
+        }
+        if self.tracer.is_some() {
+            self.trace(inst);
+        }
+        if self.caches.is_some() {
+            self.access_caches(inst);
         }
+        let pc = self.ctx.pc;
+        self.recent.record(pc);
+        let addr = match self.livelock.is_some() || self.heatmap.is_some() {
+            true => self.mem_addr(inst),
+            false => None,
+        };
         self.exec_instruction(inst);
-        match &self.exn {
-            Some(e) => {
-                return Err(Error::UnhandledException(format!(
-                    "Unhandled Exception: {}",
-                    match e {
-                        Exception::Timer => {
-                            String::from("Unimplemented!?")
-                        }
-                        Exception::Overflow => {
-                            String::from("Overflow exception")
-                        }
-                        Exception::Exit(code) => {
-                            format!("Exit with code {}", code)
-                        }
-                        Exception::Syscall(operand) => {
-                            format!("Syscall with operand {}", operand)
-                        }
-                        Exception::DivideByZero => {
-                            String::from("Divide by zero")
-                        }
-                        Exception::Memory(Error::MemoryAccessError(e)) => {
-                            format!("Memory exception: {}", e)
-                        }
-                        Exception::Memory(_) => unreachable!(),
-                        Exception::Break(operand) => {
-                            format!("Break with operand {}", operand)
-                        }
-                    }
-                )));
+        if self.tracer.is_some() {
+            self.retire();
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.record(inst, self.ctx.pc != pc);
+        }
+        if let Some(timing) = &mut self.timing {
+            timing.record(inst, self.ctx.pc != pc);
+        }
+        if let Some(predictor) = &mut self.predictor {
+            predictor.record(pc, inst, self.ctx.pc != pc);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc);
+        }
+        if let Some(calls) = &mut self.calls {
+            calls.record(pc, inst, self.ctx.pc.wrapping_add(4));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc);
+        }
+        if let (Some(heatmap), Some(addr)) = (&mut self.heatmap, addr) {
+            heatmap.record(inst, addr);
+        }
+        if let Some(livelock) = &mut self.livelock {
+            if livelock.record(pc, inst, addr) && self.exn.is_none() {
+                let (low, high) = livelock.bounds();
+                self.exn = Some(Exception::Livelock(low, high));
+            }
+        }
+        if let Some(e) = &self.exn {
+            let code = e.code(inst);
+            if let (Some(handler), Some(code)) = (self.handler, code) {
+                self.enter_handler(handler, code);
+            }
+            if let Some(e) = &self.exn {
+                return Err(self.unhandled(e, pc, inst));
             }
-            None => {}
         }
 
         self.ctx.reg[Register::ZERO as usize] = 0;
-        self.ctx.pc += 4;
+        self.ctx.pc = self.ctx.pc.wrapping_add(4);
+        self.steps += 1;
+        if self.text_end == Some(self.ctx.pc) {
+            // ran off the end of the program, which exits it
+            self.exn = Some(Exception::Exit(0));
+            return Err(self.unhandled(&Exception::Exit(0), pc, inst));
+        }
+        if Some(self.steps) == self.inst_limit {
+            self.exn = Some(Exception::InstLimit);
+            return Err(self.unhandled(&Exception::InstLimit, pc, inst));
+        }
+        if self.steps.is_multiple_of(TIMEOUT_INTERVAL)
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.exn = Some(Exception::Timeout);
+            return Err(self.unhandled(&Exception::Timeout, pc, inst));
+        }
         Ok(())
     }
 }
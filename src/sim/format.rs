@@ -0,0 +1,159 @@
+use super::{mem::Memory, SimArgs};
+use crate::common::register_name;
+
+/// A range of memory to dump when the program terminates, given to
+/// `--dump-mem` as `START..END[:FILE]`. The addresses may be symbols, which
+/// are looked up once the program is loaded.
+#[derive(Clone, Debug)]
+pub struct DumpRange {
+    pub start: String,
+    pub end: String,
+    /// Where to write the dump instead of stderr
+    pub file: Option<String>,
+}
+
+impl DumpRange {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (range, file) = match spec.split_once(':') {
+            Some((range, file)) => (range, Some(file.to_string())),
+            None => (spec, None),
+        };
+        match range.split_once("..") {
+            Some((start, end)) if !start.is_empty() && !end.is_empty() => Ok(Self {
+                start: start.to_string(),
+                end: end.to_string(),
+                file,
+            }),
+            _ => Err(format!(
+                "invalid memory range `{}`, expected START..END[:FILE]",
+                spec
+            )),
+        }
+    }
+}
+
+/// Layout of register and memory dumps, shared by the debugger and the dump
+/// printed when the program terminates (`-f`, `-m`) so that both look the
+/// same.
+#[derive(Clone, Copy)]
+pub struct DumpFormat {
+    /// Name registers by number (`$8`) rather than by name (`$t0`), `-n`
+    pub reg_nums: bool,
+    /// Lay out dumps for 136 column lines rather than 80, `-l`
+    pub long_lines: bool,
+}
+
+impl DumpFormat {
+    pub fn new(args: &SimArgs) -> Self {
+        Self {
+            reg_nums: args.reg_nums,
+            long_lines: args.long_lines,
+        }
+    }
+
+    /// Registers (or memory words) shown on each line
+    fn per_line(&self) -> usize {
+        match self.long_lines {
+            true => 8,
+            false => 4,
+        }
+    }
+
+    pub fn reg_name(&self, reg: u8) -> String {
+        match self.reg_nums {
+            true => format!("${}", reg),
+            false => format!("${}", register_name(reg)),
+        }
+    }
+
+    pub fn registers(&self, reg: &[u32; 32], pc: u32, hi: u32, lo: u32) -> String {
+        let mut out = format!(
+            "pc = 0x{:08x}   hi = 0x{:08x}   lo = 0x{:08x}\n",
+            pc, hi, lo
+        );
+        for row in (0..32u8).collect::<Vec<_>>().chunks(self.per_line()) {
+            let cells = row
+                .iter()
+                .map(|r| format!("{:>5}=0x{:08x}", self.reg_name(*r), reg[*r as usize]))
+                .collect::<Vec<_>>();
+            out.push_str(&cells.join(" "));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Dumps the words in `start..end` as hex and ASCII. Runs of identical
+    /// lines are collapsed into a `*`, unmapped words are shown as `-`, and
+    /// unmapped pages are passed over in one line.
+    pub fn memory(&self, mem: &Memory, start: u32, end: u32) -> String {
+        let step = self.per_line() as u32 * 4;
+        let mut out = String::new();
+        let mut prev: Option<Vec<Option<u32>>> = None;
+        let mut collapsed = false;
+        let mut addr = start & !3;
+        while addr < end {
+            if !mem.is_mapped(addr) {
+                let hole = addr;
+                while addr < end && !mem.is_mapped(addr) {
+                    match (addr & !(mem.page_size() - 1)).checked_add(mem.page_size()) {
+                        Some(next) => addr = next,
+                        None => break,
+                    }
+                }
+                let last = match mem.is_mapped(addr) {
+                    true => addr.min(end),
+                    // the hole runs to the end of the address space
+                    false => end,
+                };
+                out.push_str(&format!(
+                    "0x{:08x}:  (unmapped up to 0x{:08x})\n",
+                    hole, last
+                ));
+                prev = None;
+                collapsed = false;
+                if !mem.is_mapped(addr) {
+                    break;
+                }
+                continue;
+            }
+            let words = (0..self.per_line() as u32)
+                .map(|i| mem.read_word(addr.wrapping_add(i * 4)).ok())
+                .collect::<Vec<_>>();
+            if prev.as_ref() == Some(&words) {
+                if !collapsed {
+                    out.push_str("*\n");
+                    collapsed = true;
+                }
+            } else {
+                let hex = words
+                    .iter()
+                    .map(|w| w.map_or(String::from("--------"), |w| format!("{:08x}", w)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let ascii = words
+                    .iter()
+                    .flat_map(|w| match w {
+                        Some(w) => mem.endian().word_bytes(*w),
+                        None => [b' '; 4],
+                    })
+                    .map(|b| match b {
+                        0x20..=0x7e => b as char,
+                        _ => '.',
+                    })
+                    .collect::<String>();
+                out.push_str(&format!("0x{:08x}:  {}  |{}|\n", addr, hex, ascii));
+                collapsed = false;
+            }
+            prev = Some(words);
+            match addr.checked_add(step) {
+                Some(a) => addr = a,
+                None => break,
+            }
+        }
+        // show where a collapsed run ends
+        if collapsed {
+            out.push_str(&format!("0x{:08x}\n", addr));
+        }
+        out
+    }
+}
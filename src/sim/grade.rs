@@ -0,0 +1,214 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    config::{self, Entry},
+    Exec, SimArgs,
+};
+use crate::common::ObjectModule;
+
+/// Lines of unchanged output shown around each difference
+const CONTEXT: usize = 2;
+
+/// One run of the program for `--cases`, read from a manifest of
+///
+/// ```toml
+/// [[case]]
+/// name = "empty list"    # defaults to the case number
+/// stdin = "empty.in"     # input; defaults to none
+/// expect = "empty.out"   # the output expected
+/// ```
+///
+/// tables, with paths relative to the manifest.
+struct Case {
+    name: String,
+    stdin: Option<PathBuf>,
+    expect: PathBuf,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("cannot read `{}`: {}", path.display(), e))
+}
+
+/// Reads a manifest, as described for `Case`
+fn load_cases(path: &str) -> Result<Vec<Case>, String> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    // name, stdin and expect of each case, until checked at the end
+    let mut tables: Vec<[Option<String>; 3]> = vec![];
+    for (num, entry) in config::read(path)? {
+        let err = |msg: &str| format!("{}:{}: {}", path, num, msg);
+        let (key, val) = match entry {
+            Entry::ArrayTable(name) if name == "case" => {
+                tables.push(Default::default());
+                continue;
+            }
+            Entry::Pair(key, val) => (key, val),
+            _ => return Err(err("expected `[[case]]` or `key = \"value\"`")),
+        };
+        let val = config::string(&val).ok_or_else(|| err("expected a quoted string"))?;
+        let table = tables
+            .last_mut()
+            .ok_or_else(|| err("expected `[[case]]` first"))?;
+        let field = match key.as_str() {
+            "name" => &mut table[0],
+            "stdin" => &mut table[1],
+            "expect" => &mut table[2],
+            key => return Err(err(&format!("unknown key `{}`", key))),
+        };
+        *field = Some(val);
+    }
+
+    tables
+        .into_iter()
+        .enumerate()
+        .map(|(idx, [name, stdin, expect])| {
+            let name = name.unwrap_or_else(|| format!("case {}", idx + 1));
+            let expect = expect.ok_or_else(|| format!("{}: `{}` has no `expect`", path, name))?;
+            Ok(Case {
+                name,
+                stdin: stdin.map(|p| dir.join(p)),
+                expect: dir.join(expect),
+            })
+        })
+        .collect()
+}
+
+/// A readable diff from the expected output to the actual output, or None if
+/// they are the same
+pub fn diff(expected: &[u8], actual: &[u8]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let old = expected.lines().collect::<Vec<_>>();
+    let new = actual.lines().collect::<Vec<_>>();
+    if old == new {
+        return Some(String::from(
+            "  the output differs only in line endings or the final newline\n",
+        ));
+    }
+
+    // longest common subsequences of the remaining lines
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    // each line of either, marked ' ' if in both, with its expected line number
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', i, old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', i, old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', i, new[j]));
+            j += 1;
+        }
+    }
+
+    let changed = |k: usize| lines[k].0 != ' ';
+    let mut out = String::from("  --- expected\n  +++ actual\n");
+    let mut k = 0;
+    while k < lines.len() {
+        if !changed(k) {
+            k += 1;
+            continue;
+        }
+        // a hunk runs until there are more than twice the context unchanged
+        let start = k.saturating_sub(CONTEXT);
+        let mut end = k;
+        while end < lines.len() && (end..(end + 2 * CONTEXT + 1).min(lines.len())).any(changed) {
+            end += 1;
+        }
+        let end = (end + CONTEXT).min(lines.len());
+        out.push_str(&format!("  @@ line {} @@\n", lines[start].1 + 1));
+        for (mark, _, line) in &lines[start..end] {
+            out.push_str(&format!("  {}{}\n", mark, line));
+        }
+        k = end;
+    }
+    Some(out)
+}
+
+/// Checks the program's output against the file given by `--expect`,
+/// printing the difference if there is one. Returns whether it matched.
+pub fn check(path: &str, stdout: &[u8]) -> bool {
+    let expected = match read(Path::new(path)) {
+        Ok(expected) => expected,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    match diff(&expected, stdout) {
+        Some(diff) => {
+            eprint!("output differs from `{}`:\n{}", path, diff);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Runs the program on each case of the manifest given by `--cases`,
+/// printing whether each passed, returning the status rtool should exit
+/// with: 0 if every case passed, otherwise 1. A case passes if the program
+/// prints the output expected without being terminated by an exception.
+pub fn run_cases(module: ObjectModule, args: &SimArgs, path: &str) -> i32 {
+    let cases = match load_cases(path) {
+        Ok(cases) => cases,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let mut passed = 0;
+    for case in &cases {
+        let result = run_case(module.clone(), args, case);
+        match &result {
+            Ok(()) => {
+                passed += 1;
+                println!("{}: pass", case.name);
+            }
+            Err(e) => println!("{}: FAIL\n{}", case.name, e.trim_end()),
+        }
+    }
+    println!("{} of {} cases passed", passed, cases.len());
+    (passed != cases.len()) as i32
+}
+
+/// Runs one case, returning why it failed if it did
+fn run_case(module: ObjectModule, args: &SimArgs, case: &Case) -> Result<(), String> {
+    let expected = read(&case.expect)?;
+    let mut exec = Exec::new(module, args)?;
+    exec.capture_output();
+    if let Some(path) = &case.stdin {
+        exec.set_stdin(&read(path)?);
+    }
+
+    let e = exec.run().unwrap_err();
+    let _ = std::io::stderr().write_all(&exec.take_stderr());
+    let mut why = String::new();
+    if !exec.exited() {
+        why.push_str(&format!("  {}\n", e));
+    }
+    if let Some(diff) = diff(&expected, &exec.take_stdout()) {
+        why.push_str(&diff);
+    }
+    match why.is_empty() {
+        true => Ok(()),
+        false => Err(why),
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::symbols::Symbols;
+use crate::common::{instruction::opcodes::OP_SB, Instruction};
+
+/// Number of symbols and buckets listed in the heatmap report
+const HOTTEST: usize = 16;
+/// Width of the bar drawn for the hottest bucket
+const BAR: u64 = 32;
+
+/// Loads and stores counted by address
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    reads: u64,
+    writes: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+    }
+
+    fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Loads and stores by word of memory, kept when `--heatmap` is given. The
+/// words are grouped into buckets of `--heatmap-bucket` bytes, and by
+/// segment and symbol, when the report is made.
+#[derive(Clone)]
+pub struct Heatmap {
+    bucket: u32,
+    words: HashMap<u32, Counts>,
+}
+
+impl Heatmap {
+    pub fn new(bucket: u32) -> Self {
+        Self {
+            bucket,
+            words: HashMap::new(),
+        }
+    }
+
+    /// Parses `--heatmap-bucket`, which must be a power of two from a word to
+    /// a page
+    pub fn parse_bucket(s: &str) -> Result<u32, String> {
+        match s.parse::<u32>() {
+            Ok(n) if n.is_power_of_two() && (4..=super::PAGE_SIZE).contains(&n) => Ok(n),
+            _ => Err(format!(
+                "invalid bucket size `{}`, expected a power of two from 4 to {}",
+                s,
+                super::PAGE_SIZE
+            )),
+        }
+    }
+
+    /// Counts the load or store `inst` makes at `addr`
+    pub fn record(&mut self, inst: Instruction, addr: u32) {
+        let counts = self.words.entry(addr & !3).or_default();
+        match inst {
+            Instruction::I { op, .. } if op >= OP_SB => counts.writes += 1,
+            _ => counts.reads += 1,
+        }
+    }
+
+    /// The heatmap report: accesses by segment, as named by `segment`, and by
+    /// symbol, then the hottest buckets with a bar for each
+    pub fn report(&self, symbols: &Symbols, segment: impl Fn(u32) -> &'static str) -> String {
+        let mut all = Counts::default();
+        let mut by_segment = BTreeMap::<&str, Counts>::new();
+        let mut by_symbol = HashMap::<String, Counts>::new();
+        let mut by_bucket = BTreeMap::<u32, Counts>::new();
+        for (addr, counts) in &self.words {
+            let seg = segment(*addr);
+            // only text and data have symbols, the heap and stack are
+            // counted as a whole
+            let name = match seg {
+                "TEXT" | "DATA" => symbols.lookup(*addr).map(|(name, _)| name.to_string()),
+                _ => None,
+            };
+            all.add(*counts);
+            by_segment.entry(seg).or_default().add(*counts);
+            by_symbol
+                .entry(name.unwrap_or_else(|| format!("({})", seg)))
+                .or_default()
+                .add(*counts);
+            by_bucket
+                .entry(addr & !(self.bucket - 1))
+                .or_default()
+                .add(*counts);
+        }
+        let pct = |n: u64| match all.total() {
+            0 => 0.0,
+            t => n as f64 * 100.0 / t as f64,
+        };
+        let row = |name: &str, c: Counts| {
+            format!(
+                "  {:<30}{:>12}{:>12} {:>6.2}%\n",
+                name,
+                c.reads,
+                c.writes,
+                pct(c.total())
+            )
+        };
+
+        let mut out = format!(
+            "\nMemory heatmap ({} loads, {} stores):\n",
+            all.reads, all.writes
+        );
+        out.push_str(&format!(
+            "  {:<30}{:>12}{:>12}\n",
+            "segment", "loads", "stores"
+        ));
+        for (seg, counts) in &by_segment {
+            out.push_str(&row(seg, *counts));
+        }
+
+        let mut by_symbol = by_symbol.into_iter().collect::<Vec<_>>();
+        by_symbol.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(&b.0)));
+        out.push_str(&format!(
+            "\n  {:<30}{:>12}{:>12}\n",
+            "symbol", "loads", "stores"
+        ));
+        for (name, counts) in by_symbol.into_iter().take(HOTTEST) {
+            out.push_str(&row(&name, counts));
+        }
+
+        let mut hottest = by_bucket.into_iter().collect::<Vec<_>>();
+        hottest.sort_by_key(|(addr, c)| (std::cmp::Reverse(c.total()), *addr));
+        hottest.truncate(HOTTEST);
+        let max = hottest.first().map_or(1, |(_, c)| c.total());
+        out.push_str(&format!("\nHottest {}-byte buckets:\n", self.bucket));
+        for (addr, counts) in hottest {
+            let seg = segment(addr);
+            let name = match seg {
+                "TEXT" | "DATA" => symbols.describe(addr, true),
+                _ => None,
+            };
+            let at = match name {
+                Some(name) => format!("0x{:08x} <{}>", addr, name),
+                None => format!("0x{:08x} ({})", addr, seg),
+            };
+            let bar = (counts.total() * BAR).div_ceil(max) as usize;
+            out.push_str(&format!(
+                "  {:<30}{:>12}{:>12}  {}\n",
+                at,
+                counts.reads,
+                counts.writes,
+                "#".repeat(bar)
+            ));
+        }
+        out
+    }
+}
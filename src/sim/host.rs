@@ -0,0 +1,75 @@
+//! What the simulator does differently depending on the host: opening files
+//! with the flags the program gives, running commands, and catching Ctrl-C.
+//! Everything else is the same on every host.
+
+use std::{
+    fs::OpenOptions,
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set when Ctrl-C is pressed, once `catch_interrupts` has been called
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Options for opening a file as rsim's `open` syscall asks: `flags` 0 to
+/// read, 1 to write or 2 to do both, with 0x8 to append, 0x100 to create the
+/// file and 0x1000 to truncate it, and `mode` the permissions of a file it
+/// creates. On unix hosts, the permissions are used and the flags are passed
+/// on to the host as they are as well; elsewhere, both are ignored past
+/// those listed.
+pub fn open_options(flags: u32, mode: u32) -> OpenOptions {
+    let mut opts = OpenOptions::new();
+    opts.read(flags & 0x3 != 0x1)
+        .write(flags & 0x3 != 0)
+        .append(flags & 0x8 != 0)
+        .create(flags & 0x100 != 0)
+        .truncate(flags & 0x1000 != 0);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(mode).custom_flags(flags as i32);
+    }
+    opts
+}
+
+/// A command run by the host's shell, to which arguments added are passed:
+/// as `$1` onwards with `sh` on unix hosts, and after the command with `cmd`
+/// on Windows
+pub fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    }
+    #[cfg(not(windows))]
+    {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command).arg("sh");
+        cmd
+    }
+}
+
+/// Makes Ctrl-C set a flag, taken by `take_interrupt`, instead of killing
+/// rtool. Only unix hosts do; elsewhere Ctrl-C kills rtool as usual.
+pub fn catch_interrupts() {
+    #[cfg(unix)]
+    {
+        const SIGINT: i32 = 2;
+        extern "C" {
+            fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        }
+        extern "C" fn on_interrupt(_: i32) {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+        }
+        // SAFETY: the handler only stores to an atomic
+        unsafe {
+            signal(SIGINT, on_interrupt);
+        }
+    }
+}
+
+/// Whether Ctrl-C has been pressed since this was last called
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
@@ -0,0 +1,156 @@
+use clap::{Args, ValueEnum};
+
+use super::{
+    config::{self, Entry},
+    DATA_START, PAGE_MASK, STACK_START, TEXT_START,
+};
+
+/// Which way the runtime stack grows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StackDirection {
+    /// Towards lower addresses, from the stack start, as on the R2000
+    #[default]
+    Down,
+    /// Towards higher addresses, from the stack start
+    Up,
+}
+
+/// Where each segment of a program is placed in memory. The assembler lays
+/// out sections with it and the simulator loads them with it, so a program
+/// must be run with the layout it was assembled for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub text_start: u32,
+    /// Where rdata starts, followed by data, sdata, sbss, bss and the heap
+    pub data_start: u32,
+    /// The first word of the stack
+    pub stack_start: u32,
+    pub stack_direction: StackDirection,
+}
+
+impl Default for Layout {
+    /// The R2000 layout
+    fn default() -> Self {
+        Self {
+            text_start: TEXT_START,
+            data_start: DATA_START,
+            stack_start: STACK_START,
+            stack_direction: StackDirection::Down,
+        }
+    }
+}
+
+/// Parses an address given in decimal, or in hex with `0x`
+pub fn parse_addr(s: &str) -> Result<u32, String> {
+    let s = s.trim().replace('_', "");
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid address `{}`", s))
+}
+
+// Options overriding the memory layout, shared by `asm`, `link` and `run`.
+// Not a doc comment, which clap would use as the about text of `run`, and
+// not a group, which clap would show as required in usage lines.
+#[derive(Args, Clone, Debug, Default)]
+#[group(skip)]
+pub struct LayoutArgs {
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read the memory layout from FILE, which sets any of text_start, data_start and
+stack_start to an address and stack to \"down\" or \"up\", one `key = value`
+per line. Options given as well override it."
+    )]
+    layout: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = parse_addr,
+        help = "Place the text segment at ADDR instead of 0x00400000."
+    )]
+    text_start: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = parse_addr,
+        help = "Place the data segments at ADDR instead of 0x10000000."
+    )]
+    data_start: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = parse_addr,
+        help = "Start the stack at ADDR instead of 0x7fffeffc."
+    )]
+    stack_start: Option<u32>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Which way the stack grows from its start. Defaults to down."
+    )]
+    stack: Option<StackDirection>,
+}
+
+impl LayoutArgs {
+    /// The layout given by the file and options, on top of the default one
+    pub fn layout(&self) -> Result<Layout, String> {
+        let mut layout = match &self.layout {
+            Some(path) => load(path)?,
+            None => Layout::default(),
+        };
+        if let Some(addr) = self.text_start {
+            layout.text_start = addr;
+        }
+        if let Some(addr) = self.data_start {
+            layout.data_start = addr;
+        }
+        if let Some(addr) = self.stack_start {
+            layout.stack_start = addr;
+        }
+        if let Some(dir) = self.stack {
+            layout.stack_direction = dir;
+        }
+
+        if layout.text_start & PAGE_MASK != 0 || layout.data_start & PAGE_MASK != 0 {
+            return Err(String::from(
+                "the text and data segments must start on a page boundary",
+            ));
+        }
+        if layout.stack_start & 3 != 0 {
+            return Err(String::from("the stack must start on a word boundary"));
+        }
+        if layout.text_start >= layout.data_start {
+            return Err(String::from(
+                "the text segment must come before the data segments",
+            ));
+        }
+        Ok(layout)
+    }
+}
+
+/// Reads a layout file, as described for `--layout`
+fn load(path: &str) -> Result<Layout, String> {
+    let mut layout = Layout::default();
+    for (num, entry) in config::read(path)? {
+        let err = |msg: String| format!("{}:{}: {}", path, num, msg);
+        let Entry::Pair(key, val) = entry else {
+            return Err(err(String::from("expected `key = value`")));
+        };
+        // values may be quoted or not
+        let val = config::string(&val).unwrap_or(val);
+        let val = val.as_str();
+        match key.as_str() {
+            "text_start" => layout.text_start = parse_addr(val).map_err(err)?,
+            "data_start" => layout.data_start = parse_addr(val).map_err(err)?,
+            "stack_start" => layout.stack_start = parse_addr(val).map_err(err)?,
+            "stack" => {
+                layout.stack_direction = StackDirection::from_str(val, true)
+                    .map_err(|_| err(format!("invalid stack direction `{}`", val)))?
+            }
+            key => return Err(err(format!("unknown key `{}`", key))),
+        }
+    }
+    Ok(layout)
+}
@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+
+use crate::common::{instruction::opcodes::FUNCT_SYSCALL, Instruction};
+
+/// Instructions at the end of a livelock whose addresses are reported as
+/// the loop
+const LOOP_WINDOW: u64 = 1024;
+
+/// Detects a program stuck in a loop, kept when `--livelock` is given. The
+/// program is making progress when it makes a syscall or accesses a word of
+/// memory it never has before; it is taken to be livelocked once it has run
+/// the limit of instructions without doing either.
+#[derive(Clone)]
+pub struct Livelock {
+    limit: u64,
+    /// Instructions executed since the program last made progress
+    quiet: u64,
+    /// Every word of memory the program has loaded from or stored to
+    touched: HashSet<u32>,
+    /// The lowest and highest pc of the last instructions before the limit
+    low: u32,
+    high: u32,
+}
+
+impl Livelock {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            quiet: 0,
+            touched: HashSet::new(),
+            low: u32::MAX,
+            high: 0,
+        }
+    }
+
+    /// Records the instruction at `pc`, which accesses memory at `addr` if
+    /// it is a load or store. Returns whether the program is now livelocked.
+    pub fn record(&mut self, pc: u32, inst: Instruction, addr: Option<u32>) -> bool {
+        let syscall = matches!(
+            inst,
+            Instruction::R {
+                funct: FUNCT_SYSCALL,
+                ..
+            }
+        );
+        if syscall || addr.is_some_and(|addr| self.touched.insert(addr & !3)) {
+            self.quiet = 0;
+            (self.low, self.high) = (u32::MAX, 0);
+            return false;
+        }
+        self.quiet += 1;
+        if self.quiet > self.limit.saturating_sub(LOOP_WINDOW) {
+            self.low = self.low.min(pc);
+            self.high = self.high.max(pc);
+        }
+        self.quiet >= self.limit
+    }
+
+    /// The addresses of the first and last instructions of the loop
+    pub fn bounds(&self) -> (u32, u32) {
+        (self.low, self.high)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
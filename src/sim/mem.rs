@@ -1,8 +1,59 @@
-use super::{SimArgs, DATA_START, PAGE_BITS, PAGE_MASK, PAGE_SIZE, STACK_START, TEXT_START};
+use super::{Layout, SimArgs, StackDirection, PAGE_SIZE};
 use crate::common::Error;
 use crate::sim::ObjectModule;
 
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap};
+
+use clap::ValueEnum;
+
+/// Number of page translations remembered by `Memory`, so that most accesses
+/// avoid the page table.
+const TLB_SIZE: usize = 64;
+
+/// Byte order of halfword and word accesses to memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Endian {
+    /// Most significant byte first, as on the R2000
+    #[default]
+    Big,
+    Little,
+}
+
+impl Endian {
+    #[inline]
+    pub fn word(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endian::Big => u32::from_be_bytes(bytes),
+            Endian::Little => u32::from_le_bytes(bytes),
+        }
+    }
+
+    #[inline]
+    pub fn half(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endian::Big => u16::from_be_bytes(bytes),
+            Endian::Little => u16::from_le_bytes(bytes),
+        }
+    }
+
+    /// The bytes of `word`, in memory order
+    #[inline]
+    pub fn word_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            Endian::Big => word.to_be_bytes(),
+            Endian::Little => word.to_le_bytes(),
+        }
+    }
+
+    /// The bytes of `half`, in memory order
+    #[inline]
+    pub fn half_bytes(self, half: u16) -> [u8; 2] {
+        match self {
+            Endian::Big => half.to_be_bytes(),
+            Endian::Little => half.to_le_bytes(),
+        }
+    }
+}
 
 /// struct the manages the virtual address space the running program is in.
 /// Controls reads and writes to and from memory as well as allocating pages
@@ -17,6 +68,37 @@ pub struct Memory {
     pub exec: HashMap<PageID, bool>,
     /// buffer containing all pages.
     pub pages: Vec<Page>,
+    /// Recently used translations, direct mapped by virtual id. Pages are
+    /// never unmapped or have their permissions changed, so entries never
+    /// go stale.
+    tlb: [Cell<Option<TlbEntry>>; TLB_SIZE],
+    /// Byte order of halfword and word accesses
+    endian: Endian,
+    /// log2 of the size of a page, which is at most `PAGE_SIZE`. Sections
+    /// are still placed `PAGE_SIZE` apart, as the linker lays them out.
+    page_bits: u32,
+    /// Where the stack may grow to, once the program is loaded
+    stack: Option<StackRegion>,
+    /// The most pages which may be mapped, given by `--mem-limit`
+    limit: Option<usize>,
+}
+
+/// The addresses the stack may use, up to the `-s` limit. Only the page the
+/// stack starts in is mapped to begin with; the rest are mapped when the
+/// program first writes to them, and read as zero until then.
+#[derive(Clone, Copy)]
+struct StackRegion {
+    /// The lowest and highest address of the region
+    low: u32,
+    high: u32,
+    direction: StackDirection,
+}
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    virt: PageID,
+    real: PageID,
+    write: bool,
 }
 
 /// Thin wrapper around u32. Will not be greater than 20 bits long. Larger IDs
@@ -24,16 +106,38 @@ pub struct Memory {
 #[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PageID(pub u32);
 
-#[derive(Clone, Copy)]
-pub struct Page(pub [u8; PAGE_SIZE as usize]);
+/// The bytes of one page, of the size the `Memory` it is from was made with
+#[derive(Clone)]
+pub struct Page(pub Box<[u8]>);
 
 impl Memory {
+    /// Translates a virtual address to a real one, along with whether its
+    /// page is writable
+    #[inline]
+    fn translate(&self, addr: u32) -> Option<(u32, bool)> {
+        let virt = PageID(addr >> self.page_bits);
+        let slot = &self.tlb[virt.0 as usize % TLB_SIZE];
+        let entry = match slot.get() {
+            Some(entry) if entry.virt == virt => entry,
+            _ => {
+                let entry = TlbEntry {
+                    virt,
+                    real: *self.table.get(&virt)?,
+                    write: self.write.get(&virt).copied().unwrap_or(false),
+                };
+                slot.set(Some(entry));
+                entry
+            }
+        };
+        Some((
+            (entry.real.0 << self.page_bits) | (addr & self.page_mask()),
+            entry.write,
+        ))
+    }
+
     #[inline]
     fn map_virt_to_real(&self, addr: u32) -> Option<u32> {
-        let virt_page = PageID(addr >> PAGE_BITS);
-        let page_addr = addr & PAGE_MASK;
-        let real_page = self.table.get(&virt_page)?;
-        Some((real_page.0 << PAGE_BITS) | page_addr)
+        self.translate(addr).map(|(real, _)| real)
     }
 
     pub fn read_word(&self, addr: u32) -> Result<u32, Error> {
@@ -43,9 +147,9 @@ impl Memory {
                 addr,
             )))
         } else if let Some(addr) = self.map_virt_to_real(addr) {
-            let page_id = addr >> PAGE_BITS;
-            let page_addr = (addr & PAGE_MASK);
-            Ok(u32::from_be_bytes(
+            let page_id = addr >> self.page_bits;
+            let page_addr = (addr & self.page_mask());
+            Ok(self.endian.word(
                 self.pages
                     .get(page_id as usize)
                     .expect("Unmapped page in page table")
@@ -53,11 +157,13 @@ impl Memory {
                     .try_into()
                     .unwrap(),
             ))
+        } else if self.in_stack(addr) {
+            Ok(0)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with read at 0x{:08x} (PageID {})",
                 addr,
-                (addr >> PAGE_BITS),
+                (addr >> self.page_bits),
             )))
         }
     }
@@ -69,9 +175,9 @@ impl Memory {
                 addr,
             )))
         } else if let Some(addr) = self.map_virt_to_real(addr) {
-            let page_id = addr >> PAGE_BITS;
-            let page_addr = (addr & PAGE_MASK);
-            Ok(u16::from_be_bytes(
+            let page_id = addr >> self.page_bits;
+            let page_addr = (addr & self.page_mask());
+            Ok(self.endian.half(
                 self.pages
                     .get(page_id as usize)
                     .expect("Unmapped page in page table")
@@ -79,6 +185,8 @@ impl Memory {
                     .try_into()
                     .unwrap(),
             ))
+        } else if self.in_stack(addr) {
+            Ok(0)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with read at 0x{:08x}",
@@ -89,13 +197,15 @@ impl Memory {
 
     pub fn read_byte(&self, addr: u32) -> Result<u8, Error> {
         if let Some(addr) = self.map_virt_to_real(addr) {
-            let page_id = addr >> PAGE_BITS;
-            let page_addr = (addr & PAGE_MASK);
+            let page_id = addr >> self.page_bits;
+            let page_addr = (addr & self.page_mask());
             Ok(self
                 .pages
                 .get(page_id as usize)
                 .expect("PANIC: Unmapped page in page table")
                 .0[page_addr as usize])
+        } else if self.in_stack(addr) {
+            Ok(0)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with read at 0x{:08x}",
@@ -110,20 +220,16 @@ impl Memory {
                 "Unaligned memory access @ {:08x}",
                 addr,
             )))
-        } else if let Some(real_addr) = self.map_virt_to_real(addr) {
-            let page_id = real_addr >> PAGE_BITS;
-            let mut page_addr = (real_addr & PAGE_MASK);
-            if !self
-                .write
-                .get(&PageID(addr >> PAGE_BITS))
-                .expect("Unmapped page in page table")
-            {
+        } else if let Some((real_addr, write)) = self.translate(addr) {
+            let page_id = real_addr >> self.page_bits;
+            let mut page_addr = (real_addr & self.page_mask());
+            if !write {
                 Err(Error::MemoryAccessError(format!(
                     "Attempted to write to read-only page @ 0x{:08x}",
                     addr
                 )))
             } else {
-                let buf = value.to_be_bytes();
+                let buf = self.endian.word_bytes(value);
                 let p = self
                     .pages
                     .get_mut(page_id as usize)
@@ -134,6 +240,9 @@ impl Memory {
                 }
                 Ok(())
             }
+        } else if self.in_stack(addr) {
+            self.grow_stack(addr)?;
+            self.write_word(addr, value)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with read @ 0x{:08x}",
@@ -148,20 +257,16 @@ impl Memory {
                 "Unaligned memory access at 0x{:08x}",
                 addr,
             )))
-        } else if let Some(real_addr) = self.map_virt_to_real(addr) {
-            let page_id = real_addr >> PAGE_BITS;
-            let mut page_addr = (real_addr & PAGE_MASK);
-            if !self
-                .write
-                .get(&PageID(addr >> PAGE_BITS))
-                .expect("Unmapped page in page table")
-            {
+        } else if let Some((real_addr, write)) = self.translate(addr) {
+            let page_id = real_addr >> self.page_bits;
+            let mut page_addr = (real_addr & self.page_mask());
+            if !write {
                 Err(Error::MemoryAccessError(format!(
                     "Attempted to write to read-only page @ 0x{:08x}",
                     addr
                 )))
             } else {
-                let buf = value.to_be_bytes();
+                let buf = self.endian.half_bytes(value);
                 let p = self
                     .pages
                     .get_mut(page_id as usize)
@@ -172,6 +277,9 @@ impl Memory {
                 }
                 Ok(())
             }
+        } else if self.in_stack(addr) {
+            self.grow_stack(addr)?;
+            self.write_half(addr, value)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with read @ 0x{:08x}",
@@ -181,14 +289,10 @@ impl Memory {
     }
 
     pub fn write_byte(&mut self, addr: u32, value: u8) -> Result<(), Error> {
-        if let Some(real_addr) = self.map_virt_to_real(addr) {
-            let page_id = real_addr >> PAGE_BITS;
-            let page_addr = (real_addr & PAGE_MASK);
-            if !self
-                .write
-                .get(&PageID(addr >> PAGE_BITS))
-                .expect("PANIC: Unmapped page in page table")
-            {
+        if let Some((real_addr, write)) = self.translate(addr) {
+            let page_id = real_addr >> self.page_bits;
+            let page_addr = (real_addr & self.page_mask());
+            if !write {
                 Err(Error::MemoryAccessError(format!(
                     "Attempted to write to read-only page @ 0x{:08x}",
                     addr
@@ -201,6 +305,9 @@ impl Memory {
                     .0[page_addr as usize] = value;
                 Ok(())
             }
+        } else if self.in_stack(addr) {
+            self.grow_stack(addr)?;
+            self.write_byte(addr, value)
         } else {
             Err(Error::MemoryAccessError(format!(
                 "Attempted to access unmapped page with write @ 0x{:08x}",
@@ -209,27 +316,119 @@ impl Memory {
         }
     }
 
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn page_size(&self) -> u32 {
+        1 << self.page_bits
+    }
+
+    #[inline]
+    fn page_mask(&self) -> u32 {
+        self.page_size() - 1
+    }
+
+    /// Whether `addr` is in the part of the stack which has not been mapped
+    /// yet
+    fn in_stack(&self, addr: u32) -> bool {
+        self.stack
+            .is_some_and(|stack| (stack.low..=stack.high).contains(&addr))
+    }
+
+    /// Maps the pages of the stack from the one `addr` is in back to those
+    /// already mapped, which must be in the stack, or none of them if they
+    /// would pass `--mem-limit`
+    #[cold]
+    fn grow_stack(&mut self, addr: u32) -> Result<(), Error> {
+        let Some(stack) = self.stack else {
+            return Ok(());
+        };
+        let size = self.page_size();
+        let mut pages = vec![];
+        let mut page = addr & !self.page_mask();
+        while !self.is_mapped(page) {
+            pages.push(page);
+            page = match stack.direction {
+                StackDirection::Down => page.wrapping_add(size),
+                StackDirection::Up => page.wrapping_sub(size),
+            };
+        }
+        if self.pages.len() + pages.len() > self.max_pages() {
+            return Err(Error::MemoryAccessError(format!(
+                "Out of memory growing the stack to 0x{:08x} ({})",
+                addr,
+                self.limit_desc()
+            )));
+        }
+        for page in pages {
+            self.alloc_page(page, true, false);
+        }
+        Ok(())
+    }
+
+    /// Limits the memory which may be mapped to `bytes`, for `--mem-limit`
+    pub fn set_limit(&mut self, bytes: u64) {
+        self.limit = Some((bytes / self.page_size() as u64) as usize);
+    }
+
+    /// The most pages which may be mapped, by `--mem-limit` or otherwise by
+    /// the size of the address space
+    fn max_pages(&self) -> usize {
+        let all = 1 << (32 - self.page_bits);
+        self.limit.map_or(all, |limit| limit.min(all))
+    }
+
+    /// Whether no more pages may be mapped
+    pub fn out_of_memory(&self) -> bool {
+        self.pages.len() >= self.max_pages()
+    }
+
+    /// The limit on mapped memory, for out of memory errors
+    fn limit_desc(&self) -> String {
+        match self.limit {
+            Some(pages) => format!(
+                "--mem-limit of {}KB",
+                pages as u64 * self.page_size() as u64 / 1024
+            ),
+            None => String::from("the address space is full"),
+        }
+    }
+
+    pub fn is_writable(&self, addr: u32) -> bool {
+        self.translate(addr).is_some_and(|(_, write)| write)
+    }
+
+    /// Whether the page `addr` is in holds text
+    pub fn is_executable(&self, addr: u32) -> bool {
+        self.exec
+            .get(&PageID(addr >> self.page_bits))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.table.contains_key(&PageID(addr >> self.page_bits))
+    }
+
     pub fn check_exec(&self, addr: u32) -> Option<bool> {
         let addr = self.map_virt_to_real(addr)?;
-        let page_id = (addr << PAGE_BITS);
+        let page_id = (addr << self.page_bits);
         self.exec.get(&PageID(page_id)).copied()
     }
 
-    /// Will panic if the allocated page real_id exceeds (1 << 20), meaning
-    /// the program cannot allocate more than 4GB of memory.
+    /// Maps the page `v_addr` is in, returning None if it is mapped already or
+    /// if `out_of_memory`, as once `--mem-limit` (or 4GB) is mapped.
     pub fn alloc_page(&mut self, v_addr: u32, write: bool, exec: bool) -> Option<&mut Page> {
         let real_id = PageID(self.pages.len() as u32);
-        let virt_id = PageID(v_addr >> PAGE_BITS);
-
-        if real_id.0 >= (1 << (32 - PAGE_BITS)) {
-            panic!("Out of memory Exception");
-        }
+        let virt_id = PageID(v_addr >> self.page_bits);
 
-        if self.table.contains_key(&virt_id) {
+        if self.table.contains_key(&virt_id) || self.out_of_memory() {
             None
         } else {
             self.table.insert(virt_id, real_id);
-            self.pages.push(Page([0u8; PAGE_SIZE as usize]));
+            self.pages
+                .push(Page(vec![0u8; self.page_size() as usize].into()));
             self.write.insert(virt_id, write);
             self.exec.insert(virt_id, exec);
             self.pages.get_mut(real_id.0 as usize)
@@ -247,77 +446,212 @@ impl Memory {
         self.pages.get_mut(r_id.0 as usize)
     }
 
-    pub(super) fn new() -> Self {
+    /// Every writable page, by virtual address, in address order
+    pub fn writable_pages(&self) -> Vec<(u32, &Page)> {
+        self.mapped_pages()
+            .into_iter()
+            .filter(|(addr, _)| self.is_writable(*addr))
+            .collect()
+    }
+
+    /// Every mapped page, by virtual address, in address order
+    pub fn mapped_pages(&self) -> Vec<(u32, &Page)> {
+        let mut pages = self
+            .table
+            .iter()
+            .map(|(v, r)| (v.0 << self.page_bits, &self.pages[r.0 as usize]))
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|(addr, _)| *addr);
+        pages
+    }
+
+    /// Replaces the contents of the writable page at `v_addr`, mapping it if
+    /// it is not already. The page must be of this memory's page size.
+    pub fn restore_page(&mut self, v_addr: u32, page: Page) {
+        if !self.table.contains_key(&PageID(v_addr >> self.page_bits)) {
+            self.alloc_page(v_addr, true, false);
+        }
+        if let Some(p) = self.get_raw_page_virt(PageID(v_addr >> self.page_bits)) {
+            *p = page;
+        }
+    }
+
+    /// Memory with nothing mapped, in pages of `1 << page_bits` bytes
+    pub(super) fn new(page_bits: u32) -> Self {
         Self {
             table: HashMap::new(),
             write: HashMap::new(),
             exec: HashMap::new(),
             pages: Vec::new(),
+            tlb: std::array::from_fn(|_| Cell::new(None)),
+            endian: Endian::Big,
+            page_bits,
+            stack: None,
+            limit: None,
         }
     }
 
-    pub fn alloc_data(&mut self, mut base_addr: u32, data: &[u8], write: bool, exec: bool) -> u32 {
-        let mut iter = data.iter().copied().peekable();
-        while iter.peek().is_some() {
-            let mut p = self.alloc_page(base_addr, write, exec).unwrap();
-            base_addr += PAGE_SIZE;
-            let data = iter.by_ref().take(PAGE_SIZE as usize).collect::<Vec<_>>();
-            for (idx, b) in data.into_iter().enumerate() {
-                p.0[idx] = b;
+    /// Maps `data` from `base_addr`, returning the address a section after
+    /// it would start at, which is the next multiple of `PAGE_SIZE`
+    pub fn alloc_data(
+        &mut self,
+        base_addr: u32,
+        data: &[u8],
+        write: bool,
+        exec: bool,
+    ) -> Result<u32, String> {
+        let size = self.page_size();
+        for (idx, chunk) in data.chunks(size as usize).enumerate() {
+            if self.out_of_memory() {
+                return Err(format!(
+                    "out of memory loading the program ({})",
+                    self.limit_desc()
+                ));
             }
+            let p = self
+                .alloc_page(base_addr + idx as u32 * size, write, exec)
+                .unwrap();
+            p.0[..chunk.len()].copy_from_slice(chunk);
         }
 
-        base_addr
+        Ok(base_addr + (data.len() as u32).next_multiple_of(PAGE_SIZE))
     }
 
-    pub fn new_from_object(module: ObjectModule, args: &SimArgs) -> Self {
-        let mut s = Self::new();
+    /// Builds the memory image of an executable, returning it along with the
+    /// start and end of the initial heap (which follows the BSS), or why it
+    /// does not fit in `--mem-limit`.
+    pub fn new_from_object(
+        module: ObjectModule,
+        args: &SimArgs,
+        layout: &Layout,
+    ) -> Result<(Self, u32, u32), String> {
+        let mut s = Self::new(args.page_size.trailing_zeros());
+        // sections are loaded byte for byte, so a little-endian module
+        // already has its words in little-endian order
+        s.endian = args.endian;
+        if let Some(mb) = args.mem_limit {
+            s.set_limit(mb as u64 * 1024 * 1024);
+        }
 
         // Create program memory image
-        s.alloc_data(TEXT_START, module.text.as_slice(), false, true);
-        let data_start = s.alloc_data(DATA_START, module.rdata.as_slice(), false, false);
-        let sdata_start = s.alloc_data(data_start, module.data.as_slice(), true, false);
-        let sbss_start = s.alloc_data(sdata_start, module.sdata.as_slice(), true, false);
+        s.alloc_data(layout.text_start, module.text.as_slice(), false, true)?;
+        let data_start = s.alloc_data(layout.data_start, module.rdata.as_slice(), false, false)?;
+        let sdata_start = s.alloc_data(data_start, module.data.as_slice(), true, false)?;
+        let sbss_start = s.alloc_data(sdata_start, module.sdata.as_slice(), true, false)?;
         let bss_start = s.alloc_data(
             sbss_start,
-            [0].into_iter()
+            [args.bss_val]
+                .into_iter()
                 .cycle()
                 .take(module.head.data[4] as usize)
                 .collect::<Vec<_>>()
                 .as_slice(),
             true,
             false,
-        );
+        )?;
         let heap_start = s.alloc_data(
             bss_start,
-            [0].into_iter()
+            [args.bss_val]
+                .into_iter()
                 .cycle()
                 .take(module.head.data[5] as usize)
                 .collect::<Vec<_>>()
                 .as_slice(),
             true,
             false,
-        );
-        let mut heap_remaining = args.heap_size * 1024;
+        )?;
+        let size = s.page_size();
+        let heap_pages = (args.heap_size * 1024).div_ceil(size);
         let mut next_heap_addr = heap_start;
-        while heap_remaining > 0 {
-            s.alloc_page(next_heap_addr, true, false);
-            next_heap_addr += PAGE_SIZE;
-            heap_remaining -= PAGE_SIZE;
+        for _ in 0..heap_pages {
+            if s.out_of_memory() {
+                return Err(format!(
+                    "out of memory mapping the heap ({})",
+                    s.limit_desc()
+                ));
+            }
+            if let Some(p) = s.alloc_page(next_heap_addr, true, false) {
+                p.0.fill(args.bss_val);
+            }
+            next_heap_addr += size;
         }
 
-        // Alloc stack
-        let mut stack_remaining = args.stack_size * 1024;
-        let mut next_stack_addr = STACK_START;
-        while stack_remaining > 0 {
-            s.alloc_page(next_stack_addr, true, false);
-            next_stack_addr -= PAGE_SIZE;
-            stack_remaining -= PAGE_SIZE;
+        // Alloc stack, of which only the first page is mapped until the
+        // program writes below it
+        // -s is in KB, rounded up to a multiple of 8
+        let stack_size = args.stack_size.div_ceil(8) * 8 * 1024;
+        let first = layout.stack_start & !s.page_mask();
+        if s.alloc_page(first, true, false).is_none() && s.out_of_memory() {
+            return Err(format!(
+                "out of memory mapping the stack ({})",
+                s.limit_desc()
+            ));
         }
+        let span = (stack_size.div_ceil(size) - 1) * size;
+        s.stack = Some(match layout.stack_direction {
+            StackDirection::Down => StackRegion {
+                low: first.saturating_sub(span),
+                high: first + (size - 1),
+                direction: StackDirection::Down,
+            },
+            StackDirection::Up => StackRegion {
+                low: first,
+                high: first.saturating_add(span + (size - 1)),
+                direction: StackDirection::Up,
+            },
+        });
 
-        // TODO: Place environment and args onto the stack
+        Ok((s, heap_start, next_heap_addr))
+    }
+
+    /// Writes the program's arguments and environment onto the stack as the
+    /// startup routine expects them: `argc`, then the `argv` and `envp`
+    /// vectors, each ending with a null pointer, then the argument strings
+    /// and the environment strings, as rsim leaves them after pushing each in
+    /// reverse. They are put just above the stack pointer if the stack grows down, and
+    /// otherwise at the start of the stack. Returns the stack pointer to start
+    /// with, which is past them if the stack grows up.
+    pub fn place_args(
+        &mut self,
+        layout: &Layout,
+        argv: &[String],
+        envp: &[String],
+    ) -> Result<u32, String> {
+        let vectors = 4 * (1 + argv.len() + 1 + envp.len() + 1) as u32;
+        let strings = argv
+            .iter()
+            .chain(envp)
+            .map(|s| s.len() as u32 + 1)
+            .sum::<u32>();
+        let base = match layout.stack_direction {
+            // the startup routine reads argc and argv above the stack
+            // pointer, in the page below the start of the stack unless they
+            // do not fit
+            StackDirection::Down => (layout.stack_start - 0x1000)
+                .min((layout.stack_start + 4).wrapping_sub(vectors + strings) & !7),
+            StackDirection::Up => layout.stack_start,
+        };
 
-        s
+        let err = |_| String::from("the program's arguments do not fit on the stack");
+        self.write_word(base, argv.len() as u32).map_err(err)?;
+        let mut ptr = base + 4;
+        let mut string = base + vectors;
+        for list in [argv, envp] {
+            for arg in list {
+                self.write_word(ptr, string).map_err(err)?;
+                for b in arg.bytes().chain([0]) {
+                    self.write_byte(string, b).map_err(err)?;
+                    string += 1;
+                }
+                ptr += 4;
+            }
+            self.write_word(ptr, 0).map_err(err)?;
+            ptr += 4;
+        }
+        Ok(match layout.stack_direction {
+            StackDirection::Down => base,
+            StackDirection::Up => string.next_multiple_of(8),
+        })
     }
 
     pub fn dump_page_table(&self, print_stack: bool) -> () {
@@ -335,9 +669,9 @@ impl Memory {
         for (k, v) in kv {
             println!(
                 "0x{:08x} [{}] -> 0x{:08x} [{}]",
-                (k.0 << PAGE_BITS),
+                (k.0 << self.page_bits),
                 k.0,
-                (v.0 << PAGE_BITS),
+                (v.0 << self.page_bits),
                 v.0
             );
         }
@@ -4,27 +4,64 @@ use std::{
     fs::{self, File},
     io::{Read, Write},
     mem::transmute,
-    os::unix::fs::OpenOptionsExt,
 };
 
 use clap::Args;
 use lazy_static::lazy_static;
 
-use self::mem::{Memory, Page, PageID};
+use self::{
+    cache::CacheConfig,
+    debug::Debugger,
+    format::DumpRange,
+    heatmap::Heatmap,
+    mem::{Endian, Memory, Page, PageID},
+    plugin::SyscallPlugin,
+    predict::PredictorKind,
+    trace::TraceFormat,
+};
 use crate::common::{Error, Instruction, ObjectModule};
 
-pub use exec::Exec;
+pub use exec::{Exec, Isa, SyscallConvention, SyscallHandler};
+pub(crate) use layout::{Layout, LayoutArgs, StackDirection};
+pub use simulator::{Simulator, SyscallAction};
 
+mod batch;
+mod cache;
+mod callgraph;
+mod checkpoint;
+mod condition;
+mod config;
+mod coverage;
+mod debug;
 mod exec;
+mod format;
+mod grade;
+mod heatmap;
+mod host;
+mod layout;
+mod livelock;
 mod mem;
+mod plugin;
+mod predict;
+mod profile;
+mod random;
+mod recent;
+mod record;
+mod simulator;
+mod stats;
+mod symbols;
+#[cfg(feature = "threaded")]
+mod threaded;
+mod timing;
+mod trace;
+mod tui;
 
-const TEXT_START: u32 = 0x00400000;
-const DATA_START: u32 = 0x10000000;
+pub(crate) const TEXT_START: u32 = 0x00400000;
+pub(crate) const DATA_START: u32 = 0x10000000;
 const STACK_START: u32 = 0x7fffeffc;
-const PAGE_BITS: u32 = 12;
-const PAGE_SIZE: u32 = 1 << PAGE_BITS;
+pub(crate) const PAGE_BITS: u32 = 12;
+pub(crate) const PAGE_SIZE: u32 = 1 << PAGE_BITS;
 const PAGE_MASK: u32 = PAGE_SIZE - 1;
-const STACK_SIZE: u32 = 0x00100000; // 1MB stack size
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
@@ -65,6 +102,8 @@ pub enum Register {
 }
 
 #[derive(Args, Clone)]
+#[command(about = "Run an executable produced by rlink in the simulator
+")]
 pub struct SimArgs {
     #[arg(
         short = 'a',
@@ -123,11 +162,22 @@ an infinite number of instructions; the minimum allowed is one instruction.",
         default_value_t = 0
     )]
     max_inst: u32,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "debug",
+        help = "Terminate the program once it has run for SECONDS seconds of host time. Unlike
+-i, this bounds how long the simulation takes however fast it runs. rtool exits
+with status 124 when the program is stopped this way."
+    )]
+    timeout: Option<u32>,
     #[arg(
         short = 'k',
         help = "Normally, rsim will randomly change the contents of the \"kernel registers\" 
 ($k0 and $k1) during simulated execution, as would happen naturally when the 
-operating system performs exception handling. This option disables that."
+operating system performs exception handling. This option disables that, and
+cannot be given with --clobber-seed."
     )]
     no_kern_clobber: bool,
     #[arg(
@@ -158,10 +208,31 @@ simulation."
         short = 's',
         help = "Use an initial runtime stack size of NKB (N * 1024 bytes). The default is 8KB;
 the minimum allowed is 1KB. The size will be rounded up (if needed) to a
-multiple of eight.",
-        default_value_t = 8
+multiple of eight. Pages of the stack are mapped as the program first writes to
+them, up to this size.",
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u32).range(1..)
     )]
     stack_size: u32,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = PAGE_SIZE,
+        value_parser = parse_page_size,
+        help = "Map memory in pages of BYTES bytes, a power of two from 256 to 4096, so that
+small programs take less memory to simulate. Sections are still placed 4096
+bytes apart, as rlink lays them out."
+    )]
+    page_size: u32,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..4096),
+        help = "Allow the program at most NMB (N * 1024 * 1024 bytes) of memory, including
+its text and data. sbrk fails, returning -1, once the heap would pass the limit,
+and growing the stack past it raises a memory exception."
+    )]
+    mem_limit: Option<u32>,
     #[arg(
         short = 't',
         help = "Turn on instruction tracing. Each instruction will be printed (in decoded
@@ -175,7 +246,349 @@ load module is not marked as executable (e.g., because it was not completely
 linked), rsim will refuse to execute it. "
     )]
     force_exec: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the instruction trace to PATH instead of stderr. Implies -t."
+    )]
+    trace_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR|SYM",
+        help = "Only trace from the point execution reaches the address or symbol given,
+starting again each time it is reached. Implies -t."
+    )]
+    trace_from: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR|SYM",
+        help = "Stop tracing after executing the instruction at the address or symbol given.
+Implies -t."
+    )]
+    trace_to: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TraceFormat::Text,
+        help = "Print the instruction trace as text, or as one JSON object per instruction
+with the registers and memory it changed. `json` implies -t."
+    )]
+    trace_format: TraceFormat,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1000,
+        help = "Snapshot the program every N instructions under the debugger, which is
+how far apart the points reverse-step and reverse-continue restart from are."
+    )]
+    snapshot_interval: u64,
+    #[arg(
+        long,
+        requires = "debug",
+        help = "Show rbug full-screen, with the code around the pc, the registers, a window of
+memory, the watch expressions and the program's output above the command line."
+    )]
+    tui: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Record everything the program reads (from stdin or files) to FILE, so that the
+run can be reproduced with --replay."
+    )]
+    record: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Feed the program the input recorded in FILE by --record instead of reading
+stdin and files."
+    )]
+    replay: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "max_inst",
+        help = "When -i stops the program, save its state to FILE so that it can be carried on
+with --resume: its registers, memory and heap, and the files it has open."
+    )]
+    checkpoint: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Carry on running the program from the state saved in FILE by --checkpoint (or
+rbug's save). The program must be the same; files it had open are opened again
+at the same offsets. -i counts instructions from here."
+    )]
+    resume: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["debug", "batch", "cases"],
+        help = "If the program terminates other than by exiting, write a core file to FILE
+with its registers and every mapped page, which rtool dump shows."
+    )]
+    core: Option<String>,
+    #[arg(
+        long,
+        value_name = "CORE",
+        conflicts_with_all = ["resume", "core", "batch", "cases"],
+        help = "Load the core file CORE, written by --core when the program crashed, into rbug
+to inspect its registers, memory and backtrace. The program is not run again."
+    )]
+    debug_core: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR|SYM",
+        conflicts_with = "debug_core",
+        help = "Run at full speed until the pc first reaches ADDR or the symbol SYM, then stop:
+entering rbug with -d, and otherwise printing the registers and memory as -f
+does."
+    )]
+    until: Option<String>,
+    #[arg(
+        long,
+        help = "Print the number of instructions executed in each function and the most
+executed instructions at the end of the simulation."
+    )]
+    profile: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the instruction counts for each call stack to FILE, in the folded format
+taken by flamegraph tools. Implies --profile."
+    )]
+    profile_folded: Option<String>,
+    #[arg(
+        long,
+        help = "Print the number of calls to each function and the instructions executed in it,
+with and without the functions it calls, at the end of the simulation."
+    )]
+    call_graph: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the call graph to FILE in the DOT language, for Graphviz."
+    )]
+    call_graph_dot: Option<String>,
+    #[arg(
+        long,
+        help = "Report which instructions were executed, overall and for each function, and
+list those which never were, at the end of the simulation."
+    )]
+    coverage: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the coverage report to FILE instead of stderr. Implies --coverage."
+    )]
+    coverage_file: Option<String>,
+    #[arg(
+        long,
+        help = "Count the loads and stores to each word of memory, and print them by segment
+and symbol, along with the most accessed parts of memory, at the end of the
+simulation."
+    )]
+    heatmap: bool,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = Heatmap::parse_bucket,
+        help = "Group the --heatmap counts into buckets of BYTES bytes, a power of two up to a
+page, instead of 64. Implies --heatmap."
+    )]
+    heatmap_bucket: Option<u32>,
+    #[arg(
+        long,
+        value_name = "MILLIONS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Terminate the program as livelocked if it runs MILLIONS million instructions
+without making a syscall or accessing any memory it has not before. Unlike -i,
+this only stops programs which are stuck in a loop, and says so."
+    )]
+    livelock: Option<u32>,
+    #[arg(
+        long,
+        help = "Count the cycles the program would take on a simple in-order pipeline, and
+print them along with the CPI at the end of the simulation."
+    )]
+    timing: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read the latency of each class of instruction and the cost of each kind of
+stall from FILE, which has [latency] and [stall] tables of `name = cycles`
+lines. Implies --timing."
+    )]
+    timing_config: Option<String>,
+    #[arg(
+        long,
+        value_name = "SIZE:ASSOC:LINE",
+        value_parser = CacheConfig::parse,
+        help = "Simulate an instruction cache of SIZE bytes (or KB, with a K suffix), ASSOC
+ways and LINE byte lines, and print its hit and miss counts at the end of the
+simulation."
+    )]
+    icache: Option<CacheConfig>,
+    #[arg(
+        long,
+        value_name = "SIZE:ASSOC:LINE",
+        value_parser = CacheConfig::parse,
+        help = "Simulate a data cache, as --icache does for instructions."
+    )]
+    dcache: Option<CacheConfig>,
+    #[arg(
+        long,
+        value_name = "SIZE:ASSOC:LINE",
+        value_parser = CacheConfig::parse,
+        help = "Simulate a unified second level cache behind --icache and --dcache."
+    )]
+    l2cache: Option<CacheConfig>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Predict each conditional branch as it is executed, and print how accurate the
+predictions were, for each branch, at the end of the simulation."
+    )]
+    predictor: Option<PredictorKind>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Give the one-bit and two-bit predictors a direct-mapped branch target buffer of
+N entries. The default of 0 is an entry for every branch."
+    )]
+    btb_size: u32,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Endian::Big,
+        help = "Byte order of the program's halfwords and words. A little-endian module must
+have its sections in little-endian order."
+    )]
+    endian: Endian,
+    #[arg(
+        long,
+        value_name = "NUMBER=COMMAND",
+        value_parser = SyscallPlugin::parse,
+        help = "Carry out syscall NUMBER, which must not be a builtin one, by running COMMAND
+with sh (cmd on Windows). It is given $a0 to $a3 as its arguments, and
+RTOOL_SYSCALL as the syscall number, and may print up to two integers to return
+in $v0 and $v1. The program is terminated if it fails. May be given more than
+once."
+    )]
+    syscall_plugin: Vec<SyscallPlugin>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Give the program the contents of FILE as its input, instead of reading the
+terminal, so that read_int, read_string and read_char give the same results
+every run. With --batch, this is the input of programs which have no `stdin`."
+    )]
+    stdin: Option<String>,
+    #[arg(
+        long,
+        value_name = "STRING",
+        conflicts_with = "stdin",
+        value_parser = parse_stdin_str,
+        help = "Give the program STRING as its input, as --stdin does a file. \\n, \\t and \\\\
+in STRING stand for a newline, a tab and a backslash."
+    )]
+    stdin_str: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "debug",
+        help = "Compare the program's output with FILE instead of printing it, and print how
+they differ and exit with status 1 if they do."
+    )]
+    expect: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["debug", "stdin", "stdin_str", "expect", "record", "replay"],
+        help = "Run the program once for each case in the manifest FILE, comparing its output
+with what is expected as --expect does, and print which cases pass. A case
+passes if the output matches and no exception terminates the program. Each
+case is a [[case]] table of a TOML file, with `expect` giving the file of
+expected output, and optionally `stdin` its input and `name` its name."
+    )]
+    cases: Option<String>,
+    #[arg(
+        long,
+        value_name = "MANIFEST",
+        conflicts_with_all = ["debug", "expect", "cases", "record", "replay"],
+        help = "Run each program listed in the MANIFEST file and report how each one ended,
+with the instructions it executed and the time it took. Each program is a
+[[program]] table of a TOML file, with `file` giving the executable, and
+optionally `name`, `args` (a list of strings), `stdin`, `output` (a file to
+write its output to), `max_inst` (as -i), `timeout` (as --timeout) and
+`livelock` (as --livelock). Other options apply to every program."
+    )]
+    batch: Option<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "batch",
+        help = "Run up to N of the --batch programs at once."
+    )]
+    jobs: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Seed the random number generators with N, so that the random syscalls give the
+same numbers every run. By default they are seeded from the clock."
+    )]
+    seed: Option<u64>,
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "no_kern_clobber",
+        help = "Clobber $k0 and $k1 with random values seeded by N, instead of the usual fixed
+sequence, so that programs relying on them keeping their values fail in a way
+that can be repeated by giving the same N. Cannot be given with -k."
+    )]
+    clobber_seed: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SyscallConvention::R2k,
+        help = "Which simulator's syscalls to follow, so that programs written for MARS can be
+run unchanged."
+    )]
+    syscalls: SyscallConvention,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Isa::Mips1,
+        help = "Which instruction set to run the program as. mips32r2 adds the instructions gcc
+commonly emits: three-operand mul, clz, clo, movz, movn, seb, seh, ext and ins."
+    )]
+    isa: Isa,
+    #[arg(
+        long,
+        value_name = "START..END[:FILE]",
+        value_parser = DumpRange::parse,
+        help = "Dump the memory from START up to END, which may be addresses or symbols, in hex
+and ASCII when the program terminates, to FILE if one is given and otherwise to
+stderr. May be given more than once."
+    )]
+    dump_mem: Vec<DumpRange>,
+    #[cfg(feature = "threaded")]
+    #[arg(
+        long,
+        help = "Run the program by translating blocks of its text segment to threaded code,
+which is much faster for long simulations. Has no effect with -p or tracing."
+    )]
+    threaded: bool,
+    #[arg(required_unless_present = "batch", default_value = "")]
     file: String,
+    #[arg(
+        value_name = "ARGS",
+        help = "Arguments passed to the program, after `--` if any begin with `-`. The
+program's argc counts the file as well, which is argv[0]."
+    )]
     program_args: Vec<String>,
 }
 
@@ -194,21 +607,191 @@ impl SimArgs {
             force_dump: false,
             heap_size: 0,
             max_inst: 0,
+            timeout: None,
             no_kern_clobber: false,
             long_lines: false,
             error_dump: false,
             reg_nums: false,
             inst_stats: false,
             stack_size: 8,
+            page_size: PAGE_SIZE,
+            mem_limit: None,
             trace: false,
             force_exec: false,
+            trace_file: None,
+            trace_from: None,
+            trace_to: None,
+            trace_format: TraceFormat::Text,
+            snapshot_interval: 1000,
+            tui: false,
+            record: None,
+            replay: None,
+            checkpoint: None,
+            resume: None,
+            core: None,
+            debug_core: None,
+            until: None,
+            profile: false,
+            profile_folded: None,
+            call_graph: false,
+            call_graph_dot: None,
+            coverage: false,
+            coverage_file: None,
+            heatmap: false,
+            heatmap_bucket: None,
+            livelock: None,
+            timing: false,
+            timing_config: None,
+            icache: None,
+            dcache: None,
+            l2cache: None,
+            predictor: None,
+            btb_size: 0,
+            layout: LayoutArgs::default(),
+            endian: Endian::Big,
+            syscall_plugin: vec![],
+            stdin: None,
+            stdin_str: None,
+            expect: None,
+            cases: None,
+            batch: None,
+            jobs: 1,
+            seed: None,
+            clobber_seed: None,
+            syscalls: SyscallConvention::R2k,
+            isa: Isa::Mips1,
+            dump_mem: vec![],
+            #[cfg(feature = "threaded")]
+            threaded: false,
             file: String::new(),
             program_args: vec![],
         }
     }
+
+    /// The input given by `--stdin` or `--stdin-str`, if either is
+    fn stdin_input(&self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(s) = &self.stdin_str {
+            return Ok(Some(s.clone().into_bytes()));
+        }
+        match &self.stdin {
+            Some(path) => fs::read(path)
+                .map(Some)
+                .map_err(|e| format!("cannot read `{}`: {}", path, e)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parses `--page-size`, which must be a power of two no larger than the
+/// pages sections are laid out in
+fn parse_page_size(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(n) if n.is_power_of_two() && (256..=PAGE_SIZE).contains(&n) => Ok(n),
+        _ => Err(format!(
+            "invalid page size `{}`, expected a power of two from 256 to {}",
+            s, PAGE_SIZE
+        )),
+    }
+}
+
+/// Parses `--stdin-str`, replacing its escapes with the characters they
+/// stand for
+fn parse_stdin_str(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('\\') => '\\',
+            Some(c) => return Err(format!("unknown escape `\\{}`", c)),
+            None => return Err(String::from("`\\` at the end of the string")),
+        });
+    }
+    Ok(out)
+}
+
+/// Writes the memory dumps given by `--dump-mem`, each file once, with the
+/// ranges to go to it in order
+fn dump_ranges(exec: &Exec, ranges: &[DumpRange]) {
+    let mut files: Vec<(Option<&str>, String)> = vec![];
+    for range in ranges {
+        let dump = exec.resolve(&range.start).and_then(|start| {
+            let end = exec.resolve(&range.end)?;
+            if end < start {
+                return Err(format!(
+                    "`{}..{}` ends before it starts",
+                    range.start, range.end
+                ));
+            }
+            Ok(format!(
+                "\nMemory 0x{:08x}..0x{:08x}:\n{}",
+                start,
+                end,
+                exec.dump_memory(start, end)
+            ))
+        });
+        let dump = match dump {
+            Ok(dump) => dump,
+            Err(e) => {
+                eprintln!("--dump-mem: {}", e);
+                continue;
+            }
+        };
+        let file = range.file.as_deref();
+        match files.iter_mut().find(|(f, _)| *f == file) {
+            Some((_, text)) => text.push_str(&dump),
+            None => files.push((file, dump)),
+        }
+    }
+    for (file, text) in files {
+        match file {
+            Some(path) => {
+                if let Err(e) = fs::write(path, text.trim_start()) {
+                    eprintln!("cannot write `{}`: {}", path, e);
+                }
+            }
+            None => eprint!("{}", text),
+        }
+    }
+}
+
+/// Loads the core file at `path` into `exec`, checking that it was written
+/// by `program`, and returns why the program terminated
+fn load_core(exec: &mut Exec, program: &ObjectModule, path: &str) -> Result<String, String> {
+    let data = fs::read(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+    let module = ObjectModule::from_slice_u8(&data).map_err(|e| format!("`{}`: {}", path, e))?;
+    let Some(core) = module.core else {
+        return Err(format!("`{}` is not a core file", path));
+    };
+    if module.text != program.text {
+        return Err(format!("`{}` is the core of another program", path));
+    }
+    exec.load_core(&core)
+        .map_err(|e| format!("`{}`: {}", path, e))?;
+    Ok(core.reason)
 }
 
-pub fn sim(args: &SimArgs) {
+/// Where the program stopped for `--until`
+fn reached(exec: &Exec) -> String {
+    format!(
+        "stopped after {} instructions at {}",
+        exec.steps(),
+        exec.disassemble(exec.pc())
+    )
+}
+
+/// Runs the executable, returning the status rtool should exit with: the
+/// program's own exit code, 124 if `--timeout` stopped it, or 1 if it was
+/// terminated by an exception.
+pub fn sim(args: &SimArgs) -> i32 {
+    if let Some(path) = &args.batch {
+        return batch::run_batch(args, path);
+    }
     let om = ObjectModule::from_slice_u8(
         fs::read(args.file.as_str())
             .expect("Failed to read object module file")
@@ -216,11 +799,127 @@ pub fn sim(args: &SimArgs) {
     )
     .expect("Invalid object module file");
 
-    let exec = Exec::new(om, args).expect("");
+    if let Some(path) = &args.cases {
+        return grade::run_cases(om, args, path);
+    }
 
-    if !args.debug {
+    // the core file is a copy of the program with the core after it
+    let program = (args.core.is_some() || args.debug_core.is_some()).then(|| om.clone());
+    let mut exec = match Exec::new(om, args) {
+        Ok(exec) => {
+            println!(
+                "Creating new Execution ctx with entrypoint @ 0x{:08x}",
+                exec.pc()
+            );
+            exec
+        }
+        Err(e) => {
+            eprintln!("{}: {}", args.file, e);
+            return 1;
+        }
+    };
+
+    if let Some(path) = &args.resume {
+        if let Err(e) = exec.load_checkpoint(path) {
+            eprintln!("{}", e);
+            return 1;
+        }
+    }
+    if let (Some(path), Some(program)) = (&args.debug_core, &program) {
+        match load_core(&mut exec, program, path) {
+            Ok(reason) => eprintln!("{}", reason),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    }
+    match args.stdin_input() {
+        Ok(Some(input)) => exec.set_stdin(&input),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    }
+    if args.expect.is_some() {
+        exec.capture_output();
+    }
+
+    if let Some(path) = &args.replay {
+        match record::load(path) {
+            Ok(inputs) => exec.replay(inputs),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    }
+
+    if !args.debug && args.debug_core.is_none() {
         let e = exec.run().unwrap_err();
+        if let (Some(path), true) = (&args.checkpoint, exec.interrupted()) {
+            match exec.save_checkpoint(path) {
+                Ok(()) => eprintln!(
+                    "stopped after {} instructions; resume with --resume {}",
+                    exec.steps(),
+                    path
+                ),
+                Err(e) => eprintln!("{}", e),
+            }
+        } else if exec.reached_until() {
+            eprintln!("{}", reached(&exec));
+        } else if !exec.exited() {
+            eprintln!("{}", e);
+            eprint!("{}", exec.backtrace());
+            eprint!("{}", exec.recent());
+            if let (Some(path), Some(program)) = (&args.core, program) {
+                let core = ObjectModule {
+                    core: Some(exec.core_info(&e.to_string())),
+                    ..program
+                };
+                match fs::write(path, core.to_vec_u8()) {
+                    Ok(()) => eprintln!("core written to {}", path),
+                    Err(e) => eprintln!("cannot write `{}`: {}", path, e),
+                }
+            }
+        }
+        if args.force_dump || exec.reached_until() || (args.error_dump && !exec.exited()) {
+            eprint!("{}", exec.dump());
+        }
     } else {
-        todo!("Debugger not implemented");
+        if args.until.is_some() {
+            let e = exec.run().unwrap_err();
+            match exec.resume_until() {
+                true => eprintln!("{}", reached(&exec)),
+                false => eprintln!("{}", e),
+            }
+        }
+        exec = Debugger::new(exec, args).run();
+        if args.force_dump || (args.error_dump && exec.terminated() && !exec.exited()) {
+            eprint!("{}", exec.dump());
+        }
+    }
+    dump_ranges(&exec, &args.dump_mem);
+
+    if let Some(path) = &args.record {
+        if let Err(e) = record::save(path, exec.inputs()) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(path) = &args.expect {
+        let _ = std::io::stderr().write_all(&exec.take_stderr());
+        if !grade::check(path, &exec.take_stdout()) {
+            return 1;
+        }
+    }
+
+    match exec.exit_code() {
+        Some(code) => code as i32,
+        None if exec.timed_out() => 124,
+        None if args.debug_core.is_some() || exec.reached_until() => 0,
+        None if exec.terminated() => 1,
+        None => 0,
     }
 }
@@ -0,0 +1,69 @@
+use std::process::Stdio;
+
+use super::{host, Exec, Register};
+
+/// A syscall carried out by a host command, given to `--syscall-plugin` as
+/// `NUMBER=COMMAND`. The command is run by the host's shell with `$a0` to
+/// `$a3` as its arguments, in decimal, and `RTOOL_SYSCALL` set to the syscall number. It
+/// may print up to two integers, which are returned in `$v0` and `$v1`; if it
+/// fails, the program is terminated.
+#[derive(Clone, Debug)]
+pub struct SyscallPlugin {
+    number: u32,
+    command: String,
+}
+
+impl SyscallPlugin {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("invalid syscall plugin `{}`, expected NUMBER=COMMAND", spec);
+        let (number, command) = spec.split_once('=').ok_or_else(bad)?;
+        let number = number.trim().parse().map_err(|_| bad())?;
+        if command.trim().is_empty() {
+            return Err(bad());
+        }
+        Ok(Self {
+            number,
+            command: command.to_string(),
+        })
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Runs the command for a syscall made by `exec`
+    pub fn call(&self, exec: &mut Exec) -> Result<(), String> {
+        let args = [Register::A0, Register::A1, Register::A2, Register::A3]
+            .map(|reg| exec.reg(reg).to_string());
+        let out = host::shell(&self.command)
+            .args(args)
+            .env("RTOOL_SYSCALL", self.number.to_string())
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("cannot run `{}`: {}", self.command, e))?;
+        if !out.status.success() {
+            return Err(format!("`{}` failed with {}", self.command, out.status));
+        }
+
+        let out = String::from_utf8_lossy(&out.stdout);
+        let values = out.split_whitespace().collect::<Vec<_>>();
+        if values.len() > 2 {
+            return Err(format!(
+                "`{}` printed more than two values: {}",
+                self.command,
+                out.trim()
+            ));
+        }
+        for (reg, val) in [Register::V0, Register::V1].into_iter().zip(values) {
+            let val = match val.parse::<i32>() {
+                Ok(v) => v as u32,
+                Err(_) => val
+                    .parse::<u32>()
+                    .map_err(|_| format!("`{}` printed `{}`, not an integer", self.command, val))?,
+            };
+            exec.set_reg(reg, val);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+
+use crate::common::{
+    instruction::{opcodes::*, Encoding},
+    Instruction,
+};
+
+/// How conditional branches are predicted by `--predictor`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PredictorKind {
+    /// Every branch is predicted taken
+    Taken,
+    /// Every branch is predicted not taken
+    NotTaken,
+    /// Each branch is predicted to go the way it did last time
+    OneBit,
+    /// Each branch has a two-bit saturating counter, which has to be wrong
+    /// twice in a row before its prediction changes
+    TwoBit,
+}
+
+/// Predictions and outcomes of one branch
+#[derive(Clone, Copy, Default)]
+struct Site {
+    executed: u64,
+    taken: u64,
+    mispredicted: u64,
+}
+
+/// An entry in the branch target buffer
+#[derive(Clone, Copy)]
+struct Entry {
+    pc: u32,
+    /// The one or two bit history; predicted taken from `TAKEN` up
+    state: u8,
+}
+
+/// The lowest two-bit counter state, and the one-bit state, predicting taken
+const TAKEN: u8 = 2;
+
+/// Branch prediction statistics, kept when `--predictor` is given. The
+/// dynamic predictors keep their history in a direct-mapped branch target
+/// buffer, so branches which share an entry evict each other, and a branch
+/// which is not in it is predicted not taken.
+#[derive(Clone)]
+pub struct Predictor {
+    kind: PredictorKind,
+    /// The branch target buffer, or None if it has an entry for every branch
+    btb: Option<Vec<Option<Entry>>>,
+    history: HashMap<u32, u8>,
+    sites: HashMap<u32, Site>,
+}
+
+impl Predictor {
+    /// A predictor of the given kind, with a branch target buffer of
+    /// `btb_size` entries, or an unlimited one if that is 0
+    pub fn new(kind: PredictorKind, btb_size: u32) -> Self {
+        Self {
+            kind,
+            btb: (btb_size > 0).then(|| vec![None; btb_size as usize]),
+            history: HashMap::new(),
+            sites: HashMap::new(),
+        }
+    }
+
+    /// The history of the branch at `pc`, if it is in the branch target
+    /// buffer
+    fn state(&mut self, pc: u32) -> Option<&mut u8> {
+        match &mut self.btb {
+            Some(btb) => {
+                let idx = (pc >> 2) as usize % btb.len();
+                btb[idx]
+                    .as_mut()
+                    .filter(|e| e.pc == pc)
+                    .map(|e| &mut e.state)
+            }
+            None => self.history.get_mut(&pc),
+        }
+    }
+
+    /// Puts the branch at `pc` in the branch target buffer, evicting
+    /// whatever was in its entry
+    fn insert(&mut self, pc: u32, state: u8) {
+        match &mut self.btb {
+            Some(btb) => {
+                let idx = (pc >> 2) as usize % btb.len();
+                btb[idx] = Some(Entry { pc, state });
+            }
+            None => {
+                self.history.insert(pc, state);
+            }
+        }
+    }
+
+    /// Predicts the instruction at `pc` if it is a conditional branch, and
+    /// records whether it was `taken`
+    pub fn record(&mut self, pc: u32, inst: Instruction, taken: bool) {
+        if !inst.encoding().is_branch() {
+            return;
+        }
+        let kind = self.kind;
+        let predicted = match kind {
+            PredictorKind::Taken => true,
+            PredictorKind::NotTaken => false,
+            PredictorKind::OneBit | PredictorKind::TwoBit => match self.state(pc) {
+                Some(state) => {
+                    let predicted = *state >= TAKEN;
+                    *state = match (kind, taken) {
+                        (PredictorKind::OneBit, true) => TAKEN,
+                        (PredictorKind::OneBit, false) => 0,
+                        (_, true) => (*state + 1).min(3),
+                        (_, false) => state.saturating_sub(1),
+                    };
+                    predicted
+                }
+                None => {
+                    // a new entry starts off weakly in the direction taken
+                    let state = match (kind, taken) {
+                        (PredictorKind::TwoBit, false) => TAKEN - 1,
+                        (_, false) => 0,
+                        (_, true) => TAKEN,
+                    };
+                    self.insert(pc, state);
+                    false
+                }
+            },
+        };
+
+        let site = self.sites.entry(pc).or_default();
+        site.executed += 1;
+        site.taken += taken as u64;
+        site.mispredicted += (predicted != taken) as u64;
+    }
+
+    /// The prediction report: overall accuracy, then each branch executed,
+    /// disassembled by `disassemble`, the worst predicted first
+    pub fn report(&self, disassemble: impl Fn(u32) -> String) -> String {
+        let accuracy = |site: &Site| match site.executed {
+            0 => 0.0,
+            n => (n - site.mispredicted) as f64 * 100.0 / n as f64,
+        };
+        let total = self.sites.values().fold(Site::default(), |t, s| Site {
+            executed: t.executed + s.executed,
+            taken: t.taken + s.taken,
+            mispredicted: t.mispredicted + s.mispredicted,
+        });
+        let btb = match &self.btb {
+            Some(btb) => format!(", {} entry BTB", btb.len()),
+            None => String::new(),
+        };
+        let mut out = format!(
+            "\nBranch prediction ({}{}): {} of {} branches predicted ({:.2}%)\n",
+            self.kind.to_possible_value().unwrap().get_name(),
+            btb,
+            total.executed - total.mispredicted,
+            total.executed,
+            accuracy(&total)
+        );
+
+        let mut sites = self.sites.iter().collect::<Vec<_>>();
+        sites.sort_by_key(|(pc, s)| (std::cmp::Reverse(s.mispredicted), **pc));
+        out.push_str(&format!(
+            "  {:>12}{:>12}{:>14}{:>10}\n",
+            "executed", "taken", "mispredicted", "accuracy"
+        ));
+        for (pc, site) in sites {
+            out.push_str(&format!(
+                "  {:>12}{:>12}{:>14}{:>9.2}%  {}\n",
+                site.executed,
+                site.taken,
+                site.mispredicted,
+                accuracy(site),
+                disassemble(*pc)
+            ));
+        }
+        out
+    }
+}
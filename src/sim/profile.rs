@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+
+use super::{callgraph::CallGraph, symbols::Symbols, Layout};
+
+/// Number of addresses listed in the hot-spot report
+const HOTTEST: usize = 10;
+
+/// Instruction counts per address, kept when `--profile` is given. The
+/// counts per call stack are kept by the `CallGraph`.
+#[derive(Clone)]
+pub struct Profile {
+    text_start: u32,
+    data_start: u32,
+    /// Counts by text word
+    counts: Vec<u64>,
+}
+
+impl Profile {
+    pub fn new(layout: &Layout) -> Self {
+        Self {
+            text_start: layout.text_start,
+            data_start: layout.data_start,
+            counts: vec![],
+        }
+    }
+
+    /// Counts the instruction at `pc`
+    pub fn record(&mut self, pc: u32) {
+        if (self.text_start..self.data_start).contains(&pc) {
+            let idx = ((pc - self.text_start) / 4) as usize;
+            if idx >= self.counts.len() {
+                self.counts.resize(idx + 1, 0);
+            }
+            self.counts[idx] += 1;
+        }
+    }
+
+    /// The hot-spot report: instruction counts by function, as found by
+    /// `calls`, then the most executed instructions, disassembled by
+    /// `disassemble`
+    pub fn report(
+        &self,
+        calls: &CallGraph,
+        symbols: &Symbols,
+        disassemble: impl Fn(u32) -> String,
+    ) -> String {
+        let total = self.counts.iter().sum::<u64>();
+        let pct = |n: u64| match total {
+            0 => 0.0,
+            t => n as f64 * 100.0 / t as f64,
+        };
+        let addrs = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(idx, n)| (self.text_start + idx as u32 * 4, *n));
+
+        let mut by_function = BTreeMap::<u32, u64>::new();
+        for (addr, n) in addrs.clone() {
+            *by_function.entry(calls.function_of(addr)).or_default() += n;
+        }
+        let mut by_function = by_function.into_iter().collect::<Vec<_>>();
+        by_function.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+
+        let mut out = format!("\nProfile ({} instructions):\n", total);
+        for (func, n) in by_function {
+            let name = CallGraph::name(func, symbols);
+            out.push_str(&format!("  {:<30}{:>12} {:>6.2}%\n", name, n, pct(n)));
+        }
+
+        let mut hottest = addrs.collect::<Vec<_>>();
+        hottest.sort_by_key(|(addr, n)| (std::cmp::Reverse(*n), *addr));
+        out.push_str("\nHottest instructions:\n");
+        for (addr, n) in hottest.into_iter().take(HOTTEST) {
+            out.push_str(&format!(
+                "  {:>12} {:>6.2}%  {}\n",
+                n,
+                pct(n),
+                disassemble(addr)
+            ));
+        }
+        out
+    }
+}
@@ -0,0 +1,50 @@
+/// A pseudo-random number generator for the random syscalls: xorshift64*,
+/// with its state scrambled from the seed by splitmix64 so that similar seeds
+/// give unrelated sequences.
+#[derive(Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        // xorshift gets stuck at 0
+        Self {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    /// The generator's state, which `from_state` continues from
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn from_state(state: u64) -> Self {
+        Self {
+            state: state.max(1),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32
+    }
+
+    /// A number from 0 up to, but not including, `bound`, which must not be 0
+    pub fn below(&mut self, bound: u32) -> u32 {
+        // reject the top of the range which would favour small numbers
+        let limit = u32::MAX - u32::MAX % bound;
+        loop {
+            let n = self.next_u32();
+            if n < limit {
+                return n % bound;
+            }
+        }
+    }
+}
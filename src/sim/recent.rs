@@ -0,0 +1,77 @@
+use super::{mem::Memory, symbols::Symbols};
+use crate::common::{register_name, Instruction};
+
+/// Instructions kept by `Recent`, a power of two
+const RECENT: usize = 16;
+
+/// The addresses of the last instructions executed, which are always kept
+/// so that they can be printed when the program terminates abnormally,
+/// without tracing. Only the addresses are kept, as anything more slows the
+/// simulator noticeably; the instructions are read back from memory.
+#[derive(Clone)]
+pub struct Recent {
+    pcs: [u32; RECENT],
+    /// Instructions recorded, of which the last `RECENT` are kept
+    count: usize,
+}
+
+impl Recent {
+    pub fn new() -> Self {
+        Self {
+            pcs: [0; RECENT],
+            count: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, pc: u32) {
+        self.pcs[self.count % RECENT] = pc;
+        self.count = self.count.wrapping_add(1);
+    }
+
+    /// The instructions, oldest first, with their addresses and words,
+    /// followed by the registers they name as they are now
+    pub fn report(&self, mem: &Memory, symbols: &Symbols, regs: &[u32; 32]) -> String {
+        let mut out = String::from("\nLast instructions executed:\n");
+        let mut named = [false; 32];
+        let kept = self.count.min(RECENT);
+        for i in self.count - kept..self.count {
+            let pc = self.pcs[i % RECENT];
+            let name = symbols
+                .describe(pc, true)
+                .map_or(String::new(), |n| format!(" <{}>", n));
+            let at = format!("0x{:08x}{}", pc, name);
+            let Ok(word) = mem.read_word(pc) else {
+                out.push_str(&format!("  {}\n", at));
+                continue;
+            };
+            let text = match Instruction::try_from(word) {
+                Ok(inst) => {
+                    let regs = match inst {
+                        Instruction::R { rs, rt, rd, .. } => vec![rs, rt, rd],
+                        Instruction::I { rs, rt, .. } => vec![rs, rt],
+                        Instruction::J { .. } => vec![],
+                    };
+                    for r in regs {
+                        named[r as usize] = true;
+                    }
+                    inst.to_string()
+                }
+                Err(_) => String::from("<invalid>"),
+            };
+            out.push_str(&format!("  {:<24} {:08x}  {}\n", at, word, text));
+        }
+
+        let named = (1..32)
+            .filter(|r| named[*r])
+            .map(|r| format!("${:<4} 0x{:08x}", register_name(r as u8), regs[r]))
+            .collect::<Vec<_>>();
+        if !named.is_empty() {
+            out.push_str("Their registers now:\n");
+        }
+        for line in named.chunks(4) {
+            out.push_str(&format!("  {}\n", line.join("   ")));
+        }
+        out
+    }
+}
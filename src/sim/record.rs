@@ -0,0 +1,93 @@
+use std::fs;
+
+/// The kinds of nondeterministic input a program can receive, which are
+/// recorded by `--record` and fed back by `--replay`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    /// A line of stdin (`read_int`, `read_string`)
+    Line,
+    /// One byte of stdin (`read_char`)
+    Byte,
+    /// The result of a `read` from a file
+    Read,
+    /// The host clock
+    Clock,
+}
+
+impl InputKind {
+    fn name(&self) -> &'static str {
+        match self {
+            InputKind::Line => "line",
+            InputKind::Byte => "byte",
+            InputKind::Read => "read",
+            InputKind::Clock => "clock",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            InputKind::Line,
+            InputKind::Byte,
+            InputKind::Read,
+            InputKind::Clock,
+        ]
+        .into_iter()
+        .find(|k| k.name() == name)
+    }
+}
+
+/// One input received by the program
+#[derive(Clone, Debug)]
+pub struct Input {
+    /// Instructions executed before the input was read
+    pub step: u64,
+    pub kind: InputKind,
+    /// What was read, or None if reading failed
+    pub bytes: Option<Vec<u8>>,
+}
+
+/// Writes inputs to a record file, one per line as `kind step hex`, with `-`
+/// for a failed read.
+pub fn save(path: &str, inputs: &[Input]) -> Result<(), String> {
+    let text = inputs
+        .iter()
+        .map(|i| {
+            let bytes = match &i.bytes {
+                Some(b) => b.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+                None => String::from("-"),
+            };
+            format!("{} {} {}\n", i.kind.name(), i.step, bytes)
+        })
+        .collect::<String>();
+    fs::write(path, text).map_err(|e| format!("cannot write `{}`: {}", path, e))
+}
+
+/// Reads a file written by `save`
+pub fn load(path: &str) -> Result<Vec<Input>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(idx, l)| {
+            let bad = || format!("{}:{}: invalid record `{}`", path, idx + 1, l);
+            let mut fields = l.split(' ');
+            let kind = fields
+                .next()
+                .and_then(InputKind::from_name)
+                .ok_or_else(bad)?;
+            let step = fields.next().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+            let bytes = match fields.next().unwrap_or("") {
+                "-" => None,
+                hex if hex.len() % 2 == 0 => Some(
+                    (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| bad())?,
+                ),
+                _ => return Err(bad()),
+            };
+            Ok(Input { step, kind, bytes })
+        })
+        .collect()
+}
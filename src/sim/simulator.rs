@@ -0,0 +1,172 @@
+//! A simulator for other programs to drive, one instruction at a time, with
+//! callbacks around each instruction and syscall.
+//!
+//! ```no_run
+//! use rtool::{common::ObjectModule, sim::{Register, Simulator, SyscallAction}};
+//!
+//! let bytes = std::fs::read("prog.out").unwrap();
+//! let module = ObjectModule::from_slice_u8(&bytes).unwrap();
+//! let mut sim = Simulator::new(module).unwrap();
+//! sim.set_stdin(b"42\n");
+//! sim.on_syscall(|cpu, code| match code {
+//!     100 => {
+//!         cpu.set_reg(Register::V0, 7);
+//!         SyscallAction::Handled
+//!     }
+//!     _ => SyscallAction::Continue,
+//! });
+//! let _ = sim.run();
+//! println!("{}", String::from_utf8_lossy(&sim.take_stdout()));
+//! ```
+
+use super::{Exec, Register, EMPTY_ARGS};
+use crate::common::{instruction::opcodes::FUNCT_SYSCALL, Error, Instruction, ObjectModule};
+
+/// What a syscall callback did with the syscall
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallAction {
+    /// Let the next callback, or the simulator, carry it out
+    Continue,
+    /// The callback carried it out, so nothing else does
+    Handled,
+}
+
+/// Called with the machine, the pc and the instruction there
+type InstructionHook = Box<dyn FnMut(&mut Exec<'static>, u32, Instruction)>;
+/// Called with the machine and the syscall number in `$v0`
+type SyscallHook = Box<dyn FnMut(&mut Exec<'static>, u32) -> SyscallAction>;
+
+/// A program loaded into a simulated CPU. Output is kept rather than written
+/// to stdout, and input is read from stdin unless some is given with
+/// `set_stdin`.
+pub struct Simulator {
+    cpu: Exec<'static>,
+    pre_instruction: Vec<InstructionHook>,
+    post_instruction: Vec<InstructionHook>,
+    syscall: Vec<SyscallHook>,
+}
+
+impl Simulator {
+    /// Loads an executable module, with the default memory layout
+    pub fn new(module: ObjectModule) -> Result<Self, String> {
+        let mut cpu = Exec::new(module, &EMPTY_ARGS)?;
+        cpu.capture_output();
+        Ok(Self {
+            cpu,
+            pre_instruction: vec![],
+            post_instruction: vec![],
+            syscall: vec![],
+        })
+    }
+
+    /// The registers and memory of the program
+    pub fn cpu(&self) -> &Exec<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Exec<'static> {
+        &mut self.cpu
+    }
+
+    /// Calls `hook` before each instruction is executed
+    pub fn on_pre_instruction(
+        &mut self,
+        hook: impl FnMut(&mut Exec<'static>, u32, Instruction) + 'static,
+    ) {
+        self.pre_instruction.push(Box::new(hook));
+    }
+
+    /// Calls `hook` after each instruction is executed, unless it raised an
+    /// exception which terminated the program
+    pub fn on_post_instruction(
+        &mut self,
+        hook: impl FnMut(&mut Exec<'static>, u32, Instruction) + 'static,
+    ) {
+        self.post_instruction.push(Box::new(hook));
+    }
+
+    /// Calls `hook` before each syscall is carried out, in the order the
+    /// callbacks were added, until one handles it
+    pub fn on_syscall(
+        &mut self,
+        hook: impl FnMut(&mut Exec<'static>, u32) -> SyscallAction + 'static,
+    ) {
+        self.syscall.push(Box::new(hook));
+    }
+
+    /// Has `handler` carry out syscall `code`, which must not be a builtin
+    /// one. Unlike `on_syscall`, it stays with the CPU if the CPU is cloned.
+    pub fn register_syscall(
+        &mut self,
+        code: u32,
+        handler: impl Fn(&mut Exec<'static>) -> Result<(), String> + 'static,
+    ) -> Result<(), String> {
+        self.cpu.register_syscall(code, handler)
+    }
+
+    /// Executes one instruction, returning the exception which terminated
+    /// the program if it did
+    pub fn step(&mut self) -> Result<(), Error> {
+        let pc = self.cpu.pc();
+        let Some(inst) = self
+            .cpu
+            .read_word(pc)
+            .ok()
+            .and_then(|word| Instruction::try_from(word).ok())
+        else {
+            // let the simulator raise the exception
+            return self.cpu.step();
+        };
+
+        for hook in &mut self.pre_instruction {
+            hook(&mut self.cpu, pc, inst);
+        }
+        let handled = matches!(
+            inst,
+            Instruction::R {
+                funct: FUNCT_SYSCALL,
+                ..
+            }
+        ) && {
+            let code = self.cpu.reg(Register::V0);
+            self.syscall
+                .iter_mut()
+                .any(|hook| hook(&mut self.cpu, code) == SyscallAction::Handled)
+        };
+        match handled {
+            true => self.cpu.skip(),
+            false => self.cpu.step()?,
+        }
+        for hook in &mut self.post_instruction {
+            hook(&mut self.cpu, pc, inst);
+        }
+        Ok(())
+    }
+
+    /// Runs the program until it terminates, returning the exception which
+    /// terminated it, which is `Exit` if it exited
+    pub fn run(&mut self) -> Error {
+        loop {
+            if let Err(e) = self.step() {
+                return e;
+            }
+        }
+    }
+
+    /// Gives the program `input` to read instead of stdin
+    pub fn set_stdin(&mut self, input: &[u8]) {
+        self.cpu.set_stdin(input);
+    }
+
+    /// The output the program has written to stdout since this was last
+    /// called
+    pub fn take_stdout(&mut self) -> Vec<u8> {
+        self.cpu.take_stdout()
+    }
+
+    /// The output the program has written to stderr since this was last
+    /// called
+    pub fn take_stderr(&mut self) -> Vec<u8> {
+        self.cpu.take_stderr()
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::common::{
+    instruction::{mnemonic, Encoding},
+    Instruction,
+};
+
+/// Counts of executed instructions, printed at the end of the simulation when
+/// `-p` is given.
+#[derive(Clone, Default)]
+pub struct Stats {
+    total: u64,
+    loads: u64,
+    stores: u64,
+    branches_taken: u64,
+    branches_not_taken: u64,
+    jumps: u64,
+    syscalls: u64,
+    by_encoding: HashMap<Encoding, u64>,
+}
+
+impl Stats {
+    /// Counts one executed instruction. `moved` is whether it changed the pc,
+    /// which for a branch means it was taken.
+    pub fn record(&mut self, inst: Instruction, moved: bool) {
+        use crate::common::instruction::opcodes::*;
+
+        let enc = inst.encoding();
+        self.total += 1;
+        *self.by_encoding.entry(enc).or_default() += 1;
+        match enc {
+            Encoding::I(OP_LB..=OP_LWR) => self.loads += 1,
+            Encoding::I(OP_SB..=OP_SWR) => self.stores += 1,
+            enc if enc.is_branch() => match moved {
+                true => self.branches_taken += 1,
+                false => self.branches_not_taken += 1,
+            },
+            Encoding::J(_) | Encoding::R(FUNCT_JR | FUNCT_JALR) => self.jumps += 1,
+            Encoding::R(FUNCT_SYSCALL) => self.syscalls += 1,
+            _ => {}
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pct = |n: u64| match self.total {
+            0 => 0.0,
+            t => n as f64 * 100.0 / t as f64,
+        };
+        let row = |f: &mut std::fmt::Formatter<'_>, name: &str, n: u64| {
+            writeln!(f, "  {:<20}{:>12} {:>6.2}%", name, n, pct(n))
+        };
+
+        writeln!(f, "\nInstruction statistics:")?;
+        writeln!(f, "  {:<20}{:>12}", "total", self.total)?;
+        row(f, "loads", self.loads)?;
+        row(f, "stores", self.stores)?;
+        row(f, "branches taken", self.branches_taken)?;
+        row(f, "branches not taken", self.branches_not_taken)?;
+        row(f, "jumps", self.jumps)?;
+        row(f, "syscalls", self.syscalls)?;
+
+        writeln!(f, "\nBy instruction:")?;
+        let mut counts = self
+            .by_encoding
+            .iter()
+            .map(|(enc, n)| (mnemonic(*enc).unwrap_or("unknown"), *n))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (name, n) in counts {
+            row(f, name, n)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use crate::common::{ObjectModule, SYM_DEF, SYM_LBL};
+
+/// The labels of an executable, sorted by address, for describing addresses
+/// in traces and diagnostics.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    syms: Vec<(u32, String)>,
+}
+
+impl Symbols {
+    pub fn from_module(module: &ObjectModule) -> Self {
+        let mut syms = module
+            .symtab
+            .iter()
+            .filter(|s| s.has_flags(SYM_DEF | SYM_LBL))
+            .filter_map(|s| {
+                let name = module.get_str_entry(s.str_off as usize)?;
+                Some((s.val, name.to_string_lossy().into_owned()))
+            })
+            .collect::<Vec<_>>();
+        syms.sort();
+        Self { syms }
+    }
+
+    /// The address of the label `name`
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.syms.iter().find(|(_, n)| n == name).map(|(a, _)| *a)
+    }
+
+    /// Parses an address given on the command line, either as a number or
+    /// as the name of a label.
+    pub fn resolve(&self, s: &str) -> Result<u32, String> {
+        let num = match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        num.or_else(|| self.address_of(s))
+            .ok_or(format!("`{}` is not an address or a known symbol", s))
+    }
+
+    /// The label at or before `addr`, along with the distance from it
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.syms.partition_point(|(a, _)| *a <= addr);
+        let (a, name) = self.syms.get(idx.checked_sub(1)?)?;
+        Some((name.as_str(), addr - a))
+    }
+
+    /// Describes `addr` as `symbol` if a label is exactly there, or (with
+    /// `all`) as `symbol+offset` from the closest label before it.
+    pub fn describe(&self, addr: u32, all: bool) -> Option<String> {
+        match self.lookup(addr)? {
+            (name, 0) => Some(name.to_string()),
+            (name, off) if all => Some(format!("{}+0x{:x}", name, off)),
+            _ => None,
+        }
+    }
+}
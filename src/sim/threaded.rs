@@ -0,0 +1,302 @@
+//! Threaded-code backend (`--threaded`). Basic blocks of the text segment are
+//! translated into a sequence of closures the first time they are reached,
+//! and run from then on without decoding or dispatching on opcodes.
+//!
+//! Only straightforward instructions are translated. Anything else (syscalls,
+//! trapping arithmetic, multiply and divide, coprocessor instructions) ends the
+//! block and is executed by the interpreter, as is any instruction which
+//! faults, so that exceptions behave exactly as they do there. Only read-only
+//! pages are translated, so code which could be modified is always
+//! interpreted.
+
+use std::ops::Range;
+
+use super::{exec::ExecCtx, mem::Memory, Exec};
+use crate::common::{Error, Instruction};
+
+/// What happens after a translated instruction
+enum Flow {
+    /// Continue with the next instruction in the block
+    Next,
+    /// Leave the block for the given address
+    Jump(u32),
+    /// The instruction could not be completed (it faulted) and must be
+    /// executed by the interpreter, which raises the exception
+    Fault,
+}
+
+type Op = Box<dyn Fn(&mut ExecCtx, &mut Memory) -> Flow>;
+
+/// A run of translated instructions, ending with a branch or jump or just
+/// before an instruction which is left to the interpreter
+struct Block {
+    start: u32,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    /// Runs the block, returning the number of instructions executed, the
+    /// address to continue at, and whether the instruction there must be
+    /// interpreted.
+    fn run(&self, ctx: &mut ExecCtx, mem: &mut Memory) -> (u64, u32, bool) {
+        for (i, op) in self.ops.iter().enumerate() {
+            match op(ctx, mem) {
+                Flow::Next => {}
+                Flow::Jump(target) => return (i as u64 + 1, target, false),
+                Flow::Fault => return (i as u64, self.start + 4 * i as u32, true),
+            }
+        }
+        let len = self.ops.len() as u32;
+        (len as u64, self.start + 4 * len, false)
+    }
+}
+
+#[derive(Default)]
+enum Slot {
+    #[default]
+    Untranslated,
+    /// The instruction here is not translated, so no block starts here
+    Interpret,
+    Block(usize),
+}
+
+/// Blocks translated so far, indexed by the word they start at
+struct Cache {
+    /// The text segment, which is all that is translated
+    text: Range<u32>,
+    slots: Vec<Slot>,
+    blocks: Vec<Block>,
+}
+
+impl Cache {
+    /// The block starting at `pc`, translating it if this is the first time
+    /// it is reached, or None if the instruction must be interpreted
+    fn block(&mut self, mem: &Memory, pc: u32) -> Option<&Block> {
+        if !self.text.contains(&pc) || !pc.is_multiple_of(4) {
+            return None;
+        }
+        let idx = ((pc - self.text.start) / 4) as usize;
+        if idx >= self.slots.len() {
+            self.slots.resize_with(idx + 1, Slot::default);
+        }
+        if let Slot::Untranslated = self.slots[idx] {
+            self.slots[idx] = match translate_block(mem, pc, self.text.end) {
+                Some(block) => {
+                    self.blocks.push(block);
+                    Slot::Block(self.blocks.len() - 1)
+                }
+                None => Slot::Interpret,
+            };
+        }
+        match self.slots[idx] {
+            Slot::Block(b) => Some(&self.blocks[b]),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the program until it terminates, as `Exec::run` does
+pub(super) fn run(exec: &mut Exec) -> Result<(), Error> {
+    let layout = exec.layout();
+    let mut cache = Cache {
+        text: layout.text_start..layout.data_start,
+        slots: vec![],
+        blocks: vec![],
+    };
+    loop {
+        let (ctx, mem, steps) = exec.machine();
+        let interpret = match cache.block(mem, ctx.pc) {
+            Some(block) => {
+                let (executed, next, fault) = block.run(ctx, mem);
+                *steps += executed;
+                ctx.pc = next;
+                fault
+            }
+            None => true,
+        };
+        if interpret {
+            exec.step()?;
+        }
+    }
+}
+
+/// Translates instructions from `start` up to the first one which is not
+/// translated or the end of the text segment, or None if that is the first
+fn translate_block(mem: &Memory, start: u32, end: u32) -> Option<Block> {
+    let mut ops = vec![];
+    let mut at = start;
+    while at < end && mem.is_mapped(at) && !mem.is_writable(at) {
+        let Some((op, ends)) = mem
+            .read_word(at)
+            .ok()
+            .and_then(|word| Instruction::try_from(word).ok())
+            .and_then(|inst| translate(inst, at))
+        else {
+            break;
+        };
+        ops.push(op);
+        if ends {
+            break;
+        }
+        at += 4;
+    }
+    (!ops.is_empty()).then_some(Block { start, ops })
+}
+
+/// An instruction which writes `f(registers)` to `rd`, or does nothing if
+/// that is `$zero`
+fn alu(rd: usize, f: impl Fn(&[u32; 32]) -> u32 + 'static) -> Op {
+    match rd {
+        0 => Box::new(|_, _| Flow::Next),
+        _ => Box::new(move |ctx, _| {
+            ctx.reg[rd] = f(&ctx.reg);
+            Flow::Next
+        }),
+    }
+}
+
+/// A conditional branch from `at`, taken when `cond(registers)` holds
+fn branch(at: u32, imm: u16, cond: impl Fn(&[u32; 32]) -> bool + 'static) -> Op {
+    let target = at
+        .wrapping_add(((imm as i16 as i32) << 2) as u32)
+        .wrapping_add(4);
+    Box::new(move |ctx, _| match cond(&ctx.reg) {
+        true => Flow::Jump(target),
+        false => Flow::Next,
+    })
+}
+
+/// A load of `rt` from `rs + imm` using `load`
+fn load(rs: usize, rt: usize, imm: u16, load: fn(&Memory, u32) -> Option<u32>) -> Op {
+    Box::new(move |ctx, mem| {
+        let addr = ctx.reg[rs].wrapping_add(imm as i16 as u32);
+        match load(mem, addr) {
+            Some(v) => {
+                if rt != 0 {
+                    ctx.reg[rt] = v;
+                }
+                Flow::Next
+            }
+            None => Flow::Fault,
+        }
+    })
+}
+
+/// A store of `rt` to `rs + imm` using `store`
+fn store(rs: usize, rt: usize, imm: u16, store: fn(&mut Memory, u32, u32) -> bool) -> Op {
+    Box::new(move |ctx, mem| {
+        let addr = ctx.reg[rs].wrapping_add(imm as i16 as u32);
+        match store(mem, addr, ctx.reg[rt]) {
+            true => Flow::Next,
+            false => Flow::Fault,
+        }
+    })
+}
+
+/// Translates the instruction at `at`, returning it along with whether it
+/// ends the block, or None if it is left to the interpreter. Each one does
+/// exactly what `Exec::exec_instruction` does.
+fn translate(inst: Instruction, at: u32) -> Option<(Op, bool)> {
+    use crate::common::instruction::opcodes::*;
+    let op: Op = match inst {
+        Instruction::R {
+            rs,
+            rt,
+            rd,
+            shamt,
+            funct,
+        } => {
+            let (rs, rt, rd) = (rs as usize, rt as usize, rd as usize);
+            match funct {
+                FUNCT_SLL => alu(rd, move |r| r[rt] << shamt),
+                FUNCT_SRL => alu(rd, move |r| r[rt] >> shamt),
+                FUNCT_SRA => alu(rd, move |r| (r[rt] as i32 >> shamt) as u32),
+                FUNCT_SLLV => alu(rd, move |r| r[rt] << (r[rs] & 0x1F)),
+                FUNCT_SRLV => alu(rd, move |r| r[rt] >> (r[rs] & 0x1F)),
+                FUNCT_SRAV => alu(rd, move |r| (r[rt] as i32 >> (r[rs] & 0x1F)) as u32),
+                FUNCT_ADDU => alu(rd, move |r| r[rs].wrapping_add(r[rt])),
+                FUNCT_SUBU => alu(rd, move |r| r[rs].wrapping_sub(r[rt])),
+                FUNCT_AND => alu(rd, move |r| r[rs] & r[rt]),
+                FUNCT_OR => alu(rd, move |r| r[rs] | r[rt]),
+                FUNCT_XOR => alu(rd, move |r| r[rs] ^ r[rt]),
+                FUNCT_NOR => alu(rd, move |r| !(r[rs] | r[rt])),
+                FUNCT_SLT => alu(rd, move |r| ((r[rs] as i32) < (r[rt] as i32)) as u32),
+                FUNCT_SLTU => alu(rd, move |r| (r[rs] < r[rt]) as u32),
+                FUNCT_MFHI if rd != 0 => Box::new(move |ctx, _| {
+                    ctx.reg[rd] = ctx.hi;
+                    Flow::Next
+                }),
+                FUNCT_MFLO if rd != 0 => Box::new(move |ctx, _| {
+                    ctx.reg[rd] = ctx.lo;
+                    Flow::Next
+                }),
+                FUNCT_MTHI => Box::new(move |ctx, _| {
+                    ctx.hi = ctx.reg[rs];
+                    Flow::Next
+                }),
+                FUNCT_MTLO => Box::new(move |ctx, _| {
+                    ctx.lo = ctx.reg[rs];
+                    Flow::Next
+                }),
+                FUNCT_JR => {
+                    return Some((
+                        Box::new(move |ctx, _| Flow::Jump(ctx.reg[rs].wrapping_add(4))),
+                        true,
+                    ))
+                }
+                FUNCT_JALR => {
+                    let op: Op = Box::new(move |ctx, _| {
+                        ctx.reg[rd] = at;
+                        let target = ctx.reg[rs].wrapping_add(4);
+                        ctx.reg[0] = 0;
+                        Flow::Jump(target)
+                    });
+                    return Some((op, true));
+                }
+                _ => return None,
+            }
+        }
+        Instruction::I { op, rs, rt, imm } => {
+            let (rs, rt) = (rs as usize, rt as usize);
+            match op {
+                OP_BEQ => return Some((branch(at, imm, move |r| r[rs] == r[rt]), true)),
+                OP_BNE => return Some((branch(at, imm, move |r| r[rs] != r[rt]), true)),
+                OP_BLEZ => return Some((branch(at, imm, move |r| r[rs] as i32 <= 0), true)),
+                OP_BGTZ => return Some((branch(at, imm, move |r| r[rs] > 0), true)),
+                OP_ADDIU => alu(rt, move |r| r[rs].wrapping_add(imm as i16 as u32)),
+                OP_SLTI => alu(rt, move |r| ((r[rs] as i32) < (imm as i16 as i32)) as u32),
+                OP_SLTIU => alu(rt, move |r| {
+                    (imm & 0x8000 == 0 && r[rs] < imm as u32) as u32
+                }),
+                OP_ANDI => alu(rt, move |r| r[rs] & imm as u32),
+                OP_ORI => alu(rt, move |r| r[rs] | imm as u32),
+                OP_XORI => alu(rt, move |r| r[rs] ^ imm as u32),
+                OP_LUI => alu(rt, move |_| (imm as u32) << 16),
+                OP_LB => load(rs, rt, imm, |m, a| Some(m.read_byte(a).ok()? as i8 as u32)),
+                OP_LBU => load(rs, rt, imm, |m, a| Some(m.read_byte(a).ok()? as u32)),
+                OP_LH => load(rs, rt, imm, |m, a| Some(m.read_half(a).ok()? as i16 as u32)),
+                OP_LHU => load(rs, rt, imm, |m, a| Some(m.read_half(a).ok()? as u32)),
+                OP_LW => load(rs, rt, imm, |m, a| m.read_word(a).ok()),
+                OP_SB => store(rs, rt, imm, |m, a, v| m.write_byte(a, v as u8).is_ok()),
+                OP_SH => store(rs, rt, imm, |m, a, v| m.write_half(a, v as u16).is_ok()),
+                OP_SW => store(rs, rt, imm, |m, a, v| m.write_word(a, v).is_ok()),
+                _ => return None,
+            }
+        }
+        Instruction::J { op, imm } => {
+            let target = (at & 0xF0000000) | (imm << 2);
+            match op {
+                OP_J => return Some((Box::new(move |_, _| Flow::Jump(target)), true)),
+                OP_JAL => {
+                    let op: Op = Box::new(move |ctx, _| {
+                        ctx.reg[31] = at;
+                        Flow::Jump(target)
+                    });
+                    return Some((op, true));
+                }
+                _ => return None,
+            }
+        }
+    };
+    Some((op, false))
+}
@@ -0,0 +1,220 @@
+use std::fmt::Display;
+
+use super::config::{self, Entry};
+use crate::common::{
+    instruction::{opcodes::*, Encoding},
+    Instruction,
+};
+
+/// Cycle costs of each class of instruction and of each kind of stall, read
+/// from the file given by `--timing-config`, which has `[latency]` and
+/// `[stall]` tables of cycle counts:
+///
+/// ```text
+/// [latency]       # cycles taken to issue an instruction
+/// alu = 1
+/// load = 1
+/// store = 1
+/// branch = 1
+/// jump = 1
+/// mult = 1        # mult and multu
+/// div = 1         # div and divu
+/// syscall = 1
+///
+/// [stall]
+/// load_use = 1    # the next instruction uses the register loaded
+/// branch_taken = 1
+/// mult_busy = 4   # until hi and lo can be read after a multiply
+/// div_busy = 34   # until hi and lo can be read after a divide
+/// ```
+///
+/// Anything not given keeps the value shown.
+#[derive(Clone)]
+pub struct TimingConfig {
+    alu: u64,
+    load: u64,
+    store: u64,
+    branch: u64,
+    jump: u64,
+    mult: u64,
+    div: u64,
+    syscall: u64,
+    load_use: u64,
+    branch_taken: u64,
+    mult_busy: u64,
+    div_busy: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            load: 1,
+            store: 1,
+            branch: 1,
+            jump: 1,
+            mult: 1,
+            div: 1,
+            syscall: 1,
+            load_use: 1,
+            branch_taken: 1,
+            mult_busy: 4,
+            div_busy: 34,
+        }
+    }
+}
+
+impl TimingConfig {
+    /// Reads a config file, as described above
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut table = String::new();
+        for (num, entry) in config::read(path)? {
+            let err = |msg: &str| format!("{}:{}: {}", path, num, msg);
+            let (key, val) = match entry {
+                Entry::Table(name) => {
+                    table = name;
+                    continue;
+                }
+                Entry::Pair(key, val) => (key, val),
+                Entry::ArrayTable(_) => return Err(err("expected `[table]` or `key = value`")),
+            };
+            let val = val
+                .replace('_', "")
+                .parse::<u64>()
+                .map_err(|_| err(&format!("invalid cycle count `{}`", val)))?;
+            let field = match (table.as_str(), key.as_str()) {
+                ("latency", "alu") => &mut config.alu,
+                ("latency", "load") => &mut config.load,
+                ("latency", "store") => &mut config.store,
+                ("latency", "branch") => &mut config.branch,
+                ("latency", "jump") => &mut config.jump,
+                ("latency", "mult") => &mut config.mult,
+                ("latency", "div") => &mut config.div,
+                ("latency", "syscall") => &mut config.syscall,
+                ("stall", "load_use") => &mut config.load_use,
+                ("stall", "branch_taken") => &mut config.branch_taken,
+                ("stall", "mult_busy") => &mut config.mult_busy,
+                ("stall", "div_busy") => &mut config.div_busy,
+                (table, key) => return Err(err(&format!("unknown key `{}.{}`", table, key))),
+            };
+            *field = val;
+        }
+        Ok(config)
+    }
+}
+
+/// Simulated cycle counts, kept when `--timing` is given. Instructions are
+/// issued in order, one at a time, each taking the latency of its class, with
+/// stalls for using a register loaded by the previous instruction, for taken
+/// branches, and for reading hi or lo before a multiply or divide is done.
+#[derive(Clone, Default)]
+pub struct Timing {
+    config: TimingConfig,
+    cycles: u64,
+    instructions: u64,
+    /// The register loaded by the previous instruction
+    loaded: Option<u8>,
+    /// The cycle from which hi and lo can be read
+    hilo_ready: u64,
+    load_use_stalls: u64,
+    branch_stalls: u64,
+    hilo_stalls: u64,
+}
+
+/// The registers read by an instruction, as far as load-use stalls go
+fn reads(inst: Instruction) -> [Option<u8>; 2] {
+    match inst {
+        Instruction::R { funct, rs, rt, .. } => match funct {
+            FUNCT_SLL | FUNCT_SRL | FUNCT_SRA => [Some(rt), None],
+            FUNCT_JR | FUNCT_JALR | FUNCT_MTHI | FUNCT_MTLO => [Some(rs), None],
+            FUNCT_SYSCALL | FUNCT_BREAK | FUNCT_MFHI | FUNCT_MFLO => [None, None],
+            _ => [Some(rs), Some(rt)],
+        },
+        Instruction::I { op, rs, rt, .. } => match op {
+            OP_LUI | OP_COP0 => [None, None],
+            OP_BEQ | OP_BNE | OP_SB..=OP_SWR | OP_SPECIAL2 | OP_SPECIAL3 => [Some(rs), Some(rt)],
+            _ => [Some(rs), None],
+        },
+        Instruction::J { .. } => [None, None],
+    }
+}
+
+impl Timing {
+    pub fn new(config: TimingConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Counts the cycles taken by one executed instruction. `moved` is
+    /// whether it changed the pc, which for a branch means it was taken.
+    pub fn record(&mut self, inst: Instruction, moved: bool) {
+        let c = &self.config;
+        self.instructions += 1;
+
+        if let Some(reg) = self.loaded.take() {
+            if reg != 0 && reads(inst).contains(&Some(reg)) {
+                self.load_use_stalls += c.load_use;
+                self.cycles += c.load_use;
+            }
+        }
+
+        let enc = inst.encoding();
+        if let Encoding::R(FUNCT_MFHI | FUNCT_MFLO | FUNCT_MULT..=FUNCT_DIVU) = enc {
+            let stall = self.hilo_ready.saturating_sub(self.cycles);
+            self.hilo_stalls += stall;
+            self.cycles += stall;
+        }
+
+        self.cycles += match enc {
+            Encoding::I(OP_LB..=OP_LWR) => {
+                if let Instruction::I { rt, .. } = inst {
+                    self.loaded = Some(rt);
+                }
+                c.load
+            }
+            Encoding::I(OP_SB..=OP_SWR) => c.store,
+            enc if enc.is_branch() => {
+                if moved {
+                    self.branch_stalls += c.branch_taken;
+                    c.branch + c.branch_taken
+                } else {
+                    c.branch
+                }
+            }
+            Encoding::J(_) | Encoding::R(FUNCT_JR | FUNCT_JALR) => c.jump,
+            Encoding::R(FUNCT_SYSCALL) => c.syscall,
+            Encoding::R(FUNCT_MULT | FUNCT_MULTU) => {
+                self.hilo_ready = self.cycles + c.mult + c.mult_busy;
+                c.mult
+            }
+            Encoding::R(FUNCT_DIV | FUNCT_DIVU) => {
+                self.hilo_ready = self.cycles + c.div + c.div_busy;
+                c.div
+            }
+            _ => c.alu,
+        };
+    }
+}
+
+impl Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cpi = match self.instructions {
+            0 => 0.0,
+            n => self.cycles as f64 / n as f64,
+        };
+        writeln!(f, "\nTiming:")?;
+        writeln!(f, "  {:<24}{:>12}", "cycles", self.cycles)?;
+        writeln!(f, "  {:<24}{:>12}", "instructions", self.instructions)?;
+        writeln!(f, "  {:<24}{:>12.3}", "CPI", cpi)?;
+        writeln!(f, "  {:<24}{:>12}", "load-use stalls", self.load_use_stalls)?;
+        writeln!(
+            f,
+            "  {:<24}{:>12}",
+            "taken branch stalls", self.branch_stalls
+        )?;
+        writeln!(f, "  {:<24}{:>12}", "hi/lo stalls", self.hilo_stalls)
+    }
+}
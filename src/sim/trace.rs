@@ -0,0 +1,258 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+
+use super::{symbols::Symbols, SimArgs};
+use crate::{
+    asm::json_str,
+    common::{instruction::mnemonic, register_name, Instruction},
+};
+
+/// Width of the address column, enough for `0x00400000 <symbol+0x000>`
+const ADDR_WIDTH: usize = 28;
+
+/// How traced instructions are printed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    /// One line of disassembly per instruction
+    #[default]
+    Text,
+    /// One JSON object per line for each instruction, including the registers
+    /// and memory it changed
+    Json,
+}
+
+/// The registers an instruction may write, compared before and after it runs
+/// to show what it changed
+#[derive(Clone, Copy)]
+pub struct Regs {
+    pub gpr: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+}
+
+/// An instruction traced, waiting for its results
+#[derive(Clone)]
+struct Pending {
+    pc: u32,
+    word: u32,
+    inst: Instruction,
+    mem_addr: Option<u32>,
+    before: Regs,
+    /// The line printed for it in the text format, before the registers it
+    /// changed
+    line: Option<String>,
+}
+
+/// Prints executed instructions when tracing (`-t`).
+#[derive(Clone)]
+pub struct Tracer {
+    out: Arc<Mutex<dyn Write + Send>>,
+    /// Include the raw instruction word (`-c`)
+    machine_word: bool,
+    /// Describe every address relative to a symbol, rather than only exact
+    /// matches (`-a`)
+    interp_address: bool,
+    /// Pad addresses to a fixed width so that instructions line up
+    align: bool,
+    /// Tracing is switched on when execution reaches this address
+    /// (`--trace-from`)...
+    from: Option<u32>,
+    /// ... and off again after the instruction here (`--trace-to`)
+    to: Option<u32>,
+    active: bool,
+    format: TraceFormat,
+    pending: Option<Pending>,
+}
+
+impl Tracer {
+    /// Configures tracing from the command line, resolving `--trace-from` and
+    /// `--trace-to` against the executable's symbols.
+    pub fn new(args: &SimArgs, symbols: &Symbols) -> Result<Self, String> {
+        let from = args
+            .trace_from
+            .as_ref()
+            .map(|s| symbols.resolve(s))
+            .transpose()?;
+        let to = args
+            .trace_to
+            .as_ref()
+            .map(|s| symbols.resolve(s))
+            .transpose()?;
+        let tracer = Self {
+            out: Arc::new(Mutex::new(std::io::stderr())),
+            machine_word: args.print_machine,
+            interp_address: args.interp_address,
+            align: true,
+            from,
+            to,
+            active: from.is_none(),
+            format: args.trace_format,
+            pending: None,
+        };
+        match &args.trace_file {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| format!("cannot create trace file `{}`: {}", path, e))?;
+                Ok(tracer.with_output(BufWriter::new(file)))
+            }
+            None => Ok(tracer),
+        }
+    }
+
+    /// Sends the trace to `out` instead of stderr
+    pub fn with_output(mut self, out: impl Write + Send + 'static) -> Self {
+        self.out = Arc::new(Mutex::new(out));
+        self
+    }
+
+    pub fn with_align(mut self, align: bool) -> Self {
+        self.align = align;
+        self
+    }
+
+    fn addr(&self, addr: u32, symbols: &Symbols) -> String {
+        match symbols.describe(addr, self.interp_address) {
+            Some(name) => format!("0x{:08x} <{}>", addr, name),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    /// Traces the instruction at `pc` which is about to be executed, along
+    /// with the address it accesses if it is a load or store, if it is within
+    /// the traced range. Nothing is printed until the instruction is retired,
+    /// when the registers it changed are known.
+    pub fn trace(
+        &mut self,
+        pc: u32,
+        word: u32,
+        inst: Instruction,
+        mem_addr: Option<u32>,
+        regs: Regs,
+        symbols: &Symbols,
+    ) {
+        if Some(pc) == self.from {
+            self.active = true;
+        }
+        if !self.active {
+            return;
+        }
+        if Some(pc) == self.to {
+            self.active = false;
+        }
+
+        let mut pending = Pending {
+            pc,
+            word,
+            inst,
+            mem_addr,
+            before: regs,
+            line: None,
+        };
+        if self.format == TraceFormat::Json {
+            self.pending = Some(pending);
+            return;
+        }
+
+        let mut line = format!("{}:", self.addr(pc, symbols));
+        if self.align {
+            line = format!("{:<width$}", line, width = ADDR_WIDTH);
+        }
+        line.push(' ');
+        if self.machine_word {
+            line.push_str(&format!("0x{:08x}  ", word));
+        }
+        line.push_str(&inst.to_string());
+        if let Some(addr) = mem_addr {
+            line.push_str(&format!(" ; {}", self.addr(addr, symbols)));
+        }
+        pending.line = Some(line);
+        self.pending = Some(pending);
+    }
+
+    /// Finishes tracing the last instruction passed to `trace`, given the
+    /// registers after it executed.
+    pub fn retire(&mut self, after: Regs) {
+        use crate::common::instruction::opcodes::*;
+
+        let Some(p) = self.pending.take() else {
+            return;
+        };
+        let mut writes = (1..32)
+            .filter(|r| p.before.gpr[*r] != after.gpr[*r])
+            .map(|r| {
+                (
+                    format!("${}", register_name(r as u8)),
+                    p.before.gpr[r],
+                    after.gpr[r],
+                )
+            })
+            .collect::<Vec<_>>();
+        if p.before.hi != after.hi {
+            writes.push((String::from("hi"), p.before.hi, after.hi));
+        }
+        if p.before.lo != after.lo {
+            writes.push((String::from("lo"), p.before.lo, after.lo));
+        }
+        if let Some(mut line) = p.line {
+            // e.g. `$t0: 0x0 -> 0x2a`
+            let deltas = writes
+                .iter()
+                .map(|(reg, old, new)| format!("{}: {:#x} -> {:#x}", reg, old, new))
+                .collect::<Vec<_>>();
+            if !deltas.is_empty() {
+                line.push_str(&format!(" ; {}", deltas.join(", ")));
+            }
+            let mut out = self.out.lock().unwrap();
+            let _ = writeln!(out, "{}", line);
+            return;
+        }
+        let writes = writes
+            .iter()
+            .map(|(reg, old, new)| {
+                format!(
+                    "{{\"reg\":{},\"old\":{},\"new\":{}}}",
+                    json_str(reg),
+                    old,
+                    new
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mem = match (p.inst, p.mem_addr) {
+            (Instruction::I { op, rt, .. }, Some(addr)) => {
+                let size: u32 = match op {
+                    OP_LB | OP_LBU | OP_SB => 1,
+                    OP_LH | OP_LHU | OP_SH => 2,
+                    _ => 4,
+                };
+                let mask = (u64::MAX >> (64 - size * 8)) as u32;
+                let (kind, value) = match op >= OP_SB {
+                    true => ("store", p.before.gpr[rt as usize] & mask),
+                    false => ("load", after.gpr[rt as usize]),
+                };
+                format!(
+                    "{{\"kind\":\"{}\",\"addr\":{},\"size\":{},\"value\":{}}}",
+                    kind, addr, size, value
+                )
+            }
+            _ => String::new(),
+        };
+
+        let line = format!(
+            "{{\"pc\":{},\"word\":{},\"mnemonic\":{},\"text\":{},\"writes\":[{}],\"mem\":[{}]}}",
+            p.pc,
+            p.word,
+            json_str(mnemonic(p.inst.encoding()).unwrap_or("unknown")),
+            json_str(&p.inst.to_string()),
+            writes.join(","),
+            mem
+        );
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+    }
+}
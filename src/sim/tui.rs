@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Stdout},
+};
+
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
+};
+
+use super::{condition::Condition, Exec};
+use crate::common::register_name;
+
+/// Lines kept of each of the message and program output panes
+const SCROLLBACK: usize = 100;
+/// Bytes shown on each line of the memory pane
+const MEMORY_LINE: u32 = 16;
+const MEMORY_LINES: u16 = 4;
+const OUTPUT_LINES: u16 = 4;
+const MESSAGE_LINES: u16 = 3;
+/// Width of the register pane, two registers to a line
+const REGS_WIDTH: u16 = 38;
+/// Lines of the register pane: pc, hi and lo, then two registers to a line
+const REGS_LINES: u16 = 17;
+const PROMPT: &str = "(rbug) ";
+
+/// The full-screen view of rbug (`-d --tui`): the code around the pc, the
+/// registers, a window of memory, the watch expressions, the program's
+/// output and the debugger's messages, redrawn after each command above the
+/// command line. Program output is kept in its pane rather than written to
+/// the terminal, which would scroll the view away.
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    messages: VecDeque<String>,
+    output: VecDeque<String>,
+    /// Output after the last newline, which is the start of a line still
+    /// being written
+    partial: String,
+    /// The address the memory pane starts at
+    pub memory: u32,
+}
+
+fn push(lines: &mut VecDeque<String>, line: String) {
+    lines.push_back(line);
+    if lines.len() > SCROLLBACK {
+        lines.pop_front();
+    }
+}
+
+/// A pane with its title on a line above it
+fn pane(title: &str) -> Block<'_> {
+    Block::new()
+        .borders(Borders::TOP)
+        .title(title)
+        .title_style(Style::new().add_modifier(Modifier::REVERSED))
+}
+
+/// The last `count` of `lines`
+fn tail<'a>(lines: impl ExactSizeIterator<Item = &'a str>, count: u16) -> Vec<Line<'a>> {
+    let skip = lines.len().saturating_sub(count as usize);
+    lines.skip(skip).map(Line::raw).collect()
+}
+
+impl Tui {
+    /// Fails if the terminal's size cannot be found, as when stdout is not a
+    /// terminal
+    pub fn new(exec: &Exec) -> io::Result<Self> {
+        Ok(Self {
+            terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?,
+            messages: VecDeque::new(),
+            output: VecDeque::new(),
+            partial: String::new(),
+            memory: exec.layout().data_start,
+        })
+    }
+
+    /// Adds lines to the message pane
+    pub fn say(&mut self, msg: &str) {
+        for line in msg.lines() {
+            push(&mut self.messages, line.to_string());
+        }
+    }
+
+    /// Moves the output the program has written since the last call into the
+    /// output pane
+    fn collect_output(&mut self, exec: &mut Exec) {
+        let mut out = exec.take_stdout();
+        out.extend(exec.take_stderr());
+        for c in String::from_utf8_lossy(&out).chars() {
+            match c {
+                '\n' => push(&mut self.output, std::mem::take(&mut self.partial)),
+                '\r' => {}
+                c => self.partial.push(c),
+            }
+        }
+    }
+
+    /// Redraws the screen, leaving the cursor on the command line
+    pub fn draw(
+        &mut self,
+        exec: &mut Exec,
+        breakpoints: &BTreeMap<u32, Option<Condition>>,
+        watches: &[(String, Result<u32, String>)],
+    ) {
+        self.collect_output(exec);
+        let mut output = self.output.iter().map(String::as_str).collect::<Vec<_>>();
+        if !self.partial.is_empty() {
+            output.push(&self.partial);
+        }
+        let output = tail(output.into_iter(), OUTPUT_LINES);
+        let messages = tail(self.messages.iter().map(String::as_str), MESSAGE_LINES);
+        let memory = self.memory;
+
+        // the command typed after the last draw has scrolled the screen, so
+        // all of it is drawn again
+        let _ = self.terminal.clear();
+        let _ = self.terminal.draw(|frame| {
+            let [code, mem, watch, out, msgs, command] = Layout::vertical([
+                // the register pane is never cut short
+                Constraint::Min(REGS_LINES + 1),
+                Constraint::Length(MEMORY_LINES + 1),
+                Constraint::Length(watches.len().max(1) as u16 + 1),
+                Constraint::Length(OUTPUT_LINES + 1),
+                Constraint::Length(MESSAGE_LINES),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+            draw_code(frame, code, exec, breakpoints);
+            draw_memory(frame, mem, exec, memory);
+            draw_watches(frame, watch, watches);
+            frame.render_widget(Paragraph::new(output).block(pane(" output")), out);
+            frame.render_widget(Paragraph::new(messages), msgs);
+            frame.render_widget(Paragraph::new(PROMPT), command);
+            frame.set_cursor_position((command.x + PROMPT.len() as u16, command.y));
+        });
+    }
+}
+
+/// Code on the left, a few instructions back from the pc, and the registers
+/// on the right, pc, hi and lo first
+fn draw_code(
+    frame: &mut Frame,
+    area: Rect,
+    exec: &Exec,
+    breakpoints: &BTreeMap<u32, Option<Condition>>,
+) {
+    let [code, regs] =
+        Layout::horizontal([Constraint::Min(0), Constraint::Length(REGS_WIDTH)]).areas(area);
+    let pc = exec.pc();
+    let lines = code.height.saturating_sub(1) as u32;
+    let start = pc.wrapping_sub(4 * (lines / 3));
+    let text = (0..lines)
+        .map(|i| {
+            let addr = start.wrapping_add(4 * i);
+            let mark = match (addr == pc, breakpoints.contains_key(&addr)) {
+                (true, true) => "*>",
+                (true, false) => " >",
+                (false, true) => "* ",
+                (false, false) => "  ",
+            };
+            let line = Line::raw(format!("{}{}", mark, exec.disassemble(addr)));
+            match addr == pc {
+                true => line.style(Style::new().add_modifier(Modifier::BOLD)),
+                false => line,
+            }
+        })
+        .collect::<Vec<_>>();
+    let title = format!(" code [{} steps]", exec.steps());
+    frame.render_widget(Paragraph::new(text).block(pane(&title)), code);
+
+    let values = exec.registers();
+    let mut text = vec![Line::raw(format!(
+        "pc {:08x}  hi {:08x}  lo {:08x}",
+        pc,
+        exec.hi(),
+        exec.lo()
+    ))];
+    text.extend((0..16).map(|r| {
+        Line::raw(format!(
+            "{:>4} {:08x}      {:>4} {:08x}",
+            register_name(r as u8),
+            values[r],
+            register_name(r as u8 + 16),
+            values[r + 16]
+        ))
+    }));
+    frame.render_widget(Paragraph::new(text).block(pane(" registers")), regs);
+}
+
+fn draw_memory(frame: &mut Frame, area: Rect, exec: &Exec, memory: u32) {
+    let text = (0..MEMORY_LINES as u32)
+        .map(|line| {
+            let addr = memory.wrapping_add(line * MEMORY_LINE);
+            let bytes = (0..MEMORY_LINE)
+                .map(|i| exec.read_byte(addr.wrapping_add(i)).ok())
+                .collect::<Vec<_>>();
+            let hex = bytes
+                .chunks(4)
+                .map(|word| {
+                    word.iter()
+                        .map(|b| b.map_or(String::from("--"), |b| format!("{:02x}", b)))
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|b| match b {
+                    Some(b) if b.is_ascii_graphic() || *b == b' ' => *b as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            Line::raw(format!("0x{:08x}  {}  {}", addr, hex, ascii))
+        })
+        .collect::<Vec<_>>();
+    let title = format!(" memory at 0x{:08x}", memory);
+    frame.render_widget(Paragraph::new(text).block(pane(&title)), area);
+}
+
+fn draw_watches(frame: &mut Frame, area: Rect, watches: &[(String, Result<u32, String>)]) {
+    let mut text = watches
+        .iter()
+        .enumerate()
+        .map(|(idx, (expr, value))| {
+            let value = match value {
+                Ok(v) => format!("0x{:08x} ({})", v, *v as i32),
+                Err(e) => e.clone(),
+            };
+            Line::raw(format!("  {}: {} = {}", idx + 1, expr, value))
+        })
+        .collect::<Vec<_>>();
+    if text.is_empty() {
+        text.push(Line::raw("  (none; add one with `watch EXPR`)"));
+    }
+    frame.render_widget(Paragraph::new(text).block(pane(" watch")), area);
+}